@@ -1,6 +1,6 @@
 use std::error::Error;
 
-use gdbstub::{arch, target::{Target, TargetResult, ext::{base::{ResumeAction, singlethread::{SingleThreadOps, StopReason}}, breakpoints::{HwBreakpoint, HwWatchpoint, SwBreakpoint, SwBreakpointOps}}}};
+use gdbstub::{arch, target::{Target, TargetResult, ext::{base::{ResumeAction, singlethread::{SingleThreadOps, StopReason}}, breakpoints::{HwBreakpoint, HwWatchpoint, SwBreakpoint, SwBreakpointOps, WatchKind}}}};
 use crate::{EmuMessage, EmuState, emu_loop_step};
 
 impl Target for EmuState {
@@ -119,7 +119,7 @@ impl SingleThreadOps for EmuState {
         data: &mut [u8],
     ) -> gdbstub::target::TargetResult<(), Self> {
         for i in 0..data.len() {
-            data[i] = self.emu.r3000.read_bus_byte(start_addr + i as u32);
+            data[i] = self.emu.read8(start_addr + i as u32);
         }
         Ok(())
     }
@@ -139,7 +139,7 @@ impl SingleThreadOps for EmuState {
 
 impl SwBreakpoint for EmuState {
     fn add_sw_breakpoint(&mut self, addr: u32) -> gdbstub::target::TargetResult<bool, Self> {
-        self.emu.add_sw_breakpoint(addr);
+        self.emu.add_breakpoint(addr);
         TargetResult::<bool, Self>::Ok(true)
     }
 
@@ -147,7 +147,7 @@ impl SwBreakpoint for EmuState {
         &mut self,
         addr: u32,
     ) -> gdbstub::target::TargetResult<bool, Self> {
-        self.emu.remove_sw_breakpoint(addr);
+        self.emu.remove_breakpoint(addr);
         TargetResult::<bool, Self>::Ok(true)
     }
 }
@@ -155,7 +155,7 @@ impl SwBreakpoint for EmuState {
 impl HwBreakpoint for EmuState {
     fn add_hw_breakpoint(&mut self, addr: u32) -> TargetResult<bool, Self> {
         println!("Set breakpoint");
-        self.emu.add_sw_breakpoint(addr);
+        self.emu.add_breakpoint(addr);
         TargetResult::<bool, Self>::Ok(true)
     }
 
@@ -163,7 +163,7 @@ impl HwBreakpoint for EmuState {
         &mut self,
         addr: u32,
     ) -> TargetResult<bool, Self> {
-        self.emu.remove_sw_breakpoint(addr);
+        self.emu.remove_breakpoint(addr);
         TargetResult::<bool, Self>::Ok(true)
     }
 }
@@ -174,8 +174,14 @@ impl HwWatchpoint for EmuState {
         addr: u32,
         kind: gdbstub::target::ext::breakpoints::WatchKind,
     ) -> TargetResult<bool, Self> {
-        println!("Trying to add watchpoint...");
-        self.emu.add_watchpoint(addr);
+        match kind {
+            WatchKind::Read => self.emu.add_read_watchpoint(addr),
+            WatchKind::Write => self.emu.add_write_watchpoint(addr),
+            WatchKind::ReadWrite => {
+                self.emu.add_read_watchpoint(addr);
+                self.emu.add_write_watchpoint(addr);
+            }
+        }
         TargetResult::<bool, Self>::Ok(true)
     }
 
@@ -184,7 +190,14 @@ impl HwWatchpoint for EmuState {
         addr: u32,
         kind: gdbstub::target::ext::breakpoints::WatchKind,
     ) -> TargetResult<bool, Self> {
-        self.emu.remove_watchpoint(addr);
+        match kind {
+            WatchKind::Read => self.emu.remove_read_watchpoint(addr),
+            WatchKind::Write => self.emu.remove_write_watchpoint(addr),
+            WatchKind::ReadWrite => {
+                self.emu.remove_read_watchpoint(addr);
+                self.emu.remove_write_watchpoint(addr);
+            }
+        }
         TargetResult::<bool, Self>::Ok(true)
     }
 }
\ No newline at end of file
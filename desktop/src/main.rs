@@ -1,7 +1,7 @@
 use byteorder::{ByteOrder, LittleEndian};
-use disc::*;
 use gdbstub::{DisconnectReason, GdbStub, GdbStubError};
 use getopts::Options;
+use psx_emu::cdrom::disc;
 use psx_emu::controller::ButtonState;
 use psx_emu::gpu::Resolution;
 use psx_emu::PSXEmu;
@@ -19,7 +19,6 @@ use std::time::SystemTime;
 use std::time::Duration;
 use simple_logger::SimpleLogger;
 
-mod disc;
 mod gdb;
 mod gui;
 mod support;
@@ -78,7 +77,13 @@ fn main() {
         }
     };
 
-    let mut emu = PSXEmu::new(bios_data);
+    let mut emu = match PSXEmu::new(bios_data) {
+        Ok(emu) => emu,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
     emu.reset();
 
     if matches.opt_present("l") {
@@ -93,8 +98,13 @@ fn main() {
 
     if let Some(disc_path) = matches.opt_str("c") {
         println!("Loading CUE: {}", disc_path);
-        let disc = load_disc_from_cuesheet(Path::new(&disc_path).to_path_buf());
-        emu.load_disc(disc);
+        match disc::open(Path::new(&disc_path)) {
+            Ok(disc) => emu.load_disc(disc),
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        }
     }
 
     if let Some(exe_path) = matches.opt_str("e") {
@@ -252,10 +262,10 @@ fn emu_loop_step(state: &mut EmuState) -> Result<(), EmuThreadError> {
                 state.halted = false;
                 state.emu.clear_halt();
             }
-            EmuMessage::AddBreakpoint(addr) => state.emu.add_sw_breakpoint(addr),
-            EmuMessage::RemoveBreakpoint(addr) => state.emu.remove_sw_breakpoint(addr),
+            EmuMessage::AddBreakpoint(addr) => state.emu.add_breakpoint(addr),
+            EmuMessage::RemoveBreakpoint(addr) => state.emu.remove_breakpoint(addr),
             EmuMessage::Kill => return Err(EmuThreadError::Killed),
-            EmuMessage::StepCPU => state.emu.run_cpu_cycle(), // Warning! Doing this too many times will desync the gpu
+            EmuMessage::StepCPU => state.emu.run_cpu_cycle(),
             EmuMessage::UpdateControllers(button_state) => {
                 state.emu.update_controller_state(button_state)
             }
@@ -0,0 +1,57 @@
+use std::collections::VecDeque;
+
+/// Sample rate of every frame in the output queue
+pub const SAMPLE_RATE: u32 = 44100;
+//One second of audio. The oldest frames are dropped if the frontend doesn't keep up
+const CAPACITY: usize = SAMPLE_RATE as usize;
+
+/// Stereo frames waiting for the frontend. The SPU pushes its final mix in here
+pub(crate) struct AudioQueue {
+    frames: VecDeque<(i16, i16)>,
+}
+
+impl AudioQueue {
+    pub(crate) fn new() -> Self {
+        Self {
+            frames: VecDeque::with_capacity(CAPACITY),
+        }
+    }
+
+    pub(crate) fn push(&mut self, frames: &[(i16, i16)]) {
+        self.frames.extend(frames.iter());
+        let overflow = self.frames.len().saturating_sub(CAPACITY);
+        self.frames.drain(..overflow);
+    }
+
+    /// Moves every queued frame onto the end of out as interleaved left, right samples
+    pub(crate) fn drain_into(&mut self, out: &mut Vec<i16>) {
+        out.reserve(self.frames.len() * 2);
+        for (left, right) in self.frames.drain(..) {
+            out.push(left);
+            out.push(right);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drain_in_order() {
+        let mut queue = AudioQueue::new();
+        queue.push(&[(1, -1), (2, -2)]);
+        queue.push(&[(3, -3)]);
+        let mut out = vec![0];
+        queue.drain_into(&mut out);
+        assert_eq!(out, vec![0, 1, -1, 2, -2, 3, -3]);
+
+        //Overflowing keeps the newest second of audio
+        let frames: Vec<(i16, i16)> = (0..CAPACITY + 10).map(|i| (i as i16, 0)).collect();
+        queue.push(&frames);
+        out.clear();
+        queue.drain_into(&mut out);
+        assert_eq!(out.len(), CAPACITY * 2);
+        assert_eq!(out[0], 10);
+    }
+}
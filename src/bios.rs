@@ -1,12 +1,46 @@
 use byteorder::{ByteOrder, LittleEndian};
+use log::{info, warn};
+
+use crate::error::EmuError;
+
+const LOG_TARGET: &str = "vaporstation::bios";
+
+/// Every PS1 bios is a 512KB rom
+pub(crate) const BIOS_SIZE: usize = 512 * 1024;
+
+//CRC32s of the common retail dumps
+const KNOWN_BIOSES: [(u32, &str); 9] = [
+    (0x3B60_1FC8, "SCPH-1000 (v1.0 J)"),
+    (0x9BB8_7C4B, "SCPH-1002 (v2.0 E)"),
+    (0x3715_7331, "SCPH-1001 (v2.2 A)"),
+    (0xFF3E_EB8C, "SCPH-5500 (v3.0 J)"),
+    (0x8D8C_B7E4, "SCPH-5501 (v3.0 A)"),
+    (0xD786_F0B9, "SCPH-5502 (v3.0 E)"),
+    (0x5022_24B6, "SCPH-7001 (v4.1 A)"),
+    (0x3181_78BF, "SCPH-7502 (v4.1 E)"),
+    (0x171B_DCEC, "SCPH-101 (v4.5 A)"),
+];
 
 pub struct Bios {
     data: Vec<u8>,
 }
 
 impl Bios {
-    pub fn new(data: Vec<u8>) -> Bios {
-        Bios { data }
+    pub fn new(data: Vec<u8>) -> Result<Bios, EmuError> {
+        if data.len() != BIOS_SIZE {
+            return Err(EmuError::BiosWrongSize(data.len()));
+        }
+        //Anything the right size is allowed to run, but a bad dump is the usual reason nothing boots
+        let crc = crc32(&data);
+        match KNOWN_BIOSES.iter().find(|(known, _)| *known == crc) {
+            Some((_, model)) => info!(target: LOG_TARGET, "BIOS: {}", model),
+            None => warn!(
+                target: LOG_TARGET,
+                "Unrecognized BIOS (CRC32 {:08X}). If nothing boots, check it's a dump of a real PS1 BIOS",
+                crc
+            ),
+        }
+        Ok(Bios { data })
     }
 
     pub fn read_word(&self, addr: u32) -> u32 {
@@ -21,3 +55,30 @@ impl Bios {
         &self.data
     }
 }
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut table = [0; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        *entry = (0..8).fold(i as u32, |crc, _| match crc & 1 {
+            0 => crc >> 1,
+            _ => (crc >> 1) ^ 0xEDB8_8320,
+        });
+    }
+    !data.iter().fold(!0, |crc, byte| (crc >> 8) ^ table[((crc ^ *byte as u32) & 0xFF) as usize])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_log;
+    use log::Level;
+
+    #[test]
+    fn test_unrecognized_bios_warns() {
+        test_log::capture();
+        assert!(Bios::new(vec![0; BIOS_SIZE]).is_ok());
+        assert!(test_log::logged(LOG_TARGET, Level::Warn, "Unrecognized BIOS (CRC32 75660AAC)"));
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert!(matches!(Bios::new(vec![0; 1024]), Err(EmuError::BiosWrongSize(1024))));
+    }
+}
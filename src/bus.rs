@@ -1,3 +1,5 @@
+use std::ops::RangeInclusive;
+
 use log::{error, info, warn};
 
 use crate::bios::Bios;
@@ -8,15 +10,82 @@ use crate::gpu::Gpu;
 use crate::memory::Memory;
 use crate::spu::SPU;
 
+/// Extra cycles the cpu stalls for when reading from each region. Writes go through the write queue and don't stall
+const RAM_WAIT_CYCLES: u32 = 4;
+const BIOS_WAIT_CYCLES: u32 = 20;
+const IO_WAIT_CYCLES: u32 = 2;
+
+//Masks from each 512MB segment of the virtual address space to physical addresses.
+//KUSEG is mapped as is, KSEG0 and KSEG1 mirror the first 512MB, and KSEG2 only holds the cache control registers
+const SEGMENT_MASKS: [u32; 8] = [
+    0xFFFF_FFFF, 0xFFFF_FFFF, 0xFFFF_FFFF, 0xFFFF_FFFF, //KUSEG
+    0x7FFF_FFFF, //KSEG0
+    0x1FFF_FFFF, //KSEG1
+    0xFFFF_FFFF, 0xFFFF_FFFF, //KSEG2
+];
+
+//The 2MB of RAM shows up 4 times over the first 8MB
+const RAM_MIRROR_MASK: u32 = 0x1F_FFFF;
+const SCRATCHPAD_BASE: u32 = 0x1F80_0000;
+const SCRATCHPAD_SIZE: u32 = 1024;
+const BIOS_BASE: u32 = 0x1FC0_0000;
+//Expansion region 1, where a cartridge on the parallel port shows up
+const EXP1_BASE: u32 = 0x1F00_0000;
+const EXP1_END: u32 = 0x1F7F_FFFF;
+
+/// Physical address a virtual address maps to
+pub(crate) fn physical_address(addr: u32) -> u32 {
+    addr & SEGMENT_MASKS[(addr >> 29) as usize]
+}
+
+/// Regions only read a word at a time. Narrower reads get their part of the word they're in
+fn is_word_wide(addr: u32) -> bool {
+    matches!(
+        addr,
+        0x1F80_1000..=0x1F80_1023 //Memory control
+            | 0x1F80_1060..=0x1F80_1063 //RAM size
+            | 0x1F80_1080..=0x1F80_10F7 //DMA
+            | 0x1F80_1810..=0x1F80_1817 //GPU
+            | 0x1FC0_0000..=0x1FC7_FFFF //BIOS
+    )
+}
+
+/// The hardware registers. What the bus log covers unless it's given another range
+pub const IO_REGION: RangeInclusive<u32> = 0x1F80_1000..=0x1F80_2FFF;
+
+/// A cpu load or store, as recorded by the bus log
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BusAccess {
+    /// Address of the instruction that made the access
+    pub pc: u32,
+    /// Physical address accessed
+    pub addr: u32,
+    pub value: u32,
+    /// Access size in bytes
+    pub width: u8,
+    pub write: bool,
+}
+
+struct BusLog {
+    range: RangeInclusive<u32>,
+    entries: Vec<BusAccess>,
+}
+
+pub(crate) fn is_scratchpad(addr: u32) -> bool {
+    (SCRATCHPAD_BASE..SCRATCHPAD_BASE + SCRATCHPAD_SIZE).contains(&physical_address(addr))
+}
+
 pub struct MainBus {
     pub bios: Bios,
     pub memory: Memory,
     pub gpu: Gpu,
     pub dma: DMAState,
-    spu: SPU,
+    pub(crate) spu: SPU,
     pub cd_drive: CDDrive,
-    scratchpad: Memory,
+    pub(crate) scratchpad: Memory,
     pub(super) controllers: Controllers,
+    exp1_rom: Option<Vec<u8>>,
+    bus_log: Option<BusLog>,
 }
 
 impl MainBus {
@@ -30,40 +99,97 @@ impl MainBus {
             cd_drive: CDDrive::new(),
             scratchpad: Memory::new_scratchpad(),
             controllers: Controllers::new(),
+            exp1_rom: None,
+            bus_log: None,
+        }
+    }
+
+    /// A bus with a blank bios and nothing attached, for unit tests
+    #[cfg(test)]
+    pub(crate) fn for_test() -> MainBus {
+        MainBus::new(Bios::new(vec![0; 512 * 1024]).unwrap(), Memory::new(), Gpu::new())
+    }
+
+    /// Starts recording cpu accesses to physical addresses in range, or stops with None. Anything already recorded is kept
+    pub fn set_bus_log(&mut self, range: Option<RangeInclusive<u32>>) {
+        self.bus_log = range.map(|range| BusLog {
+            range,
+            entries: self.bus_log.take().map(|log| log.entries).unwrap_or_default(),
+        });
+    }
+
+    /// Accesses recorded since the last take, oldest first
+    pub fn take_bus_log(&mut self) -> Vec<BusAccess> {
+        self.bus_log.as_mut().map(|log| std::mem::take(&mut log.entries)).unwrap_or_default()
+    }
+
+    pub(crate) fn bus_log_enabled(&self) -> bool {
+        self.bus_log.is_some()
+    }
+
+    pub(crate) fn log_access(&mut self, access: BusAccess) {
+        if let Some(log) = &mut self.bus_log {
+            if log.range.contains(&access.addr) {
+                log.entries.push(access);
+            }
+        }
+    }
+
+    /// Plugs a rom into the parallel port. Without one, expansion region 1 reads as open bus
+    pub fn attach_exp1_rom(&mut self, rom: Vec<u8>) {
+        self.exp1_rom = Some(rom);
+    }
+
+    /// Little endian read of `width` bytes from expansion region 1. Anything past the end of the rom is open bus
+    fn read_exp1(&self, addr: u32, width: u32) -> u32 {
+        (0..width).fold(0, |value, i| {
+            let offset = (addr - EXP1_BASE + i) as usize;
+            let byte = self.exp1_rom.as_ref().and_then(|rom| rom.get(offset)).copied().unwrap_or(0xFF);
+            value | ((byte as u32) << (i * 8))
+        })
+    }
+
+    /// Wait states added to an uncached read from the given address
+    pub fn read_wait_cycles(&self, og_addr: u32) -> u32 {
+        match physical_address(og_addr) {
+            0x0..=0x007f_ffff => RAM_WAIT_CYCLES,
+            0x1F800000..=0x1F8003FF => 0, //Scratchpad is on chip
+            0x1fc0_0000..=0x1fc7_ffff => BIOS_WAIT_CYCLES,
+            _ => IO_WAIT_CYCLES,
         }
     }
 
     pub fn read_word(&mut self, og_addr: u32) -> u32 {
-        let addr = og_addr & 0x1fffffff;
+        let addr = physical_address(og_addr);
         let word = match addr {
-            0x0..=0x001f_ffff => self.memory.read_word(addr),
+            0x0..=0x007f_ffff => self.memory.read_word(addr & RAM_MIRROR_MASK),
             0x1f801810 => self.gpu.read_word_gp0(),
             0x1f801814 => self.gpu.read_status_register(),
             0x1F80101C => 0x00070777, //Expansion 2 delay/size
             0x1F801080..=0x1F8010F4 => self.dma.read_word(addr),
-            0x1fc0_0000..=0x1fc7_ffff => self.bios.read_word(addr - 0x1fc0_0000),
-            0x1F800000..=0x1F8003FF => self.scratchpad.read_word(addr - 0x1F800000),
+            0x1fc0_0000..=0x1fc7_ffff => self.bios.read_word(addr - BIOS_BASE),
+            0x1F800000..=0x1F8003FF => self.scratchpad.read_word(addr - SCRATCHPAD_BASE),
             0x1F801014 => 0x200931E1, //SPU_DELAY
             0x1F801060 => 0x00000B88, //RAM_SIZE
-            _ => panic!(
-                "Invalid word read at address {:#X}! This address is not mapped to any device.",
-                addr
-            ),
+            0x1F801C00..=0x1F801FFF => self.spu.read_half_word(addr) as u32 | (self.spu.read_half_word(addr + 2) as u32) << 16,
+            EXP1_BASE..=EXP1_END => self.read_exp1(addr, 4),
+            _ => {
+                error!("Invalid word read at address {:#X}! This address is not mapped to any device.", og_addr);
+                0
+            }
         };
-        //println!("Read {:#X} word from bus address {:#X}", word, addr);
         word
     }
 
     pub fn write_word(&mut self, og_addr: u32, word: u32) {
-        let addr = og_addr & 0x1fffffff;
-        //println!("Writing {:#X} to addr {:#X}", word, addr);
-        if addr == 0xCAF50 {println!("Hit the thing {:#X}", word)};
-        match addr & 0x1fffffff {
+        let addr = physical_address(og_addr);
+        match addr {
             0x1F802002 => info!("Serial: {}", word),
             0x1F802023 => info!("DUART A: {}", word),
             0x1F80202B => info!("DUART B: {}", word),
             0x1F801050 => info!("SIO: {}", word),
-            0x0..=0x001f_ffff => self.memory.write_word(addr, word), //KUSEG
+            0x0..=0x007f_ffff => self.memory.write_word(addr & RAM_MIRROR_MASK, word),
+            EXP1_BASE..=EXP1_END => (), //Rom, or nothing at all
             0x1F801000 => info!("Expansion 1 base write"),
             0x1F801004 => info!("Expansion 2 base write"),
             0x1F801008 => info!("Expansion 1 delay/size write"),
@@ -77,56 +203,63 @@ impl MainBus {
             0x1F80100C => info!("Expansion 3 Delay/size write"),
             0x1F801810 => self.gpu.send_gp0_command(word),
             0x1F801814 => self.gpu.send_gp1_command(word),
-            0x1F800000..=0x1F8003FF => self.scratchpad.write_word(addr - 0x1F800000, word),
-            0x1f80_1000..=0x1f80_2fff => warn!("Something tried to write to the hardware control registers. These are not currently emulated. The address was {:#X}. Value {:#X}", addr, word),
-            0x1FFE0000..=0x1FFE0200 => warn!("Something tried to write to the cache control registers. These are not currently emulated. The address was {:#X}", addr),
-            _ => {
-                panic!(
-                    "Invalid word write at address {:#X}! This address is not mapped to any device.",
-                    addr
-                );
+            0x1F800000..=0x1F8003FF => self.scratchpad.write_word(addr - SCRATCHPAD_BASE, word),
+            0x1F801C00..=0x1F801FFF => {
+                self.spu.write_half_word(addr, word as u16);
+                self.spu.write_half_word(addr + 2, (word >> 16) as u16);
             }
+            0x1f80_1000..=0x1f80_2fff => warn!("Something tried to write to the hardware control registers. These are not currently emulated. The address was {:#X}. Value {:#X}", addr, word),
+            0xFFFE0000..=0xFFFE0200 => warn!("Something tried to write to the cache control registers. These are not currently emulated. The address was {:#X}", addr),
+            _ => error!(
+                "Invalid word write at address {:#X}! This address is not mapped to any device. Value {:#X}",
+                og_addr, word
+            ),
         }
     }
 
     pub fn read_half_word(&mut self, og_addr: u32) -> u16 {
-        let addr = og_addr & 0x1fffffff;
+        let addr = physical_address(og_addr);
 
 
-        match addr & 0x1fffffff {
-            0x1F801070 => {
-                panic!("Tried to read i_status half");
-            },
-            0x0..=0x001f_ffff => self.memory.read_half_word(addr),
-            0x1F801C00..=0x1F801E80 => self.spu.read_half_word(addr),
-            0x1F800000..=0x1F8003FF => self.scratchpad.read_half_word(addr - 0x1F800000),
+        match addr {
+            0x0..=0x007f_ffff => self.memory.read_half_word(addr & RAM_MIRROR_MASK),
+            0x1F801C00..=0x1F801FFF => self.spu.read_half_word(addr),
+            0x1F800000..=0x1F8003FF => self.scratchpad.read_half_word(addr - SCRATCHPAD_BASE),
             0x1F80_1040..=0x1F80_104E => self.controllers.read_half_word(addr),
-            _ => panic!("Invalid half word read at address {:#X}! This address is not mapped to any device.", addr)
+            EXP1_BASE..=EXP1_END => self.read_exp1(addr, 2) as u16,
+            addr if is_word_wide(addr) => (self.read_word_lane(addr) >> ((addr & 2) * 8)) as u16,
+            _ => {
+                error!("Invalid half word read at address {:#X}! This address is not mapped to any device.", og_addr);
+                0
+            }
         }
     }
 
     pub fn write_half_word(&mut self, og_addr: u32, value: u16) {
-        let addr = og_addr & 0x1fffffff;
-        if addr == 0x7F10 {println!("Hit the thing half")};
-
+        let addr = physical_address(og_addr);
 
-        match addr & 0x1fffffff {
+        match addr {
             0x1F802002 => info!("Serial: {}", value),
             0x1F802023 => info!("DUART A: {}", value),
             0x1F80202B => info!("DUART B: {}", value),
             0x1F801050 => info!("SIO: {}", value),
-            0x0..=0x001f_ffff => self.memory.write_half_word(addr, value), //KUSEG
-            0x1F801C00..=0x1F801E80 => self.spu.write_half_word(addr, value),
-            0x1F800000..=0x1F8003FF => self.scratchpad.write_half_word(addr - 0x1F800000, value),
+            0x0..=0x007f_ffff => self.memory.write_half_word(addr & RAM_MIRROR_MASK, value),
+            EXP1_BASE..=EXP1_END => (),
+            0x1F801C00..=0x1F801FFF => self.spu.write_half_word(addr, value),
+            0x1F800000..=0x1F8003FF => self.scratchpad.write_half_word(addr - SCRATCHPAD_BASE, value),
             0x1F80_1040..=0x1F80_104E => self.controllers.write_half_word(addr, value),
+            addr if is_word_wide(addr) && addr < BIOS_BASE => self.write_word_lane(addr, value as u32, 0xFFFF),
             0x1F80_1000..=0x1F80_2000 => warn!("Something tried to half word write to the I/O ports. This is not currently emulated. The address was {:#X}. value was {:#X}", addr, value),
-            _ => panic!("Invalid half word write at address {:#X}! This address is not mapped to any device.", addr)
+            _ => error!(
+                "Invalid half word write at address {:#X}! This address is not mapped to any device. Value {:#X}",
+                og_addr, value
+            ),
         }
     }
 
     pub fn read_byte(&mut self, og_addr: u32) -> u8 {
-        let addr = og_addr & 0x1fffffff;
-        match addr & 0x1fffffff {
+        let addr = physical_address(og_addr);
+        match addr {
             0x1F801070 => {
                 warn!("Tried to read i_status word");
                 0
@@ -135,43 +268,151 @@ impl MainBus {
                 warn!("Tried to read i_mask byte");
                 0
             }
-            0x0..=0x001f_ffff => self.memory.read_byte(addr), //KUSEG
-            0x1F00_0000..=0x1f00_FFFF => {
-                //println!("Something tried to read the parallel port. This is not currently emulated, so a 0 was returned. The address was {:#X}", addr);
-                0
-            }
-            0x1fc0_0000..=0x1fc7_ffff => self.bios.read_byte(addr - 0x1fc0_0000),
+            0x0..=0x007f_ffff => self.memory.read_byte(addr & RAM_MIRROR_MASK),
+            EXP1_BASE..=EXP1_END => self.read_exp1(addr, 1) as u8,
+            0x1fc0_0000..=0x1fc7_ffff => self.bios.read_byte(addr - BIOS_BASE),
             0x1F801800..=0x1F801803 => self.cd_drive.read_byte(addr), //CDROM
             0x1F80_1040..=0x1F80_104E => self.controllers.read_byte(addr),
-            0x1F800000..=0x1F8003FF => self.scratchpad.read_byte(addr - 0x1F800000),
+            0x1F800000..=0x1F8003FF => self.scratchpad.read_byte(addr - SCRATCHPAD_BASE),
+            0x1F801C00..=0x1F801FFF => (self.spu.read_half_word(addr & !1) >> ((addr & 1) * 8)) as u8,
+            addr if is_word_wide(addr) => (self.read_word_lane(addr) >> ((addr & 3) * 8)) as u8,
             _ => {
-                error!(
-                    "Invalid byte read at address {:#X}! This address is not mapped to any device.",
-                    addr
-                );
+                error!("Invalid byte read at address {:#X}! This address is not mapped to any device.", og_addr);
                 0
             }
         }
     }
 
+    /// The word a narrow read of a word wide register takes its lane from. Only the lowest lane of GPUREAD
+    /// reads the port, the rest reuse that word so a byte by byte read doesn't pull several words out of the gpu
+    fn read_word_lane(&mut self, addr: u32) -> u32 {
+        match addr {
+            0x1F80_1811..=0x1F80_1813 => self.gpu.gpuread_latch(),
+            _ => self.read_word(addr & !3),
+        }
+    }
+
+    /// Narrow writes to word wide registers replace their lane and keep the rest of the word.
+    /// GP0/GP1 read back as other registers and memory control isn't kept, so their other lanes are zero
+    fn write_word_lane(&mut self, addr: u32, value: u32, mask: u32) {
+        let word_addr = addr & !3;
+        let shift = (addr & 3) * 8;
+        let current = match word_addr {
+            //DICR's flags are write one to clear, so writing them back would acknowledge them
+            0x1F80_10F4 => self.dma.read_word(word_addr) & 0x00FF_FFFF,
+            0x1F80_1080..=0x1F80_10F0 => self.dma.read_word(word_addr),
+            _ => 0,
+        };
+        self.write_word(word_addr, (current & !(mask << shift)) | ((value & mask) << shift));
+    }
+
     pub fn write_byte(&mut self, og_addr: u32, value: u8) {
-        let addr = og_addr & 0x1fffffff;
-        if addr == 0x7F10 {println!("Hit the thing byte")};
+        let addr = physical_address(og_addr);
 
-        match addr & 0x1fffffff {
-            0x0..=0x001f_ffff => self.memory.write_byte(addr, value), //KUSEG
+        match addr {
+            0x0..=0x007f_ffff => self.memory.write_byte(addr & RAM_MIRROR_MASK, value),
             0x1F801800..=0x1F801803 => self.cd_drive.write_byte(addr, value), //CDROM
             0x1F802002 => info!("Serial: {}", value),
             0x1F802023 => info!("DUART A: {}", value),
             0x1F80202B => info!("DUART B: {}", value),
             0x1F801050 => info!("SIO: {}", value),
             0x1F802000..=0x1F803000 => (), //Expansion port 2
+            EXP1_BASE..=EXP1_END => (),
             0x1F801040 => self.controllers.write_byte(addr, value),
-            0x1F800000..=0x1F8003FF => self.scratchpad.write_byte(addr - 0x1F800000, value),
+            0x1F800000..=0x1F8003FF => self.scratchpad.write_byte(addr - SCRATCHPAD_BASE, value),
+            addr if is_word_wide(addr) && addr < BIOS_BASE => self.write_word_lane(addr, value as u32, 0xFF),
             _ => error!(
-                "Invalid byte write at address {:#X}! This address is not mapped to any device.",
-                addr
+                "Invalid byte write at address {:#X}! This address is not mapped to any device. Value {:#X}",
+                og_addr, value
             ),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_bus() -> MainBus {
+        MainBus::for_test()
+    }
+
+    #[test]
+    fn test_segment_mirroring() {
+        let mut bus = test_bus();
+        bus.write_word(0x0000_1000, 0xDEAD_BEEF);
+        //KUSEG, KSEG0 and KSEG1, and the RAM mirrors up to 8MB
+        for addr in [0x0000_1000, 0x8000_1000, 0xA000_1000, 0x0020_1000, 0x8060_1000] {
+            assert_eq!(bus.read_word(addr), 0xDEAD_BEEF, "{:#X}", addr);
+        }
+        bus.write_byte(0xA000_1003, 0x12);
+        assert_eq!(bus.read_half_word(0x8000_1002), 0x12AD);
+
+        assert_eq!(physical_address(0xBFC0_0000), 0x1FC0_0000);
+        assert_eq!(physical_address(0xFFFE_0130), 0xFFFE_0130);
+
+        //Unmapped accesses are logged, not fatal
+        assert_eq!(bus.read_word(0x1F00_F000 | 0x4000_0000), 0);
+        bus.write_half_word(0x1E00_0000, 1);
+    }
+
+    #[test]
+    fn test_narrow_io_reads() {
+        let mut bus = test_bus();
+        let status = bus.read_word(0x1F80_1814);
+        for byte in 0..4 {
+            assert_eq!(bus.read_byte(0x1F80_1814 + byte), (status >> (byte * 8)) as u8);
+        }
+        assert_eq!(bus.read_half_word(0xBF80_1816), (status >> 16) as u16);
+
+        bus.write_word(0x1F80_10F0, 0x1234_5678); //DPCR
+        assert_eq!(bus.read_half_word(0x1F80_10F2), 0x1234);
+        assert_eq!(bus.read_byte(0x1F80_10F1), 0x56);
+        assert_eq!(bus.read_byte(0x1F80_1061), 0x0B);
+
+        bus.gpu.send_gp1_command(0x1000_0007); //Latch the gpu version
+        assert_eq!(bus.read_byte(0x1F80_1810), 2);
+        assert_eq!(bus.read_byte(0x1F80_1811), 0);
+    }
+
+    #[test]
+    fn test_narrow_io_writes() {
+        let mut bus = test_bus();
+        bus.write_word(0x1F80_10A0, 0x0012_3456); //DMA2 base address
+        bus.write_byte(0x1F80_10A1, 0xAB);
+        assert_eq!(bus.read_word(0x1F80_10A0), 0x0012_AB56);
+        bus.write_half_word(0x1F80_10A2, 0x0008);
+        assert_eq!(bus.read_word(0x1F80_10A0), 0x0008_AB56);
+
+        bus.write_word(0x1F80_10F4, 0x0000_0012); //DICR
+        bus.write_byte(0x1F80_10F6, 0x7F);
+        assert_eq!(bus.read_word(0x1F80_10F4), 0x007F_0012);
+    }
+
+    #[test]
+    fn test_exp1_rom() {
+        let mut bus = test_bus();
+        //Open bus with nothing attached
+        assert_eq!(bus.read_byte(0x1F00_0000), 0xFF);
+        assert_eq!(bus.read_word(0x1F00_0084), 0xFFFF_FFFF);
+        bus.write_word(0x1F00_0000, 0);
+
+        bus.attach_exp1_rom(vec![0x12, 0x34, 0x56]);
+        assert_eq!(bus.read_byte(0xBF00_0000), 0x12);
+        assert_eq!(bus.read_half_word(0x1F00_0000), 0x3412);
+        assert_eq!(bus.read_word(0x1F00_0000), 0xFF56_3412);
+    }
+
+    #[test]
+    fn test_scratchpad() {
+        let mut bus = test_bus();
+        bus.write_word(0x1F80_0004, 0x1234_5678);
+        assert_eq!(bus.read_word(0x1F80_0004), 0x1234_5678);
+        assert_eq!(bus.read_half_word(0x9F80_0006), 0x1234);
+        assert_eq!(bus.read_byte(0x1F80_0004), 0x78);
+        //Separate from RAM
+        assert_eq!(bus.read_word(0x0000_0004), 0);
+        assert!(is_scratchpad(0x1F80_03FF));
+        assert!(!is_scratchpad(0x1F80_0400));
+    }
+}
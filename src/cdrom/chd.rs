@@ -0,0 +1,811 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use byteorder::{BigEndian, ByteOrder};
+use log::error;
+
+use super::disc::{
+    missing_sector, region_from_license, Disc, DiscIndex, Region, TrackType, BYTES_PER_SECTOR, LEAD_IN_SECTORS, LICENSE_SECTOR,
+    MODE_OFFSET, SECTORS_PER_SECOND, SYNC_SIZE,
+};
+use super::inflate::inflate;
+use super::{flac, lzma};
+use super::LOG_TARGET;
+use crate::error::EmuError;
+
+const HEADER_TAG: &[u8] = b"MComprHD";
+const HEADER_SIZE: usize = 124;
+const VERSION: u32 = 5;
+//Codecs and metadata are tagged with four characters
+const CODEC_NONE: u32 = 0;
+const CODEC_CD_ZLIB: u32 = u32::from_be_bytes(*b"cdzl");
+const CODEC_CD_LZMA: u32 = u32::from_be_bytes(*b"cdlz");
+const CODEC_CD_FLAC: u32 = u32::from_be_bytes(*b"cdfl");
+const TRACK_METADATA: u32 = u32::from_be_bytes(*b"CHT2");
+const OLD_TRACK_METADATA: u32 = u32::from_be_bytes(*b"CHTR");
+//Guards against metadata entries that link back on themselves
+const MAX_METADATA_ENTRIES: usize = 1024;
+
+//Each frame is a sector followed by its 96 bytes of subcode
+const SUBCODE_SIZE: usize = 96;
+const FRAME_SIZE: usize = BYTES_PER_SECTOR + SUBCODE_SIZE;
+//Tracks are padded out to a multiple of 4 frames
+const TRACK_PADDING: usize = 4;
+const CACHED_HUNKS: usize = 8;
+//A full 99 minute disc. Anything bigger comes from a corrupt header
+const MAX_FRAMES: u64 = 99 * 60 * SECTORS_PER_SECOND as u64;
+
+//Hunk types in the compressed map. 0-3 are compressed with that codec from the header
+const COMPRESSION_NONE: u8 = 4;
+const COMPRESSION_SELF: u8 = 5;
+const COMPRESSION_PARENT: u8 = 6;
+const COMPRESSION_RLE_SMALL: u8 = 7;
+const COMPRESSION_RLE_LARGE: u8 = 8;
+const COMPRESSION_SELF_0: u8 = 9;
+const COMPRESSION_SELF_1: u8 = 10;
+const COMPRESSION_PARENT_SELF: u8 = 11;
+const COMPRESSION_PARENT_1: u8 = 13;
+//Length of each map entry as it's checksummed
+const MAP_ENTRY_SIZE: usize = 12;
+
+const SYNC_HEADER: [u8; SYNC_SIZE] = [0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
+//Parity bytes at the end of mode 1 and mode 2 form 1 sectors
+const ECC_P_OFFSET: usize = 2076;
+const ECC_Q_OFFSET: usize = 2248;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Hunk {
+    Compressed { codec: u32, offset: u64, length: usize },
+    Uncompressed { offset: u64 },
+    //Same data as an earlier hunk
+    Copy(usize),
+    //Never written, so all zero
+    Zero,
+}
+
+struct ChdTrack {
+    track_type: TrackType,
+    //Sectors in the image, pregap included when the image holds it
+    frames: usize,
+    pregap: usize,
+    //Frame the track starts at in the image, after the padding of the tracks before it
+    first_frame: usize,
+}
+
+/// Reads hunks from the image, keeping the most recently used ones decompressed
+struct HunkReader {
+    file: File,
+    //Most recently used first
+    cache: VecDeque<(usize, Vec<u8>)>,
+}
+
+/// A compressed MAME CHD (version 5) cd image. Hunks of a few frames each are decompressed
+/// as they're read. The cdzl (deflate), cdlz (LZMA) and cdfl (FLAC) codecs chdman uses by default are supported
+pub struct ChdDisc {
+    title: String,
+    region: Region,
+    tracks: Vec<ChdTrack>,
+    hunk_bytes: usize,
+    map: Vec<Hunk>,
+    reader: RefCell<HunkReader>,
+}
+
+impl ChdDisc {
+    pub fn from_file(path: &Path) -> Result<Self, EmuError> {
+        let title = path.file_name().and_then(|name| name.to_str()).unwrap_or("disc");
+        let unsupported = |reason: String| EmuError::UnsupportedDiscFormat(format!("{}: {}", path.display(), reason));
+        let mut file = File::open(path).map_err(|e| EmuError::from_io(path.to_path_buf(), e))?;
+        let mut header = [0; HEADER_SIZE];
+        if file.read_exact(&mut header).is_err() || &header[..8] != HEADER_TAG {
+            return Err(unsupported(String::from("not a CHD image")));
+        }
+        let version = BigEndian::read_u32(&header[12..]);
+        if version != VERSION {
+            return Err(unsupported(format!("CHD version {} isn't supported, only version {}", version, VERSION)));
+        }
+        let mut codecs = [0; 4];
+        BigEndian::read_u32_into(&header[16..32], &mut codecs);
+        let logical_bytes = BigEndian::read_u64(&header[32..]);
+        let map_offset = BigEndian::read_u64(&header[40..]);
+        let metadata_offset = BigEndian::read_u64(&header[48..]);
+        let hunk_bytes = BigEndian::read_u32(&header[56..]) as usize;
+        let unit_bytes = BigEndian::read_u32(&header[60..]) as usize;
+        if unit_bytes != FRAME_SIZE || hunk_bytes == 0 || !hunk_bytes.is_multiple_of(FRAME_SIZE) {
+            return Err(unsupported(String::from("not a cd image")));
+        }
+        //The map is sized from this, so it's checked before anything is allocated
+        if logical_bytes > MAX_FRAMES * FRAME_SIZE as u64 {
+            return Err(unsupported(String::from("the image is bigger than a cd")));
+        }
+
+        let hunk_count = logical_bytes.div_ceil(hunk_bytes as u64) as usize;
+        let map = match codecs[0] {
+            CODEC_NONE => read_uncompressed_map(&mut file, map_offset, hunk_count, hunk_bytes),
+            _ => read_compressed_map(&mut file, map_offset, hunk_count, hunk_bytes, &codecs),
+        }
+        .map_err(unsupported)?;
+        //Better to fail now than partway into a game
+        for hunk in &map {
+            match hunk {
+                Hunk::Compressed { codec, .. } if ![CODEC_CD_ZLIB, CODEC_CD_LZMA, CODEC_CD_FLAC].contains(codec) => {
+                    return Err(unsupported(format!("the {} codec isn't supported", codec_name(*codec))))
+                }
+                _ => (),
+            }
+        }
+        let tracks = read_tracks(&mut file, metadata_offset).map_err(unsupported)?;
+
+        let mut disc = Self {
+            title: String::from(title),
+            region: Region::NorthAmerica,
+            tracks,
+            hunk_bytes,
+            map,
+            reader: RefCell::new(HunkReader {
+                file,
+                cache: VecDeque::with_capacity(CACHED_HUNKS + 1),
+            }),
+        };
+        if disc.tracks[0].frames > LICENSE_SECTOR {
+            let license = disc.read_sector(&DiscIndex::from_sector(LEAD_IN_SECTORS + LICENSE_SECTOR));
+            disc.region = region_from_license(&license).unwrap_or(disc.region);
+        }
+        Ok(disc)
+    }
+
+    /// Index of the track holding an absolute sector, and how far into the track it is
+    fn locate(&self, location: &DiscIndex) -> Option<(usize, usize)> {
+        let mut sector = location.as_sector().checked_sub(LEAD_IN_SECTORS)?;
+        for (i, track) in self.tracks.iter().enumerate() {
+            if sector < track.frames {
+                return Some((i, sector));
+            }
+            sector -= track.frames;
+        }
+        None
+    }
+}
+
+impl Disc for ChdDisc {
+    fn title(&self) -> &str {
+        &self.title
+    }
+
+    fn region(&self) -> Region {
+        self.region
+    }
+
+    fn read_sector(&self, location: &DiscIndex) -> [u8; BYTES_PER_SECTOR] {
+        let (index, offset) = match self.locate(location) {
+            Some(position) => position,
+            None => return missing_sector(location),
+        };
+        let track = &self.tracks[index];
+        let position = (track.first_frame + offset) * FRAME_SIZE;
+        let mut sector = [0; BYTES_PER_SECTOR];
+        match self.reader.borrow_mut().hunk(position / self.hunk_bytes, &self.map, self.hunk_bytes) {
+            Ok(hunk) => sector.copy_from_slice(&hunk[position % self.hunk_bytes..][..BYTES_PER_SECTOR]),
+            Err(reason) => error!(
+                target: LOG_TARGET,
+                "CD: Unable to read sector {} of {}: {}",
+                location.as_sector(),
+                self.title,
+                reason
+            ),
+        }
+        //Audio is stored big endian
+        if track.track_type == TrackType::Audio {
+            sector.chunks_exact_mut(2).for_each(|sample| sample.swap(0, 1));
+        }
+        sector
+    }
+
+    fn track_count(&self) -> usize {
+        self.tracks.len()
+    }
+
+    fn track_start(&self, track: usize) -> Option<DiscIndex> {
+        let previous_tracks = match track {
+            0 => &self.tracks[..],
+            _ if track <= self.tracks.len() => &self.tracks[..track - 1],
+            _ => return None,
+        };
+        let sectors_before: usize = previous_tracks.iter().map(|t| t.frames).sum();
+        let pregap = if track == 0 { 0 } else { self.tracks[track - 1].pregap };
+        Some(DiscIndex::from_sector(LEAD_IN_SECTORS + sectors_before + pregap))
+    }
+
+    fn track_number_at(&self, location: &DiscIndex) -> usize {
+        self.locate(location).map_or(self.tracks.len(), |(index, _)| index + 1)
+    }
+
+    fn track_type(&self, track: usize) -> Option<TrackType> {
+        self.tracks.get(track.checked_sub(1)?).map(|t| t.track_type)
+    }
+}
+
+impl HunkReader {
+    fn hunk(&mut self, number: usize, map: &[Hunk], hunk_bytes: usize) -> Result<&[u8], String> {
+        match self.cache.iter().position(|(cached, _)| *cached == number) {
+            Some(position) => {
+                let entry = self.cache.remove(position).unwrap();
+                self.cache.push_front(entry);
+            }
+            None => {
+                let data = self.load(number, map, hunk_bytes)?;
+                self.cache.push_front((number, data));
+                self.cache.truncate(CACHED_HUNKS);
+            }
+        }
+        Ok(&self.cache[0].1)
+    }
+
+    fn load(&mut self, number: usize, map: &[Hunk], hunk_bytes: usize) -> Result<Vec<u8>, String> {
+        match *map.get(number).ok_or("hunk past the end of the map")? {
+            Hunk::Compressed { codec, offset, length } => {
+                let data = read_at(&mut self.file, offset, length).map_err(|e| e.to_string())?;
+                decompress_cd(codec, &data, hunk_bytes)
+            }
+            Hunk::Uncompressed { offset } => read_at(&mut self.file, offset, hunk_bytes).map_err(|e| e.to_string()),
+            Hunk::Copy(source) => self.hunk(source, map, hunk_bytes).map(<[u8]>::to_vec),
+            Hunk::Zero => Ok(vec![0; hunk_bytes]),
+        }
+    }
+}
+
+/// Lengths come from the image, so they're checked against the file before anything is allocated
+fn read_at(file: &mut File, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+    let file_length = file.metadata()?.len();
+    if offset.checked_add(length as u64).is_none_or(|end| end > file_length) {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "read past the end of the file"));
+    }
+    let mut data = vec![0; length];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn codec_name(codec: u32) -> String {
+    String::from_utf8_lossy(&codec.to_be_bytes()).into_owned()
+}
+
+/// Map of an image stored without compression. Each entry is the hunk's offset divided by the hunk size
+fn read_uncompressed_map(file: &mut File, offset: u64, hunk_count: usize, hunk_bytes: usize) -> Result<Vec<Hunk>, String> {
+    let data = read_at(file, offset, hunk_count * 4).map_err(|e| e.to_string())?;
+    Ok(data
+        .chunks_exact(4)
+        .map(|entry| match BigEndian::read_u32(entry) as u64 {
+            0 => Hunk::Zero,
+            position => Hunk::Uncompressed { offset: position * hunk_bytes as u64 },
+        })
+        .collect())
+}
+
+/// Most significant bit first. Reads past the end come back as zeros and get caught by the map's checksum
+struct MapBits<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> MapBits<'a> {
+    fn read(&mut self, count: u32) -> u64 {
+        let mut value = 0;
+        for _ in 0..count {
+            let bit = self.data.get(self.position / 8).map_or(0, |byte| (byte >> (7 - self.position % 8)) & 1);
+            value = (value << 1) | bit as u64;
+            self.position += 1;
+        }
+        value
+    }
+}
+
+/// Huffman code for the hunk types in the map. Codes are handed out from the longest length down
+struct MapHuffman {
+    //(length, code) of each symbol. Unused symbols have a length of 0
+    codes: Vec<(u32, u64)>,
+}
+
+impl MapHuffman {
+    const SYMBOLS: usize = 16;
+    const MAX_LENGTH: usize = 8;
+
+    /// The code lengths are 4 bits each. A 1 is an escape: 1 again means a length of 1,
+    /// anything else is a length repeated for the 4 bit count after it, plus 3
+    fn read(bits: &mut MapBits) -> Result<Self, String> {
+        let mut lengths = Vec::with_capacity(Self::SYMBOLS);
+        while lengths.len() < Self::SYMBOLS {
+            match bits.read(4) as usize {
+                1 => match bits.read(4) as usize {
+                    1 => lengths.push(1),
+                    length => {
+                        let repeat = bits.read(4) as usize + 3;
+                        lengths.extend(std::iter::repeat_n(length, repeat));
+                    }
+                },
+                length => lengths.push(length),
+            }
+        }
+        if lengths.len() != Self::SYMBOLS || lengths.iter().any(|length| *length > Self::MAX_LENGTH) {
+            return Err(String::from("bad hunk type code"));
+        }
+
+        let mut next_code = [0; Self::MAX_LENGTH + 1];
+        let mut start = 0;
+        for length in (1..=Self::MAX_LENGTH).rev() {
+            let count = lengths.iter().filter(|l| **l == length).count() as u64;
+            next_code[length] = start;
+            start = (start + count) >> 1;
+        }
+        let codes = lengths
+            .iter()
+            .map(|length| match length {
+                0 => (0, 0),
+                length => {
+                    next_code[*length] += 1;
+                    (*length as u32, next_code[*length] - 1)
+                }
+            })
+            .collect();
+        Ok(Self { codes })
+    }
+
+    fn decode(&self, bits: &mut MapBits) -> Result<u8, String> {
+        let mut code = 0;
+        for length in 1..=Self::MAX_LENGTH as u32 {
+            code = (code << 1) | bits.read(1);
+            if let Some(symbol) = self.codes.iter().position(|c| *c == (length, code)) {
+                return Ok(symbol as u8);
+            }
+        }
+        Err(String::from("bad hunk type"))
+    }
+}
+
+/// The compressed map: a 16 byte header, then the huffman coded type of every hunk,
+/// then the length, offset or checksum each type needs
+fn read_compressed_map(
+    file: &mut File,
+    offset: u64,
+    hunk_count: usize,
+    hunk_bytes: usize,
+    codecs: &[u32; 4],
+) -> Result<Vec<Hunk>, String> {
+    let header = read_at(file, offset, 16).map_err(|e| e.to_string())?;
+    let map_bytes = BigEndian::read_u32(&header[0..]) as usize;
+    let mut position = BigEndian::read_u48(&header[4..]);
+    let map_crc = BigEndian::read_u16(&header[10..]);
+    let (length_bits, self_bits) = (header[12] as u32, header[13] as u32);
+    let data = read_at(file, offset + 16, map_bytes).map_err(|e| e.to_string())?;
+    let mut bits = MapBits { data: &data, position: 0 };
+
+    //Runs of the same type are packed down
+    let code = MapHuffman::read(&mut bits)?;
+    let mut types = Vec::with_capacity(hunk_count);
+    let mut last = 0;
+    while types.len() < hunk_count {
+        let count = match code.decode(&mut bits)? {
+            COMPRESSION_RLE_SMALL => 3 + code.decode(&mut bits)? as usize,
+            COMPRESSION_RLE_LARGE => 19 + ((code.decode(&mut bits)? as usize) << 4) + code.decode(&mut bits)? as usize,
+            compression => {
+                last = compression;
+                1
+            }
+        };
+        types.extend(std::iter::repeat_n(last, count));
+    }
+    types.truncate(hunk_count);
+
+    //The checksum covers each entry as type, 24 bit length, 48 bit offset and 16 bit crc of the hunk
+    let mut entries = Vec::with_capacity(hunk_count * MAP_ENTRY_SIZE);
+    let mut map = Vec::with_capacity(hunk_count);
+    let mut last_self = 0;
+    for (number, compression) in types.into_iter().enumerate() {
+        let (hunk, compression, length, offset, crc) = match compression {
+            0..=3 => {
+                let length = bits.read(length_bits) as usize;
+                let crc = bits.read(16);
+                let codec = codecs[compression as usize];
+                position += length as u64;
+                (Hunk::Compressed { codec, offset: position - length as u64, length }, compression, length, position - length as u64, crc)
+            }
+            COMPRESSION_NONE => {
+                let crc = bits.read(16);
+                position += hunk_bytes as u64;
+                (Hunk::Uncompressed { offset: position - hunk_bytes as u64 }, compression, hunk_bytes, position - hunk_bytes as u64, crc)
+            }
+            COMPRESSION_SELF | COMPRESSION_SELF_0 | COMPRESSION_SELF_1 => {
+                match compression {
+                    COMPRESSION_SELF => last_self = bits.read(self_bits) as usize,
+                    COMPRESSION_SELF_1 => last_self += 1,
+                    _ => (),
+                }
+                if last_self >= number {
+                    return Err(format!("hunk {} is a copy of a hunk after it", number));
+                }
+                (Hunk::Copy(last_self), COMPRESSION_SELF, 0, last_self as u64, 0)
+            }
+            COMPRESSION_PARENT | COMPRESSION_PARENT_SELF..=COMPRESSION_PARENT_1 => {
+                return Err(String::from("images that need a parent CHD aren't supported"))
+            }
+            _ => return Err(format!("bad type for hunk {}", number)),
+        };
+        let mut entry = [0; MAP_ENTRY_SIZE];
+        entry[0] = compression;
+        BigEndian::write_u24(&mut entry[1..], length as u32);
+        BigEndian::write_u48(&mut entry[4..], offset);
+        BigEndian::write_u16(&mut entry[10..], crc as u16);
+        entries.extend_from_slice(&entry);
+        map.push(hunk);
+    }
+    if crc16(&entries) != map_crc {
+        return Err(String::from("the hunk map is corrupt"));
+    }
+    Ok(map)
+}
+
+/// CRC-16/CCITT, as the map is checksummed with
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, byte| {
+        (0..8).fold(crc ^ ((*byte as u16) << 8), |crc, _| match crc & 0x8000 {
+            0 => crc << 1,
+            _ => (crc << 1) ^ 0x1021,
+        })
+    })
+}
+
+/// Tracks from the metadata, a linked list of tagged entries. Track entries are text like
+/// "TRACK:1 TYPE:MODE2_RAW SUBTYPE:NONE FRAMES:1234 PREGAP:0 PGTYPE:MODE1 PGSUB:NONE POSTGAP:0"
+fn read_tracks(file: &mut File, mut offset: u64) -> Result<Vec<ChdTrack>, String> {
+    let mut entries = Vec::new();
+    for _ in 0..MAX_METADATA_ENTRIES {
+        if offset == 0 {
+            break;
+        }
+        let header = read_at(file, offset, 16).map_err(|e| e.to_string())?;
+        let tag = BigEndian::read_u32(&header[0..]);
+        if tag == TRACK_METADATA || tag == OLD_TRACK_METADATA {
+            let text = read_at(file, offset + 16, BigEndian::read_u24(&header[5..]) as usize).map_err(|e| e.to_string())?;
+            entries.push(parse_track(&String::from_utf8_lossy(&text))?);
+        }
+        offset = BigEndian::read_u64(&header[8..]);
+    }
+    if entries.is_empty() {
+        return Err(String::from("no track list"));
+    }
+    entries.sort_by_key(|(number, _)| *number);
+
+    let mut first_frame = 0;
+    Ok(entries
+        .into_iter()
+        .map(|(_, mut track)| {
+            track.first_frame = first_frame;
+            first_frame += track.frames.div_ceil(TRACK_PADDING) * TRACK_PADDING;
+            track
+        })
+        .collect())
+}
+
+fn parse_track(text: &str) -> Result<(usize, ChdTrack), String> {
+    let field = |key: &str| {
+        text.trim_end_matches('\0')
+            .split_whitespace()
+            .find_map(|word| word.strip_prefix(key)?.strip_prefix(':'))
+    };
+    let number = |key: &str| {
+        field(key)
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| format!("track entry without {}: {}", key, text))
+    };
+    let track_type = match field("TYPE") {
+        Some("MODE1_RAW") => TrackType::Mode1,
+        Some("MODE2_RAW") => TrackType::Mode2,
+        Some("AUDIO") => TrackType::Audio,
+        other => {
+            return Err(format!(
+                "{} tracks aren't supported, only raw 2352 byte sectors",
+                other.unwrap_or("untyped")
+            ))
+        }
+    };
+    //The pregap is only in the image when its type starts with V. Otherwise it's left off, like PREGAP in a cue sheet
+    let pregap = match field("PGTYPE") {
+        Some(pregap_type) if pregap_type.starts_with('V') => number("PREGAP")?,
+        _ => 0,
+    };
+    let track = ChdTrack {
+        track_type,
+        frames: number("FRAMES")?,
+        pregap,
+        first_frame: 0,
+    };
+    Ok((number("TRACK")?, track))
+}
+
+/// A cdzl or cdlz hunk is a bitmap of the frames that had their sync header and ECC taken out, the 16 or 24 bit length
+/// of the compressed sector data, then the sector data and the deflated subcode. A cdfl hunk is the sector data
+/// as FLAC frames, then the deflated subcode
+fn decompress_cd(codec: u32, data: &[u8], hunk_bytes: usize) -> Result<Vec<u8>, String> {
+    let frames = hunk_bytes / FRAME_SIZE;
+    let (header, sectors, subcode) = match codec {
+        CODEC_CD_FLAC => {
+            let (sectors, length) = flac::decode(data, frames * BYTES_PER_SECTOR / 4)?;
+            (&[][..], sectors, &data[length..])
+        }
+        _ => {
+            let ecc_bytes = frames.div_ceil(8);
+            let header_bytes = ecc_bytes + if hunk_bytes < 0x10000 { 2 } else { 3 };
+            let header = data.get(..header_bytes).ok_or("hunk is cut short")?;
+            let sector_length = header[ecc_bytes..].iter().fold(0, |length, byte| (length << 8) | *byte as usize);
+            let compressed_sectors = data.get(header_bytes..header_bytes + sector_length).ok_or("hunk is cut short")?;
+            let sectors = match codec {
+                CODEC_CD_LZMA => lzma::decompress(compressed_sectors, frames * BYTES_PER_SECTOR)?,
+                _ => inflate(compressed_sectors, frames * BYTES_PER_SECTOR)?,
+            };
+            (header, sectors, &data[header_bytes + sector_length..])
+        }
+    };
+    let subcode = inflate(subcode, frames * SUBCODE_SIZE)?;
+
+    let mut hunk = Vec::with_capacity(hunk_bytes);
+    for frame in 0..frames {
+        let start = hunk.len();
+        hunk.extend_from_slice(&sectors[frame * BYTES_PER_SECTOR..][..BYTES_PER_SECTOR]);
+        hunk.extend_from_slice(&subcode[frame * SUBCODE_SIZE..][..SUBCODE_SIZE]);
+        if header.get(frame / 8).is_some_and(|ecc| ecc & (1 << (frame % 8)) != 0) {
+            let sector = &mut hunk[start..start + BYTES_PER_SECTOR];
+            sector[..SYNC_SIZE].copy_from_slice(&SYNC_HEADER);
+            generate_ecc(sector);
+        }
+    }
+    Ok(hunk)
+}
+
+/// Fills in the P and Q parity of a mode 1 or mode 2 form 1 sector (ECMA-130 annex A)
+fn generate_ecc(sector: &mut [u8]) {
+    //Multiplying by alpha in GF(2^8), and dividing by alpha + 1
+    let mut low = [0; 256];
+    let mut high = [0; 256];
+    for i in 0..256 {
+        low[i] = ((i << 1) ^ if i & 0x80 != 0 { 0x11D } else { 0 }) as u8;
+        high[i ^ low[i] as usize] = i as u8;
+    }
+    //Offsets count from the header. P runs down 86 columns of 24 bytes, and Q along 52 diagonals of 43
+    for column in 0..86 {
+        let (p0, p1) = parity(sector, &low, &high, (0..24).map(|row| column + row * 86));
+        sector[ECC_P_OFFSET + column] = p0;
+        sector[ECC_P_OFFSET + 86 + column] = p1;
+    }
+    for diagonal in 0..52 {
+        let offsets = (0..43).map(|step| (diagonal / 2 * 86 + step * 88) % 2236 + diagonal % 2);
+        let (q0, q1) = parity(sector, &low, &high, offsets);
+        sector[ECC_Q_OFFSET + diagonal] = q0;
+        sector[ECC_Q_OFFSET + 52 + diagonal] = q1;
+    }
+}
+
+fn parity(sector: &[u8], low: &[u8; 256], high: &[u8; 256], offsets: impl Iterator<Item = usize>) -> (u8, u8) {
+    let mode2 = sector[MODE_OFFSET] == 2;
+    let (mut a, mut b) = (0, 0);
+    for offset in offsets {
+        //Mode 2 leaves the header out
+        let byte = if mode2 && offset < 4 { 0 } else { sector[SYNC_SIZE + offset] };
+        a ^= byte;
+        b ^= byte;
+        a = low[a as usize];
+    }
+    a = high[(low[a as usize] ^ b) as usize];
+    (a, b ^ a)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HUNK_BYTES: usize = 2 * FRAME_SIZE;
+    //Test images list these codecs, so hunks compressed with them have these types
+    const CODECS: [u32; 3] = [CODEC_CD_ZLIB, CODEC_CD_LZMA, CODEC_CD_FLAC];
+
+    /// Most significant bit first, like the map
+    #[derive(Default)]
+    struct BitWriter {
+        data: Vec<u8>,
+        length: usize,
+    }
+
+    impl BitWriter {
+        fn write(&mut self, value: u64, count: u32) {
+            for i in (0..count).rev() {
+                if self.length.is_multiple_of(8) {
+                    self.data.push(0);
+                }
+                if (value >> i) & 1 != 0 {
+                    *self.data.last_mut().unwrap() |= 0x80 >> (self.length % 8);
+                }
+                self.length += 1;
+            }
+        }
+    }
+
+    /// Deflate stream of a single stored block
+    fn stored(data: &[u8]) -> Vec<u8> {
+        let length = data.len() as u16;
+        let mut stream = vec![0x01];
+        stream.extend_from_slice(&length.to_le_bytes());
+        stream.extend_from_slice(&(!length).to_le_bytes());
+        stream.extend_from_slice(data);
+        stream
+    }
+
+    /// Hunk of two frames. Frames with their bit set in ecc_frames are stored without their sync header and parity,
+    /// which cdfl can't do
+    fn cd_hunk(codec: u32, frames: &[Vec<u8>], ecc_frames: u8) -> Vec<u8> {
+        let mut sectors = Vec::new();
+        let mut subcode = Vec::new();
+        for (i, frame) in frames.iter().enumerate() {
+            let mut sector = frame[..BYTES_PER_SECTOR].to_vec();
+            if ecc_frames & (1 << i) != 0 {
+                sector[..SYNC_SIZE].fill(0);
+                sector[ECC_P_OFFSET..].fill(0);
+            }
+            sectors.extend_from_slice(&sector);
+            subcode.extend_from_slice(&frame[BYTES_PER_SECTOR..]);
+        }
+        let sectors = match codec {
+            CODEC_CD_FLAC => return [flac::tests::encode_verbatim(&sectors), stored(&subcode)].concat(),
+            CODEC_CD_LZMA => lzma::tests::encode_literals(&sectors),
+            _ => stored(&sectors),
+        };
+        let mut hunk = vec![ecc_frames];
+        hunk.extend_from_slice(&(sectors.len() as u16).to_be_bytes());
+        hunk.extend_from_slice(&sectors);
+        hunk.extend_from_slice(&stored(&subcode));
+        hunk
+    }
+
+    fn frame(seed: u8) -> Vec<u8> {
+        (0..FRAME_SIZE).map(|i| (i as u8).wrapping_mul(seed)).collect()
+    }
+
+    fn big_endian(frame: &[u8]) -> Vec<u8> {
+        let mut frame = frame.to_vec();
+        frame[..BYTES_PER_SECTOR].chunks_exact_mut(2).for_each(|sample| sample.swap(0, 1));
+        frame
+    }
+
+    fn metadata_entry(text: &str, next: u64) -> Vec<u8> {
+        let mut entry = TRACK_METADATA.to_be_bytes().to_vec();
+        entry.push(0x01);
+        entry.extend_from_slice(&(text.len() as u32 + 1).to_be_bytes()[1..]);
+        entry.extend_from_slice(&next.to_be_bytes());
+        entry.extend_from_slice(text.as_bytes());
+        entry.push(0);
+        entry
+    }
+
+    /// Track 1 is 3 data sectors and track 2 is 3 audio sectors, each padded out to 4 frames. They're stored as
+    /// a hunk compressed with the data codec, an uncompressed hunk, a hunk compressed with the audio codec
+    /// and a copy of the uncompressed one. The codecs are indices into CODECS
+    fn build_chd(data_frames: &[Vec<u8>], audio_frames: &[Vec<u8>], data_codec: u8, audio_codec: u8) -> Vec<u8> {
+        let hunks = [
+            cd_hunk(CODECS[data_codec as usize], &data_frames[..2], 0b01),
+            [&data_frames[2][..], &[0; FRAME_SIZE]].concat(),
+            cd_hunk(CODECS[audio_codec as usize], &[big_endian(&audio_frames[0]), big_endian(&audio_frames[1])], 0),
+        ];
+        let first_offset = HEADER_SIZE as u64;
+        let map_offset = first_offset + hunks.iter().map(|hunk| hunk.len() as u64).sum::<u64>();
+
+        let mut bits = BitWriter::default();
+        //Every type gets a 4 bit code, which works out to the type itself
+        (0..16).for_each(|_| bits.write(4, 4));
+        for compression in [data_codec, COMPRESSION_NONE, audio_codec, COMPRESSION_SELF].iter() {
+            bits.write(*compression as u64, 4);
+        }
+        bits.write(hunks[0].len() as u64, 16);
+        bits.write(0, 16);
+        bits.write(0, 16);
+        bits.write(hunks[2].len() as u64, 16);
+        bits.write(0, 16);
+        bits.write(1, 8);
+        let raw_entries = [
+            (data_codec, hunks[0].len(), first_offset),
+            (COMPRESSION_NONE, HUNK_BYTES, first_offset + hunks[0].len() as u64),
+            (audio_codec, hunks[2].len(), first_offset + (hunks[0].len() + hunks[1].len()) as u64),
+            (COMPRESSION_SELF, 0, 1),
+        ];
+        let mut entries = Vec::new();
+        for (compression, length, offset) in raw_entries.iter() {
+            let mut entry = [0; MAP_ENTRY_SIZE];
+            entry[0] = *compression;
+            BigEndian::write_u24(&mut entry[1..], *length as u32);
+            BigEndian::write_u48(&mut entry[4..], *offset);
+            entries.extend_from_slice(&entry);
+        }
+        let mut map = (bits.data.len() as u32).to_be_bytes().to_vec();
+        map.extend_from_slice(&first_offset.to_be_bytes()[2..]);
+        map.extend_from_slice(&crc16(&entries).to_be_bytes());
+        map.extend_from_slice(&[16, 8, 0, 0]);
+        map.extend_from_slice(&bits.data);
+
+        //Listed out of order, the track numbers decide the order
+        let metadata_offset = map_offset + map.len() as u64;
+        let first_track = metadata_entry(
+            "TRACK:1 TYPE:MODE2_RAW SUBTYPE:NONE FRAMES:3 PREGAP:0 PGTYPE:MODE1 PGSUB:NONE POSTGAP:0",
+            0,
+        );
+        let second_track = metadata_entry(
+            "TRACK:2 TYPE:AUDIO SUBTYPE:NONE FRAMES:3 PREGAP:0 PGTYPE:MODE1 PGSUB:NONE POSTGAP:0",
+            metadata_offset,
+        );
+
+        let mut header = vec![0; HEADER_SIZE];
+        header[..8].copy_from_slice(HEADER_TAG);
+        BigEndian::write_u32(&mut header[8..], HEADER_SIZE as u32);
+        BigEndian::write_u32(&mut header[12..], VERSION);
+        BigEndian::write_u32_into(&CODECS, &mut header[16..28]);
+        BigEndian::write_u64(&mut header[32..], (4 * HUNK_BYTES) as u64);
+        BigEndian::write_u64(&mut header[40..], map_offset);
+        BigEndian::write_u64(&mut header[48..], metadata_offset + first_track.len() as u64);
+        BigEndian::write_u32(&mut header[56..], HUNK_BYTES as u32);
+        BigEndian::write_u32(&mut header[60..], FRAME_SIZE as u32);
+        [header, hunks.concat(), map, first_track, second_track].concat()
+    }
+
+    #[test]
+    fn test_read_chd() {
+        //A mode 1 sector with its parity, which gets left out of the image
+        let mut ecc_frame = frame(1);
+        ecc_frame[..SYNC_SIZE].copy_from_slice(&SYNC_HEADER);
+        ecc_frame[SYNC_SIZE..SYNC_SIZE + 4].copy_from_slice(&[0x00, 0x02, 0x00, 0x01]);
+        generate_ecc(&mut ecc_frame[..BYTES_PER_SECTOR]);
+        let data_frames = [ecc_frame, frame(3), frame(5)];
+        let audio_frames = [frame(7), frame(9)];
+        let path = std::env::temp_dir().join(format!("vaporstation_chd_test_{}.chd", std::process::id()));
+        //cdzl for everything, then cdlz for data and cdfl for audio
+        for (data_codec, audio_codec) in [(0, 0), (1, 2)].iter() {
+            std::fs::write(&path, build_chd(&data_frames, &audio_frames, *data_codec, *audio_codec)).unwrap();
+            let disc = super::super::disc::open(&path).unwrap();
+
+            assert_eq!(disc.track_count(), 2);
+            assert_eq!(disc.track_start(2).unwrap().as_sector(), 150 + 3);
+            assert_eq!(disc.track_start(0).unwrap().as_sector(), 150 + 6);
+            let read = |sector: usize| disc.read_sector(&DiscIndex::from_sector(150 + sector)).to_vec();
+            for (sector, frame) in data_frames.iter().enumerate() {
+                assert_eq!(read(sector), frame[..BYTES_PER_SECTOR], "sector {}", sector);
+            }
+            //Audio comes out little endian. The last audio sector is in the copy of the uncompressed hunk
+            assert_eq!(read(3), audio_frames[0][..BYTES_PER_SECTOR]);
+            assert_eq!(read(4), audio_frames[1][..BYTES_PER_SECTOR]);
+            assert_eq!(read(5), big_endian(&data_frames[2])[..BYTES_PER_SECTOR]);
+            assert_eq!(disc.subchannel_q(&DiscIndex::from_sector(154)).control, 0x01);
+        }
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+
+        let mut unsupported = std::fs::read(&path).unwrap();
+        unsupported[20..24].copy_from_slice(b"cdzs");
+        std::fs::write(&path, unsupported).unwrap();
+        assert!(matches!(ChdDisc::from_file(&path), Err(EmuError::UnsupportedDiscFormat(_))));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_chd_sizes() {
+        let frames = [frame(1), frame(3), frame(5)];
+        let image = build_chd(&frames, &frames, 0, 0);
+        let map_offset = BigEndian::read_u64(&image[40..]) as usize;
+        let path = std::env::temp_dir().join(format!("vaporstation_corrupt_chd_test_{}.chd", std::process::id()));
+
+        let mut huge_disc = image.clone();
+        BigEndian::write_u64(&mut huge_disc[32..], u64::MAX);
+        std::fs::write(&path, huge_disc).unwrap();
+        assert!(matches!(ChdDisc::from_file(&path), Err(EmuError::UnsupportedDiscFormat(_))));
+
+        let mut huge_map = image;
+        BigEndian::write_u32(&mut huge_map[map_offset..], u32::MAX);
+        std::fs::write(&path, huge_map).unwrap();
+        assert!(matches!(ChdDisc::from_file(&path), Err(EmuError::UnsupportedDiscFormat(_))));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,77 +1,168 @@
-use super::{Block, CDDrive, DriveState, IntCause, MotorState, Packet, disc::dec_to_bcd};
-use crate::cdrom::disc::{BYTES_PER_SECTOR, DiscIndex};
+use bit_field::BitField;
+use log::debug;
+use std::ops::RangeInclusive;
+
+use super::{Block, CDDrive, FIFO_SIZE, LOG_TARGET, DriveState, IntCause, MotorState, Packet, disc::{bcd_to_dec, dec_to_bcd, is_bcd}};
+use crate::cdrom::disc::{BYTES_PER_SECTOR, DiscIndex, LEAD_IN_SECTORS};
 
 pub(super) const AVG_FIRST_RESPONSE_TIME: u32 = 0xc4e1;
 pub(super) const AVG_SECOND_RESPONSE_TIME: u32 = 0x1000;
+//Seeks cost at least this much, mostly waiting for the disc to come round. Half that at double speed
+pub(super) const MIN_SEEK_TIME: u32 = 40000;
+//Sled travel for a seek across a whole 74 minute disc. Shorter seeks scale with the square root of the distance
+pub(super) const FULL_STROKE_SEEK_TIME: u32 = 25_000_000;
+const FULL_STROKE_SECTORS: usize = 74 * 60 * 75;
+//Average time between sectors at single speed. Double speed takes half as long
+pub(super) const READ_SECTOR_TIME: u32 = 0x6e1cd;
+//Time for the drive to resync after switching between single and double speed
+pub(super) const SPEED_CHANGE_TIME: u32 = 1_000_000;
+pub(super) const INIT_SECOND_RESPONSE_TIME: u32 = 0x13cce;
+pub(super) const GET_ID_SECOND_RESPONSE_TIME: u32 = 0x4a00;
+//One audio sector at single speed, 75 sectors a second
+pub(super) const PLAY_SECTOR_TIME: u32 = 451584;
 
-pub(super) fn get_bios_date() -> Packet {
-    Packet {
-        cause: IntCause::INT3,
-        response: vec![0x94, 0x09, 0x19, 0xC0], //PSX (PU-7) rev a
-        execution_cycles: AVG_FIRST_RESPONSE_TIME,
-        extra_response: None,
-        command: 0x19,
+//Delay before a command's second (completion) response. Average timings from the psx-spx response timing table,
+//for the commands it lists. Everything else falls back to the generic average. First responses all take AVG_FIRST_RESPONSE_TIME
+pub(super) fn second_response_time(state: &CDDrive, command: u8) -> u32 {
+    match command {
+        0x9 if state.drive_state != DriveState::Read => 0x1df2, //Pause while already paused
+        0x9 if state.drive_mode.get_bit(7) => 0x10bd93, //Pause at double speed
+        0x9 => 0x21181c, //Pause at single speed
+        0x8 if state.motor_state != MotorState::On => 0x1df2, //Stop while already stopped
+        0x8 if state.drive_mode.get_bit(7) => 0x18a6076, //Stop at double speed
+        0x8 => 0xd38aca, //Stop at single speed
+        0xA => INIT_SECOND_RESPONSE_TIME,
+        0x1A => GET_ID_SECOND_RESPONSE_TIME,
+        _ => AVG_SECOND_RESPONSE_TIME,
     }
 }
 
+// Cycles to move the head from where it is to the seek target
+pub(super) fn seek_time(state: &CDDrive) -> u32 {
+    let distance = state.seek_target.as_sector().abs_diff(state.head_sector());
+    let stroke = (distance.min(FULL_STROKE_SECTORS) as f64 / FULL_STROKE_SECTORS as f64).sqrt();
+    let latency = if state.drive_mode.get_bit(7) { MIN_SEEK_TIME / 2 } else { MIN_SEEK_TIME };
+    latency + (stroke * FULL_STROKE_SEEK_TIME as f64) as u32
+}
+
+pub(super) fn get_bios_date() -> Packet {
+    Packet::int3(0x19, vec![0x94, 0x09, 0x19, 0xC0]) //PSX (PU-7) rev a
+}
+
 fn stat(state: &CDDrive, command: u8) -> Packet {
     //TODO: Error handling
+    Packet::int3(command, vec![state.get_stat()])
+}
 
-    Packet {
-        cause: IntCause::INT3,
-        response: vec![state.get_stat()],
-        execution_cycles: AVG_FIRST_RESPONSE_TIME,
-        extra_response: None,
-        command
+// Error response for commands the drive doesn't know
+pub(super) fn invalid_command(state: &CDDrive, command: u8) -> Packet {
+    Packet::int5(command, vec![state.get_stat() | 0x1, 0x40])
+}
+
+// Error response for commands sent with too few or too many parameters
+pub(super) fn wrong_parameter_count(state: &CDDrive, command: u8) -> Packet {
+    Packet::int5(command, vec![state.get_stat() | 0x1, 0x20])
+}
+
+/// How many parameters a command takes. Unknown commands accept anything so they get the invalid command error
+pub(super) fn parameter_count(command: u8) -> RangeInclusive<usize> {
+    match command {
+        0x2 => 3..=3,
+        0x3 => 0..=1,
+        0xD => 2..=2,
+        0xE | 0x12 | 0x14 => 1..=1,
+        0x19 => 1..=FIFO_SIZE,
+        0x1D => 2..=2,
+        0x1 | 0x4..=0xC | 0xF..=0x11 | 0x13 | 0x15 | 0x16 | 0x1A..=0x1C | 0x1E => 0..=0,
+        _ => 0..=FIFO_SIZE,
     }
 }
 
-pub(super) fn get_stat(state: &CDDrive) -> Packet {
-    stat(state, 0x1)
+// Error response for commands sent while the lid is open
+pub(super) fn lid_open_error(state: &CDDrive, command: u8) -> Packet {
+    Packet::int5(command, vec![state.get_stat() | 0x1, 0x08])
+}
+
+// Reading the stat acknowledges a lid that was opened and closed again
+pub(super) fn get_stat(state: &mut CDDrive) -> Packet {
+    let response = stat(state, 0x1);
+    if !state.lid_open {
+        state.shell_open_latched = false;
+    }
+    response
 }
 
 pub(super) fn get_id(state: &CDDrive) -> Packet {
     //Only handles 'No Disk' and 'Licensed Game' states
-    if state.disc.is_some() {
-        let mut first_response = stat(state, 0x1a);
-        let second_response = Packet {
-            cause: IntCause::INT2,
-            response: vec![state.get_stat(), 0x00, 0x20, 0x00, 0x53, 0x43, 0x45, 0x41], //SCEA disk inserted
-            execution_cycles: AVG_SECOND_RESPONSE_TIME,
-            extra_response: None,
-            command: 0x1a,
-        };
-        first_response.extra_response = Some(Box::new(second_response));
-        first_response
+    let second_response = if let Some(disc) = &state.disc {
+        //Licensed disk inserted
+        let mut response = vec![state.get_stat(), 0x00, 0x20, 0x00];
+        response.extend_from_slice(disc.region().license_string());
+        Packet::int2(0x1a, response)
     } else {
-        let mut first_response = stat(state, 0x1a);
-        let second_response = Packet {
-            cause: IntCause::INT5,
-            response: vec![0x08, 0x40, 0, 0, 0, 0, 0, 0], //No disk
-            execution_cycles: AVG_SECOND_RESPONSE_TIME,
-            extra_response: None,
-            command: 0x1a
-        };
-        first_response.extra_response = Some(Box::new(second_response));
-        first_response
-    }
+        //No disk
+        Packet::int5(0x1a, vec![0x08, 0x40, 0, 0, 0, 0, 0, 0])
+    };
+    stat(state, 0x1a).with_extra(second_response.after_cycles(second_response_time(state, 0x1a)))
 }
 
 pub(super) fn init(state: &mut CDDrive) -> Packet {
     state.motor_state = MotorState::On;
-    let mut first_response = stat(state, 0x0a);
-    let mut second_response = stat(state, 0x0a);
+    let mut second_response = stat(state, 0x0a).after_cycles(second_response_time(state, 0x0a));
     second_response.cause = IntCause::INT2;
-    first_response.extra_response = Some(Box::new(second_response));
-    first_response
+    stat(state, 0x0a).with_extra(second_response)
+}
+
+// Spin the motor down. The second response comes once it has stopped
+pub(super) fn stop(state: &mut CDDrive) -> Packet {
+    let second_time = second_response_time(state, 0x8);
+    let initial_response = stat(state, 0x8);
+    state.drive_state = DriveState::Stopped;
+    state.motor_state = MotorState::Off;
+    state.read_enabled = false;
+    initial_response.with_extra(Packet::int2(0x8, vec![state.get_stat()]).after_cycles(second_time))
+}
+
+// Back to the power-on state, with the motor off. The disc and lid are left alone
+pub(super) fn reset(state: &mut CDDrive) -> Packet {
+    state.drive_state = DriveState::Stopped;
+    state.motor_state = MotorState::Off;
+    state.drive_mode = 0;
+    state.data_queue.clear();
+    state.response_queue.clear();
+    state.want_data = false;
+    state.seek_target = DiscIndex::new(0, 0, 0);
+    state.seek_complete = false;
+    state.read_offset = 0;
+    state.read_enabled = false;
+    state.muted = false;
+    state.xa_file = 0;
+    state.xa_channel = 0;
+    stat(state, 0x1C)
 }
 
 pub(super) fn set_loc(state: &mut CDDrive, minutes: u8, seconds: u8, frames: u8) -> Packet {
-    state.seek_target = DiscIndex::new(minutes as usize, seconds as usize, frames as usize);
+    //Seconds and frames past the end of a minute or second are as invalid as digits that aren't BCD
+    if ![minutes, seconds, frames].iter().all(|value| is_bcd(*value as usize)) || seconds >= 0x60 || frames >= 0x75 {
+        return Packet::int5(0x2, vec![state.get_stat() | 0x1, 0x10]);
+    }
+    //Nothing before 00:02:00 can be seeked to, that's the lead-in
+    let target = DiscIndex::new(minutes as usize, seconds as usize, frames as usize);
+    if target.as_sector() < LEAD_IN_SECTORS {
+        return Packet::int5(0x2, vec![state.get_stat() | 0x1, 0x10]);
+    }
+    //Only the first of several Setlocs moves the origin. The head hasn't gone anywhere since
+    state.seek_origin = state.head_sector();
+    state.seek_target = target;
     state.seek_complete = false;
     state.read_offset = 0;
     state.data_queue.clear();
-    //println!("set_loc to {:?}, total sectors: {}", state.seek_target, state.seek_target.as_address() / BYTES_PER_SECTOR as u32);
+    debug!(
+        target: LOG_TARGET,
+        "CD: Setloc to {:?}, total sectors: {}",
+        state.seek_target,
+        state.seek_target.as_address() / BYTES_PER_SECTOR as u32
+    );
     stat(state, 0x2)
 }
 
@@ -81,69 +172,90 @@ pub(super) fn seek_data(state: &mut CDDrive) -> Packet {
     let mut second_response = stat(state, 0x15);
     second_response.execution_cycles = AVG_FIRST_RESPONSE_TIME;
 
+    let seek_cycles = seek_time(state);
     state.drive_state = DriveState::Seek;
+    //The head only reaches the target once the INT2 is delivered
+    state.seek_complete = false;
     let mut first_response = stat(state, 0x15);
     second_response.cause = IntCause::INT2;
-    second_response.execution_cycles = 120000;
+    second_response.execution_cycles = seek_cycles;
     first_response.extra_response = Some(Box::new(second_response));
     first_response
 }
 
 pub(super) fn set_mode(state: &mut CDDrive, mode: u8) -> Packet {
+    let speed_changed = (state.drive_mode ^ mode).get_bit(7);
     state.drive_mode = mode;
-    //println!("CD MODE: {:#X}", state.drive_mode);
-    stat(state, 0xE)
+    debug!(target: LOG_TARGET, "CD: Mode {:#X}", state.drive_mode);
+    let initial_response = stat(state, 0xE);
+
+    //Keep reading after the mode change. The next sector is late if the drive has to change speed first
+    if state.read_enabled {
+        let delay = state.read_sector_time() + if speed_changed { SPEED_CHANGE_TIME } else { 0 };
+        initial_response.with_extra(Packet::int1(0x6, vec![state.get_stat()]).after_cycles(delay))
+    } else {
+        initial_response
+    }
 }
 
 //ReadN
 //This is only the initial return. All of the reading is handled in the post condition
 //It's messy, but it works for now
+//If there was a Setloc without a SeekL, the drive seeks first and the first sector arrives late
 pub(super) fn read_with_retry(state: &mut CDDrive) -> Packet {
-    let mut initial_response = stat(state, 0x6);
+    let implicit_seek = !state.seek_complete;
+    let seek_cycles = seek_time(state);
+    if implicit_seek {
+        state.drive_state = DriveState::Seek;
+    }
+    let initial_response = stat(state, 0x6);
     state.drive_state = DriveState::Read;
     state.read_enabled = true;
-    let mut response_packet = Packet {
-        cause: IntCause::INT1,
-        response: vec![state.get_stat()],
-        execution_cycles: 0x36cd2,
-        extra_response: None,
-        command: 0x6,
-    };
-    initial_response.execution_cycles = AVG_FIRST_RESPONSE_TIME;
-    initial_response.extra_response = Some(Box::new(response_packet));
-
-    initial_response
+    state.seek_complete = true;
+    let delay = state.read_sector_time() + if implicit_seek { seek_cycles } else { 0 };
+    initial_response.with_extra(Packet::int1(0x6, vec![state.get_stat()]).after_cycles(delay))
 }
 
 //Pause
 pub(super) fn stop_read(state: &mut CDDrive) -> Packet {
-    //println!("stop read (pause)");
-    let mut initial_response = stat(state, 0x9);
+    debug!(target: LOG_TARGET, "CD: Pause");
+    let second_time = second_response_time(state, 0x9);
+    let initial_response = stat(state, 0x9);
     state.drive_state = DriveState::Idle;
     state.read_enabled = false;
 
-    let response_packet = Packet {
-        cause: IntCause::INT2,
-        response: vec![state.get_stat()],
-        execution_cycles: 1_000_000,
-        extra_response: None,
-        command: 0x9,
-    };
-    initial_response.execution_cycles = AVG_FIRST_RESPONSE_TIME;
+    initial_response.with_extra(Packet::int2(0x9, vec![state.get_stat()]).after_cycles(second_time))
+}
 
-    initial_response.extra_response = Some(Box::new(response_packet));
-    initial_response
+// Select the XA-ADPCM file and channel to play when the XA filter is enabled
+pub(super) fn set_filter(state: &mut CDDrive, file: u8, channel: u8) -> Packet {
+    state.xa_file = file;
+    state.xa_channel = channel;
+    stat(state, 0xD)
+}
+
+pub(super) fn mute(state: &mut CDDrive) -> Packet {
+    state.muted = true;
+    stat(state, 0xB)
 }
 
 pub(super) fn demute(state: &mut CDDrive) -> Packet {
+    state.muted = false;
     stat(state, 0xC)
 }
 
+// Stat, mode, a null byte, then the XA filter file and channel
+pub(super) fn get_param(state: &CDDrive) -> Packet {
+    let mut response = stat(state, 0xF);
+    response.response.extend_from_slice(&[state.drive_mode, 0x00, state.xa_file, state.xa_channel]);
+    response
+}
+
 // Get number of tracks in session
 // Assumes theres only one session
 pub(super) fn get_tn(state: &mut CDDrive) -> Packet {
     let first_track = 0x1;
-    let last_track = dec_to_bcd(state.disc.as_ref().expect("Tried to read non-existant disc!").track_count() + 1);
+    let last_track = dec_to_bcd(state.disc.as_ref().expect("Tried to read non-existant disc!").track_count());
 
     let mut initial_response = stat(state, 0x13);
 
@@ -153,18 +265,105 @@ pub(super) fn get_tn(state: &mut CDDrive) -> Packet {
     initial_response
 }
 
-// Get starting index of given track
-// Because I'm lazy I'm just going to return the start of the first track, 00:02
-// In practice this will probably send code instead of music to the SPU, and play some crazy audio
-// Future colin, you have been warned
+// Get starting index of given track as BCD minutes and seconds
+// Track 0 is the end of the disc
 pub(super) fn get_td(state: &mut CDDrive, track: u8) -> Packet {
-    let mut initial_response = stat(state, 0x14);
-    initial_response.response.push(0x0);
-    initial_response.response.push(0x2);
+    let start = state
+        .disc
+        .as_ref()
+        .filter(|_| is_bcd(track as usize))
+        .and_then(|disc| disc.track_start(bcd_to_dec(track as usize)));
+    match start {
+        Some(start) => {
+            let mut initial_response = stat(state, 0x14);
+            initial_response.response.push(dec_to_bcd(start.minutes) as u8);
+            initial_response.response.push(dec_to_bcd(start.seconds) as u8);
+            initial_response
+        }
+        //No such track
+        None => Packet::int5(0x14, vec![state.get_stat() | 0x1, 0x10]),
+    }
+}
 
-    initial_response
+// The sector the drive is on. read_offset has already moved past the last sector read
+fn current_location(state: &CDDrive) -> DiscIndex {
+    state.seek_target.plus_sector_offset(state.read_offset.saturating_sub(1))
+}
+
+// Header and subheader of the last sector read
+pub(super) fn get_loc_l(state: &CDDrive) -> Packet {
+    match &state.disc {
+        Some(disc) => {
+            let header = disc.read_sector(&current_location(state))[12..20].to_vec();
+            Packet::int3(0x10, header)
+        }
+        None => Packet::int5(0x10, vec![state.get_stat() | 0x1, 0x80]),
+    }
 }
 
-pub(super) fn play(state: &mut CDDrive) -> Packet {
+// Track, index, then the position within the track and on the whole disc, all in BCD
+pub(super) fn get_loc_p(state: &CDDrive) -> Packet {
+    let disc = match &state.disc {
+        Some(disc) => disc,
+        None => return Packet::int5(0x11, vec![state.get_stat() | 0x1, 0x80]),
+    };
+    let position = disc.subchannel_q(&current_location(state));
+    let response = [
+        position.track,
+        position.index,
+        position.relative.minutes,
+        position.relative.seconds,
+        position.relative.sectors,
+        position.absolute.minutes,
+        position.absolute.seconds,
+        position.absolute.sectors,
+    ]
+    .iter()
+    .map(|value| dec_to_bcd(*value) as u8)
+    .collect();
+    Packet::int3(0x11, response)
+}
+
+// Raw subchannel Q of the sector under the head. The drive leaves the CRC off the end
+pub(super) fn get_q(state: &CDDrive) -> Packet {
+    let disc = match &state.disc {
+        Some(disc) => disc,
+        None => return Packet::int5(0x1D, vec![state.get_stat() | 0x1, 0x80]),
+    };
+    let position = disc.subchannel_q(&current_location(state));
+    let mut subq = vec![position.control];
+    subq.extend(
+        [
+            position.track,
+            position.index,
+            position.relative.minutes,
+            position.relative.seconds,
+            position.relative.sectors,
+            0,
+            position.absolute.minutes,
+            position.absolute.seconds,
+            position.absolute.sectors,
+        ]
+        .iter()
+        .map(|value| dec_to_bcd(*value) as u8),
+    );
+    stat(state, 0x1D).with_extra(Packet::int2(0x1D, subq).after_cycles(second_response_time(state, 0x1D)))
+}
+
+// Start CD-DA playback from the start of the given track, or from the Setloc position if there's no track.
+// Sectors are streamed by step_cycle while the drive is in the play state
+pub(super) fn play(state: &mut CDDrive, track: Option<u8>) -> Packet {
+    let track_start = track
+        .filter(|track| *track != 0 && is_bcd(*track as usize))
+        .and_then(|track| state.disc.as_ref()?.track_start(bcd_to_dec(track as usize)));
+    if let Some(start) = track_start {
+        state.seek_target = start;
+        state.read_offset = 0;
+    }
+    state.seek_complete = true;
+    state.read_enabled = false;
+    state.data_queue.clear();
+    state.drive_state = DriveState::Play;
+    state.play_timer = state.play_sector_time();
     stat(state, 0x3)
 }
\ No newline at end of file
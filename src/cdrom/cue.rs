@@ -0,0 +1,143 @@
+use super::disc::{TrackType, SECTORS_PER_SECOND};
+use crate::error::EmuError;
+
+/// A track from a cue sheet. start_sector is where its INDEX 01 sits inside the track's file
+#[derive(Debug, PartialEq)]
+pub(super) struct CueTrack {
+    pub(super) number: usize,
+    pub(super) track_type: TrackType,
+    pub(super) start_sector: usize,
+}
+
+/// A FILE entry from a cue sheet along with the tracks stored in it
+#[derive(Debug, PartialEq)]
+pub(super) struct CueFile {
+    pub(super) name: String,
+    pub(super) tracks: Vec<CueTrack>,
+}
+
+fn unsupported(line_number: usize, reason: &str) -> EmuError {
+    EmuError::UnsupportedDiscFormat(format!("cue sheet line {}: {}", line_number + 1, reason))
+}
+
+//Cue sheet times are mm:ss:ff in decimal, 75 frames to the second
+fn parse_msf(msf: &str) -> Option<usize> {
+    let parts: Vec<usize> = msf.split(':').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    match parts[..] {
+        [minutes, seconds, frames] => Some((minutes * 60 + seconds) * SECTORS_PER_SECOND + frames),
+        _ => None,
+    }
+}
+
+/// Reads the FILE, TRACK and INDEX 01 lines of a cue sheet. Everything else (REM, TITLE, PREGAP, ...) is skipped
+pub(super) fn parse_cue(sheet: &str) -> Result<Vec<CueFile>, EmuError> {
+    let mut files: Vec<CueFile> = Vec::new();
+
+    for (line_number, line) in sheet.lines().enumerate() {
+        let line = line.trim();
+        let keyword = line.split_whitespace().next().unwrap_or("");
+        match keyword {
+            "FILE" => {
+                //File names can have spaces, so they're quoted. Unquoted names run to the next space
+                let rest = line["FILE".len()..].trim_start();
+                let name = if let Some(quoted) = rest.strip_prefix('"') {
+                    quoted.split('"').next()
+                } else {
+                    rest.split_whitespace().next()
+                };
+                match name {
+                    Some(name) if !name.is_empty() => files.push(CueFile {
+                        name: name.to_string(),
+                        tracks: Vec::new(),
+                    }),
+                    _ => return Err(unsupported(line_number, "FILE without a file name")),
+                }
+            }
+            "TRACK" => {
+                let mut words = line.split_whitespace().skip(1);
+                let number = words
+                    .next()
+                    .and_then(|number| number.parse().ok())
+                    .ok_or_else(|| unsupported(line_number, "bad track number"))?;
+                let track_type = match words.next() {
+                    Some("MODE1/2352") => TrackType::Mode1,
+                    Some("MODE2/2352") => TrackType::Mode2,
+                    Some("AUDIO") => TrackType::Audio,
+                    Some(other) => {
+                        return Err(unsupported(
+                            line_number,
+                            &format!("{} tracks aren't supported, only raw 2352 byte sectors", other),
+                        ))
+                    }
+                    None => return Err(unsupported(line_number, "TRACK without a type")),
+                };
+                let file = files
+                    .last_mut()
+                    .ok_or_else(|| unsupported(line_number, "TRACK before any FILE"))?;
+                file.tracks.push(CueTrack {
+                    number,
+                    track_type,
+                    start_sector: 0,
+                });
+            }
+            "INDEX" => {
+                let mut words = line.split_whitespace().skip(1);
+                let index = words.next();
+                let start_sector = words
+                    .next()
+                    .and_then(parse_msf)
+                    .ok_or_else(|| unsupported(line_number, "bad INDEX time"))?;
+                //INDEX 00 is the pregap. Tracks are addressed from INDEX 01
+                if index == Some("01") {
+                    let track = files
+                        .last_mut()
+                        .and_then(|file| file.tracks.last_mut())
+                        .ok_or_else(|| unsupported(line_number, "INDEX before any TRACK"))?;
+                    track.start_sector = start_sector;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if files.iter().all(|file| file.tracks.is_empty()) {
+        return Err(EmuError::UnsupportedDiscFormat("cue sheet has no tracks".to_string()));
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cue() {
+        let sheet = "REM a comment\n\
+                     FILE \"Game (Track 1).bin\" BINARY\n\
+                     \x20 TRACK 01 MODE2/2352\n\
+                     \x20   INDEX 01 00:00:00\n\
+                     \x20 TRACK 02 AUDIO\n\
+                     \x20   INDEX 00 01:00:00\n\
+                     \x20   INDEX 01 01:02:05\n\
+                     FILE track3.bin BINARY\n\
+                     \x20 TRACK 03 AUDIO\n\
+                     \x20   INDEX 01 00:00:00\n";
+        let files = parse_cue(sheet).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].name, "Game (Track 1).bin");
+        assert_eq!(
+            files[0].tracks,
+            vec![
+                CueTrack { number: 1, track_type: TrackType::Mode2, start_sector: 0 },
+                CueTrack { number: 2, track_type: TrackType::Audio, start_sector: 62 * 75 + 5 },
+            ]
+        );
+        assert_eq!(files[1].name, "track3.bin");
+        assert_eq!(files[1].tracks[0].number, 3);
+
+        assert!(matches!(
+            parse_cue("FILE a.bin BINARY\nTRACK 01 MODE2/2336\n"),
+            Err(EmuError::UnsupportedDiscFormat(_))
+        ));
+    }
+}
@@ -1,20 +1,46 @@
-use super::SectorSize;
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+
+use super::chd::ChdDisc;
+use super::cue::parse_cue;
+use super::{SectorSize, LOG_TARGET};
+use crate::error::EmuError;
+use log::warn;
 
 pub(super) const SECTORS_PER_SECOND: usize = 75;
 pub(super) const BYTES_PER_SECTOR: usize = 2352;
+//The first track starts 2 seconds in, at 00:02:00
+pub(super) const LEAD_IN_SECTORS: usize = 2 * SECTORS_PER_SECOND;
+//Sector of the first track holding the license text
+pub(super) const LICENSE_SECTOR: usize = 4;
+//Sector layout: 12 sync bytes, then the minute, second and frame in BCD and the mode
+pub(super) const SYNC_SIZE: usize = 12;
+pub(super) const MODE_OFFSET: usize = 15;
+//User data in a mode 1 sector, and all that a .iso keeps of each one
+const ISO_SECTOR_SIZE: usize = 2048;
 
 #[derive(Debug)]
 pub struct DiscIndex {
-    minutes: usize,
-    seconds: usize,
-    sectors: usize,
+    pub(super) minutes: usize,
+    pub(super) seconds: usize,
+    pub(super) sectors: usize,
+}
+
+/// True for bytes made of two decimal digits, 0x00 to 0x99
+pub fn is_bcd(value: usize) -> bool {
+    value <= 0x99 && value & 0x0F <= 9
 }
 
+/// Packed BCD to its value. Anything from the guest has to be checked with is_bcd first
 pub fn bcd_to_dec(hex: usize) -> usize {
+    debug_assert!(is_bcd(hex), "{:#X} isn't BCD", hex);
     ((hex & 0xF0) >> 4) * 10 + (hex & 0x0F)
 }
 
+/// Value to packed BCD. Only goes up to 99
 pub fn dec_to_bcd(dec: usize) -> usize {
+    debug_assert!(dec <= 99, "{} doesn't fit in a BCD byte", dec);
     (dec/10*16) + (dec%10)
 }
 
@@ -35,13 +61,30 @@ impl DiscIndex {
         }
     }
 
+    /// Byte offset into the disc's sectors. Locations before 00:02:00 are in the lead-in and don't have one
     pub fn as_address(&self) -> u32 {
         let total_seconds = (self.minutes * 60) +self.seconds;
-        let total_frames = ((total_seconds * SECTORS_PER_SECOND) + self.sectors) - 150;
+        let total_frames = ((total_seconds * SECTORS_PER_SECOND) + self.sectors) - LEAD_IN_SECTORS;
         //println!(">>>>>>> Self {:?} SECTOR {}", self, total_frames);
         (total_frames * BYTES_PER_SECTOR) as u32
     }
 
+    /// Index of an absolute sector number, counting from 00:00:00
+    pub fn from_sector(sector: usize) -> DiscIndex {
+        let seconds = sector / SECTORS_PER_SECOND;
+        DiscIndex::new_dec(seconds / 60, seconds % 60, sector % SECTORS_PER_SECOND)
+    }
+
+    /// Absolute sector number, counting from 00:00:00
+    pub fn as_sector(&self) -> usize {
+        (self.minutes * 60 + self.seconds) * SECTORS_PER_SECOND + self.sectors
+    }
+
+    /// Like as_address, but None for locations in the lead-in
+    pub fn checked_address(&self) -> Option<usize> {
+        Some(self.as_sector().checked_sub(LEAD_IN_SECTORS)? * BYTES_PER_SECTOR)
+    }
+
     pub fn plus_sector_offset(&self, offset_sectors: usize) -> DiscIndex {
         let sectors = (self.sectors + offset_sectors) % 75;
         let raw_seconds = self.seconds + ((self.sectors + offset_sectors) / SECTORS_PER_SECOND);
@@ -51,63 +94,418 @@ impl DiscIndex {
     }
 }
 
+/// Position held in subchannel Q. In a pregap (index 0) the relative time counts down to the track start
+#[derive(Debug)]
+pub struct SubchannelQ {
+    //Control/ADR byte. Bit 6 marks a data track, and ADR 1 says this is a position
+    pub control: u8,
+    pub track: usize,
+    pub index: usize,
+    pub relative: DiscIndex,
+    pub absolute: DiscIndex,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrackType {
+    Mode1,
+    Mode2,
+    Audio,
+}
+
+/// Licensing region, reported by GetID as the last letter of SCEA/SCEE/SCEI
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+    NorthAmerica,
+    Europe,
+    Japan,
+}
+
+impl Region {
+    pub fn license_string(&self) -> &'static [u8; 4] {
+        match self {
+            Region::NorthAmerica => b"SCEA",
+            Region::Europe => b"SCEE",
+            Region::Japan => b"SCEI",
+        }
+    }
+}
+
 pub struct DiscTrack {
     data: Vec<u8>,
+    track_type: TrackType,
+    //Sectors at the start of data before the track itself begins (INDEX 01)
+    pregap: usize,
 }
 
 impl DiscTrack {
+    /// Data track holding raw 2352 byte mode 2 sectors
     pub fn new(data: Vec<u8>) -> Self {
+        Self::with_type(data, TrackType::Mode2)
+    }
+
+    pub fn with_type(data: Vec<u8>, track_type: TrackType) -> Self {
         Self {
-            data
+            data,
+            track_type,
+            pregap: 0,
         }
     }
+
+    pub fn with_pregap(mut self, sectors: usize) -> Self {
+        self.pregap = sectors;
+        self
+    }
+
+    pub fn track_type(&self) -> TrackType {
+        self.track_type
+    }
 }
 
-pub struct Disc {
+/// A disc image the drive can read from. Sectors are always the full 2352 bytes, whatever the image stores.
+/// They're handed out as copies, since compressed images only keep a few of them decompressed at a time
+pub trait Disc: Send {
+    fn title(&self) -> &str;
+
+    fn region(&self) -> Region;
+
+    /// The whole 2352 byte sector, sync and header included. Audio sectors are all sample data
+    fn read_sector(&self, location: &DiscIndex) -> [u8; BYTES_PER_SECTOR];
+
+    fn track_count(&self) -> usize;
+
+    /// Where a track begins on the disc. Tracks count from 1, and track 0 gives the lead-out (the end of the disc)
+    fn track_start(&self, track: usize) -> Option<DiscIndex>;
+
+    /// Number of the track holding the given location, counting from 1. A track's pregap belongs to it
+    fn track_number_at(&self, location: &DiscIndex) -> usize;
+
+    fn track_type(&self, track: usize) -> Option<TrackType>;
+
+    /// The part of a sector handed to the cpu. Whole sectors skip the sync bytes, and data only reads
+    /// skip the header too, along with the subheader on mode 2 sectors
+    fn read_data(&self, location: &DiscIndex, sector_size: &SectorSize) -> Vec<u8> {
+        let sector = self.read_sector(location);
+        let start = match sector_size {
+            SectorSize::WholeSector => SYNC_SIZE,
+            SectorSize::DataOnly if sector[MODE_OFFSET] == 1 => SYNC_SIZE + 4,
+            SectorSize::DataOnly => SYNC_SIZE + 12,
+        };
+        sector[start..start + *sector_size as usize].to_vec()
+    }
+
+    /// Subchannel Q of the sector at location
+    fn subchannel_q(&self, location: &DiscIndex) -> SubchannelQ {
+        let track = self.track_number_at(location);
+        let track_start = self.track_start(track).map_or(0, |start| start.as_sector());
+        let sector = location.as_sector();
+        let (index, relative) = match sector.checked_sub(track_start) {
+            Some(offset) => (1, offset),
+            None => (0, track_start - sector),
+        };
+        let control = match self.track_type(track) {
+            Some(TrackType::Audio) | None => 0x01,
+            Some(_) => 0x41,
+        };
+        SubchannelQ {
+            control,
+            track,
+            index,
+            relative: DiscIndex::from_sector(relative),
+            absolute: DiscIndex::from_sector(sector),
+        }
+    }
+}
+
+/// Opens a disc image by its extension: .bin/.img/.cue images of raw sectors, a .iso of 2048 byte sectors,
+/// or a compressed .chd
+pub fn open(path: &Path) -> Result<Box<dyn Disc>, EmuError> {
+    let extension = path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_lowercase());
+    match extension.as_deref() {
+        Some("iso") => Ok(Box::new(RawIsoDisc::from_file(path)?)),
+        Some("chd") => Ok(Box::new(ChdDisc::from_file(path)?)),
+        Some("bin") | Some("img") | Some("cue") => Ok(Box::new(CueDisc::from_file(path)?)),
+        _ => Err(EmuError::UnsupportedDiscFormat(format!(
+            "{} isn't a .bin, .img, .cue, .iso or .chd file",
+            path.display()
+        ))),
+    }
+}
+
+/// A disc of raw 2352 byte sectors, from a .bin/.img image or a cue sheet
+pub struct CueDisc {
     tracks: Vec<DiscTrack>,
     title: String,
+    region: Region,
 }
 
-impl Disc {
+impl CueDisc {
     pub fn new(title: &str) -> Self {
         Self {
             tracks: Vec::new(),
             title: String::from(title),
+            region: Region::NorthAmerica,
         }
     }
 
-    pub fn title(&self) -> &str {
-        &self.title
+    /// Loads a raw .bin/.img image as a single data track, or a .cue sheet and the .bin files it references.
+    /// Every file has to be made of whole 2352 byte sectors
+    pub fn from_file(path: &Path) -> Result<Self, EmuError> {
+        let title = path.file_name().and_then(|name| name.to_str()).unwrap_or("disc");
+        let extension = path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_lowercase());
+        let mut disc = CueDisc::new(title);
+        match extension.as_deref() {
+            Some("bin") | Some("img") => disc.add_track(DiscTrack::new(read_image_file(path)?)),
+            Some("cue") => {
+                let sheet = fs::read_to_string(path).map_err(|e| EmuError::from_io(path.to_path_buf(), e))?;
+                let dir = path.parent().unwrap_or_else(|| Path::new(""));
+                for file in parse_cue(&sheet)? {
+                    let mut data = read_image_file(&dir.join(&file.name))?;
+                    //Files can hold several tracks. Split them at each track's INDEX 01, working back from the last
+                    let mut tracks = Vec::new();
+                    for (i, track) in file.tracks.iter().enumerate().rev() {
+                        let start = if i == 0 { 0 } else { track.start_sector * BYTES_PER_SECTOR };
+                        if start > data.len() {
+                            return Err(EmuError::UnsupportedDiscFormat(format!(
+                                "track {} starts past the end of {}",
+                                track.number, file.name
+                            )));
+                        }
+                        //The first track in a file keeps any pregap before its INDEX 01
+                        let pregap = if i == 0 { track.start_sector } else { 0 };
+                        tracks.push(DiscTrack::with_type(data.split_off(start), track.track_type).with_pregap(pregap));
+                    }
+                    tracks.into_iter().rev().for_each(|track| disc.add_track(track));
+                }
+            }
+            _ => {
+                return Err(EmuError::UnsupportedDiscFormat(format!(
+                    "{} isn't a .bin, .img or .cue file",
+                    title
+                )))
+            }
+        };
+        if let Some(region) = disc.detect_region() {
+            disc.region = region;
+        }
+        Ok(disc)
     }
 
-    pub fn add_track(&mut self, track: DiscTrack) {
-        self.tracks.push(track);
+    /// Reads the region from the license text in sector 4 of the first track
+    pub fn detect_region(&self) -> Option<Region> {
+        let track = self.tracks.first()?;
+        region_from_license(track.data.get(LICENSE_SECTOR * BYTES_PER_SECTOR..(LICENSE_SECTOR + 1) * BYTES_PER_SECTOR)?)
     }
 
-    pub fn read_sector(&self, location: DiscIndex, sector_size: &SectorSize) -> &[u8] {
-        let address = location.as_address() as usize;
-        let (track, track_offset) = self.track_of_offset(address as usize);
-        let sector_address = (address - track_offset) + 24;
-        let data = &track.data[sector_address..sector_address + *sector_size as usize];
-        //println!("data Byte 0 {:#X}", data[0]);
-        //println!("Reading sector from address {}. Sector mode: {} Sector size {:?}", address, track.data[address + 15], sector_size);
-        //println!("According to the sector header, this is M: {:X} ({}) S: {:X} ({}) F: {:X} ({})", track.data[(address - track_offset) + 12], bcd_to_dec(track.data[(address - track_offset) + 12] as usize), track.data[(address - track_offset) + 13], bcd_to_dec(track.data[(address - track_offset) + 13] as usize), track.data[(address - track_offset) + 14], bcd_to_dec(track.data[(address - track_offset) + 14] as usize));
-        data
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    pub fn add_track(&mut self, track: DiscTrack) {
+        self.tracks.push(track);
     }
 
-    fn track_of_offset(&self, offset: usize) -> (&DiscTrack, usize) {
+    fn track_of_offset(&self, offset: usize) -> Option<(&DiscTrack, usize)> {
         let mut total_size = 0;
         for track in &self.tracks {
             if offset >= total_size && offset < total_size + track.data.len() {
-                return (&track, total_size);
+                return Some((track, total_size));
             }
             total_size += track.data.len();
         }
-        panic!("Unable to locate track at offset {}!", offset);
+        None
+    }
+}
+
+impl Disc for CueDisc {
+    fn title(&self) -> &str {
+        &self.title
+    }
+
+    fn region(&self) -> Region {
+        self.region
+    }
+
+    fn read_sector(&self, location: &DiscIndex) -> [u8; BYTES_PER_SECTOR] {
+        let found = location.checked_address().and_then(|address| Some((address, self.track_of_offset(address)?)));
+        match found {
+            Some((address, (track, track_offset))) => {
+                let start = address - track_offset;
+                track.data[start..start + BYTES_PER_SECTOR].try_into().unwrap()
+            }
+            None => missing_sector(location),
+        }
     }
 
-    pub fn track_count(&self) -> usize {
+    fn track_count(&self) -> usize {
         self.tracks.len()
     }
+
+    fn track_start(&self, track: usize) -> Option<DiscIndex> {
+        let previous_tracks = match track {
+            0 => &self.tracks[..],
+            _ if track <= self.tracks.len() => &self.tracks[..track - 1],
+            _ => return None,
+        };
+        let sectors_before: usize = previous_tracks.iter().map(|t| t.data.len() / BYTES_PER_SECTOR).sum();
+        let pregap = if track == 0 { 0 } else { self.tracks[track - 1].pregap };
+        Some(DiscIndex::from_sector(LEAD_IN_SECTORS + sectors_before + pregap))
+    }
+
+    fn track_number_at(&self, location: &DiscIndex) -> usize {
+        let address = location.checked_address().unwrap_or(0);
+        let mut total_size = 0;
+        for (i, track) in self.tracks.iter().enumerate() {
+            total_size += track.data.len();
+            if address < total_size {
+                return i + 1;
+            }
+        }
+        self.tracks.len()
+    }
+
+    fn track_type(&self, track: usize) -> Option<TrackType> {
+        self.tracks.get(track.checked_sub(1)?).map(|t| t.track_type)
+    }
+}
+
+/// What's read from outside the disc's tracks. The drive stops at the lead-out, so this is only reached by a bad location
+pub(super) fn missing_sector(location: &DiscIndex) -> [u8; BYTES_PER_SECTOR] {
+    warn!(target: LOG_TARGET, "CD: No sector at {:?}, reading zeros", location);
+    [0; BYTES_PER_SECTOR]
+}
+
+/// A .iso image holding only the 2048 bytes of user data from each sector of a single mode 1 track.
+/// The sync and header are filled in on load. EDC/ECC are left zeroed, nothing checks them
+pub struct RawIsoDisc {
+    //Rebuilt 2352 byte sectors
+    data: Vec<u8>,
+    title: String,
+    region: Region,
+}
+
+impl RawIsoDisc {
+    pub fn new(title: &str, user_data: &[u8]) -> Self {
+        let mut data = Vec::with_capacity(user_data.len() / ISO_SECTOR_SIZE * BYTES_PER_SECTOR);
+        for (i, user) in user_data.chunks(ISO_SECTOR_SIZE).enumerate() {
+            let location = DiscIndex::from_sector(LEAD_IN_SECTORS + i);
+            let mut sector = [0; BYTES_PER_SECTOR];
+            sector[1..SYNC_SIZE - 1].fill(0xFF);
+            sector[SYNC_SIZE..MODE_OFFSET].copy_from_slice(&[
+                dec_to_bcd(location.minutes) as u8,
+                dec_to_bcd(location.seconds) as u8,
+                dec_to_bcd(location.sectors) as u8,
+            ]);
+            sector[MODE_OFFSET] = 1;
+            sector[MODE_OFFSET + 1..][..user.len()].copy_from_slice(user);
+            data.extend_from_slice(&sector);
+        }
+        let region = data
+            .get(LICENSE_SECTOR * BYTES_PER_SECTOR..(LICENSE_SECTOR + 1) * BYTES_PER_SECTOR)
+            .and_then(region_from_license)
+            .unwrap_or(Region::NorthAmerica);
+        Self {
+            data,
+            title: String::from(title),
+            region,
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, EmuError> {
+        let title = path.file_name().and_then(|name| name.to_str()).unwrap_or("disc");
+        let data = fs::read(path).map_err(|e| EmuError::from_io(path.to_path_buf(), e))?;
+        if data.is_empty() || data.len() % ISO_SECTOR_SIZE != 0 {
+            return Err(EmuError::UnsupportedDiscFormat(format!(
+                "{} isn't made of {} byte sectors",
+                path.display(),
+                ISO_SECTOR_SIZE
+            )));
+        }
+        Ok(Self::new(title, &data))
+    }
 }
 
+impl Disc for RawIsoDisc {
+    fn title(&self) -> &str {
+        &self.title
+    }
+
+    fn region(&self) -> Region {
+        self.region
+    }
+
+    fn read_sector(&self, location: &DiscIndex) -> [u8; BYTES_PER_SECTOR] {
+        let sector = location
+            .checked_address()
+            .and_then(|start| self.data.get(start..start + BYTES_PER_SECTOR));
+        match sector {
+            Some(sector) => sector.try_into().unwrap(),
+            None => missing_sector(location),
+        }
+    }
+
+    fn track_count(&self) -> usize {
+        1
+    }
+
+    fn track_start(&self, track: usize) -> Option<DiscIndex> {
+        match track {
+            0 => Some(DiscIndex::from_sector(LEAD_IN_SECTORS + self.data.len() / BYTES_PER_SECTOR)),
+            1 => Some(DiscIndex::from_sector(LEAD_IN_SECTORS)),
+            _ => None,
+        }
+    }
+
+    fn track_number_at(&self, _location: &DiscIndex) -> usize {
+        1
+    }
+
+    fn track_type(&self, track: usize) -> Option<TrackType> {
+        match track {
+            1 => Some(TrackType::Mode1),
+            _ => None,
+        }
+    }
+}
+
+/// Region from the license text, "Licensed by Sony Computer Entertainment Amer ica/Euro pe/Inc."
+pub(super) fn region_from_license(sector: &[u8]) -> Option<Region> {
+    let contains = |text: &[u8]| sector.windows(text.len()).any(|window| window == text);
+    if contains(b"Amer") {
+        Some(Region::NorthAmerica)
+    } else if contains(b"Euro") {
+        Some(Region::Europe)
+    } else if contains(b"Inc.") {
+        Some(Region::Japan)
+    } else {
+        None
+    }
+}
+
+fn read_image_file(path: &Path) -> Result<Vec<u8>, EmuError> {
+    let data = fs::read(path).map_err(|e| EmuError::from_io(path.to_path_buf(), e))?;
+    if data.is_empty() || data.len() % BYTES_PER_SECTOR != 0 {
+        return Err(EmuError::UnsupportedDiscFormat(format!(
+            "{} isn't made of raw {} byte sectors",
+            path.display(),
+            BYTES_PER_SECTOR
+        )));
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bcd_round_trip() {
+        for value in 0..=99 {
+            assert!(is_bcd(dec_to_bcd(value)));
+            assert_eq!(bcd_to_dec(dec_to_bcd(value)), value);
+        }
+        assert_eq!(dec_to_bcd(75), 0x75);
+        assert!(!is_bcd(0x1A));
+        assert!(!is_bcd(0xA0));
+        assert!(!is_bcd(0x100));
+    }
+}
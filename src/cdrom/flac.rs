@@ -0,0 +1,484 @@
+//FLAC decoding, for the cdfl codec in CHD images. CHD keeps just the frames, without the stream header.
+//They always hold 16 bit stereo, which comes out big endian like the rest of the image's audio
+
+//Predictor coefficients for the fixed subframes of each order
+const FIXED_COEFFICIENTS: [&[i32]; 5] = [&[], &[1], &[2, -1], &[3, -3, 1], &[4, -6, 4, -1]];
+const FRAME_SYNC: u32 = 0x7FFC;
+const SAMPLE_BITS: u32 = 16;
+//Channel assignments other than independent stereo code the difference between the channels in a side channel
+const INDEPENDENT_STEREO: u32 = 1;
+const LEFT_SIDE: u32 = 8;
+const SIDE_RIGHT: u32 = 9;
+const MID_SIDE: u32 = 10;
+
+/// Most significant bit first
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn bits(&mut self, count: u32) -> Result<u32, &'static str> {
+        let mut value = 0u64;
+        for _ in 0..count {
+            let byte = self.data.get(self.position / 8).ok_or("ran out of input")?;
+            value = (value << 1) | ((byte >> (7 - self.position % 8)) & 1) as u64;
+            self.position += 1;
+        }
+        Ok(value as u32)
+    }
+
+    fn signed(&mut self, count: u32) -> Result<i32, &'static str> {
+        let value = self.bits(count)?;
+        Ok(match count {
+            0 => 0,
+            _ => ((value << (32 - count)) as i32) >> (32 - count),
+        })
+    }
+
+    /// Number of zeros before the next one
+    fn unary(&mut self) -> Result<u32, &'static str> {
+        let mut count = 0;
+        while self.bits(1)? == 0 {
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn align_to_byte(&mut self) {
+        self.position = (self.position + 7) & !7;
+    }
+}
+
+/// Decodes frames until there are samples stereo samples, returned as big endian bytes,
+/// along with how many bytes of data the frames took up
+pub(super) fn decode(data: &[u8], samples: usize) -> Result<(Vec<u8>, usize), &'static str> {
+    let mut bits = BitReader { data, position: 0 };
+    let mut out = Vec::with_capacity(samples * 4);
+    while out.len() < samples * 4 {
+        let start = bits.position / 8;
+        let (left, right) = frame(&mut bits)?;
+        bits.align_to_byte();
+        if crc16(&data[start..bits.position / 8]) != bits.bits(16)? as u16 {
+            return Err("frame is corrupt");
+        }
+        if out.len() + left.len() * 4 > samples * 4 {
+            return Err("decoded past the expected size");
+        }
+        for (left, right) in left.iter().zip(right.iter()) {
+            out.extend_from_slice(&(*left as i16).to_be_bytes());
+            out.extend_from_slice(&(*right as i16).to_be_bytes());
+        }
+    }
+    Ok((out, bits.position / 8))
+}
+
+/// A frame header, then a subframe for each channel
+fn frame(bits: &mut BitReader) -> Result<(Vec<i32>, Vec<i32>), &'static str> {
+    let start = bits.position / 8;
+    if bits.bits(15)? != FRAME_SYNC {
+        return Err("lost frame sync");
+    }
+    //Blocking strategy
+    bits.bits(1)?;
+    let block_size_code = bits.bits(4)?;
+    let sample_rate_code = bits.bits(4)?;
+    let channels = bits.bits(4)?;
+    //0 takes the sample size from the stream header, which is always 16 bit
+    if !matches!(bits.bits(3)?, 0 | 4) {
+        return Err("only 16 bit samples are supported");
+    }
+    bits.bits(1)?;
+    //Frame or sample number, coded like utf-8
+    let first = bits.bits(8)? as u8;
+    for _ in 1..first.leading_ones().max(1) {
+        bits.bits(8)?;
+    }
+    let block_size = match block_size_code {
+        1 => 192,
+        2..=5 => 576 << (block_size_code - 2),
+        6 => bits.bits(8)? as usize + 1,
+        7 => bits.bits(16)? as usize + 1,
+        8..=15 => 256 << (block_size_code - 8),
+        _ => return Err("reserved block size"),
+    };
+    match sample_rate_code {
+        12 => {
+            bits.bits(8)?;
+        }
+        13 | 14 => {
+            bits.bits(16)?;
+        }
+        15 => return Err("bad sample rate"),
+        _ => (),
+    }
+    if crc8(&bits.data[start..bits.position / 8]) != bits.bits(8)? as u8 {
+        return Err("frame header is corrupt");
+    }
+
+    //The side channel needs an extra bit
+    let side_bits = |channel: u32| match (channels, channel) {
+        (LEFT_SIDE, 1) | (SIDE_RIGHT, 0) | (MID_SIDE, 1) => SAMPLE_BITS + 1,
+        _ => SAMPLE_BITS,
+    };
+    if !matches!(channels, INDEPENDENT_STEREO | LEFT_SIDE | SIDE_RIGHT | MID_SIDE) {
+        return Err("only stereo is supported");
+    }
+    let first = subframe(bits, block_size, side_bits(0))?;
+    let second = subframe(bits, block_size, side_bits(1))?;
+    Ok(match channels {
+        LEFT_SIDE => {
+            let right = first.iter().zip(&second).map(|(left, side)| left.wrapping_sub(*side)).collect();
+            (first, right)
+        }
+        SIDE_RIGHT => {
+            let left = first.iter().zip(&second).map(|(side, right)| side.wrapping_add(*right)).collect();
+            (left, second)
+        }
+        MID_SIDE => first
+            .iter()
+            .zip(&second)
+            .map(|(mid, side)| {
+                let mid = (mid << 1) | (side & 1);
+                (mid.wrapping_add(*side) >> 1, mid.wrapping_sub(*side) >> 1)
+            })
+            .unzip(),
+        _ => (first, second),
+    })
+}
+
+fn subframe(bits: &mut BitReader, block_size: usize, sample_bits: u32) -> Result<Vec<i32>, &'static str> {
+    if bits.bits(1)? != 0 {
+        return Err("bad subframe padding");
+    }
+    let kind = bits.bits(6)?;
+    //Low bits that are zero in every sample can be left out
+    let wasted = match bits.bits(1)? {
+        0 => 0,
+        _ => bits.unary()? + 1,
+    };
+    let sample_bits = sample_bits.checked_sub(wasted).ok_or("too many wasted bits")?;
+
+    let mut samples = Vec::with_capacity(block_size);
+    match kind {
+        0 => samples.resize(block_size, bits.signed(sample_bits)?),
+        1 => {
+            for _ in 0..block_size {
+                samples.push(bits.signed(sample_bits)?);
+            }
+        }
+        8..=12 => {
+            let coefficients = FIXED_COEFFICIENTS[kind as usize - 8];
+            for _ in 0..coefficients.len() {
+                samples.push(bits.signed(sample_bits)?);
+            }
+            residual(bits, block_size, &mut samples)?;
+            predict(&mut samples, coefficients, 0);
+        }
+        32..=63 => {
+            let order = kind as usize - 31;
+            for _ in 0..order {
+                samples.push(bits.signed(sample_bits)?);
+            }
+            let precision = bits.bits(4)? + 1;
+            if precision == 16 {
+                return Err("bad coefficient precision");
+            }
+            let shift = bits.signed(5)?;
+            if shift < 0 {
+                return Err("negative prediction shift");
+            }
+            let mut coefficients = Vec::with_capacity(order);
+            for _ in 0..order {
+                coefficients.push(bits.signed(precision)?);
+            }
+            residual(bits, block_size, &mut samples)?;
+            predict(&mut samples, &coefficients, shift as u32);
+        }
+        _ => return Err("reserved subframe type"),
+    }
+    if samples.len() != block_size {
+        return Err("subframe is the wrong size");
+    }
+    samples.iter_mut().for_each(|sample| *sample = sample.wrapping_shl(wasted));
+    Ok(samples)
+}
+
+/// Rice coded residuals for the samples after the warm up ones, split into partitions with their own parameter
+fn residual(bits: &mut BitReader, block_size: usize, samples: &mut Vec<i32>) -> Result<(), &'static str> {
+    let parameter_bits = match bits.bits(2)? {
+        0 => 4,
+        1 => 5,
+        _ => return Err("reserved residual coding"),
+    };
+    //All ones means the partition is stored as plain numbers
+    let escape = (1 << parameter_bits) - 1;
+    let partition_order = bits.bits(4)?;
+    let partition_size = block_size >> partition_order;
+    if partition_size << partition_order != block_size || partition_size < samples.len() {
+        return Err("bad partition order");
+    }
+    for partition in 0..1 << partition_order {
+        //The first partition doesn't include the warm up samples
+        let count = if partition == 0 { partition_size - samples.len() } else { partition_size };
+        let parameter = bits.bits(parameter_bits)?;
+        if parameter == escape {
+            let raw_bits = bits.bits(5)?;
+            for _ in 0..count {
+                samples.push(bits.signed(raw_bits)?);
+            }
+            continue;
+        }
+        for _ in 0..count {
+            let value = ((bits.unary()? as u64) << parameter) | bits.bits(parameter)? as u64;
+            if value > u32::MAX as u64 {
+                return Err("residual out of range");
+            }
+            samples.push((value >> 1) as i32 ^ -((value & 1) as i32));
+        }
+    }
+    Ok(())
+}
+
+/// Adds the prediction from the samples before to each residual after the warm up samples
+fn predict(samples: &mut [i32], coefficients: &[i32], shift: u32) {
+    for i in coefficients.len()..samples.len() {
+        let prediction = coefficients
+            .iter()
+            .enumerate()
+            .fold(0i64, |sum, (j, coefficient)| sum.wrapping_add(*coefficient as i64 * samples[i - 1 - j] as i64));
+        samples[i] = samples[i].wrapping_add((prediction >> shift) as i32);
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| match crc & 0x80 {
+            0 => crc << 1,
+            _ => (crc << 1) ^ 0x07,
+        })
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ ((*byte as u16) << 8), |crc, _| match crc & 0x8000 {
+            0 => crc << 1,
+            _ => (crc << 1) ^ 0x8005,
+        })
+    })
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    /// Most significant bit first
+    #[derive(Default)]
+    struct BitWriter {
+        data: Vec<u8>,
+        length: usize,
+    }
+
+    impl BitWriter {
+        fn write(&mut self, value: u64, count: u32) {
+            for i in (0..count).rev() {
+                if self.length.is_multiple_of(8) {
+                    self.data.push(0);
+                }
+                if (value >> i) & 1 != 0 {
+                    *self.data.last_mut().unwrap() |= 0x80 >> (self.length % 8);
+                }
+                self.length += 1;
+            }
+        }
+
+        fn signed(&mut self, value: i32, count: u32) {
+            self.write(value as u64 & ((1 << count) - 1), count);
+        }
+    }
+
+    enum Subframe {
+        Constant,
+        Verbatim,
+        Fixed(usize),
+        //Coefficients, their precision and the shift
+        Lpc(&'static [i32], u32, u32),
+    }
+
+    /// Partitions alternate between a rice parameter of 3 and being stored with 20 bits a residual
+    fn write_residual(bits: &mut BitWriter, residuals: &[i32], partition_order: u32, block_size: usize) {
+        bits.write(0, 2);
+        bits.write(partition_order as u64, 4);
+        let partition_size = block_size >> partition_order;
+        let warm_up = block_size - residuals.len();
+        let mut residuals = residuals.iter();
+        for partition in 0..1 << partition_order {
+            let count = if partition == 0 { partition_size - warm_up } else { partition_size };
+            if partition % 2 == 1 {
+                bits.write(15, 4);
+                bits.write(20, 5);
+                residuals.by_ref().take(count).for_each(|residual| bits.signed(*residual, 20));
+                continue;
+            }
+            bits.write(3, 4);
+            for residual in residuals.by_ref().take(count) {
+                let value = ((*residual << 1) ^ (*residual >> 31)) as u32;
+                (0..value >> 3).for_each(|_| bits.write(0, 1));
+                bits.write(1, 1);
+                bits.write(value as u64 & 7, 3);
+            }
+        }
+    }
+
+    fn write_subframe(bits: &mut BitWriter, samples: &[i32], sample_bits: u32, kind: &Subframe, wasted: u32) {
+        let (order, coefficients, shift) = match kind {
+            Subframe::Constant => (0, &[][..], 0),
+            Subframe::Verbatim => (0, &[][..], 0),
+            Subframe::Fixed(order) => (*order, FIXED_COEFFICIENTS[*order], 0),
+            Subframe::Lpc(coefficients, _, shift) => (coefficients.len(), *coefficients, *shift),
+        };
+        bits.write(0, 1);
+        bits.write(
+            match kind {
+                Subframe::Constant => 0,
+                Subframe::Verbatim => 1,
+                Subframe::Fixed(order) => 8 + *order as u64,
+                Subframe::Lpc(coefficients, ..) => 31 + coefficients.len() as u64,
+            },
+            6,
+        );
+        if wasted > 0 {
+            bits.write(1, 1);
+            bits.write(1, wasted);
+        } else {
+            bits.write(0, 1);
+        }
+        let samples: Vec<i32> = samples.iter().map(|sample| sample >> wasted).collect();
+        let sample_bits = sample_bits - wasted;
+        match kind {
+            Subframe::Constant => return bits.signed(samples[0], sample_bits),
+            Subframe::Verbatim => return samples.iter().for_each(|sample| bits.signed(*sample, sample_bits)),
+            _ => (),
+        }
+        samples[..order].iter().for_each(|sample| bits.signed(*sample, sample_bits));
+        if let Subframe::Lpc(coefficients, precision, shift) = kind {
+            bits.write(*precision as u64 - 1, 4);
+            bits.write(*shift as u64, 5);
+            coefficients.iter().for_each(|coefficient| bits.signed(*coefficient, *precision));
+        }
+        let residuals: Vec<i32> = (order..samples.len())
+            .map(|i| {
+                let prediction: i64 = (0..order).map(|j| coefficients[j] as i64 * samples[i - 1 - j] as i64).sum();
+                samples[i] - (prediction >> shift) as i32
+            })
+            .collect();
+        write_residual(bits, &residuals, 1, samples.len());
+    }
+
+    /// One frame, with the channels coded as channel_assignment says
+    fn write_frame(left: &[i32], right: &[i32], channel_assignment: u32, subframes: [(Subframe, u32); 2]) -> Vec<u8> {
+        let mut bits = BitWriter::default();
+        bits.write(0xFFF8, 16);
+        //16 bit block size, 44.1kHz, 16 bit samples
+        bits.write(7, 4);
+        bits.write(9, 4);
+        bits.write(channel_assignment as u64, 4);
+        bits.write(4, 3);
+        bits.write(0, 1);
+        bits.write(0, 8);
+        bits.write(left.len() as u64 - 1, 16);
+        let crc = crc8(&bits.data);
+        bits.write(crc as u64, 8);
+
+        let (first, second): (Vec<i32>, Vec<i32>) = match channel_assignment {
+            LEFT_SIDE => (left.to_vec(), left.iter().zip(right).map(|(l, r)| l - r).collect()),
+            SIDE_RIGHT => (left.iter().zip(right).map(|(l, r)| l - r).collect(), right.to_vec()),
+            MID_SIDE => left.iter().zip(right).map(|(l, r)| ((l + r) >> 1, l - r)).unzip(),
+            _ => (left.to_vec(), right.to_vec()),
+        };
+        let side_bits = |channel: u32| match (channel_assignment, channel) {
+            (LEFT_SIDE, 1) | (SIDE_RIGHT, 0) | (MID_SIDE, 1) => SAMPLE_BITS + 1,
+            _ => SAMPLE_BITS,
+        };
+        let [(first_kind, first_wasted), (second_kind, second_wasted)] = subframes;
+        write_subframe(&mut bits, &first, side_bits(0), &first_kind, first_wasted);
+        write_subframe(&mut bits, &second, side_bits(1), &second_kind, second_wasted);
+        let crc = crc16(&bits.data);
+        bits.length = bits.data.len() * 8;
+        bits.write(crc as u64, 16);
+        bits.data
+    }
+
+    /// Big endian bytes of interleaved stereo samples
+    fn sample_bytes(left: &[i32], right: &[i32]) -> Vec<u8> {
+        left.iter()
+            .zip(right)
+            .flat_map(|(l, r)| [(*l as i16).to_be_bytes(), (*r as i16).to_be_bytes()].concat())
+            .collect()
+    }
+
+    /// Frames of up to 4096 verbatim stereo samples, taken from big endian bytes
+    pub(in crate::cdrom) fn encode_verbatim(data: &[u8]) -> Vec<u8> {
+        let samples: Vec<i32> = data.chunks_exact(2).map(|sample| i16::from_be_bytes([sample[0], sample[1]]) as i32).collect();
+        samples
+            .chunks(2 * 4096)
+            .flat_map(|block| {
+                let left: Vec<i32> = block.iter().step_by(2).copied().collect();
+                let right: Vec<i32> = block.iter().skip(1).step_by(2).copied().collect();
+                write_frame(&left, &right, INDEPENDENT_STEREO, [(Subframe::Verbatim, 0), (Subframe::Verbatim, 0)])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_flac_decode() {
+        let left: Vec<i32> = (0..48).map(|i| (i * i * 37 % 2000) - 1000).collect();
+        let right: Vec<i32> = (0..48).map(|i| (i * 97 % 4000) - 2000).collect();
+        //A constant left channel and a right one with its low bits clear, for wasted bits
+        let flat_left = [256; 8];
+        let flat_right: Vec<i32> = (0..8).map(|i| i * 12 - 40).collect();
+
+        let mut data = write_frame(
+            &left[..16],
+            &right[..16],
+            INDEPENDENT_STEREO,
+            [(Subframe::Fixed(2), 0), (Subframe::Lpc(&[3, -1], 4, 1), 0)],
+        );
+        data.extend(write_frame(
+            &left[16..32],
+            &right[16..32],
+            MID_SIDE,
+            [(Subframe::Verbatim, 0), (Subframe::Fixed(4), 0)],
+        ));
+        data.extend(write_frame(
+            &flat_left,
+            &flat_right,
+            LEFT_SIDE,
+            [(Subframe::Constant, 0), (Subframe::Fixed(1), 2)],
+        ));
+        data.extend(write_frame(
+            &left[32..48],
+            &right[32..48],
+            SIDE_RIGHT,
+            [(Subframe::Lpc(&[5, -3, 1], 5, 2), 0), (Subframe::Fixed(0), 0)],
+        ));
+        let expected = [
+            sample_bytes(&left[..32], &right[..32]),
+            sample_bytes(&flat_left, &flat_right),
+            sample_bytes(&left[32..], &right[32..]),
+        ]
+        .concat();
+        let length = data.len();
+        data.extend_from_slice(&[0xAA; 5]);
+
+        assert_eq!(decode(&data, 56), Ok((expected, length)));
+        assert!(decode(&data, 50).is_err());
+
+        let samples = [0x12, 0x34, 0xFF, 0xFE, 0x80, 0x00, 0x7F, 0xFF];
+        assert_eq!(decode(&encode_verbatim(&samples), 2).unwrap().0, samples);
+
+        data[20] ^= 1;
+        assert!(decode(&data, 56).is_err());
+    }
+}
@@ -0,0 +1,225 @@
+//Raw deflate (RFC 1951) decoding, for the zlib based codecs in CHD images
+
+const LENGTH_BASE: [usize; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u32; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [usize; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u32; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+//Order the code length code lengths are sent in
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+const MAX_CODE_LENGTH: usize = 15;
+const END_OF_BLOCK: u16 = 256;
+
+/// Least significant bit first, the way deflate packs everything but its huffman codes
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn bits(&mut self, count: u32) -> Result<usize, &'static str> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = self.data.get(self.position / 8).ok_or("ran out of input")?;
+            value |= (((byte >> (self.position % 8)) & 1) as usize) << i;
+            self.position += 1;
+        }
+        Ok(value)
+    }
+
+    fn align_to_byte(&mut self) {
+        self.position = (self.position + 7) & !7;
+    }
+}
+
+/// Canonical huffman code from its code lengths, decoded a bit at a time
+struct Huffman {
+    //Number of codes of each length
+    counts: [usize; MAX_CODE_LENGTH + 1],
+    //Symbols ordered by code
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0; MAX_CODE_LENGTH + 1];
+        lengths.iter().for_each(|length| counts[*length as usize] += 1);
+        counts[0] = 0;
+        let mut symbols: Vec<u16> = (0..lengths.len() as u16).filter(|symbol| lengths[*symbol as usize] != 0).collect();
+        symbols.sort_by_key(|symbol| lengths[*symbol as usize]);
+        Self { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut BitReader) -> Result<u16, &'static str> {
+        //Codes of each length come right after the last code of the length before, so walk them a length at a time
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for length in 1..=MAX_CODE_LENGTH {
+            code |= bits.bits(1)?;
+            let count = self.counts[length];
+            if code < first + count {
+                return Ok(self.symbols[index + code - first]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("bad huffman code")
+    }
+}
+
+/// Decompresses a raw deflate stream, which has to come out to exactly expected_length bytes.
+/// Decoding stops as soon as the output goes past it, so a bad stream can't blow up in size
+pub(super) fn inflate(data: &[u8], expected_length: usize) -> Result<Vec<u8>, &'static str> {
+    let mut bits = BitReader { data, position: 0 };
+    let mut out = Vec::with_capacity(expected_length);
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => stored_block(&mut bits, &mut out, expected_length)?,
+            1 => {
+                let (literals, distances) = fixed_codes();
+                compressed_block(&mut bits, &mut out, expected_length, &literals, &distances)?
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut bits)?;
+                compressed_block(&mut bits, &mut out, expected_length, &literals, &distances)?
+            }
+            _ => return Err("bad block type"),
+        }
+        if last {
+            break;
+        }
+    }
+    match out.len() == expected_length {
+        true => Ok(out),
+        false => Err("decompressed to the wrong size"),
+    }
+}
+
+const TOO_LONG: &str = "decompressed past the expected size";
+
+fn stored_block(bits: &mut BitReader, out: &mut Vec<u8>, expected_length: usize) -> Result<(), &'static str> {
+    bits.align_to_byte();
+    let length = bits.bits(16)?;
+    if bits.bits(16)? != !length & 0xFFFF {
+        return Err("stored block length doesn't match its complement");
+    }
+    if out.len() + length > expected_length {
+        return Err(TOO_LONG);
+    }
+    let start = bits.position / 8;
+    let block = bits.data.get(start..start + length).ok_or("ran out of input")?;
+    out.extend_from_slice(block);
+    bits.position += length * 8;
+    Ok(())
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_codes(bits: &mut BitReader) -> Result<(Huffman, Huffman), &'static str> {
+    let literal_count = bits.bits(5)? + 257;
+    let distance_count = bits.bits(5)? + 1;
+    let length_code_count = bits.bits(4)? + 4;
+    let mut length_lengths = [0; 19];
+    for index in CODE_LENGTH_ORDER.iter().take(length_code_count) {
+        length_lengths[*index] = bits.bits(3)? as u8;
+    }
+    let length_code = Huffman::new(&length_lengths);
+
+    //Literal and distance code lengths are sent as one run, with 16-18 repeating lengths
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (length, repeat) = match length_code.decode(bits)? {
+            16 => (*lengths.last().ok_or("repeat with no length before it")?, 3 + bits.bits(2)?),
+            17 => (0, 3 + bits.bits(3)?),
+            18 => (0, 11 + bits.bits(7)?),
+            length => (length as u8, 1),
+        };
+        lengths.extend(std::iter::repeat_n(length, repeat));
+    }
+    if lengths.len() != literal_count + distance_count {
+        return Err("code lengths run past the end");
+    }
+    Ok((Huffman::new(&lengths[..literal_count]), Huffman::new(&lengths[literal_count..])))
+}
+
+fn compressed_block(
+    bits: &mut BitReader,
+    out: &mut Vec<u8>,
+    expected_length: usize,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<(), &'static str> {
+    loop {
+        let symbol = literals.decode(bits)?;
+        if symbol < END_OF_BLOCK {
+            if out.len() == expected_length {
+                return Err(TOO_LONG);
+            }
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == END_OF_BLOCK {
+            return Ok(());
+        }
+        let symbol = symbol as usize - 257;
+        if symbol >= LENGTH_BASE.len() {
+            return Err("bad length symbol");
+        }
+        let length = LENGTH_BASE[symbol] + bits.bits(LENGTH_EXTRA[symbol])?;
+        let symbol = distances.decode(bits)? as usize;
+        if symbol >= DISTANCE_BASE.len() {
+            return Err("bad distance symbol");
+        }
+        let distance = DISTANCE_BASE[symbol] + bits.bits(DISTANCE_EXTRA[symbol])?;
+        if distance > out.len() {
+            return Err("distance reaches back before the start");
+        }
+        if out.len() + length > expected_length {
+            return Err(TOO_LONG);
+        }
+        //Copies can overlap what they're writing, so go a byte at a time
+        let start = out.len() - distance;
+        for i in 0..length {
+            out.push(out[start + i]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inflate_block_types() {
+        //Stored
+        assert_eq!(inflate(&[0x01, 0x03, 0x00, 0xFC, 0xFF, 1, 2, 3], 3), Ok(vec![1, 2, 3]));
+        //Fixed codes, with a copy that overlaps itself
+        let fixed = [0x4B, 0x4C, 0x4A, 0x4E, 0x84, 0xA1, 0x14, 0x00];
+        assert_eq!(inflate(&fixed, 13).unwrap(), b"abcabcabcabcd");
+        //Dynamic codes
+        let dynamic = [
+            0x1D, 0xC7, 0x41, 0x11, 0x00, 0x20, 0x00, 0xC3, 0x30, 0x2B, 0xB3, 0xD6, 0x16, 0xFF, 0x1A, 0xE0, 0xC8, 0x2F,
+            0x8C, 0x42, 0x39, 0x90, 0x63, 0x18, 0x5F, 0x7A, 0xE4, 0x25, 0x26, 0x17,
+        ];
+        assert_eq!(inflate(&dynamic, 40).unwrap(), b"a accabbadaacb a abcaaaaaacbbdbacaaca ba");
+
+        assert_eq!(inflate(&fixed, 12), Err(TOO_LONG));
+        assert_eq!(inflate(&[0x01, 0x03, 0x00, 0xFC, 0xFF, 1, 2, 3], 2), Err(TOO_LONG));
+        assert!(inflate(&fixed[..4], 13).is_err());
+        assert!(inflate(&[0x01, 0x03, 0x00, 0xFC, 0xFE, 1, 2, 3], 3).is_err());
+    }
+}
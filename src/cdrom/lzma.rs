@@ -0,0 +1,353 @@
+//Raw LZMA decoding, for the cdlz codec in CHD images. CHD leaves out the stream header and always uses
+//lc=3, lp=0 and pb=2. Each hunk is decompressed on its own, so what's been decoded so far is the dictionary
+
+const LITERAL_CONTEXT_BITS: u32 = 3;
+const POSITION_BITS: u32 = 2;
+const POSITION_STATES: usize = 1 << POSITION_BITS;
+const STATES: usize = 12;
+//Literals after a match are coded against the byte at the last match distance
+const FIRST_MATCHED_LITERAL_STATE: usize = 7;
+const MIN_MATCH_LENGTH: usize = 2;
+//Distances past the first 4 slots get their low bits from trees up to this slot, then from direct bits
+const LENGTH_TO_POSITION_STATES: usize = 4;
+const END_POSITION_MODEL_INDEX: usize = 14;
+const FULL_DISTANCES: usize = 1 << (END_POSITION_MODEL_INDEX / 2);
+const ALIGN_BITS: u32 = 4;
+const END_MARKER: u32 = 0xFFFF_FFFF;
+
+const PROBABILITY_BITS: u32 = 11;
+const PROBABILITY_INIT: u16 = 1 << (PROBABILITY_BITS - 1);
+const MOVE_BITS: u32 = 5;
+const TOP_VALUE: u32 = 1 << 24;
+
+struct RangeDecoder<'a> {
+    data: &'a [u8],
+    position: usize,
+    range: u32,
+    code: u32,
+}
+
+impl<'a> RangeDecoder<'a> {
+    fn new(data: &'a [u8]) -> Result<Self, &'static str> {
+        let mut decoder = Self { data, position: 0, range: 0xFFFF_FFFF, code: 0 };
+        if decoder.byte()? != 0 {
+            return Err("bad range coder start");
+        }
+        for _ in 0..4 {
+            decoder.code = (decoder.code << 8) | decoder.byte()? as u32;
+        }
+        Ok(decoder)
+    }
+
+    fn byte(&mut self) -> Result<u8, &'static str> {
+        let byte = *self.data.get(self.position).ok_or("ran out of input")?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn normalize(&mut self) -> Result<(), &'static str> {
+        if self.range < TOP_VALUE {
+            self.range <<= 8;
+            self.code = (self.code << 8) | self.byte()? as u32;
+        }
+        Ok(())
+    }
+
+    /// Bits with an even chance of being 0 or 1, most significant first
+    fn direct_bits(&mut self, count: u32) -> Result<u32, &'static str> {
+        let mut value = 0;
+        for _ in 0..count {
+            self.range >>= 1;
+            let bit = self.code >= self.range;
+            if bit {
+                self.code -= self.range;
+            }
+            value = (value << 1) | bit as u32;
+            self.normalize()?;
+        }
+        Ok(value)
+    }
+
+    /// A bit coded with an adaptive probability, which is updated with what was decoded
+    fn bit(&mut self, probability: &mut u16) -> Result<u32, &'static str> {
+        let bound = (self.range >> PROBABILITY_BITS) * *probability as u32;
+        let bit = if self.code < bound {
+            *probability += ((1 << PROBABILITY_BITS) - *probability) >> MOVE_BITS;
+            self.range = bound;
+            0
+        } else {
+            *probability -= *probability >> MOVE_BITS;
+            self.code -= bound;
+            self.range -= bound;
+            1
+        };
+        self.normalize()?;
+        Ok(bit)
+    }
+
+    /// count bits through a binary tree of probabilities, most significant first
+    fn tree(&mut self, probabilities: &mut [u16], count: u32) -> Result<u32, &'static str> {
+        let mut node = 1;
+        for _ in 0..count {
+            node = (node << 1) | self.bit(&mut probabilities[node as usize])?;
+        }
+        Ok(node - (1 << count))
+    }
+
+    /// count bits through a binary tree of probabilities, least significant first
+    fn reverse_tree(&mut self, probabilities: &mut [u16], count: u32) -> Result<u32, &'static str> {
+        let (mut node, mut value) = (1, 0);
+        for i in 0..count {
+            let bit = self.bit(&mut probabilities[node])?;
+            node = (node << 1) | bit as usize;
+            value |= bit << i;
+        }
+        Ok(value)
+    }
+}
+
+/// Match lengths: 3 bits for 2-9, 3 more for 10-17, or 8 for 18-273
+struct LengthDecoder {
+    choice: u16,
+    choice_2: u16,
+    low: [[u16; 1 << 3]; POSITION_STATES],
+    mid: [[u16; 1 << 3]; POSITION_STATES],
+    high: [u16; 1 << 8],
+}
+
+impl LengthDecoder {
+    fn new() -> Self {
+        Self {
+            choice: PROBABILITY_INIT,
+            choice_2: PROBABILITY_INIT,
+            low: [[PROBABILITY_INIT; 1 << 3]; POSITION_STATES],
+            mid: [[PROBABILITY_INIT; 1 << 3]; POSITION_STATES],
+            high: [PROBABILITY_INIT; 1 << 8],
+        }
+    }
+
+    fn decode(&mut self, range: &mut RangeDecoder, position_state: usize) -> Result<usize, &'static str> {
+        let length = if range.bit(&mut self.choice)? == 0 {
+            range.tree(&mut self.low[position_state], 3)?
+        } else if range.bit(&mut self.choice_2)? == 0 {
+            8 + range.tree(&mut self.mid[position_state], 3)?
+        } else {
+            16 + range.tree(&mut self.high, 8)?
+        };
+        Ok(length as usize)
+    }
+}
+
+/// Decompresses a raw LZMA stream up to expected_length bytes. An end marker is allowed but not needed
+pub(super) fn decompress(data: &[u8], expected_length: usize) -> Result<Vec<u8>, &'static str> {
+    let mut range = RangeDecoder::new(data)?;
+    let mut out = Vec::with_capacity(expected_length);
+
+    let mut literals = vec![PROBABILITY_INIT; 0x300 << LITERAL_CONTEXT_BITS];
+    let mut is_match = [PROBABILITY_INIT; STATES * POSITION_STATES];
+    let mut is_rep = [PROBABILITY_INIT; STATES];
+    let mut is_rep_g0 = [PROBABILITY_INIT; STATES];
+    let mut is_rep_g1 = [PROBABILITY_INIT; STATES];
+    let mut is_rep_g2 = [PROBABILITY_INIT; STATES];
+    let mut is_rep_0_long = [PROBABILITY_INIT; STATES * POSITION_STATES];
+    let mut position_slots = [[PROBABILITY_INIT; 1 << 6]; LENGTH_TO_POSITION_STATES];
+    let mut position_bits = [PROBABILITY_INIT; 1 + FULL_DISTANCES - END_POSITION_MODEL_INDEX];
+    let mut align = [PROBABILITY_INIT; 1 << ALIGN_BITS];
+    let mut lengths = LengthDecoder::new();
+    let mut rep_lengths = LengthDecoder::new();
+
+    let mut state = 0;
+    //The last four match distances, most recent first
+    let mut reps = [0; 4];
+    while out.len() < expected_length {
+        let position_state = out.len() & (POSITION_STATES - 1);
+        if range.bit(&mut is_match[state * POSITION_STATES + position_state])? == 0 {
+            let previous = out.last().copied().unwrap_or(0);
+            let probabilities = &mut literals[0x300 * (previous >> (8 - LITERAL_CONTEXT_BITS)) as usize..][..0x300];
+            let mut symbol = 1;
+            if state >= FIRST_MATCHED_LITERAL_STATE {
+                let mut match_byte = *out.len().checked_sub(reps[0] + 1).and_then(|i| out.get(i)).ok_or("bad match distance")?;
+                while symbol < 0x100 {
+                    let match_bit = (match_byte >> 7) as usize & 1;
+                    match_byte <<= 1;
+                    let bit = range.bit(&mut probabilities[((1 + match_bit) << 8) + symbol])? as usize;
+                    symbol = (symbol << 1) | bit;
+                    if match_bit != bit {
+                        break;
+                    }
+                }
+            }
+            while symbol < 0x100 {
+                symbol = (symbol << 1) | range.bit(&mut probabilities[symbol])? as usize;
+            }
+            out.push(symbol as u8);
+            state = match state {
+                0..=3 => 0,
+                4..=9 => state - 3,
+                _ => state - 6,
+            };
+            continue;
+        }
+
+        let length = if range.bit(&mut is_rep[state])? != 0 {
+            if out.is_empty() {
+                return Err("repeated match before any output");
+            }
+            if range.bit(&mut is_rep_g0[state])? == 0 {
+                //A single byte from the last distance
+                if range.bit(&mut is_rep_0_long[state * POSITION_STATES + position_state])? == 0 {
+                    state = if state < FIRST_MATCHED_LITERAL_STATE { 9 } else { 11 };
+                    let byte = *out.len().checked_sub(reps[0] + 1).and_then(|i| out.get(i)).ok_or("bad match distance")?;
+                    out.push(byte);
+                    continue;
+                }
+            } else {
+                let index = if range.bit(&mut is_rep_g1[state])? == 0 {
+                    1
+                } else if range.bit(&mut is_rep_g2[state])? == 0 {
+                    2
+                } else {
+                    3
+                };
+                reps[..=index].rotate_right(1);
+            }
+            state = if state < FIRST_MATCHED_LITERAL_STATE { 8 } else { 11 };
+            rep_lengths.decode(&mut range, position_state)?
+        } else {
+            reps.rotate_right(1);
+            let length = lengths.decode(&mut range, position_state)?;
+            state = if state < FIRST_MATCHED_LITERAL_STATE { 7 } else { 10 };
+
+            let slot = range.tree(&mut position_slots[length.min(LENGTH_TO_POSITION_STATES - 1)], 6)? as usize;
+            let distance = if slot < 4 {
+                slot as u32
+            } else {
+                let direct_bits = (slot >> 1) as u32 - 1;
+                let base = (2 | (slot & 1) as u32) << direct_bits;
+                if slot < END_POSITION_MODEL_INDEX {
+                    base + range.reverse_tree(&mut position_bits[base as usize - slot..], direct_bits)?
+                } else {
+                    let high = range.direct_bits(direct_bits - ALIGN_BITS)? << ALIGN_BITS;
+                    base.wrapping_add(high).wrapping_add(range.reverse_tree(&mut align, ALIGN_BITS)?)
+                }
+            };
+            if distance == END_MARKER {
+                break;
+            }
+            reps[0] = distance as usize;
+            length
+        };
+
+        let length = length + MIN_MATCH_LENGTH;
+        if reps[0] >= out.len() {
+            return Err("distance reaches back before the start");
+        }
+        if out.len() + length > expected_length {
+            return Err("decompressed past the expected size");
+        }
+        //Copies can overlap what they're writing, so go a byte at a time
+        let start = out.len() - reps[0] - 1;
+        for i in 0..length {
+            out.push(out[start + i]);
+        }
+    }
+    match out.len() == expected_length {
+        true => Ok(out),
+        false => Err("decompressed to the wrong size"),
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    /// Range coder that writes out what RangeDecoder reads
+    struct RangeEncoder {
+        low: u64,
+        range: u32,
+        //Last byte waiting to go out, with the 0xFF bytes after it, in case a carry reaches them
+        cache: u8,
+        cache_size: usize,
+        out: Vec<u8>,
+    }
+
+    impl RangeEncoder {
+        fn bit(&mut self, probability: &mut u16, bit: u32) {
+            let bound = (self.range >> PROBABILITY_BITS) * *probability as u32;
+            if bit == 0 {
+                *probability += ((1 << PROBABILITY_BITS) - *probability) >> MOVE_BITS;
+                self.range = bound;
+            } else {
+                *probability -= *probability >> MOVE_BITS;
+                self.low += bound as u64;
+                self.range -= bound;
+            }
+            while self.range < TOP_VALUE {
+                self.range <<= 8;
+                self.shift_low();
+            }
+        }
+
+        fn shift_low(&mut self) {
+            if self.low < 0xFF00_0000 || self.low > 0xFFFF_FFFF {
+                let carry = (self.low >> 32) as u8;
+                self.out.push(self.cache.wrapping_add(carry));
+                self.out.extend(std::iter::repeat_n(0xFFu8.wrapping_add(carry), self.cache_size - 1));
+                self.cache = (self.low >> 24) as u8;
+                self.cache_size = 0;
+            }
+            self.cache_size += 1;
+            self.low = (self.low & 0x00FF_FFFF) << 8;
+        }
+    }
+
+    /// A stream of nothing but literals, which is enough to make test images
+    pub(in crate::cdrom) fn encode_literals(data: &[u8]) -> Vec<u8> {
+        let mut range = RangeEncoder { low: 0, range: 0xFFFF_FFFF, cache: 0, cache_size: 1, out: Vec::new() };
+        let mut literals = vec![PROBABILITY_INIT; 0x300 << LITERAL_CONTEXT_BITS];
+        let mut is_match = [PROBABILITY_INIT; POSITION_STATES];
+        for (i, byte) in data.iter().enumerate() {
+            range.bit(&mut is_match[i & (POSITION_STATES - 1)], 0);
+            let previous = if i == 0 { 0 } else { data[i - 1] };
+            let probabilities = &mut literals[0x300 * (previous >> (8 - LITERAL_CONTEXT_BITS)) as usize..][..0x300];
+            let mut symbol = 1;
+            for bit in (0..8).rev().map(|i| (*byte as u32 >> i) & 1) {
+                range.bit(&mut probabilities[symbol], bit);
+                symbol = (symbol << 1) | bit as usize;
+            }
+        }
+        for _ in 0..5 {
+            range.shift_low();
+        }
+        range.out
+    }
+
+    //Made with lc=3, lp=0, pb=2, and ending with a marker
+    const SHORT: [u8; 27] = [
+        0x00, 0x30, 0x98, 0x88, 0xA7, 0xEA, 0x9D, 0xCB, 0x5E, 0xE4, 0x56, 0xDB, 0x39, 0x4F, 0xFC, 0x6F, 0x1D, 0x71, 0xDA,
+        0xCF, 0x3F, 0xFF, 0xFF, 0xEF, 0xFD, 0x80, 0x00,
+    ];
+    //Noise, a run, the noise again from far enough back to need the align bits, then short repeats
+    const LONG: [u8; 69] = [
+        0x00, 0x01, 0x8B, 0x25, 0xA5, 0x12, 0x28, 0xD6, 0xF8, 0xAB, 0x1D, 0xFD, 0x81, 0x83, 0x7A, 0x32, 0x4F, 0xA0, 0xDB,
+        0xDF, 0x2D, 0xDE, 0x3B, 0x81, 0x19, 0x85, 0x59, 0x77, 0x0C, 0x39, 0x2F, 0x1F, 0x78, 0x90, 0x70, 0x54, 0xFA, 0x76,
+        0x84, 0x79, 0x1C, 0x4C, 0x0D, 0x0B, 0x0C, 0xC9, 0x64, 0x6F, 0xAC, 0x6F, 0x2E, 0xAE, 0x34, 0x06, 0x39, 0x33, 0x30,
+        0x8B, 0x5A, 0xE2, 0x08, 0xBE, 0xBF, 0xFF, 0xFF, 0x93, 0xB6, 0xD0, 0x00,
+    ];
+
+    #[test]
+    fn test_lzma_decompress() {
+        let short = [&b"abcabcabcabcdabcd".repeat(3)[..], b"xyzabcabcabcxyzxyz"].concat();
+        assert_eq!(decompress(&SHORT, short.len()).unwrap(), short);
+
+        let noise: Vec<u8> = (0..40u32).map(|i| ((i * i * 31 + 7 * i + 3) % 256) as u8).collect();
+        let long = [&noise[..], &[b'a'; 100], &noise, &b"abcabcabcabcdabcd".repeat(3)].concat();
+        assert_eq!(decompress(&LONG, long.len()).unwrap(), long);
+
+        let literals: Vec<u8> = (0..1000u32).map(|i| (i * i % 251) as u8).collect();
+        assert_eq!(decompress(&encode_literals(&literals), literals.len()).unwrap(), literals);
+
+        assert!(decompress(&LONG, long.len() + 1).is_err());
+        assert!(decompress(&LONG[..30], long.len()).is_err());
+    }
+}
@@ -1,14 +1,26 @@
 use bit_field::BitField;
+use byteorder::{ByteOrder, LittleEndian};
 use commands::*;
 use disc::*;
-use log::{trace, warn};
+use log::{debug, trace, warn};
 
 use crate::cpu::{InterruptSource, R3000};
+use xa::{XaDecoder, XaSubheader};
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
 use std::{borrow::{Borrow, BorrowMut}, collections::VecDeque};
 
 mod commands;
+mod cue;
+mod chd;
+mod flac;
 pub mod disc;
+mod inflate;
+mod lzma;
+mod xa;
 
+pub(super) const LOG_TARGET: &str = "vaporstation::cdrom";
+//Both the parameter and response FIFOs hold 16 bytes
+const FIFO_SIZE: usize = 16;
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub(super) enum DriveState {
@@ -16,6 +28,8 @@ pub(super) enum DriveState {
     Seek,
     Read,
     Idle,
+    //Motor spun down by Stop or Reset
+    Stopped,
 }
 
 #[derive(Debug, PartialEq, Copy, Clone)]
@@ -57,9 +71,24 @@ impl IntCause {
             IntCause::INT10h => 0x10,
         }
     }
+
+    fn from_bitflag(flag: u8) -> Option<Self> {
+        match flag {
+            1 => Some(IntCause::INT1),
+            2 => Some(IntCause::INT2),
+            3 => Some(IntCause::INT3),
+            4 => Some(IntCause::INT4),
+            5 => Some(IntCause::INT5),
+            6 => Some(IntCause::INT6),
+            7 => Some(IntCause::INT7),
+            8 => Some(IntCause::INT8),
+            0x10 => Some(IntCause::INT10h),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub(super) struct Packet {
     cause: IntCause,
     response: Vec<u8>,
@@ -68,6 +97,79 @@ pub(super) struct Packet {
     command: u8,
 }
 
+impl Packet {
+    /// Response delivered AVG_FIRST_RESPONSE_TIME cycles after it is queued
+    pub(super) fn new(cause: IntCause, command: u8, response: Vec<u8>) -> Self {
+        Packet {
+            cause,
+            response,
+            execution_cycles: AVG_FIRST_RESPONSE_TIME,
+            extra_response: None,
+            command,
+        }
+    }
+
+    pub(super) fn int1(command: u8, response: Vec<u8>) -> Self {
+        Self::new(IntCause::INT1, command, response)
+    }
+
+    pub(super) fn int2(command: u8, response: Vec<u8>) -> Self {
+        Self::new(IntCause::INT2, command, response)
+    }
+
+    pub(super) fn int3(command: u8, response: Vec<u8>) -> Self {
+        Self::new(IntCause::INT3, command, response)
+    }
+
+    pub(super) fn int5(command: u8, response: Vec<u8>) -> Self {
+        Self::new(IntCause::INT5, command, response)
+    }
+
+    pub(super) fn after_cycles(mut self, cycles: u32) -> Self {
+        self.execution_cycles = cycles;
+        self
+    }
+
+    /// Queues another response to be delivered after this one
+    pub(super) fn with_extra(mut self, extra: Packet) -> Self {
+        self.extra_response = Some(Box::new(extra));
+        self
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.cause.bitflag());
+        writer.write_bytes(&self.response);
+        writer.write_u32(self.execution_cycles);
+        writer.write_u8(self.command);
+        match &self.extra_response {
+            Some(extra) => {
+                writer.write_bool(true);
+                extra.save_state(writer);
+            }
+            None => writer.write_bool(false),
+        }
+    }
+
+    fn load_state(reader: &mut StateReader) -> Result<Self, SaveStateError> {
+        let cause = IntCause::from_bitflag(reader.read_u8()?).ok_or(SaveStateError::Corrupt)?;
+        let response = reader.read_bytes()?;
+        let execution_cycles = reader.read_u32()?;
+        let command = reader.read_u8()?;
+        let extra_response = if reader.read_bool()? {
+            Some(Box::new(Packet::load_state(reader)?))
+        } else {
+            None
+        };
+        Ok(Packet {
+            cause,
+            response,
+            execution_cycles,
+            extra_response,
+            command,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub(super) struct Block {
     data: Vec<u8>
@@ -83,7 +185,7 @@ pub struct CDDrive {
     motor_state: MotorState,
     drive_mode: u8,
 
-    disc: Option<Disc>,
+    disc: Option<Box<dyn Disc>>,
 
     parameter_queue: VecDeque<u8>,
     data_queue: VecDeque<u8>,
@@ -96,11 +198,27 @@ pub struct CDDrive {
     seek_target: DiscIndex,
     seek_complete: bool,
     read_offset: usize,
+    //Absolute sector the head was on when the pending Setloc came in
+    seek_origin: usize,
 
     reg_interrupt_flag: u8,
     reg_interrupt_enable: u8,
 
     read_enabled: bool,
+    lid_open: bool,
+    //Audio keeps streaming while muted, it just comes out silent
+    muted: bool,
+    //Stat keeps reporting the shell as opened until a GetStat after it's closed again
+    shell_open_latched: bool,
+    //Cycles until the next audio sector while playing
+    play_timer: u32,
+
+    //File and channel XA-ADPCM sectors have to match when the XA filter is on
+    xa_file: u8,
+    xa_channel: u8,
+    xa_decoder: XaDecoder,
+
+    ignored_commands: u32,
 
     //Probably useless registers
     reg_sound_map_data_out: u8,
@@ -130,8 +248,19 @@ impl CDDrive {
             seek_target: DiscIndex::new(0, 0, 0),
             seek_complete: false,
             read_offset: 0,
+            seek_origin: DiscIndex::new(0, 2, 0).as_sector(),
 
             read_enabled: false,
+            lid_open: false,
+            muted: false,
+            shell_open_latched: false,
+            play_timer: 0,
+
+            xa_file: 0,
+            xa_channel: 0,
+            xa_decoder: XaDecoder::default(),
+
+            ignored_commands: 0,
 
             reg_interrupt_flag: 0,
             reg_interrupt_enable: 0,
@@ -147,15 +276,15 @@ impl CDDrive {
             0x1F801801 => match self.status_index {
                 0 => self.execute_command(val),
                 1 => self.reg_sound_map_data_out = val,
-                2 => panic!("CD: 0x1F801801 write byte unknown index 2"),
-                3 => trace!("CD: Wrote Right-CD-Out Right SPU volume"),
+                2 => warn!(target: LOG_TARGET, "CD: Wrote unknown 0x1F801801 index 2 {:#X}", val),
+                3 => trace!(target: LOG_TARGET, "CD: Wrote Right-CD-Out Right SPU volume"),
                 _ => unreachable!(),
             },
             0x1F801802 => match self.status_index {
                 0 => self.push_parameter(val),
                 1 => self.write_interrupt_enable_register(val),
-                2 => trace!("CD: Wrote Left-CD-Out Right SPU volume"),
-                3 => trace!("CD: Wrote Right-CD-Out Left SPU volume"),
+                2 => trace!(target: LOG_TARGET, "CD: Wrote Left-CD-Out Right SPU volume"),
+                3 => trace!(target: LOG_TARGET, "CD: Wrote Right-CD-Out Left SPU volume"),
                 _ => unreachable!(),
             },
             0x1F801803 => match self.status_index {
@@ -164,11 +293,12 @@ impl CDDrive {
                     //self.data_queue.clear();
                 },
                 1 => self.write_interrupt_flag_register(val),
-                2 => trace!("CD: Wrote Left-CD-Out Left SPU volume"),
+                2 => trace!(target: LOG_TARGET, "CD: Wrote Left-CD-Out Left SPU volume"),
                 3 => (),
                 _ => unreachable!(),
             },
-            _ => panic!(
+            _ => warn!(
+                target: LOG_TARGET,
                 "CD: Tried to write unknown byte. Address: {:#X} Value: {:#X} Index: {}",
                 addr, val, self.status_index
             ),
@@ -179,29 +309,31 @@ impl CDDrive {
         match addr {
             0x1F801800 => self.get_status_register(),
             0x1F801801 => match self.status_index {
-                0 => panic!("CD: 0x1F801801 read byte unknown index 0"),
                 1 => self.pop_response(),
-                2 => panic!("CD: 0x1F801803 read byte unknown index 2"),
-                3 => panic!("CD: 0x1F801801 read byte unknown index 3"),
+                index => {
+                    warn!(target: LOG_TARGET, "CD: Read unknown 0x1F801801 index {}. Returning 0...", index);
+                    0
+                }
+            },
+            0x1F801802 => self.pop_data(),
+            //The top 3 bits of both interrupt registers always read back set
+            0x1F801803 => match self.status_index {
+                0 | 2 => self.reg_interrupt_enable | 0xE0,
+                1 | 3 => self.reg_interrupt_flag | 0xE0,
                 _ => unreachable!(),
             },
-            0x1F801803 => {
-                match self.status_index {
-                    0 => self.pop_data(),
-                    1 => self.reg_interrupt_flag,
-                    2 => panic!("CD: 0x1F801803 read byte unknown index 2"),
-                    3 => self.reg_interrupt_flag, //Register mirror
-                    _ => unreachable!(),
-                }
+            _ => {
+                warn!(
+                    target: LOG_TARGET,
+                    "CD: Tried to read unknown byte. Address: {:#X} Index: {}. Returning 0...",
+                    addr, self.status_index
+                );
+                0
             }
-            _ => panic!(
-                "CD: Tried to read unknown byte. Address: {:#X} Index: {}",
-                addr, self.status_index
-            ),
         }
     }
 
-    pub fn load_disc(&mut self, disc: Disc) {
+    pub fn load_disc(&mut self, disc: Box<dyn Disc>) {
         self.disc = Some(disc);
     }
 
@@ -209,10 +341,133 @@ impl CDDrive {
         self.disc = None;
     }
 
-    pub fn disc(&self) -> &Option<Disc> {
+    /// Opens or closes the drive lid. Either way the drive forgets where it was seeking or reading.
+    /// Closing it only spins the motor back up if there's a disc inside
+    pub fn set_lid_open(&mut self, open: bool) {
+        if open {
+            self.shell_open_latched = true;
+            self.motor_state = MotorState::Off;
+        } else if self.disc.is_some() {
+            self.motor_state = MotorState::On;
+        }
+        self.lid_open = open;
+        self.drive_state = DriveState::Idle;
+        self.read_enabled = false;
+        self.seek_complete = false;
+        self.read_offset = 0;
+        self.data_queue.clear();
+        self.pending_response = None;
+    }
+
+    pub fn lid_open(&self) -> bool {
+        self.lid_open
+    }
+
+    pub fn disc(&self) -> &Option<Box<dyn Disc>> {
         &self.disc
     }
 
+    /// Number of commands the drive didn't recognize
+    pub fn ignored_commands(&self) -> u32 {
+        self.ignored_commands
+    }
+
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u32(self.cycle_counter);
+        writer.write_u32(self.command_start_cycle);
+        match &self.pending_response {
+            Some(packet) => {
+                writer.write_bool(true);
+                packet.save_state(writer);
+            }
+            None => writer.write_bool(false),
+        }
+        writer.write_u8(match self.drive_state {
+            DriveState::Play => 0,
+            DriveState::Seek => 1,
+            DriveState::Read => 2,
+            DriveState::Idle => 3,
+            DriveState::Stopped => 4,
+        });
+        writer.write_u8(match self.motor_state {
+            MotorState::Off => 0,
+            MotorState::SpinUp => 1,
+            MotorState::On => 2,
+        });
+        writer.write_u8(self.drive_mode);
+        writer.write_bytes(&self.parameter_queue.iter().copied().collect::<Vec<u8>>());
+        writer.write_bytes(&self.data_queue.iter().copied().collect::<Vec<u8>>());
+        writer.write_bytes(&self.response_queue.iter().copied().collect::<Vec<u8>>());
+        writer.write_bool(self.want_data);
+        writer.write_u8(self.status_index);
+        writer.write_u32(self.seek_target.minutes as u32);
+        writer.write_u32(self.seek_target.seconds as u32);
+        writer.write_u32(self.seek_target.sectors as u32);
+        writer.write_bool(self.seek_complete);
+        writer.write_u32(self.read_offset as u32);
+        writer.write_u32(self.seek_origin as u32);
+        writer.write_u8(self.reg_interrupt_flag);
+        writer.write_u8(self.reg_interrupt_enable);
+        writer.write_bool(self.read_enabled);
+        writer.write_bool(self.lid_open);
+        writer.write_bool(self.muted);
+        writer.write_bool(self.shell_open_latched);
+        writer.write_u32(self.play_timer);
+        writer.write_u8(self.xa_file);
+        writer.write_u8(self.xa_channel);
+        writer.write_u8(self.reg_sound_map_data_out);
+        self.xa_decoder.save_state(writer);
+    }
+
+    /// Restores drive state. The loaded disc is not part of the state and is left alone
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.cycle_counter = reader.read_u32()?;
+        self.command_start_cycle = reader.read_u32()?;
+        self.pending_response = if reader.read_bool()? {
+            Some(Packet::load_state(reader)?)
+        } else {
+            None
+        };
+        self.drive_state = match reader.read_u8()? {
+            0 => DriveState::Play,
+            1 => DriveState::Seek,
+            2 => DriveState::Read,
+            3 => DriveState::Idle,
+            4 => DriveState::Stopped,
+            _ => return Err(SaveStateError::Corrupt),
+        };
+        self.motor_state = match reader.read_u8()? {
+            0 => MotorState::Off,
+            1 => MotorState::SpinUp,
+            2 => MotorState::On,
+            _ => return Err(SaveStateError::Corrupt),
+        };
+        self.drive_mode = reader.read_u8()?;
+        self.parameter_queue = reader.read_bytes()?.into_iter().collect();
+        self.data_queue = reader.read_bytes()?.into_iter().collect();
+        self.response_queue = reader.read_bytes()?.into_iter().collect();
+        self.want_data = reader.read_bool()?;
+        self.status_index = reader.read_u8()? & 0x3;
+        let minutes = reader.read_u32()? as usize;
+        let seconds = reader.read_u32()? as usize;
+        let sectors = reader.read_u32()? as usize;
+        self.seek_target = DiscIndex::new_dec(minutes, seconds, sectors);
+        self.seek_complete = reader.read_bool()?;
+        self.read_offset = reader.read_u32()? as usize;
+        self.seek_origin = reader.read_u32()? as usize;
+        self.reg_interrupt_flag = reader.read_u8()?;
+        self.reg_interrupt_enable = reader.read_u8()?;
+        self.read_enabled = reader.read_bool()?;
+        self.lid_open = reader.read_bool()?;
+        self.muted = reader.read_bool()?;
+        self.shell_open_latched = reader.read_bool()?;
+        self.play_timer = reader.read_u32()?;
+        self.xa_file = reader.read_u8()?;
+        self.xa_channel = reader.read_u8()?;
+        self.reg_sound_map_data_out = reader.read_u8()?;
+        self.xa_decoder.load_state(reader)
+    }
+
     fn execute_command(&mut self, command: u8) {
         // Make sure theres no pending command
         let is_readn = if let Some(res) = &self.pending_response {
@@ -221,38 +476,68 @@ impl CDDrive {
             false
         };
 
-        //println!("Attemping to execute command!");
+        debug!(target: LOG_TARGET, "CD: Command {:#X} parameters {:X?}", command, self.parameter_queue);
 
         if self.pending_response.is_none() || is_readn {
-            //println!("Executing");
             //Execute
             {
                 let parameters: Vec<u8> = self.parameter_queue.iter().map(|v| v.clone()).collect();
                 let response = match command {
+                    //Only GetStat and the test commands work with the lid open
+                    _ if self.lid_open && command != 0x1 && command != 0x19 => lid_open_error(self, command),
+                    _ if !parameter_count(command).contains(&parameters.len()) => {
+                        warn!(target: LOG_TARGET, "CD: Command {:#X} sent {} parameters", command, parameters.len());
+                        wrong_parameter_count(self, command)
+                    }
                     0x1 => get_stat(self),
                     0x2 => set_loc(self, parameters[0], parameters[1], parameters[2]),
-                    0x3 => play(self),
+                    0x3 => play(self, parameters.first().copied()),
                     0x6 => read_with_retry(self),
+                    0x8 => stop(self),
                     0x9 => stop_read(self),
                     0xA => init(self),
                     0xE => set_mode(self, parameters[0]),
+                    0xF => get_param(self),
+                    0x10 => get_loc_l(self),
+                    0x11 => get_loc_p(self),
                     0x13 => get_tn(self),
                     0x14 => get_td(self, parameters[0]),
                     0x15 => seek_data(self),
                     0x16 => seek_data(self), //This should actually be seek_p, but I'm never using audio discs so we can reuse the data seek function
                     0x1A => get_id(self),
+                    0x1C => reset(self),
+                    0x1D => get_q(self),
+                    0xB => mute(self),
                     0xC => demute(self),
+                    0xD => set_filter(self, parameters[0], parameters[1]),
                     0x19 => {
                         //sub_function commands
                         match parameters[0] {
                             0x20 => commands::get_bios_date(),
-                            _ => panic!("CD: Unknown sub_function command {:#X}", parameters[0]),
+                            _ => {
+                                warn!(target: LOG_TARGET, "CD: Unknown sub_function command {:#X}", parameters[0]);
+                                self.ignored_commands += 1;
+                                invalid_command(self, command)
+                            }
                         }
                     }
-                    _ => panic!("CD: Unknown command {:#X}!", command),
+                    _ => {
+                        warn!(target: LOG_TARGET, "CD: Unknown command {:#X}!", command);
+                        self.ignored_commands += 1;
+                        invalid_command(self, command)
+                    }
                 };
                 self.pending_response = Some(response);
             }
+        } else if command == 0x2 && self.parameter_queue.len() >= 3 {
+            //Still busy with the last command, but a Setloc moves the target anyway so the latest one wins.
+            //Its own response comes right after the pending one
+            let parameters: Vec<u8> = self.parameter_queue.iter().copied().collect();
+            let mut response = set_loc(self, parameters[0], parameters[1], parameters[2]);
+            if let Some(pending) = &mut self.pending_response {
+                response.extra_response = pending.extra_response.take();
+                pending.extra_response = Some(Box::new(response));
+            }
         }
 
         //Clear out old parameters
@@ -266,14 +551,14 @@ impl CDDrive {
         //3 prmempt
         status |= (self.parameter_queue.is_empty() as u8) << 3;
         //4 prmrdy
-        status |= (!(self.parameter_queue.len() >= 16) as u8) << 4;
+        status |= ((self.parameter_queue.len() < FIFO_SIZE) as u8) << 4;
         //5 RSLRRDY
         status |= (!self.response_queue.is_empty() as u8) << 5;
         //6 DRQSTS
         status |= (!self.data_queue.is_empty() as u8) << 6;
-        // 7 BUSYSTS
-        //TODO when I find out what it means to be busy
-        //status |= 0 << 7;
+        //7 BUSYSTS, set from a command being sent until its first response arrives
+        let busy = matches!(&self.pending_response, Some(packet) if packet.cause == IntCause::INT3 || packet.cause == IntCause::INT5);
+        status |= (busy as u8) << 7;
 
         status
     }
@@ -284,13 +569,17 @@ impl CDDrive {
             DriveState::Play => 0x80,
             DriveState::Seek => 0x40,
             DriveState::Read => 0x20,
-            DriveState::Idle => 0,
+            DriveState::Idle | DriveState::Stopped => 0,
         };
 
         if self.motor_state == MotorState::On {
             status |= 0x2;
         };
 
+        if self.lid_open || self.shell_open_latched {
+            status |= 0x10;
+        }
+
         status
     }
 
@@ -302,6 +591,10 @@ impl CDDrive {
     }
 
     fn push_parameter(&mut self, val: u8) {
+        if self.parameter_queue.len() >= FIFO_SIZE {
+            warn!(target: LOG_TARGET, "CD: Parameter FIFO full, dropping {:#X}", val);
+            return;
+        }
         self.parameter_queue.push_back(val);
     }
 
@@ -309,7 +602,7 @@ impl CDDrive {
         match self.response_queue.pop_front() {
             Some(val) => val,
             None => {
-                warn!("CD: Tried to read response from empty response queue! Returning 0...");
+                warn!(target: LOG_TARGET, "CD: Tried to read response from empty response queue! Returning 0...");
                 0
             }
         }
@@ -319,41 +612,142 @@ impl CDDrive {
         if self.want_data && self.data_queue.is_empty() {
             //Out of data, get some more
             //println!("Fetching more data!");
-            let data = self.disc.as_ref().expect("Tried to read nonexistant disc!").read_sector(
-                        self.seek_target.plus_sector_offset(self.read_offset),
-                        self.sector_size()
-                    );
-        
-            self.read_offset += 1;
-            self.data_queue.extend(data.iter());
+            let location = self.seek_target.plus_sector_offset(self.read_offset);
+            if self.past_end_of_disc(&location) {
+                warn!(target: LOG_TARGET, "CD: Tried to read past the end of the disc at {:?}", location);
+            } else {
+                let data = self.disc.as_ref().expect("Tried to read nonexistant disc!").read_data(&location, self.sector_size());
+                self.read_offset += 1;
+                self.data_queue.extend(data.iter());
+            }
             //println!("Fetched {} bytes!", self.data_queue.len())
         }
         match self.data_queue.pop_front() {
             Some(val) => val,
             None => {
-                warn!("CD: Tried to read data from empty data queue! Returning 0...");
+                warn!(target: LOG_TARGET, "CD: Tried to read data from empty data queue! Returning 0...");
                 0
             }
         }
     }
 
-    pub fn sector_data_take(&mut self) -> &[u8] {
-        
-        //println!("Fetching more data!");
-        let data = self.disc.as_ref().expect("Tried to read nonexistant disc!").read_sector(
-                    self.seek_target.plus_sector_offset(self.read_offset),
-                    self.sector_size()
-                );
-    
+    /// True from the lead-out on, where the disc has nothing left to read. Always true without a disc
+    fn past_end_of_disc(&self, location: &DiscIndex) -> bool {
+        self.disc
+            .as_ref()
+            .and_then(|disc| disc.track_start(0))
+            .is_none_or(|lead_out| location.as_sector() >= lead_out.as_sector())
+    }
+
+    /// Stops reading or playing at the end of the disc, which the cpu hears about as a DataEnd INT4
+    fn data_end(&mut self, command: u8) -> Packet {
+        self.drive_state = DriveState::Idle;
+        self.read_enabled = false;
+        Packet::new(IntCause::INT4, command, vec![self.get_stat()]).after_cycles(1)
+    }
+
+    /// Absolute sector under the head. Until a pending Setloc is seeked to, that's wherever the head was before it
+    fn head_sector(&self) -> usize {
+        match self.seek_complete {
+            true => self.seek_target.as_sector() + self.read_offset,
+            false => self.seek_origin,
+        }
+    }
+
+    fn read_sector_time(&self) -> u32 {
+        match self.drive_mode.get_bit(7) {
+            true => READ_SECTOR_TIME / 2,
+            false => READ_SECTOR_TIME,
+        }
+    }
+
+    fn play_sector_time(&self) -> u32 {
+        match self.drive_mode.get_bit(7) {
+            true => PLAY_SECTOR_TIME / 2,
+            false => PLAY_SECTOR_TIME,
+        }
+    }
+
+    /// Decodes the next audio sector into 588 (left, right) frames and moves on to the one after.
+    /// Queues a position report every 10 sectors when report mode is on
+    fn play_sector(&mut self) -> Vec<(i16, i16)> {
+        let location = self.seek_target.plus_sector_offset(self.read_offset);
+        if self.disc.is_some() && self.past_end_of_disc(&location) {
+            let data_end = self.data_end(0x3);
+            //A command that's still waiting on its response gets it first
+            if self.pending_response.is_none() {
+                self.pending_response = Some(data_end);
+            }
+            return Vec::new();
+        }
         self.read_offset += 1;
-        data
-        
+        let disc = match &self.disc {
+            Some(disc) => disc,
+            None => return Vec::new(),
+        };
+        let frames: Vec<(i16, i16)> = disc
+            .read_sector(&location)
+            .chunks_exact(4)
+            .map(|frame| (LittleEndian::read_i16(&frame[0..2]), LittleEndian::read_i16(&frame[2..4])))
+            .collect();
+
+        if self.drive_mode.get_bit(2) && location.sectors.is_multiple_of(10) && self.pending_response.is_none() {
+            let position = disc.subchannel_q(&location);
+            let peak = frames.iter().map(|(left, _)| left.unsigned_abs()).max().unwrap_or(0).min(0x7FFF);
+            //Reports alternate between the absolute time and the time into the track, which has bit 7 of the seconds set
+            let (time, relative_flag) = match location.sectors % 20 {
+                0 => (&position.absolute, 0),
+                _ => (&position.relative, 0x80),
+            };
+            let report = vec![
+                self.get_stat(),
+                dec_to_bcd(position.track) as u8,
+                dec_to_bcd(position.index) as u8,
+                dec_to_bcd(time.minutes) as u8,
+                dec_to_bcd(time.seconds) as u8 | relative_flag,
+                dec_to_bcd(time.sectors) as u8,
+                peak as u8,
+                (peak >> 8) as u8,
+            ];
+            self.pending_response = Some(Packet::int1(0x3, report).after_cycles(1));
+        }
+        frames
+    }
+
+    /// With XA-ADPCM on, audio sectors are played instead of being handed to the cpu. If the sector at the read
+    /// position is one, it's skipped over and its decoded frames are returned. Sectors the XA filter rejects give no frames
+    fn read_xa_sector(&mut self) -> Option<Vec<(i16, i16)>> {
+        if !self.drive_mode.get_bit(6) {
+            return None;
+        }
+        let sector = self
+            .disc
+            .as_ref()?
+            .read_sector(&self.seek_target.plus_sector_offset(self.read_offset));
+        let subheader = XaSubheader::from_sector(&sector);
+        if !subheader.is_audio() {
+            return None;
+        }
+        self.read_offset += 1;
+        if self.drive_mode.get_bit(3) && (subheader.file != self.xa_file || subheader.channel != self.xa_channel) {
+            return Some(Vec::new());
+        }
+        Some(self.xa_decoder.decode_sector_resampled(&sector))
+    }
+
+    /// Frames as they leave the drive. Silent while muted
+    fn audio_output(&self, frames: Vec<(i16, i16)>) -> Vec<(i16, i16)> {
+        match self.muted {
+            true => vec![(0, 0); frames.len()],
+            false => frames,
+        }
     }
 
     fn write_interrupt_flag_register(&mut self, val: u8) {
         self.reg_interrupt_flag &= !val;
         self.response_queue = VecDeque::new(); //Reset queue
-        if self.reg_interrupt_flag.get_bit(6) {
+        //Bit 6 resets the parameter FIFO
+        if val.get_bit(6) {
             self.parameter_queue = VecDeque::new();
         }
     }
@@ -364,28 +758,55 @@ impl CDDrive {
 }
 
 pub fn step_cycle(cpu: &mut R3000) {
+    let drive = &mut cpu.main_bus.cd_drive;
+    if drive.drive_state == DriveState::Play {
+        drive.play_timer = drive.play_timer.saturating_sub(1);
+        if drive.play_timer == 0 {
+            drive.play_timer = drive.play_sector_time();
+            let frames = drive.play_sector();
+            cpu.main_bus.spu.push_cd_audio(&drive.audio_output(frames));
+        }
+    }
+
+    //The next response can't come in until the CPU acknowledges the last one
+    let acknowledged = cpu.main_bus.cd_drive.reg_interrupt_flag & 0x1F == 0;
     if let Some(pending_response) = &mut cpu.main_bus.cd_drive.pending_response {
-        pending_response.execution_cycles -= 1;
+        pending_response.execution_cycles = pending_response.execution_cycles.saturating_sub(1);
         //println!("{}", pending_response.execution_cycles);
-        if pending_response.execution_cycles == 0 {
+        if pending_response.execution_cycles == 0 && acknowledged {
     
             let mut packet = cpu.main_bus.cd_drive.pending_response.take().unwrap();
+
+            //Reads stop at the end of the disc instead of delivering another sector
+            if packet.command == 0x6 && packet.cause == IntCause::INT1 {
+                let drive = &mut cpu.main_bus.cd_drive;
+                if drive.past_end_of_disc(&drive.seek_target.plus_sector_offset(drive.read_offset)) {
+                    packet = drive.data_end(0x6);
+                }
+            }
+
+            //XA audio sectors go to the audio output without interrupting. Move straight on to the next sector
+            if packet.command == 0x6 && packet.cause == IntCause::INT1 {
+                if let Some(frames) = cpu.main_bus.cd_drive.read_xa_sector() {
+                    cpu.main_bus.spu.push_cd_audio(&cpu.main_bus.cd_drive.audio_output(frames));
+                    cpu.main_bus.cd_drive.pending_response = Some(packet.after_cycles(cpu.main_bus.cd_drive.read_sector_time()));
+                    return;
+                }
+            }
            
             cpu.main_bus.cd_drive.response_queue = VecDeque::with_capacity(packet.response.len()); //Clear queue
-            cpu.main_bus.cd_drive.response_queue.extend(packet.response.iter());
+            cpu.main_bus.cd_drive.response_queue.extend(packet.response.iter().take(FIFO_SIZE));
             cpu.main_bus.cd_drive.reg_interrupt_flag = packet.cause.bitflag();
         
     
             
             //Check if interrupt enabled. If so, fire interrupt
             //println!("Interrupts {:#X} cause {:#X} command {:#X}", cpu.main_bus.cd_drive.reg_interrupt_enable, packet.cause.bitflag(), packet.command);
-            if cpu.main_bus.cd_drive.reg_interrupt_enable & packet.cause.bitflag()
-            == packet.cause.bitflag()
-            {
+            if cpu.main_bus.cd_drive.reg_interrupt_enable & packet.cause.bitflag() != 0 {
                 cpu.fire_external_interrupt(InterruptSource::CDROM);
             }
     
-            //If the response has an extra response, push that to the front of the line
+            //If the response has an extra response, push that to the front of the line. It waits for this one to be acknowledged
             if let Some(ext_response) = packet.extra_response.take() {
                 //println!("Extra response, filling. {:?}", ext_response);
                 cpu.main_bus
@@ -396,11 +817,12 @@ pub fn step_cycle(cpu: &mut R3000) {
     
             
             match packet.command {
-                0x15 => {
+                0x15 | 0x16 => {
                     //Make sure this is the second response
-                    if packet.extra_response.is_some() {
-                        //End seek and return drive to idle state
+                    if packet.cause == IntCause::INT2 {
+                        //End seek and return drive to idle state. Reads start from the target from now on
                         cpu.main_bus.cd_drive.read_offset = 0;
+                        cpu.main_bus.cd_drive.seek_complete = true;
                         cpu.main_bus.cd_drive.drive_state = DriveState::Idle;
                     }
                 }
@@ -409,15 +831,11 @@ pub fn step_cycle(cpu: &mut R3000) {
                     //ReadN
                     //println!("Post ReadN");
                   
-                    if cpu.main_bus.cd_drive.read_enabled {
-                        let response_packet = Packet {
-                            cause: IntCause::INT1,
-                            response: vec![cpu.main_bus.cd_drive.get_stat()],
-                            execution_cycles: 0x36cd2,
-                            extra_response: None,
-                            command: 0x6,
-                        };
-    
+                    //Only queue the next sector once the previous one was delivered,
+                    //otherwise the first (possibly seek delayed) sector would get replaced
+                    if cpu.main_bus.cd_drive.read_enabled && packet.cause == IntCause::INT1 {
+                        let response_packet = Packet::int1(0x6, vec![cpu.main_bus.cd_drive.get_stat()])
+                            .after_cycles(cpu.main_bus.cd_drive.read_sector_time());
                         cpu.main_bus.cd_drive.pending_response = Some(response_packet);
                     }
                 }
@@ -426,3 +844,700 @@ pub fn step_cycle(cpu: &mut R3000) {
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::MainBus;
+
+    const SECTOR_COUNT: usize = 300;
+
+    /// Disc with a single data track. The first data byte of each sector is its index (mod 256)
+    fn test_disc() -> CueDisc {
+        let mut data = vec![0; SECTOR_COUNT * disc::BYTES_PER_SECTOR];
+        for sector in 0..SECTOR_COUNT {
+            data[sector * disc::BYTES_PER_SECTOR + 24] = sector as u8;
+        }
+        let mut disc = CueDisc::new("test");
+        disc.add_track(DiscTrack::new(data));
+        disc
+    }
+
+    fn test_cpu() -> R3000 {
+        let bus = MainBus::for_test();
+        let mut cpu = R3000::new(bus);
+        cpu.main_bus.cd_drive.load_disc(Box::new(test_disc()));
+        cpu
+    }
+
+    fn send_command(cpu: &mut R3000, command: u8, parameters: &[u8]) {
+        cpu.main_bus.cd_drive.write_byte(0x1F801800, 0);
+        for param in parameters {
+            cpu.main_bus.cd_drive.write_byte(0x1F801802, *param);
+        }
+        cpu.main_bus.cd_drive.write_byte(0x1F801801, command);
+    }
+
+    /// Steps the drive until the given interrupt is raised, acknowledges it, and returns the cycles it took
+    fn wait_for_int(cpu: &mut R3000, cause: IntCause) -> u32 {
+        let mut cycles = 0;
+        while cpu.main_bus.cd_drive.reg_interrupt_flag != cause.bitflag() {
+            step_cycle(cpu);
+            cycles += 1;
+            assert!(cycles < 50_000_000, "Timed out waiting for {:?}", cause);
+        }
+        cpu.main_bus.cd_drive.write_byte(0x1F801800, 1);
+        cpu.main_bus.cd_drive.write_byte(0x1F801803, 0x1F);
+        cycles
+    }
+
+    /// CD audio the drive has handed to the SPU, as interleaved left, right samples
+    fn cd_audio(cpu: &mut R3000) -> Vec<i16> {
+        let frames = cpu.main_bus.spu.take_cd_input();
+        frames.iter().flat_map(|(left, right)| [*left, *right]).collect()
+    }
+
+    #[test]
+    fn test_packet_builder() {
+        let hand_written = Packet {
+            cause: IntCause::INT3,
+            response: vec![0x2],
+            execution_cycles: AVG_FIRST_RESPONSE_TIME,
+            extra_response: Some(Box::new(Packet {
+                cause: IntCause::INT2,
+                response: vec![0x2, 0x0],
+                execution_cycles: MIN_SEEK_TIME,
+                extra_response: None,
+                command: 0x15,
+            })),
+            command: 0x15,
+        };
+        let built = Packet::int3(0x15, vec![0x2])
+            .with_extra(Packet::int2(0x15, vec![0x2, 0x0]).after_cycles(MIN_SEEK_TIME));
+        assert_eq!(built, hand_written);
+    }
+
+    #[test]
+    fn test_init_and_get_id_response_timing() {
+        let mut cpu = test_cpu();
+        send_command(&mut cpu, 0xA, &[]);
+        assert_eq!(wait_for_int(&mut cpu, IntCause::INT3), AVG_FIRST_RESPONSE_TIME);
+        assert_eq!(wait_for_int(&mut cpu, IntCause::INT2), INIT_SECOND_RESPONSE_TIME);
+
+        send_command(&mut cpu, 0x1A, &[]);
+        assert_eq!(wait_for_int(&mut cpu, IntCause::INT3), AVG_FIRST_RESPONSE_TIME);
+        assert_eq!(wait_for_int(&mut cpu, IntCause::INT2), GET_ID_SECOND_RESPONSE_TIME);
+    }
+
+    #[test]
+    fn test_second_response_waits_for_acknowledge() {
+        let mut cpu = test_cpu();
+        cpu.main_bus.cd_drive.disc = None;
+        cpu.main_bus.cd_drive.write_byte(0x1F801800, 1);
+        cpu.main_bus.cd_drive.write_byte(0x1F801802, 0x1F);
+        send_command(&mut cpu, 0x1A, &[]);
+        while cpu.main_bus.cd_drive.reg_interrupt_flag == 0 {
+            step_cycle(&mut cpu);
+        }
+        //INT3 shows up in the flag register and raises the CDROM interrupt
+        cpu.main_bus.cd_drive.write_byte(0x1F801800, 1);
+        assert_eq!(cpu.main_bus.cd_drive.read_byte(0x1F801803), 0xE3);
+        assert_eq!(cpu.i_status & 0x4, 0x4);
+        cpu.i_status = 0;
+
+        //GetID's second response is due long before this, but it's held back until INT3 is acknowledged
+        for _ in 0..GET_ID_SECOND_RESPONSE_TIME * 2 {
+            step_cycle(&mut cpu);
+        }
+        assert_eq!(cpu.main_bus.cd_drive.read_byte(0x1F801803), 0xE3);
+        assert_eq!(cpu.main_bus.cd_drive.response_queue.len(), 1);
+        assert_eq!(cpu.i_status, 0);
+
+        cpu.main_bus.cd_drive.write_byte(0x1F801803, 0x07);
+        assert_eq!(cpu.main_bus.cd_drive.read_byte(0x1F801803), 0xE0);
+        step_cycle(&mut cpu);
+        //With no disc GetID fails with INT5
+        assert_eq!(cpu.main_bus.cd_drive.read_byte(0x1F801803) & 0x7, IntCause::INT5.bitflag());
+        assert_eq!(cpu.i_status & 0x4, 0x4);
+        let response: Vec<u8> = (0..8).map(|_| cpu.main_bus.cd_drive.read_byte(0x1F801801)).collect();
+        assert_eq!(response, vec![0x08, 0x40, 0, 0, 0, 0, 0, 0]);
+    }
+
+    /// Sectors whose first data byte is their index on the disc, starting from first
+    fn marked_sectors(first: usize, count: usize) -> Vec<u8> {
+        let mut data = vec![0; count * disc::BYTES_PER_SECTOR];
+        for sector in 0..count {
+            data[sector * disc::BYTES_PER_SECTOR + 24] = (first + sector) as u8;
+        }
+        data
+    }
+
+    fn read_first_byte(cpu: &mut R3000, minutes: u8, seconds: u8, frames: u8) -> u8 {
+        send_command(cpu, 0x2, &[minutes, seconds, frames]);
+        wait_for_int(cpu, IntCause::INT3);
+        send_command(cpu, 0x6, &[]);
+        wait_for_int(cpu, IntCause::INT3);
+        wait_for_int(cpu, IntCause::INT1);
+        send_command(cpu, 0x9, &[]);
+        wait_for_int(cpu, IntCause::INT3);
+        wait_for_int(cpu, IntCause::INT2);
+        cpu.main_bus.cd_drive.want_data = true;
+        let byte = cpu.main_bus.cd_drive.pop_data();
+        cpu.main_bus.cd_drive.data_queue.clear();
+        byte
+    }
+
+    #[test]
+    fn test_dma_sector() {
+        let mut cpu = test_cpu();
+        send_command(&mut cpu, 0x2, &[0x00, 0x02, 0x05]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        send_command(&mut cpu, 0x6, &[]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        wait_for_int(&mut cpu, IntCause::INT1);
+        cpu.main_bus.cd_drive.write_byte(0x1F801800, 0);
+        cpu.main_bus.cd_drive.write_byte(0x1F801803, 0x80); //Want data
+
+        cpu.main_bus.dma.write_word(0x1F8010F0, 0x8000); //Enable channel 3
+        cpu.main_bus.dma.write_word(0x1F8010B0, 0x1000);
+        cpu.main_bus.dma.write_word(0x1F8010B4, 0x0001_0200);
+        cpu.main_bus.dma.write_word(0x1F8010B8, 0x11000000);
+        crate::dma::execute_dma_cycle(&mut cpu);
+        assert_eq!(cpu.main_bus.read_byte(0x1000), 5);
+        //The whole 0x800 byte sector went over, so the FIFO is empty
+        assert_eq!(cpu.main_bus.cd_drive.read_byte(0x1F801800) & 0x40, 0);
+        assert_eq!(cpu.main_bus.dma.read_word(0x1F8010B0), 0x1800);
+    }
+
+    #[test]
+    fn test_load_cue_sheet() {
+        let dir = std::env::temp_dir().join(format!("vaporstation_cue_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        //Data track and an audio track sharing the first file, then a second file with one more data track
+        std::fs::write(dir.join("game (track 1).bin"), marked_sectors(0, 6)).unwrap();
+        std::fs::write(dir.join("track3.bin"), marked_sectors(6, 3)).unwrap();
+        let cue_path = dir.join("game.cue");
+        std::fs::write(
+            &cue_path,
+            "FILE \"game (track 1).bin\" BINARY\n  TRACK 01 MODE2/2352\n    INDEX 01 00:00:00\n  \
+             TRACK 02 AUDIO\n    INDEX 01 00:00:04\nFILE \"track3.bin\" BINARY\n  TRACK 03 MODE2/2352\n    INDEX 01 00:00:00\n",
+        )
+        .unwrap();
+
+        let disc = CueDisc::from_file(&cue_path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(disc.track_count(), 3);
+
+        let mut cpu = test_cpu();
+        cpu.main_bus.cd_drive.load_disc(Box::new(disc));
+        //Setloc is in BCD and the disc starts at 00:02:00
+        assert_eq!(read_first_byte(&mut cpu, 0x00, 0x02, 0x02), 2);
+        assert_eq!(read_first_byte(&mut cpu, 0x00, 0x02, 0x07), 7);
+    }
+
+    #[test]
+    fn test_load_raw_iso() {
+        let path = std::env::temp_dir().join(format!("vaporstation_iso_test_{}.iso", std::process::id()));
+        let mut data = vec![0; 10 * 2048];
+        for sector in 0..10 {
+            data[sector * 2048] = sector as u8;
+        }
+        std::fs::write(&path, data).unwrap();
+        let disc = disc::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(disc.track_count(), 1);
+        assert_eq!(disc.track_start(0).unwrap().as_sector(), 150 + 10);
+
+        //Sync and a mode 1 header for 00:02:07 are filled in ahead of the user data
+        let sector = disc.read_sector(&DiscIndex::new(0x00, 0x02, 0x07));
+        assert_eq!(sector[..12], [0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00]);
+        assert_eq!(sector[12..17], [0x00, 0x02, 0x07, 0x01, 7]);
+        assert_eq!(disc.subchannel_q(&DiscIndex::new(0x00, 0x02, 0x07)).control, 0x41);
+
+        let mut cpu = test_cpu();
+        cpu.main_bus.cd_drive.load_disc(disc);
+        assert_eq!(read_first_byte(&mut cpu, 0x00, 0x02, 0x03), 3);
+    }
+
+    /// Sends a command and returns its first response without acknowledging it
+    fn first_response(cpu: &mut R3000, command: u8, parameters: &[u8]) -> (u8, Vec<u8>) {
+        send_command(cpu, command, parameters);
+        while cpu.main_bus.cd_drive.reg_interrupt_flag == 0 {
+            step_cycle(cpu);
+        }
+        let response = cpu.main_bus.cd_drive.response_queue.iter().copied().collect();
+        let cause = cpu.main_bus.cd_drive.reg_interrupt_flag;
+        cpu.main_bus.cd_drive.write_byte(0x1F801800, 1);
+        cpu.main_bus.cd_drive.write_byte(0x1F801803, 0x1F);
+        (cause, response)
+    }
+
+    #[test]
+    fn test_get_tn() {
+        let mut cpu = test_cpu();
+        let mut disc = CueDisc::new("test");
+        disc.add_track(DiscTrack::new(marked_sectors(0, 75)));
+        for _ in 0..2 {
+            disc.add_track(DiscTrack::with_type(marked_sectors(0, 75), TrackType::Audio));
+        }
+        cpu.main_bus.cd_drive.load_disc(Box::new(disc));
+        let stat = cpu.main_bus.cd_drive.get_stat();
+        assert_eq!(first_response(&mut cpu, 0x13, &[]), (3, vec![stat, 0x01, 0x03]));
+
+        //Track numbers are BCD
+        let mut disc = CueDisc::new("test");
+        for _ in 0..12 {
+            disc.add_track(DiscTrack::new(marked_sectors(0, 75)));
+        }
+        cpu.main_bus.cd_drive.load_disc(Box::new(disc));
+        assert_eq!(first_response(&mut cpu, 0x13, &[]), (3, vec![stat, 0x01, 0x12]));
+    }
+
+    #[test]
+    fn test_get_td() {
+        let mut cpu = test_cpu();
+        //Second track starts 3 seconds and 10 sectors after the first, which starts at 00:02
+        let mut disc = CueDisc::new("test");
+        disc.add_track(DiscTrack::new(marked_sectors(0, 3 * 75 + 10)));
+        disc.add_track(DiscTrack::with_type(marked_sectors(0, 20 * 75), TrackType::Audio));
+        cpu.main_bus.cd_drive.load_disc(Box::new(disc));
+        let stat = cpu.main_bus.cd_drive.get_stat();
+
+        assert_eq!(first_response(&mut cpu, 0x14, &[0x01]), (3, vec![stat, 0x00, 0x02]));
+        assert_eq!(first_response(&mut cpu, 0x14, &[0x02]), (3, vec![stat, 0x00, 0x05]));
+        //Lead-out, 00:02 + 3s + 20s plus change
+        assert_eq!(first_response(&mut cpu, 0x14, &[0x00]), (3, vec![stat, 0x00, 0x25]));
+        assert_eq!(first_response(&mut cpu, 0x14, &[0x03]).0, 5);
+    }
+
+    #[test]
+    fn test_setloc_then_readn_seeks() {
+        let mut cpu = test_cpu();
+        send_command(&mut cpu, 0x2, &[0x00, 0x02, 0x05]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+
+        send_command(&mut cpu, 0x6, &[]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        let cycles = wait_for_int(&mut cpu, IntCause::INT1);
+        assert!(cycles >= MIN_SEEK_TIME + cpu.main_bus.cd_drive.read_sector_time());
+
+        cpu.main_bus.cd_drive.want_data = true;
+        assert_eq!(cpu.main_bus.cd_drive.pop_data(), 5);
+    }
+
+    #[test]
+    fn test_seekl_completes_on_int2() {
+        let mut cpu = test_cpu();
+        send_command(&mut cpu, 0x2, &[0x00, 0x02, 0x05]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        send_command(&mut cpu, 0x15, &[]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        //Still moving the head
+        assert!(!cpu.main_bus.cd_drive.seek_complete);
+        let cycles = wait_for_int(&mut cpu, IntCause::INT2);
+        assert!(cycles >= MIN_SEEK_TIME);
+        assert!(cpu.main_bus.cd_drive.seek_complete);
+        assert_eq!(cpu.main_bus.cd_drive.drive_state, DriveState::Idle);
+
+        //Already on the target, so the first sector doesn't wait for another seek
+        send_command(&mut cpu, 0x6, &[]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        let cycles = wait_for_int(&mut cpu, IntCause::INT1);
+        assert!(cycles <= cpu.main_bus.cd_drive.read_sector_time());
+        cpu.main_bus.cd_drive.want_data = true;
+        assert_eq!(cpu.main_bus.cd_drive.pop_data(), 5);
+    }
+
+    #[test]
+    fn test_repeated_setloc_uses_last_target() {
+        let mut cpu = test_cpu();
+        //A finished SeekL to the first target mustn't count as a seek to the later ones
+        send_command(&mut cpu, 0x2, &[0x00, 0x02, 0x10]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        send_command(&mut cpu, 0x15, &[]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        wait_for_int(&mut cpu, IntCause::INT2);
+        //The last two are sent without waiting for the first to be acknowledged
+        send_command(&mut cpu, 0x2, &[0x00, 0x02, 0x20]);
+        send_command(&mut cpu, 0x2, &[0x00, 0x02, 0x30]);
+        //Each one is still acknowledged
+        wait_for_int(&mut cpu, IntCause::INT3);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        assert!(cpu.main_bus.cd_drive.pending_response.is_none());
+        assert!(!cpu.main_bus.cd_drive.seek_complete);
+        assert_eq!(cpu.main_bus.cd_drive.read_offset, 0);
+
+        send_command(&mut cpu, 0x6, &[]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        let cycles = wait_for_int(&mut cpu, IntCause::INT1);
+        assert!(cycles >= MIN_SEEK_TIME + cpu.main_bus.cd_drive.read_sector_time());
+        cpu.main_bus.cd_drive.want_data = true;
+        assert_eq!(cpu.main_bus.cd_drive.pop_data(), 30);
+    }
+
+    #[test]
+    fn test_set_mode_speed_change_during_read() {
+        let mut cpu = test_cpu();
+        send_command(&mut cpu, 0x6, &[]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        wait_for_int(&mut cpu, IntCause::INT1);
+
+        //Same speed, reading carries on at the normal rate
+        send_command(&mut cpu, 0xE, &[0x00]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        let cycles = wait_for_int(&mut cpu, IntCause::INT1);
+        assert!(cycles < READ_SECTOR_TIME + SPEED_CHANGE_TIME);
+        assert_eq!(cpu.main_bus.cd_drive.read_sector_time(), READ_SECTOR_TIME);
+
+        //Switching to double speed has to resync first
+        send_command(&mut cpu, 0xE, &[0x80]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        let cycles = wait_for_int(&mut cpu, IntCause::INT1);
+        assert!(cycles >= READ_SECTOR_TIME / 2 + SPEED_CHANGE_TIME);
+        //Then sectors come twice as fast
+        assert_eq!(cpu.main_bus.cd_drive.read_sector_time(), READ_SECTOR_TIME / 2);
+        assert_eq!(wait_for_int(&mut cpu, IntCause::INT1), READ_SECTOR_TIME / 2);
+    }
+
+    /// Audio sectors where every left sample counts up from 0 and the right sample is its negative
+    fn audio_sectors(count: usize) -> Vec<u8> {
+        let mut data = vec![0; count * disc::BYTES_PER_SECTOR];
+        for (i, frame) in data.chunks_exact_mut(4).enumerate() {
+            LittleEndian::write_i16(&mut frame[0..2], i as i16);
+            LittleEndian::write_i16(&mut frame[2..4], -(i as i16));
+        }
+        data
+    }
+
+    #[test]
+    fn test_play_audio_track() {
+        let mut cpu = test_cpu();
+        let mut disc = CueDisc::new("test");
+        disc.add_track(DiscTrack::new(marked_sectors(0, 8)));
+        disc.add_track(DiscTrack::with_type(audio_sectors(30), TrackType::Audio));
+        cpu.main_bus.cd_drive.load_disc(Box::new(disc));
+
+        //Report mode on, then play track 2
+        send_command(&mut cpu, 0xE, &[0x04]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        send_command(&mut cpu, 0x3, &[0x02]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        assert_eq!(cpu.main_bus.cd_drive.get_stat() & 0x80, 0x80);
+
+        //Track 2 starts at 00:02:08, so the first report is for 00:02:10, the third sector played.
+        //That one gives the time into the track
+        while cpu.main_bus.cd_drive.reg_interrupt_flag == 0 {
+            step_cycle(&mut cpu);
+        }
+        assert_eq!(cpu.main_bus.cd_drive.reg_interrupt_flag, IntCause::INT1.bitflag());
+        let report: Vec<u8> = cpu.main_bus.cd_drive.response_queue.iter().copied().collect();
+        assert_eq!(report[1..6], [0x02, 0x01, 0x00, 0x80, 0x02]);
+        cpu.main_bus.cd_drive.write_byte(0x1F801800, 1);
+        cpu.main_bus.cd_drive.write_byte(0x1F801803, 0x1F);
+
+        let samples = cd_audio(&mut cpu);
+        assert_eq!(samples.len(), 3 * 588 * 2);
+        assert_eq!(samples[..6], [0, 0, 1, -1, 2, -2]);
+        assert_eq!(samples[588 * 2..588 * 2 + 2], [588, -588]);
+
+        //Pausing stops the audio
+        send_command(&mut cpu, 0x9, &[]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        wait_for_int(&mut cpu, IntCause::INT2);
+        cd_audio(&mut cpu);
+        for _ in 0..PLAY_SECTOR_TIME * 2 {
+            step_cycle(&mut cpu);
+        }
+        assert!(cd_audio(&mut cpu).is_empty());
+    }
+
+    #[test]
+    fn test_data_end() {
+        let mut cpu = test_cpu();
+        //The test disc's lead-out is at 00:06:00, so there are two sectors left to read
+        send_command(&mut cpu, 0x2, &[0x00, 0x05, 0x73]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        send_command(&mut cpu, 0x6, &[]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        cpu.main_bus.cd_drive.want_data = true;
+        for _ in 0..2 {
+            wait_for_int(&mut cpu, IntCause::INT1);
+            cpu.main_bus.cd_drive.pop_data();
+            cpu.main_bus.cd_drive.data_queue.clear();
+        }
+        wait_for_int(&mut cpu, IntCause::INT4);
+        assert!(!cpu.main_bus.cd_drive.read_enabled);
+        assert_eq!(cpu.main_bus.cd_drive.drive_state, DriveState::Idle);
+        assert_eq!(cpu.main_bus.cd_drive.pop_data(), 0);
+
+        //Playing stops at the end of the disc too
+        let mut disc = CueDisc::new("test");
+        disc.add_track(DiscTrack::new(marked_sectors(0, 8)));
+        disc.add_track(DiscTrack::with_type(audio_sectors(3), TrackType::Audio));
+        cpu.main_bus.cd_drive.load_disc(Box::new(disc));
+        send_command(&mut cpu, 0x3, &[0x02]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        wait_for_int(&mut cpu, IntCause::INT4);
+        assert_eq!(cpu.main_bus.cd_drive.drive_state, DriveState::Idle);
+        assert_eq!(cd_audio(&mut cpu).len(), 3 * 588 * 2);
+
+        //The lead-in can't be seeked to, and reading outside the disc gives zeros
+        let stat = cpu.main_bus.cd_drive.get_stat();
+        assert_eq!(first_response(&mut cpu, 0x2, &[0x00, 0x01, 0x74]), (5, vec![stat | 0x1, 0x10]));
+        let disc = cpu.main_bus.cd_drive.disc.as_ref().unwrap();
+        assert_eq!(disc.read_sector(&DiscIndex::new_dec(0, 1, 0)), [0; disc::BYTES_PER_SECTOR]);
+        assert_eq!(disc.read_sector(&DiscIndex::new_dec(10, 0, 0)), [0; disc::BYTES_PER_SECTOR]);
+        let iso = RawIsoDisc::new("test", &[1; 2048]);
+        assert_eq!(iso.read_sector(&DiscIndex::new_dec(0, 2, 1)), [0; disc::BYTES_PER_SECTOR]);
+    }
+
+    #[test]
+    fn test_report_across_track_boundary() {
+        let mut cpu = test_cpu();
+        let mut disc = CueDisc::new("test");
+        disc.add_track(DiscTrack::with_type(audio_sectors(15), TrackType::Audio));
+        disc.add_track(DiscTrack::with_type(audio_sectors(30), TrackType::Audio));
+        cpu.main_bus.cd_drive.load_disc(Box::new(disc));
+        send_command(&mut cpu, 0xE, &[0x04]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        send_command(&mut cpu, 0x3, &[0x01]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+
+        let mut reports = Vec::new();
+        while reports.len() < 4 {
+            step_cycle(&mut cpu);
+            if cpu.main_bus.cd_drive.reg_interrupt_flag == IntCause::INT1.bitflag() {
+                reports.push(cpu.main_bus.cd_drive.response_queue.iter().skip(1).take(5).copied().collect::<Vec<u8>>());
+                cpu.main_bus.cd_drive.write_byte(0x1F801800, 1);
+                cpu.main_bus.cd_drive.write_byte(0x1F801803, 0x1F);
+            }
+        }
+        //Track 2 starts at 00:02:15
+        assert_eq!(reports[0], [0x01, 0x01, 0x00, 0x02, 0x00]);
+        assert_eq!(reports[1], [0x01, 0x01, 0x00, 0x80, 0x10]);
+        assert_eq!(reports[2], [0x02, 0x01, 0x00, 0x02, 0x20]);
+        assert_eq!(reports[3], [0x02, 0x01, 0x00, 0x80, 0x15]);
+    }
+
+    #[test]
+    fn test_get_loc() {
+        let mut cpu = test_cpu();
+        send_command(&mut cpu, 0x2, &[0x00, 0x03, 0x05]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        send_command(&mut cpu, 0x6, &[]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        wait_for_int(&mut cpu, IntCause::INT1);
+        cpu.main_bus.cd_drive.want_data = true;
+        assert_eq!(cpu.main_bus.cd_drive.pop_data(), 80);
+        send_command(&mut cpu, 0x9, &[]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        wait_for_int(&mut cpu, IntCause::INT2);
+
+        //Track 1 starts at 00:02:00, so 00:03:05 is 1 second and 5 sectors in
+        assert_eq!(
+            first_response(&mut cpu, 0x11, &[]),
+            (3, vec![0x01, 0x01, 0x00, 0x01, 0x05, 0x00, 0x03, 0x05])
+        );
+        assert_eq!(first_response(&mut cpu, 0x10, &[]).1.len(), 8);
+    }
+
+    #[test]
+    fn test_get_q() {
+        let mut cpu = test_cpu();
+        send_command(&mut cpu, 0x2, &[0x00, 0x03, 0x05]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        send_command(&mut cpu, 0x15, &[]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        wait_for_int(&mut cpu, IntCause::INT2);
+
+        assert_eq!(first_response(&mut cpu, 0x1D, &[0x01, 0x00]).0, 3);
+        while cpu.main_bus.cd_drive.reg_interrupt_flag == 0 {
+            step_cycle(&mut cpu);
+        }
+        assert_eq!(cpu.main_bus.cd_drive.reg_interrupt_flag, IntCause::INT2.bitflag());
+        let subq: Vec<u8> = cpu.main_bus.cd_drive.response_queue.iter().copied().collect();
+        assert_eq!(subq, [0x41, 0x01, 0x01, 0x00, 0x01, 0x05, 0x00, 0x00, 0x03, 0x05]);
+    }
+
+    #[test]
+    fn test_get_id_region() {
+        let mut cpu = test_cpu();
+        let mut disc = CueDisc::new("test");
+        let mut data = marked_sectors(0, 8);
+        let license = b"Sony Computer Entertainment Euro pe";
+        data[4 * disc::BYTES_PER_SECTOR + 24..][..license.len()].copy_from_slice(license);
+        disc.add_track(DiscTrack::new(data));
+        assert_eq!(disc.detect_region(), Some(Region::Europe));
+        disc.set_region(Region::Europe);
+        cpu.main_bus.cd_drive.load_disc(Box::new(disc));
+
+        send_command(&mut cpu, 0x1A, &[]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        while cpu.main_bus.cd_drive.reg_interrupt_flag == 0 {
+            step_cycle(&mut cpu);
+        }
+        let response: Vec<u8> = cpu.main_bus.cd_drive.response_queue.iter().copied().collect();
+        assert_eq!(response[4..], *b"SCEE");
+    }
+
+    #[test]
+    fn test_setloc_takes_bcd() {
+        let mut drive = CDDrive::new();
+        set_loc(&mut drive, 0x01, 0x12, 0x30);
+        let target = &drive.seek_target;
+        assert_eq!((target.minutes, target.seconds, target.sectors), (1, 12, 30));
+    }
+
+    #[test]
+    fn test_setloc_rejects_invalid_bcd() {
+        let mut cpu = test_cpu();
+        let stat = cpu.main_bus.cd_drive.get_stat();
+        for target in [[0x00, 0x1A, 0x00], [0x00, 0x60, 0x00], [0x00, 0x02, 0x75]] {
+            assert_eq!(first_response(&mut cpu, 0x2, &target), (5, vec![stat | 0x1, 0x10]));
+        }
+        assert_eq!(first_response(&mut cpu, 0x14, &[0x0A]), (5, vec![stat | 0x1, 0x10]));
+        assert_eq!(first_response(&mut cpu, 0x2, &[0x00, 0x59, 0x74]).0, 3);
+    }
+
+    #[test]
+    fn test_lid_open() {
+        let mut cpu = test_cpu();
+        send_command(&mut cpu, 0x6, &[]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        cpu.main_bus.cd_drive.set_lid_open(true);
+        assert!(!cpu.main_bus.cd_drive.read_enabled);
+
+        let (cause, response) = first_response(&mut cpu, 0x1, &[]);
+        assert_eq!(cause, 3);
+        assert_eq!(response[0] & 0x10, 0x10);
+        //Other commands fail while the lid is open
+        assert_eq!(first_response(&mut cpu, 0x6, &[]), (5, vec![response[0] | 0x1, 0x08]));
+
+        //The shell open bit sticks around for one more GetStat after closing
+        cpu.main_bus.cd_drive.set_lid_open(false);
+        assert_eq!(first_response(&mut cpu, 0x1, &[]).1[0] & 0x10, 0x10);
+        assert_eq!(first_response(&mut cpu, 0x1, &[]).1[0] & 0x12, 0x2);
+
+        //An empty drive doesn't spin up
+        cpu.main_bus.cd_drive.set_lid_open(true);
+        cpu.main_bus.cd_drive.remove_disc();
+        cpu.main_bus.cd_drive.set_lid_open(false);
+        first_response(&mut cpu, 0x1, &[]);
+        assert_eq!(first_response(&mut cpu, 0x1, &[]).1[0], 0);
+    }
+
+    #[test]
+    fn test_fifo_status_bits() {
+        let mut cpu = test_cpu();
+        let status = |cpu: &mut R3000| cpu.main_bus.cd_drive.read_byte(0x1F801800) & 0xF8;
+        //Parameter FIFO empty and not full, nothing to read, not busy
+        assert_eq!(status(&mut cpu), 0x18);
+        cpu.main_bus.cd_drive.write_byte(0x1F801800, 0);
+        cpu.main_bus.cd_drive.write_byte(0x1F801802, 0x01);
+        assert_eq!(status(&mut cpu), 0x10);
+        cpu.main_bus.cd_drive.write_byte(0x1F801801, 0x14);
+        //Parameters are used up, and the drive is busy until the response comes in
+        assert_eq!(status(&mut cpu), 0x98);
+        while cpu.main_bus.cd_drive.reg_interrupt_flag == 0 {
+            step_cycle(&mut cpu);
+        }
+        assert_eq!(status(&mut cpu), 0x38);
+
+        //Response bytes come out until the FIFO runs dry
+        let stat = cpu.main_bus.cd_drive.get_stat();
+        cpu.main_bus.cd_drive.write_byte(0x1F801800, 1);
+        let mut response = Vec::new();
+        while status(&mut cpu) & 0x20 != 0 {
+            response.push(cpu.main_bus.cd_drive.read_byte(0x1F801801));
+        }
+        assert_eq!(response, [stat, 0x00, 0x02]);
+        cpu.main_bus.cd_drive.write_byte(0x1F801803, 0x1F);
+
+        //Parameters past 16 are dropped, and the FIFO reports itself full
+        cpu.main_bus.cd_drive.write_byte(0x1F801800, 0);
+        for param in 0..20 {
+            cpu.main_bus.cd_drive.write_byte(0x1F801802, param);
+        }
+        assert_eq!(status(&mut cpu), 0x00);
+        assert_eq!(cpu.main_bus.cd_drive.parameter_queue.len(), FIFO_SIZE);
+        cpu.main_bus.cd_drive.write_byte(0x1F801800, 1);
+        cpu.main_bus.cd_drive.write_byte(0x1F801803, 0x40);
+        assert_eq!(status(&mut cpu), 0x18);
+
+        //Commands with the wrong number of parameters are rejected
+        assert_eq!(first_response(&mut cpu, 0x14, &[]), (5, vec![stat | 0x1, 0x20]));
+        assert_eq!(first_response(&mut cpu, 0x2, &[0x00, 0x02]), (5, vec![stat | 0x1, 0x20]));
+        assert_eq!(first_response(&mut cpu, 0x1, &[0x00]), (5, vec![stat | 0x1, 0x20]));
+    }
+
+    #[test]
+    fn test_set_filter_get_param() {
+        let mut cpu = test_cpu();
+        send_command(&mut cpu, 0xE, &[0x48]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        send_command(&mut cpu, 0xD, &[0x01, 0x05]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        let stat = cpu.main_bus.cd_drive.get_stat();
+        assert_eq!(first_response(&mut cpu, 0xF, &[]), (3, vec![stat, 0x48, 0x00, 0x01, 0x05]));
+    }
+
+    #[test]
+    fn test_mute() {
+        let mut cpu = test_cpu();
+        let mut disc = CueDisc::new("test");
+        disc.add_track(DiscTrack::with_type(audio_sectors(4), TrackType::Audio));
+        cpu.main_bus.cd_drive.load_disc(Box::new(disc));
+        send_command(&mut cpu, 0xB, &[]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        send_command(&mut cpu, 0x3, &[0x01]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        for _ in 0..PLAY_SECTOR_TIME * 2 {
+            step_cycle(&mut cpu);
+        }
+        //Still playing, just silent
+        let samples = cd_audio(&mut cpu);
+        assert_eq!(samples.len(), 2 * 588 * 2);
+        assert!(samples.iter().all(|sample| *sample == 0));
+    }
+
+    #[test]
+    fn test_stop_and_reset() {
+        let mut cpu = test_cpu();
+        send_command(&mut cpu, 0x6, &[]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        send_command(&mut cpu, 0x8, &[]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        wait_for_int(&mut cpu, IntCause::INT2);
+        assert_eq!(cpu.main_bus.cd_drive.motor_state, MotorState::Off);
+        assert_eq!(cpu.main_bus.cd_drive.drive_state, DriveState::Stopped);
+        assert!(!cpu.main_bus.cd_drive.read_enabled);
+
+        send_command(&mut cpu, 0xE, &[0x80]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        send_command(&mut cpu, 0x1C, &[]);
+        wait_for_int(&mut cpu, IntCause::INT3);
+        assert_eq!(cpu.main_bus.cd_drive.drive_mode, 0);
+    }
+
+    /// Cycles between a SeekL to the given BCD location and its completion
+    fn seek_cycles(cpu: &mut R3000, minutes: u8, seconds: u8, frames: u8) -> u32 {
+        send_command(cpu, 0x2, &[minutes, seconds, frames]);
+        wait_for_int(cpu, IntCause::INT3);
+        send_command(cpu, 0x15, &[]);
+        wait_for_int(cpu, IntCause::INT3);
+        wait_for_int(cpu, IntCause::INT2)
+    }
+
+    #[test]
+    fn test_seek_time_scales_with_distance() {
+        let mut cpu = test_cpu();
+        let near = seek_cycles(&mut cpu, 0x00, 0x02, 0x10);
+        let far = seek_cycles(&mut cpu, 0x60, 0x00, 0x00);
+        //Coming back to where it started is as far again
+        let back = seek_cycles(&mut cpu, 0x00, 0x02, 0x10);
+        assert!((MIN_SEEK_TIME..200_000).contains(&near));
+        assert!(far > 10 * near);
+        assert_eq!(back, far);
+    }
+}
@@ -0,0 +1,169 @@
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+//Raw sector layout: 12 sync bytes, 4 header bytes, then the 8 byte mode 2 subheader
+const SUBHEADER_OFFSET: usize = 16;
+const DATA_OFFSET: usize = 24;
+
+const SOUND_GROUPS: usize = 18;
+const SOUND_GROUP_SIZE: usize = 128;
+const SAMPLES_PER_BLOCK: usize = 28;
+
+//Real-time, form 2 and audio submode bits. Sectors with all three are XA-ADPCM
+const SUBMODE_XA_AUDIO: u8 = 0x64;
+
+const OUTPUT_RATE: usize = 44100;
+
+//ADPCM prediction filters, in 1/64ths
+const POS_TABLE: [i32; 4] = [0, 60, 115, 98];
+const NEG_TABLE: [i32; 4] = [0, 0, -52, -55];
+
+/// The parts of a mode 2 subheader the XA decoder cares about
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct XaSubheader {
+    pub(super) file: u8,
+    pub(super) channel: u8,
+    submode: u8,
+    coding_info: u8,
+}
+
+impl XaSubheader {
+    pub(super) fn from_sector(sector: &[u8]) -> Self {
+        Self {
+            file: sector[SUBHEADER_OFFSET],
+            channel: sector[SUBHEADER_OFFSET + 1],
+            submode: sector[SUBHEADER_OFFSET + 2],
+            coding_info: sector[SUBHEADER_OFFSET + 3],
+        }
+    }
+
+    pub(super) fn is_audio(&self) -> bool {
+        self.submode & SUBMODE_XA_AUDIO == SUBMODE_XA_AUDIO
+    }
+
+    fn stereo(&self) -> bool {
+        self.coding_info & 0x3 == 1
+    }
+
+    fn sample_rate(&self) -> usize {
+        match self.coding_info & 0xC {
+            0 => 37800,
+            _ => 18900,
+        }
+    }
+
+    fn eight_bit(&self) -> bool {
+        self.coding_info & 0x30 == 0x10
+    }
+}
+
+/// XA-ADPCM decoder. Keeps the last two samples of each channel, since prediction carries over between sectors
+#[derive(Debug, Default)]
+pub(super) struct XaDecoder {
+    history: [[i32; 2]; 2],
+}
+
+impl XaDecoder {
+    /// Decodes an XA audio sector into (left, right) frames at the sector's own sample rate. Mono samples go to both sides
+    pub(super) fn decode_sector(&mut self, sector: &[u8]) -> Vec<(i16, i16)> {
+        let subheader = XaSubheader::from_sector(sector);
+        let blocks = if subheader.eight_bit() { 4 } else { 8 };
+        let mut frames = Vec::new();
+        for group in sector[DATA_OFFSET..].chunks_exact(SOUND_GROUP_SIZE).take(SOUND_GROUPS) {
+            if subheader.stereo() {
+                //Even blocks are the left channel, odd blocks the right
+                for block in (0..blocks).step_by(2) {
+                    let left = self.decode_block(group, block, 0, subheader.eight_bit());
+                    let right = self.decode_block(group, block + 1, 1, subheader.eight_bit());
+                    frames.extend(left.iter().copied().zip(right.iter().copied()));
+                }
+            } else {
+                for block in 0..blocks {
+                    let samples = self.decode_block(group, block, 0, subheader.eight_bit());
+                    frames.extend(samples.iter().map(|sample| (*sample, *sample)));
+                }
+            }
+        }
+        frames
+    }
+
+    /// Decodes an XA audio sector and resamples it to 44.1kHz for the audio output
+    pub(super) fn decode_sector_resampled(&mut self, sector: &[u8]) -> Vec<(i16, i16)> {
+        let rate = XaSubheader::from_sector(sector).sample_rate();
+        let frames = self.decode_sector(sector);
+        let output_len = frames.len() * OUTPUT_RATE / rate;
+        (0..output_len).map(|i| frames[i * rate / OUTPUT_RATE]).collect()
+    }
+
+    pub(super) fn save_state(&self, writer: &mut StateWriter) {
+        for sample in self.history.iter().flatten() {
+            writer.write_u32(*sample as u32);
+        }
+    }
+
+    pub(super) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        for sample in self.history.iter_mut().flatten() {
+            *sample = reader.read_u32()? as i32;
+        }
+        Ok(())
+    }
+
+    fn decode_block(&mut self, group: &[u8], block: usize, channel: usize, eight_bit: bool) -> [i16; SAMPLES_PER_BLOCK] {
+        let header = group[4 + block];
+        let shift = match header & 0xF {
+            shift if shift > 12 => 9,
+            shift => shift,
+        };
+        let filter = ((header >> 4) & 0x3) as usize;
+        let [mut old, mut older] = self.history[channel];
+
+        let mut samples = [0; SAMPLES_PER_BLOCK];
+        for (i, sample) in samples.iter_mut().enumerate() {
+            //Samples are interleaved. Each 4 byte word holds one sample of every block
+            let raw = if eight_bit {
+                ((group[16 + i * 4 + block] as i8 as i32) << 8) >> shift
+            } else {
+                let nibble = (group[16 + i * 4 + block / 2] >> ((block & 1) * 4)) & 0xF;
+                ((((nibble << 4) as i8) as i32) << 8) >> shift
+            };
+            let predicted = raw + ((old * POS_TABLE[filter] + older * NEG_TABLE[filter] + 32) >> 6);
+            let clamped = predicted.clamp(i16::MIN as i32, i16::MAX as i32);
+            older = old;
+            old = clamped;
+            *sample = clamped as i16;
+        }
+        self.history[channel] = [old, older];
+        samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_sector() {
+        //Mono, 4 bit, 37.8kHz
+        let mut sector = vec![0; 2352];
+        sector[SUBHEADER_OFFSET + 2] = SUBMODE_XA_AUDIO;
+        let group = &mut sector[DATA_OFFSET..DATA_OFFSET + SOUND_GROUP_SIZE];
+        //Block 0 has no shift or filter, block 1 shifts by 4 and predicts with filter 1
+        group[4] = 0x00;
+        group[5] = 0x14;
+        //Block 0 samples 1, 2, -1, ..., 1. Block 1 samples 1, 0, ...
+        group[16] = 0x11;
+        group[16 + 4] = 0x02;
+        group[16 + 8] = 0x0F;
+        group[16 + 27 * 4] = 0x01;
+
+        let frames = XaDecoder::default().decode_sector(&sector);
+        assert_eq!(frames.len(), 18 * 8 * 28);
+        assert_eq!(frames[..4], [(4096, 4096), (8192, 8192), (-4096, -4096), (0, 0)]);
+        assert_eq!(frames[27], (4096, 4096));
+        //(1 << 12 >> 4) + 4096 * 60 / 64, then just the prediction
+        assert_eq!(frames[28..30], [(4096, 4096), (3840, 3840)]);
+
+        let resampled = XaDecoder::default().decode_sector_resampled(&sector);
+        assert_eq!(resampled.len(), frames.len() * 7 / 6);
+        assert!(XaSubheader::from_sector(&sector).is_audio());
+    }
+}
@@ -0,0 +1,103 @@
+use crate::bus::MainBus;
+use crate::error::EmuError;
+
+//Code types, from the top byte of the address half of a line
+const WRITE_8: u32 = 0x30;
+const WRITE_16: u32 = 0x80;
+const IF_EQUAL_16: u32 = 0xD0;
+const IF_NOT_EQUAL_16: u32 = 0xD1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CheatOp {
+    Write8(u32, u8),
+    Write16(u32, u16),
+    //Conditionals only let the line after them run when the comparison holds
+    IfEqual16(u32, u16),
+    IfNotEqual16(u32, u16),
+}
+
+/// A GameShark code. Each line is "AAAAAAAA VVVV", and every line is reapplied once a frame
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Cheat {
+    ops: Vec<CheatOp>,
+}
+
+impl Cheat {
+    /// Parses one or more lines of a code. The address and value can be split by a space or a colon
+    pub(crate) fn parse(code: &str) -> Result<Self, EmuError> {
+        let ops = code
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(parse_line)
+            .collect::<Result<Vec<_>, _>>()?;
+        if ops.is_empty() {
+            return Err(EmuError::InvalidCheat(code.to_string()));
+        }
+        Ok(Self { ops })
+    }
+
+    pub(crate) fn apply(&self, bus: &mut MainBus) {
+        let mut skip_next = false;
+        for op in &self.ops {
+            if std::mem::take(&mut skip_next) {
+                continue;
+            }
+            match *op {
+                CheatOp::Write8(addr, value) => bus.write_byte(addr, value),
+                CheatOp::Write16(addr, value) => bus.write_half_word(addr, value),
+                CheatOp::IfEqual16(addr, value) => skip_next = bus.read_half_word(addr) != value,
+                CheatOp::IfNotEqual16(addr, value) => skip_next = bus.read_half_word(addr) == value,
+            }
+        }
+    }
+}
+
+fn parse_line(line: &str) -> Result<CheatOp, EmuError> {
+    let invalid = || EmuError::InvalidCheat(line.to_string());
+    let mut parts = line.split(|c: char| c.is_whitespace() || c == ':').filter(|part| !part.is_empty());
+    let (address, value) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(address), Some(value), None) if address.len() == 8 && value.len() == 4 => (address, value),
+        _ => return Err(invalid()),
+    };
+    let address = u32::from_str_radix(address, 16).map_err(|_| invalid())?;
+    let value = u16::from_str_radix(value, 16).map_err(|_| invalid())?;
+    //The code type sits in the top byte. The rest is an offset into RAM
+    let addr = address & 0x00FF_FFFF;
+    match address >> 24 {
+        WRITE_8 => Ok(CheatOp::Write8(addr, value as u8)),
+        WRITE_16 => Ok(CheatOp::Write16(addr, value)),
+        IF_EQUAL_16 => Ok(CheatOp::IfEqual16(addr, value)),
+        IF_NOT_EQUAL_16 => Ok(CheatOp::IfNotEqual16(addr, value)),
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let cheat = Cheat::parse("80012340 FFFF\n30012342:0063").unwrap();
+        assert_eq!(cheat.ops, [CheatOp::Write16(0x12340, 0xFFFF), CheatOp::Write8(0x12342, 0x63)]);
+        for bad in ["", "8001234 FFFF", "80012340 FFFFF", "E0012340 0001", "8001234G 0000", "80012340 0000 0000"] {
+            assert_eq!(Cheat::parse(bad), Err(EmuError::InvalidCheat(bad.to_string())), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_conditional() {
+        let mut bus = MainBus::for_test();
+        let cheat = Cheat::parse("D0001000 0001\n80001002 1234\nD1001000 0001\n30001004 0056").unwrap();
+        cheat.apply(&mut bus);
+        assert_eq!(bus.read_half_word(0x1002), 0);
+        assert_eq!(bus.read_byte(0x1004), 0x56);
+
+        bus.write_half_word(0x1000, 1);
+        bus.write_byte(0x1004, 0);
+        cheat.apply(&mut bus);
+        assert_eq!(bus.read_half_word(0x1002), 0x1234);
+        assert_eq!(bus.read_byte(0x1004), 0);
+    }
+}
@@ -0,0 +1,69 @@
+//Vram pixels are BGR555: red in the low 5 bits, then green, then blue, with the mask bit on top.
+//Colors in gpu commands are 24 bit with red in the low byte
+
+pub const RED_SHIFT: u16 = 0;
+pub const GREEN_SHIFT: u16 = 5;
+pub const BLUE_SHIFT: u16 = 10;
+/// One 5 bit channel, once shifted down
+pub const CHANNEL_MASK: u16 = 0x1F;
+/// Set on pixels drawn with the mask bit on. Not part of the color
+pub const MASK_BIT: u16 = 0x8000;
+/// The 24 bit color in the low bits of a gpu command word
+pub const COMMAND_COLOR_MASK: u32 = 0xFF_FFFF;
+
+/// Expands a vram pixel to 8 bits per channel. Alpha is always opaque, the mask bit isn't transparency
+pub fn bgr555_to_rgba8(pixel: u16) -> [u8; 4] {
+    let (r, g, b) = channels(pixel);
+    //Repeat the top bits in the bottom so 0x1F becomes 0xFF
+    let expand = |channel: u8| (channel << 3) | (channel >> 2);
+    [expand(r), expand(g), expand(b), 0xFF]
+}
+
+/// Drops each 8 bit channel to 5 bits
+pub fn rgb24_to_bgr555(r: u8, g: u8, b: u8) -> u16 {
+    from_channels(r >> 3, g >> 3, b >> 3)
+}
+
+/// Vram pixel for the color in a gpu command word
+pub fn command_color_to_bgr555(word: u32) -> u16 {
+    let color = word & COMMAND_COLOR_MASK;
+    rgb24_to_bgr555(color as u8, (color >> 8) as u8, (color >> 16) as u8)
+}
+
+/// 5 bit (red, green, blue) of a vram pixel
+pub(crate) fn channels(pixel: u16) -> (u8, u8, u8) {
+    (
+        ((pixel >> RED_SHIFT) & CHANNEL_MASK) as u8,
+        ((pixel >> GREEN_SHIFT) & CHANNEL_MASK) as u8,
+        ((pixel >> BLUE_SHIFT) & CHANNEL_MASK) as u8,
+    )
+}
+
+/// Vram pixel from 5 bit channels
+pub(crate) fn from_channels(r: u8, g: u8, b: u8) -> u16 {
+    ((r as u16) << RED_SHIFT) | ((g as u16) << GREEN_SHIFT) | ((b as u16) << BLUE_SHIFT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_order() {
+        //(r, g, b), bgr555, command word
+        let colors = [
+            ((0xFF, 0, 0), 0x001F, 0x00_00FF),
+            ((0, 0xFF, 0), 0x03E0, 0x00_FF00),
+            ((0, 0, 0xFF), 0x7C00, 0xFF_0000),
+            ((0xFF, 0xFF, 0xFF), 0x7FFF, 0xFF_FFFF),
+        ];
+        for ((r, g, b), pixel, word) in colors.iter().copied() {
+            assert_eq!(rgb24_to_bgr555(r, g, b), pixel);
+            assert_eq!(command_color_to_bgr555(word), pixel);
+            assert_eq!(bgr555_to_rgba8(pixel), [r, g, b, 0xFF]);
+            assert_eq!(bgr555_to_rgba8(pixel | MASK_BIT), [r, g, b, 0xFF]);
+        }
+        //The command byte above the color is ignored
+        assert_eq!(command_color_to_bgr555(0x6000_00FF), 0x001F);
+    }
+}
@@ -4,6 +4,7 @@ use bit_field::BitField;
 use log::{error, warn};
 
 use crate::cpu::{InterruptSource, R3000};
+use crate::memory_card::MemoryCard;
 
 pub(super) const JOY_DATA: u32 = 0x1F801040;
 pub(super) const JOY_STAT: u32 = 0x1F801044;
@@ -16,6 +17,46 @@ const DEFAULT_JOY_BAUD: u16 = 0x88;
 const MEMORY_CARD_SELECT_BYTE: u8 = 0x81;
 const CONTROLER_SELECT_BYTE: u8 = 0x1;
 
+//Controller commands, sent right after the select byte
+const POLL_COMMAND: u8 = 0x42;
+const CONFIG_MODE_COMMAND: u8 = 0x43;
+const SET_ANALOG_COMMAND: u8 = 0x44;
+const STATUS_COMMAND: u8 = 0x45;
+const RUMBLE_CONFIG_COMMAND: u8 = 0x4D;
+
+//ID bytes. The low nibble is how many half words of data follow
+const DIGITAL_ID: u8 = 0x41;
+const ANALOG_ID: u8 = 0x73;
+const CONFIG_ID: u8 = 0xF3;
+
+//Stick axes rest in the middle
+const STICK_CENTER: u8 = 0x80;
+
+//Values in the rumble mapping. Anything else leaves the byte unused
+const SMALL_MOTOR: u8 = 0x0;
+const LARGE_MOTOR: u8 = 0x1;
+
+//What the data line reads as when nothing drives it
+const NO_DEVICE_RESPONSE: u8 = 0xFF;
+
+//Button bits for PSXEmu::set_button_state, in the order the pad sends them. A set bit means pressed
+pub const BUTTON_SELECT: u16 = 1 << 0;
+pub const BUTTON_L3: u16 = 1 << 1;
+pub const BUTTON_R3: u16 = 1 << 2;
+pub const BUTTON_START: u16 = 1 << 3;
+pub const BUTTON_UP: u16 = 1 << 4;
+pub const BUTTON_RIGHT: u16 = 1 << 5;
+pub const BUTTON_DOWN: u16 = 1 << 6;
+pub const BUTTON_LEFT: u16 = 1 << 7;
+pub const BUTTON_L2: u16 = 1 << 8;
+pub const BUTTON_R2: u16 = 1 << 9;
+pub const BUTTON_L1: u16 = 1 << 10;
+pub const BUTTON_R1: u16 = 1 << 11;
+pub const BUTTON_TRIANGLE: u16 = 1 << 12;
+pub const BUTTON_CIRCLE: u16 = 1 << 13;
+pub const BUTTON_CROSS: u16 = 1 << 14;
+pub const BUTTON_SQUARE: u16 = 1 << 15;
+
 pub enum ControllerType {
     DigitalPad,
 }
@@ -73,34 +114,29 @@ impl ButtonState {
         }
     }
 
-    fn digital_low_byte(&self) -> u8 {
-        let mut result = 0;
-
-        result.set_bit(0, !self.button_select);
-        result.set_bit(1, !self.button_l3);
-        result.set_bit(2, !self.button_r3);
-        result.set_bit(3, !self.button_start);
-        result.set_bit(4, !self.button_up);
-        result.set_bit(5, !self.button_right);
-        result.set_bit(6, !self.button_down);
-        result.set_bit(7, !self.button_left);
-
-        result
-    }
-
-    fn digital_high_byte(&self) -> u8 {
-        let mut result = 0;
-
-        result.set_bit(0, !self.button_l2);
-        result.set_bit(1, !self.button_r2);
-        result.set_bit(2, !self.button_l1);
-        result.set_bit(3, !self.button_r1);
-        result.set_bit(4, !self.button_triangle);
-        result.set_bit(5, !self.button_circle);
-        result.set_bit(6, !self.button_x);
-        result.set_bit(7, !self.button_square);
-
-        result
+    /// The pressed buttons as BUTTON_* bits
+    pub fn bits(&self) -> u16 {
+        [
+            (self.button_select, BUTTON_SELECT),
+            (self.button_l3, BUTTON_L3),
+            (self.button_r3, BUTTON_R3),
+            (self.button_start, BUTTON_START),
+            (self.button_up, BUTTON_UP),
+            (self.button_right, BUTTON_RIGHT),
+            (self.button_down, BUTTON_DOWN),
+            (self.button_left, BUTTON_LEFT),
+            (self.button_l2, BUTTON_L2),
+            (self.button_r2, BUTTON_R2),
+            (self.button_l1, BUTTON_L1),
+            (self.button_r1, BUTTON_R1),
+            (self.button_triangle, BUTTON_TRIANGLE),
+            (self.button_circle, BUTTON_CIRCLE),
+            (self.button_x, BUTTON_CROSS),
+            (self.button_square, BUTTON_SQUARE),
+        ]
+        .iter()
+        .filter(|(pressed, _)| *pressed)
+        .fold(0, |bits, (_, bit)| bits | bit)
     }
 }
 
@@ -129,7 +165,23 @@ pub(super) struct Controllers {
     pub(super) pending_irq: bool,
     irq_cycle_timer: usize,
 
-    latest_button_state: ButtonState,
+    //Pressed buttons and whether a pad is plugged in, for each port
+    buttons: [u16; 2],
+    connected: [bool; 2],
+    //Right x, right y, left x, left y for each port
+    sticks: [[u8; 4]; 2],
+    memory_cards: [Option<MemoryCard>; 2],
+
+    //Command byte of the transfer in progress
+    command: u8,
+    //The rest is per port, since each pad keeps its own modes and motors
+    config_mode: [bool; 2],
+    //Analog pads send stick axes after the buttons
+    analog_mode: [bool; 2],
+    //Which parameter bytes of a poll drive the motors, set with command 0x4D
+    motor_mapping: [[u8; 6]; 2],
+    small_motor: [u8; 2],
+    large_motor: [u8; 2],
 }
 
 impl Controllers {
@@ -146,12 +198,56 @@ impl Controllers {
             pending_irq: false,
             irq_cycle_timer: 0,
 
-            latest_button_state: ButtonState::new_digital_pad(),
+            buttons: [0; 2],
+            connected: [true, false],
+            sticks: [[STICK_CENTER; 4]; 2],
+            memory_cards: [None, None],
+
+            command: 0,
+            config_mode: [false; 2],
+            analog_mode: [false; 2],
+            motor_mapping: [[0xFF; 6]; 2],
+            small_motor: [0; 2],
+            large_motor: [0; 2],
+        }
+    }
+
+    /// Current (small, large) rumble motor values of the pad in the given port
+    pub(super) fn rumble_state(&self, port: usize) -> (u8, u8) {
+        match (self.small_motor.get(port), self.large_motor.get(port)) {
+            (Some(small), Some(large)) => (*small, *large),
+            _ => (0, 0),
         }
     }
 
     pub(super) fn update_button_state(&mut self, new_state: ButtonState) {
-        self.latest_button_state = new_state;
+        self.buttons[0] = new_state.bits();
+    }
+
+    /// Sets the pressed buttons of the pad in the given port, plugging one in if the port was empty
+    pub(super) fn set_button_state(&mut self, port: usize, buttons: u16) {
+        if port < self.buttons.len() {
+            self.buttons[port] = buttons;
+            self.connected[port] = true;
+        }
+    }
+
+    /// Sets the stick axes of the pad in the given port. They're only sent while the pad is in analog mode
+    pub(super) fn set_analog(&mut self, port: usize, sticks: [u8; 4]) {
+        if port < self.sticks.len() {
+            self.sticks[port] = sticks;
+        }
+    }
+
+    pub(super) fn attach_memory_card(&mut self, slot: usize, card: MemoryCard) {
+        if slot < self.memory_cards.len() {
+            self.memory_cards[slot] = Some(card);
+        }
+    }
+
+    /// JOY_CTRL bit 13 picks which port the transfer talks to
+    fn selected_port(&self) -> usize {
+        self.joy_ctrl.get_bit(13) as usize
     }
 
     pub(super) fn write_half_word(&mut self, addr: u32, val: u16) {
@@ -239,18 +335,19 @@ impl Controllers {
                 TXstate::Disabled
             }
             TXstate::Ready => {
-                let slot = if val == CONTROLER_SELECT_BYTE {
-                    Slot::Controller
-                } else {
-                    Slot::MemoryCard
+                let port = self.selected_port();
+                let (slot, present) = match val {
+                    CONTROLER_SELECT_BYTE => (Slot::Controller, self.connected[port]),
+                    MEMORY_CARD_SELECT_BYTE => (Slot::MemoryCard, self.memory_cards[port].is_some()),
+                    _ => (Slot::Controller, false),
                 };
 
-                if slot == Slot::MemoryCard {
+                //Nothing answers, so the BIOS sees no ack and gives up on the port
+                if !present {
+                    self.push_rx_buf(NO_DEVICE_RESPONSE);
                     return;
                 }
 
-               
-
                 self.push_rx_buf(0);
                 self.queue_interrupt();
                 TXstate::Transfering {
@@ -260,16 +357,24 @@ impl Controllers {
             }
             TXstate::Transfering { slot, step } => {
                 if slot == Slot::Controller {
-                     
+                    let port = self.selected_port();
+                    //Config mode commands and analog polls take 6 parameters, a digital poll takes 2
+                    let id = match (self.config_mode[port], self.analog_mode[port]) {
+                        (true, _) => CONFIG_ID,
+                        (false, true) => ANALOG_ID,
+                        (false, false) => DIGITAL_ID,
+                    };
+                    let parameter_count = (id & 0xF) as usize * 2;
                     let response = match step {
-                        0 => 0x41, // Digital pad idlo
+                        0 => {
+                            self.command = val;
+                            id
+                        }
                         1 => 0x5A, // Digital pad idhi
-                        2 => self.latest_button_state.digital_low_byte(),
-                        3 => self.latest_button_state.digital_high_byte(),
-                        _ => 0,
+                        _ => self.controller_parameter(step - 2, val),
                     };
                     self.push_rx_buf(response);
-                    if step < 3 {
+                    if step < parameter_count + 1 {
                         self.queue_interrupt();
                     }
                     TXstate::Transfering {
@@ -277,24 +382,84 @@ impl Controllers {
                         step: step + 1
                     }
                 } else {
-                    panic!("Tried to read memory card! It's not implemented yet :(");
+                    let port = self.selected_port();
+                    let (response, ack) = match self.memory_cards[port].as_mut() {
+                        Some(card) => card.transfer(step, val),
+                        None => (NO_DEVICE_RESPONSE, false),
+                    };
+                    self.push_rx_buf(response);
+                    if ack {
+                        self.queue_interrupt();
+                    }
+                    TXstate::Transfering {
+                        slot,
+                        step: step + 1,
+                    }
                 }
             }
         };
         self.tx_state = new_state;
     }
 
+    /// Handles a parameter byte of the current controller command and returns the byte sent back
+    fn controller_parameter(&mut self, index: usize, val: u8) -> u8 {
+        let port = self.selected_port();
+        let buttons = self.poll_data(index);
+        let config_mode = self.config_mode[port];
+        match self.command {
+            POLL_COMMAND => {
+                match self.motor_mapping[port].get(index) {
+                    Some(&SMALL_MOTOR) => self.small_motor[port] = val,
+                    Some(&LARGE_MOTOR) => self.large_motor[port] = val,
+                    _ => (),
+                }
+                buttons
+            }
+            CONFIG_MODE_COMMAND => {
+                //The first parameter enters (1) or leaves (0) config mode. Outside config mode this doubles as a poll
+                let response = if config_mode { 0 } else { buttons };
+                if index == 0 {
+                    self.config_mode[port] = val == 1;
+                }
+                response
+            }
+            SET_ANALOG_COMMAND if config_mode => {
+                //The first parameter picks analog (1) or digital (0). The second can lock the analog button, which isn't emulated
+                if index == 0 {
+                    self.analog_mode[port] = val == 1;
+                }
+                0
+            }
+            STATUS_COMMAND if config_mode => {
+                //DualShock, and whether analog mode is on
+                [0x01, 0x02, self.analog_mode[port] as u8, 0x02, 0x01, 0x00][index.min(5)]
+            }
+            RUMBLE_CONFIG_COMMAND if config_mode && index < 6 => {
+                //Replies with the old mapping while the new one is sent
+                std::mem::replace(&mut self.motor_mapping[port][index], val)
+            }
+            _ => if config_mode { 0 } else { buttons },
+        }
+    }
+
+    /// Byte index of a poll's data: buttons active low, then the stick axes in analog mode
+    fn poll_data(&self, index: usize) -> u8 {
+        let port = self.selected_port();
+        let buttons = !self.buttons[port];
+        match index {
+            0 => buttons as u8,
+            1 => (buttons >> 8) as u8,
+            2..=5 if self.analog_mode[port] => self.sticks[port][index - 2],
+            _ => 0,
+        }
+    }
+
     fn read_joy_stat(&mut self) -> u16 {
         let mut val: u16 = 0;
 
 
-        if self.tx_state != TXstate::Disabled {
-            val |= 0x1;
-        };
-
-        if self.tx_state != TXstate::Ready {
-            val |= 0x4;
-        }
+        //Bytes go out as soon as they're written, so TX is always ready and finished
+        val |= 0x5;
 
         if self.irq_status {
             val |= 0x200;
@@ -366,4 +531,127 @@ pub(super) fn controller_execute_cycle(cpu: &mut R3000) {
         cpu.fire_external_interrupt(InterruptSource::Controller);
         cpu.main_bus.controllers.pending_irq = false;
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs a whole controller transfer on port 1 and returns the bytes received back
+    fn transfer(controllers: &mut Controllers, bytes: &[u8]) -> Vec<u8> {
+        transfer_on_port(controllers, 0, bytes)
+    }
+
+    fn transfer_on_port(controllers: &mut Controllers, port: usize, bytes: &[u8]) -> Vec<u8> {
+        //Bit 13 of JOY_CTRL selects the second port
+        controllers.write_half_word(JOY_CTRL, 0x1003 | ((port as u16) << 13));
+        let received = bytes
+            .iter()
+            .map(|byte| {
+                controllers.write_byte(JOY_DATA, *byte);
+                controllers.read_byte(JOY_DATA)
+            })
+            .collect();
+        controllers.write_half_word(JOY_CTRL, 0);
+        received
+    }
+
+    #[test]
+    fn test_rumble() {
+        let mut controllers = Controllers::new();
+        //Motor bytes do nothing until they're mapped
+        transfer(&mut controllers, &[0x01, 0x42, 0x00, 0x40, 0xFF]);
+        assert_eq!(controllers.rumble_state(0), (0, 0));
+
+        assert_eq!(transfer(&mut controllers, &[0x01, 0x43, 0x00, 0x01, 0x00])[1], 0x41);
+        let reply = transfer(&mut controllers, &[0x01, 0x4D, 0x00, 0x00, 0x01, 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(reply, vec![0x00, 0xF3, 0x5A, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        transfer(&mut controllers, &[0x01, 0x43, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+
+        let reply = transfer(&mut controllers, &[0x01, 0x42, 0x00, 0x01, 0xC0]);
+        assert_eq!(reply, vec![0x00, 0x41, 0x5A, 0xFF, 0xFF]);
+        assert_eq!(controllers.rumble_state(0), (0x01, 0xC0));
+    }
+
+    #[test]
+    fn test_digital_pad_poll() {
+        let mut controllers = Controllers::new();
+        controllers.set_button_state(0, BUTTON_START | BUTTON_CROSS | BUTTON_UP);
+        let reply = transfer(&mut controllers, &[0x01, 0x42, 0x00, 0x00, 0x00]);
+        assert_eq!(reply, vec![0x00, 0x41, 0x5A, 0xE7, 0xBF]);
+
+        //Nothing in port 2 until a pad is set up there
+        controllers.pending_irq = false;
+        controllers.write_half_word(JOY_CTRL, 0x3003);
+        controllers.write_byte(JOY_DATA, 0x01);
+        assert_eq!(controllers.read_byte(JOY_DATA), 0xFF);
+        assert!(!controllers.pending_irq);
+        controllers.write_half_word(JOY_CTRL, 0);
+
+        controllers.set_button_state(1, BUTTON_SQUARE);
+        controllers.write_half_word(JOY_CTRL, 0x3003);
+        let reply: Vec<u8> = [0x01, 0x42, 0x00, 0x00, 0x00]
+            .iter()
+            .map(|byte| {
+                controllers.write_byte(JOY_DATA, *byte);
+                controllers.read_byte(JOY_DATA)
+            })
+            .collect();
+        assert_eq!(reply, vec![0x00, 0x41, 0x5A, 0xFF, 0x7F]);
+        assert_eq!(controllers.read_half_word(JOY_STAT) & 0x5, 0x5);
+    }
+
+    #[test]
+    fn test_analog_mode() {
+        let mut controllers = Controllers::new();
+        controllers.set_button_state(0, BUTTON_CIRCLE);
+        controllers.set_analog(0, [0x10, 0x20, 0x30, 0x40]);
+        //Digital until the game switches modes
+        assert_eq!(transfer(&mut controllers, &[0x01, 0x42, 0x00, 0x00, 0x00])[1], DIGITAL_ID);
+
+        transfer(&mut controllers, &[0x01, 0x43, 0x00, 0x01, 0x00]);
+        transfer(&mut controllers, &[0x01, 0x44, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00, 0x00]);
+        let status = transfer(&mut controllers, &[0x01, 0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(status[3..], [0x01, 0x02, 0x01, 0x02, 0x01, 0x00]);
+        transfer(&mut controllers, &[0x01, 0x43, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+
+        let reply = transfer(&mut controllers, &[0x01, 0x42, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(reply, vec![0x00, ANALOG_ID, 0x5A, 0xFF, 0xDF, 0x10, 0x20, 0x30, 0x40]);
+
+        //And back to digital
+        transfer(&mut controllers, &[0x01, 0x43, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        transfer(&mut controllers, &[0x01, 0x44, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        transfer(&mut controllers, &[0x01, 0x43, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(transfer(&mut controllers, &[0x01, 0x42, 0x00, 0x00, 0x00])[1], DIGITAL_ID);
+    }
+
+    #[test]
+    fn test_ports_have_their_own_modes() {
+        let mut controllers = Controllers::new();
+        controllers.set_button_state(1, 0);
+        transfer_on_port(&mut controllers, 1, &[0x01, 0x43, 0x00, 0x01, 0x00]);
+        transfer_on_port(&mut controllers, 1, &[0x01, 0x44, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00, 0x00]);
+        transfer_on_port(&mut controllers, 1, &[0x01, 0x4D, 0x00, 0x00, 0x01, 0xFF, 0xFF, 0xFF, 0xFF]);
+        transfer_on_port(&mut controllers, 1, &[0x01, 0x43, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+
+        //Port 1 is untouched by port 2 switching to analog and mapping its motors
+        assert_eq!(transfer(&mut controllers, &[0x01, 0x42, 0x00, 0x01, 0xC0])[1], DIGITAL_ID);
+        assert_eq!(controllers.rumble_state(0), (0, 0));
+
+        let reply = transfer_on_port(&mut controllers, 1, &[0x01, 0x42, 0x00, 0x01, 0xC0, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(reply[1], ANALOG_ID);
+        assert_eq!(controllers.rumble_state(1), (0x01, 0xC0));
+        assert_eq!(controllers.rumble_state(2), (0, 0));
+    }
+
+    #[test]
+    fn test_memory_card_slot() {
+        let mut controllers = Controllers::new();
+        //No card yet
+        assert_eq!(transfer(&mut controllers, &[0x81, 0x52]), vec![0xFF, 0xFF]);
+
+        controllers.attach_memory_card(0, MemoryCard::new());
+        let reply = transfer(&mut controllers, &[0x81, 0x52, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(reply, vec![0x00, 0x08, 0x5A, 0x5D, 0x00, 0x00, 0x5C, 0x5D, 0x00, 0x00, b'M']);
+        assert!(controllers.pending_irq);
+    }
+}
@@ -1,6 +1,50 @@
 use bit_field::BitField;
 
 use crate::cpu::Exception;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+//Debug breakpoint registers
+const BPC: usize = 3;
+const BDA: usize = 5;
+const DCIC: usize = 7;
+const BAD_VADDR: usize = 8;
+const BDAM: usize = 9;
+const BPCM: usize = 11;
+const STATUS: usize = 12;
+const CAUSE: usize = 13;
+const EPC: usize = 14;
+const PRID: usize = 15;
+
+/// Status register Boot Exception Vectors bit
+const STATUS_BEV: usize = 22;
+/// Cause IP bit driven by the interrupt controller (I_STAT & I_MASK)
+const CAUSE_EXTERNAL_INTERRUPT: usize = 10;
+/// Cause branch delay bit, set when the exception was in a delay slot
+const CAUSE_BRANCH_DELAY: usize = 31;
+/// Only the two software interrupt bits of Cause can be written. The rest is set by the hardware
+const CAUSE_WRITABLE: u32 = 0x300;
+
+/// DCIC hit flags, set when a breakpoint fires
+const DCIC_ANY_HIT: usize = 0;
+const DCIC_CODE_HIT: usize = 1;
+const DCIC_DATA_HIT: usize = 2;
+const DCIC_READ_HIT: usize = 3;
+const DCIC_WRITE_HIT: usize = 4;
+/// DCIC breakpoint enables. Nothing breaks unless both super-master enables and the master enable are set too
+const DCIC_CODE_BREAK: usize = 24;
+const DCIC_DATA_BREAK: usize = 25;
+const DCIC_DATA_READ_BREAK: usize = 26;
+const DCIC_DATA_WRITE_BREAK: usize = 27;
+const DCIC_MASTER_ENABLE: usize = 30;
+const DCIC_SUPER_MASTER_ENABLES: [usize; 2] = [23, 31];
+
+const GENERAL_EXCEPTION_VECTOR: u32 = 0x8000_0080;
+const BOOT_EXCEPTION_VECTOR: u32 = 0xBFC0_0180;
+/// Hardware breakpoints (BPC/BDA) have their own handlers
+const DEBUG_EXCEPTION_VECTOR: u32 = 0x8000_0040;
+const BOOT_DEBUG_EXCEPTION_VECTOR: u32 = 0xBFC0_0140;
+/// Processor revision ID reported by the R3000A
+const PRID_VALUE: u32 = 0x2;
 
 #[derive(Debug)]
 pub struct Cop0 {
@@ -11,6 +55,7 @@ impl Cop0 {
     pub fn new() -> Cop0 {
         let mut regs = [0; 32];
         regs[12] = 1; //Initially enable interrupts
+        regs[PRID] = PRID_VALUE;
         Cop0 {
             gen_registers: regs,
         }
@@ -21,11 +66,70 @@ impl Cop0 {
         self.gen_registers[register_number as usize]
     }
 
-    /// Sets register to given value. Prevents setting R0, which should always be zero. Will panic if register_number > 31
+    /// Sets register to given value, as MTC0 does. Only the writable bits of each register change, so writes to R0
+    /// and the read-only BadVaddr and PRID registers are ignored. Will panic if register_number > 31
     pub fn write_reg(&mut self, register_number: u8, value: u32) {
-        self.gen_registers[register_number as usize] = value;
-        if self.gen_registers[13].get_bits(8..=12) != 0 {
-            //panic!("INT bits set!");
+        let reg = register_number as usize;
+        let writable = Self::writable_mask(reg);
+        self.gen_registers[reg] = (self.gen_registers[reg] & !writable) | (value & writable);
+    }
+
+    fn writable_mask(register_number: usize) -> u32 {
+        match register_number {
+            0 | BAD_VADDR | PRID => 0, //Read only
+            CAUSE => CAUSE_WRITABLE,
+            _ => 0xFFFF_FFFF,
+        }
+    }
+
+    /// Records whether the exception being taken came from a branch delay slot
+    pub fn set_branch_delay(&mut self, in_delay_slot: bool) {
+        self.gen_registers[CAUSE].set_bit(CAUSE_BRANCH_DELAY, in_delay_slot);
+    }
+
+    /// Records the address that caused an address error. Software can't write BadVaddr itself
+    pub fn set_bad_vaddr(&mut self, addr: u32) {
+        self.gen_registers[BAD_VADDR] = addr;
+    }
+
+    /// True when a code breakpoint covers the instruction at pc. Flags the hit in DCIC
+    pub fn code_breakpoint_hit(&mut self, pc: u32) -> bool {
+        let hit = self.breakpoint_enabled(DCIC_CODE_BREAK) && self.address_matches(pc, BPC, BPCM);
+        if hit {
+            self.flag_breakpoint_hit(&[DCIC_CODE_HIT]);
+        }
+        hit
+    }
+
+    /// True when a data breakpoint covers a read or write of addr. Flags the hit in DCIC
+    pub fn data_breakpoint_hit(&mut self, addr: u32, write: bool) -> bool {
+        let (condition, flag) = match write {
+            true => (DCIC_DATA_WRITE_BREAK, DCIC_WRITE_HIT),
+            false => (DCIC_DATA_READ_BREAK, DCIC_READ_HIT),
+        };
+        let hit = self.breakpoint_enabled(DCIC_DATA_BREAK)
+            && self.gen_registers[DCIC].get_bit(condition)
+            && self.address_matches(addr, BDA, BDAM);
+        if hit {
+            self.flag_breakpoint_hit(&[DCIC_DATA_HIT, flag]);
+        }
+        hit
+    }
+
+    fn breakpoint_enabled(&self, enable: usize) -> bool {
+        let dcic = self.gen_registers[DCIC];
+        dcic.get_bit(enable) && dcic.get_bit(DCIC_MASTER_ENABLE) && DCIC_SUPER_MASTER_ENABLES.iter().all(|bit| dcic.get_bit(*bit))
+    }
+
+    /// Only the bits set in the mask register are compared
+    fn address_matches(&self, addr: u32, address_reg: usize, mask_reg: usize) -> bool {
+        (addr ^ self.gen_registers[address_reg]) & self.gen_registers[mask_reg] == 0
+    }
+
+    fn flag_breakpoint_hit(&mut self, flags: &[usize]) {
+        self.gen_registers[DCIC].set_bit(DCIC_ANY_HIT, true);
+        for flag in flags {
+            self.gen_registers[DCIC].set_bit(*flag, true);
         }
     }
 
@@ -34,13 +138,74 @@ impl Cop0 {
     }
 
     pub fn set_cause_execode(&mut self, exception: &Exception) {
-        self.gen_registers[13] =
-            (!((0x1F as u32) << 2) & self.gen_registers[13]) | ((exception.clone() as u32) << 2);
+        self.gen_registers[CAUSE] =
+            (!((0x1F as u32) << 2) & self.gen_registers[CAUSE]) | ((exception.clone() as u32) << 2);
     }
 
     pub fn interrupt_enabled(&self) -> bool {
         self.gen_registers[12].get_bit(0)
     }
+
+    /// True when interrupts are enabled (IEc) and one of the pending Cause IP bits is unmasked by the Status IM bits
+    pub fn interrupt_pending(&self) -> bool {
+        let mask = self.gen_registers[STATUS].get_bits(8..=15);
+        let pending = self.gen_registers[CAUSE].get_bits(8..=15);
+        self.interrupt_enabled() && (mask & pending) != 0
+    }
+
+    /// Drives the hardware interrupt line from the interrupt controller into Cause bit 10
+    pub fn set_external_interrupt(&mut self, pending: bool) {
+        self.gen_registers[CAUSE].set_bit(CAUSE_EXTERNAL_INTERRUPT, pending);
+    }
+
+    /// Records an exception and returns the address of the handler to jump to.
+    /// The interrupt enable/kernel mode pairs in the low six bits of Status are pushed one level deeper,
+    /// leaving the current pair as kernel mode with interrupts disabled.
+    pub fn enter_exception(&mut self, epc: u32, exception: Exception) -> u32 {
+        self.push_exception(epc, exception);
+        if self.gen_registers[STATUS].get_bit(STATUS_BEV) {
+            BOOT_EXCEPTION_VECTOR
+        } else {
+            GENERAL_EXCEPTION_VECTOR
+        }
+    }
+
+    /// Records a BPC/BDA breakpoint hit and returns the address of the debug handler
+    pub fn enter_debug_exception(&mut self, epc: u32) -> u32 {
+        self.push_exception(epc, Exception::Bp);
+        if self.gen_registers[STATUS].get_bit(STATUS_BEV) {
+            BOOT_DEBUG_EXCEPTION_VECTOR
+        } else {
+            DEBUG_EXCEPTION_VECTOR
+        }
+    }
+
+    fn push_exception(&mut self, epc: u32, exception: Exception) {
+        self.gen_registers[EPC] = epc;
+        self.set_cause_execode(&exception);
+
+        let status = self.gen_registers[STATUS];
+        self.gen_registers[STATUS] = (status & !0x3F) | ((status << 2) & 0x3F);
+    }
+
+    /// Pops the Status interrupt enable/kernel mode stack (RFE). The oldest pair is left as is
+    pub fn return_from_exception(&mut self) {
+        let status = self.gen_registers[STATUS];
+        self.gen_registers[STATUS] = (status & !0xF) | ((status & 0x3F) >> 2);
+    }
+
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        for reg in self.gen_registers.iter() {
+            writer.write_u32(*reg);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        for reg in self.gen_registers.iter_mut() {
+            *reg = reader.read_u32()?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -55,4 +220,109 @@ mod cop0_tests {
         cop0.write_reg(12, 0);
         assert_eq!(cop0.cache_isolated(), false);
     }
+
+    #[test]
+    fn test_enter_exception() {
+        let mut cop0 = Cop0::new();
+        cop0.write_reg(12, 0x0000_0009); //KUp, IEc
+        let vector = cop0.enter_exception(0x8001_0000, Exception::Sys);
+        assert_eq!(vector, GENERAL_EXCEPTION_VECTOR);
+        assert_eq!(cop0.read_reg(14), 0x8001_0000);
+        assert_eq!(cop0.read_reg(12) & 0x3F, 0x24);
+        assert_eq!((cop0.read_reg(13) >> 2) & 0x1F, Exception::Sys as u32);
+
+        cop0.write_reg(12, 1 << STATUS_BEV);
+        assert_eq!(cop0.enter_exception(0, Exception::Bp), BOOT_EXCEPTION_VECTOR);
+    }
+
+    #[test]
+    fn test_enter_debug_exception() {
+        let mut cop0 = Cop0::new();
+        assert_eq!(cop0.enter_debug_exception(0x8001_0000), DEBUG_EXCEPTION_VECTOR);
+        assert_eq!(cop0.read_reg(14), 0x8001_0000);
+        assert_eq!((cop0.read_reg(13) >> 2) & 0x1F, Exception::Bp as u32);
+
+        cop0.write_reg(12, 1 << STATUS_BEV);
+        assert_eq!(cop0.enter_debug_exception(0), BOOT_DEBUG_EXCEPTION_VECTOR);
+    }
+
+    #[test]
+    fn test_return_from_exception() {
+        let mut cop0 = Cop0::new();
+        cop0.write_reg(12, 0x1040_0009);
+        cop0.enter_exception(0x8001_0000, Exception::Int);
+        assert_eq!(cop0.read_reg(12), 0x1040_0024);
+        cop0.return_from_exception();
+        assert_eq!(cop0.read_reg(12), 0x1040_0029);
+        assert_eq!(cop0.read_reg(12) & 0xF, 0x9);
+    }
+
+    #[test]
+    fn test_interrupt_pending() {
+        let mut cop0 = Cop0::new();
+        cop0.set_external_interrupt(true);
+        cop0.write_reg(12, (1 << 10) | 1);
+        assert!(cop0.interrupt_pending());
+
+        //Masked by IM
+        cop0.write_reg(12, (1 << 9) | 1);
+        assert!(!cop0.interrupt_pending());
+
+        //Masked by IEc
+        cop0.write_reg(12, 1 << 10);
+        assert!(!cop0.interrupt_pending());
+    }
+
+    #[test]
+    fn test_read_only_registers() {
+        let mut cop0 = Cop0::new();
+        cop0.write_reg(0, 0xFFFFFFFF);
+        assert_eq!(cop0.read_reg(0), 0);
+
+        cop0.write_reg(15, 0xFFFFFFFF);
+        assert_eq!(cop0.read_reg(15), PRID_VALUE);
+    }
+
+    #[test]
+    fn test_data_breakpoint() {
+        let mut cop0 = Cop0::new();
+        cop0.write_reg(BDA as u8, 0x8001_0000);
+        cop0.write_reg(BDAM as u8, 0xFFFF_FFF0);
+        //Breaks on writes only
+        cop0.write_reg(DCIC as u8, 0xCA80_0000);
+        assert!(!cop0.data_breakpoint_hit(0x8001_0004, false));
+        assert!(!cop0.data_breakpoint_hit(0x8001_0010, true));
+        assert_eq!(cop0.read_reg(DCIC as u8) & 0x3F, 0);
+        assert!(cop0.data_breakpoint_hit(0x8001_0004, true));
+        assert_eq!(cop0.read_reg(DCIC as u8) & 0x3F, 0x15);
+
+        //Without the super-master enable nothing fires
+        cop0.write_reg(DCIC as u8, 0x4A80_0000);
+        assert!(!cop0.data_breakpoint_hit(0x8001_0004, true));
+    }
+
+    #[test]
+    fn test_writable_bits() {
+        let mut cop0 = Cop0::new();
+        cop0.set_external_interrupt(true);
+        cop0.set_cause_execode(&Exception::Sys);
+        //Only the software interrupt bits of Cause take the write
+        cop0.write_reg(13, 0xFFFF_FFFF);
+        assert_eq!(cop0.read_reg(13), (1 << 10) | 0x300 | ((Exception::Sys as u32) << 2));
+        cop0.write_reg(13, 0);
+        assert_eq!(cop0.read_reg(13), (1 << 10) | ((Exception::Sys as u32) << 2));
+
+        cop0.write_reg(12, 0x1040_0001);
+        assert_eq!(cop0.read_reg(12), 0x1040_0001);
+    }
+
+    #[test]
+    fn test_set_cause_execode() {
+        let mut cop0 = Cop0::new();
+        cop0.write_reg(13, 0x300);
+        cop0.set_branch_delay(true);
+        cop0.set_cause_execode(&Exception::Int);
+        cop0.set_cause_execode(&Exception::Sys);
+        assert_eq!(cop0.read_reg(13), 0x8000_0300 | ((Exception::Sys as u32) << 2));
+    }
 }
@@ -0,0 +1,112 @@
+use super::instruction::Instruction;
+use super::opcodes::decode;
+
+const REGISTER_NAMES: [&str; 32] = [
+    "$zero", "$at", "$v0", "$v1", "$a0", "$a1", "$a2", "$a3", //
+    "$t0", "$t1", "$t2", "$t3", "$t4", "$t5", "$t6", "$t7", //
+    "$s0", "$s1", "$s2", "$s3", "$s4", "$s5", "$s6", "$s7", //
+    "$t8", "$t9", "$k0", "$k1", "$gp", "$sp", "$fp", "$ra",
+];
+
+fn reg(register_number: u8) -> &'static str {
+    REGISTER_NAMES[register_number as usize]
+}
+
+fn address(addr: u32) -> String {
+    format!("0x{:04x}_{:04x}", addr >> 16, addr & 0xFFFF)
+}
+
+fn signed_hex(val: u16) -> String {
+    let val = val as i16;
+    if val < 0 {
+        format!("-{:#x}", -(val as i32))
+    } else {
+        format!("{:#x}", val)
+    }
+}
+
+/// Turns an instruction into assembly text. pc is the address of the instruction, used to resolve branch and jump targets
+pub fn disassemble(instruction: u32, pc: u32) -> String {
+    if instruction == 0 {
+        return "nop".to_string();
+    }
+    let info = match decode(instruction) {
+        Some(info) => info,
+        None => return format!("illegal {:#010x}", instruction),
+    };
+    let mnemonic = info.mnemonic.to_lowercase();
+    let (rs, rt, rd) = (
+        reg(instruction.rs()),
+        reg(instruction.rt()),
+        reg(instruction.rd()),
+    );
+    let branch_target = pc
+        .wrapping_add(4)
+        .wrapping_add(instruction.immediate_sign_extended() << 2);
+    let memory_operand = format!("{}({})", signed_hex(instruction.immediate()), rs);
+
+    let operands = match info.mnemonic {
+        "SLL" | "SRL" | "SRA" => format!("{}, {}, {}", rd, rt, instruction.shamt()),
+        "SLLV" | "SRLV" | "SRAV" => format!("{}, {}, {}", rd, rt, rs),
+        "JR" | "MTHI" | "MTLO" => rs.to_string(),
+        "JALR" => format!("{}, {}", rd, rs),
+        "SYSCALL" | "BREAK" | "RFE" => String::new(),
+        "MFHI" | "MFLO" => rd.to_string(),
+        "MULT" | "MULTU" | "DIV" | "DIVU" => format!("{}, {}", rs, rt),
+        "BLTZ" | "BGEZ" | "BLTZAL" | "BGEZAL" | "BLEZ" | "BGTZ" => {
+            format!("{}, {}", rs, address(branch_target))
+        }
+        "BEQ" | "BNE" => format!("{}, {}, {}", rs, rt, address(branch_target)),
+        "J" | "JAL" => address((pc.wrapping_add(4) & 0xF000_0000) | (instruction.address() << 2)),
+        "ADDI" | "ADDIU" | "SLTI" | "SLTIU" => {
+            format!("{}, {}, {}", rt, rs, signed_hex(instruction.immediate()))
+        }
+        "ANDI" | "ORI" | "XORI" => format!("{}, {}, {:#x}", rt, rs, instruction.immediate()),
+        "LUI" => format!("{}, {:#x}", rt, instruction.immediate()),
+        "MFC0" | "MTC0" | "CFC0" | "CTC0" | "MFC2" | "MTC2" | "CFC2" | "CTC2" => {
+            format!("{}, ${}", rt, instruction.rd())
+        }
+        "COP2" => format!("{:#x}", instruction & 0x1FF_FFFF),
+        "LWC2" | "SWC2" => format!("${}, {}", instruction.rt(), memory_operand),
+        "LB" | "LH" | "LWL" | "LW" | "LBU" | "LHU" | "LWR" | "SB" | "SH" | "SWL" | "SW" | "SWR" => {
+            format!("{}, {}", rt, memory_operand)
+        }
+        //Three register ALU ops
+        _ => format!("{}, {}, {}", rd, rs, rt),
+    };
+
+    if operands.is_empty() {
+        mnemonic
+    } else {
+        format!("{} {}", mnemonic, operands)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        let cases: [(u32, u32, &str); 15] = [
+            (0x2528_0010, 0x8001_0000, "addiu $t0, $t1, 0x10"),
+            (0x1485_0003, 0x8004_1224, "bne $a0, $a1, 0x8004_1234"),
+            (0x0000_0000, 0x0, "nop"),
+            (0x0004_2080, 0x0, "sll $a0, $a0, 2"),
+            (0x03E0_0008, 0x0, "jr $ra"),
+            (0x0C00_0C00, 0xBFC0_0000, "jal 0xb000_3000"),
+            (0x8FBF_0014, 0x0, "lw $ra, 0x14($sp)"),
+            (0xAFA4_FFF8, 0x0, "sw $a0, -0x8($sp)"),
+            (0x3C01_1F80, 0x0, "lui $at, 0x1f80"),
+            (0x0085_1021, 0x0, "addu $v0, $a0, $a1"),
+            (0x4082_6000, 0x0, "mtc0 $v0, $12"),
+            (0x4042_6000, 0x0, "cfc0 $v0, $12"),
+            (0x4200_0010, 0x0, "rfe"),
+            (0x4A18_0001, 0x0, "cop2 0x180001"),
+            (0xFC00_0000, 0x0, "illegal 0xfc000000"),
+        ];
+        for (instruction, pc, expected) in cases.iter() {
+            assert_eq!(disassemble(*instruction, *pc), *expected);
+        }
+    }
+}
@@ -1,7 +1,15 @@
 use bit_field::BitField;
 use fixed::types::{I16F16, I20F12, I28F4, I4F12, I8F24, I8F8};
-use log::error;
+use log::{error, warn};
+use std::sync::Once;
 
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+const LOG_TARGET: &str = "vaporstation::gte";
+//Games run NCDS all the time, so the stub only says so once
+static NCDS_STUB_WARNING: Once = Once::new();
+
+#[derive(Clone, Copy)]
 struct Color {
     pub r: u8,
     pub g: u8,
@@ -25,8 +33,14 @@ impl Color {
         self.b = ((val >> 16) & 0xFF) as u8;
         self.c = ((val >> 24) & 0xFF) as u8;
     }
+
+    fn word(&self) -> u32 {
+        (self.r as u32) | (self.g as u32) << 8 | (self.b as u32) << 16 | (self.c as u32) << 24
+    }
 }
 
+//Fields are named after the registers in psx-spx, so they're easy to check against it
+#[allow(non_snake_case)]
 pub(super) struct GTE {
     // Control Registers
     ZSF3: i16,
@@ -74,7 +88,6 @@ pub(super) struct GTE {
     TRZ: i32,
     FLAG: u32,
     LZCS: i32,
-    LZCR: i32,
 
     // Data registers
     VX0: i16,
@@ -90,6 +103,7 @@ pub(super) struct GTE {
     IR1: i16,
     IR2: i16,
     IR3: i16,
+    OTZ: u16,
     MAC0: i32,
     MAC1: i32,
     MAC2: i32,
@@ -98,13 +112,17 @@ pub(super) struct GTE {
     SZ1: u16,
     SZ2: u16,
     SZ3: u16,
-    SX0: u16,
-    SX1: u16,
-    SX2: u16,
-    SY0: u16,
-    SY1: u16,
-    SY2: u16,
+    SX0: i16,
+    SX1: i16,
+    SX2: i16,
+    SY0: i16,
+    SY1: i16,
+    SY2: i16,
     RGB: Color,
+    RGB0: Color,
+    RGB1: Color,
+    RGB2: Color,
+    RES1: u32,
 }
 
 // Interface
@@ -157,7 +175,6 @@ impl GTE {
             TRZ: 0,
             FLAG: 0,
             LZCS: 0,
-            LZCR: 0,
 
             // Data Registers
             VX0: 0,
@@ -173,6 +190,7 @@ impl GTE {
             IR1: 0,
             IR2: 0,
             IR3: 0,
+            OTZ: 0,
             MAC0: 0,
             MAC1: 0,
             MAC2: 0,
@@ -188,6 +206,10 @@ impl GTE {
             SY1: 0,
             SY2: 0,
             RGB: Color::new(),
+            RGB0: Color::new(),
+            RGB1: Color::new(),
+            RGB2: Color::new(),
+            RES1: 0,
         }
     }
 
@@ -260,7 +282,8 @@ impl GTE {
             28 => {self.DQB = val as i32},
             29 => {self.ZSF3 = val as i16},
             30 => {self.ZSF4 = val as i16},
-            _ => error!("Tried to write unknown GTE control register {} ({} RAW)", ctrl_reg_name[reg], reg)
+            31 => {self.FLAG = val & 0x7FFF_F000},
+            _ => error!(target: LOG_TARGET, "Tried to write unknown GTE control register {} ({} RAW)", ctrl_reg_name[reg], reg)
         }
     }
 
@@ -282,46 +305,162 @@ impl GTE {
             },
             5 => {self.VZ2 = val as i16},
             6 => self.RGB.set_word(val),
+            7 => self.OTZ = val as u16,
             8 => self.IR0 = val as i16,
             9 => {self.IR1 = val as i16},
             10 => {self.IR2 = val as i16},
             11 => {self.IR3 = val as i16},
+            12 => {
+                self.SX0 = val as i16;
+                self.SY0 = (val >> 16) as i16;
+            },
+            13 => {
+                self.SX1 = val as i16;
+                self.SY1 = (val >> 16) as i16;
+            },
+            14 => {
+                self.SX2 = val as i16;
+                self.SY2 = (val >> 16) as i16;
+            },
+            15 => {
+                //Writing SXYP pushes onto the FIFO
+                self.push_sx(val as i16);
+                self.push_sy((val >> 16) as i16);
+            },
+            16 => self.SZ0 = val as u16,
+            17 => self.SZ1 = val as u16,
+            18 => self.SZ2 = val as u16,
+            19 => self.SZ3 = val as u16,
+            20 => self.RGB0.set_word(val),
+            21 => self.RGB1.set_word(val),
+            22 => self.RGB2.set_word(val),
+            23 => self.RES1 = val,
+            24 => self.MAC0 = val as i32,
+            25 => self.MAC1 = val as i32,
+            26 => self.MAC2 = val as i32,
+            27 => self.MAC3 = val as i32,
+            28 => {
+                self.IR1 = ((val & 0x1F) * 0x80) as i16;
+                self.IR2 = (((val >> 5) & 0x1F) * 0x80) as i16;
+                self.IR3 = (((val >> 10) & 0x1F) * 0x80) as i16;
+            },
+            29 | 31 => (), //Read only
             30 => self.LZCS = val as i32,
-            _ => error!("Tried to write unknown GTE data register {} ({} RAW)", data_reg_name[reg], reg)
+            _ => error!(target: LOG_TARGET, "Tried to write unknown GTE data register {} ({} RAW)", data_reg_name[reg], reg)
         }
     }
 
     pub(super) fn data_register(&self, reg: usize) -> u32 {
         match reg {
-            0 => ((self.VY0 as u32) << 16 & self.VX0 as u32),
+            0 => ((self.VY0 as u16 as u32) << 16) | self.VX0 as u16 as u32,
             1 => self.VZ0 as u32,
-            2 => ((self.VY1 as u32) << 16 & self.VX1 as u32),
+            2 => ((self.VY1 as u16 as u32) << 16) | self.VX1 as u16 as u32,
             3 => self.VZ1 as u32,
-            4 => ((self.VY2 as u32) << 16 & self.VX2 as u32),
+            4 => ((self.VY2 as u16 as u32) << 16) | self.VX2 as u16 as u32,
             5 => self.VZ2 as u32,
+            6 => self.RGB.word(),
+            7 => self.OTZ as u32,
+            8 => self.IR0 as u32,
             9 => self.IR1 as u32,
             10 => self.IR2 as u32,
             11 => self.IR3 as u32,
+            12 => screen_xy(self.SX0, self.SY0),
+            13 => screen_xy(self.SX1, self.SY1),
+            14 | 15 => screen_xy(self.SX2, self.SY2),
+            16 => self.SZ0 as u32,
+            17 => self.SZ1 as u32,
+            18 => self.SZ2 as u32,
+            19 => self.SZ3 as u32,
+            20 => self.RGB0.word(),
+            21 => self.RGB1.word(),
+            22 => self.RGB2.word(),
+            23 => self.RES1,
             24 => self.MAC0 as u32,
+            25 => self.MAC1 as u32,
+            26 => self.MAC2 as u32,
+            27 => self.MAC3 as u32,
+            28 | 29 => self.orgb(),
+            30 => self.LZCS as u32,
             31 => self.lzcr(),
-            _ => {error!("Tried to read unknown GTE data register {} ({} RAW)", data_reg_name[reg], reg); 0}
+            _ => {error!(target: LOG_TARGET, "Tried to read unknown GTE data register {} ({} RAW)", data_reg_name[reg], reg); 0}
         }
     }
 
     pub(super) fn control_register(&self, reg: usize) -> u32 {
         match reg {
-            31 => self.FLAG,
-            _ => {error!("Tried to read unknown GTE control register {} ({} RAW)", ctrl_reg_name[reg], reg); 0}
+            0 => halfwords(self.RT11, self.RT12),
+            1 => halfwords(self.RT13, self.RT21),
+            2 => halfwords(self.RT22, self.RT23),
+            3 => halfwords(self.RT31, self.RT32),
+            4 => self.RT33 as u32,
+            5 => self.TRX as u32,
+            6 => self.TRY as u32,
+            7 => self.TRZ as u32,
+            8 => halfwords(self.L11, self.L12),
+            9 => halfwords(self.L13, self.L21),
+            10 => halfwords(self.L22, self.L23),
+            11 => halfwords(self.L31, self.L32),
+            12 => self.L33 as u32,
+            13 => self.RBK as u32,
+            14 => self.GBK as u32,
+            15 => self.BBK as u32,
+            16 => halfwords(self.LR1, self.LR2),
+            17 => halfwords(self.LR3, self.LG1),
+            18 => halfwords(self.LG2, self.LG3),
+            19 => halfwords(self.LB1, self.LB2),
+            20 => self.LB3 as u32,
+            21 => self.RFC as u32,
+            22 => self.GFC as u32,
+            23 => self.BFC as u32,
+            24 => self.OFX as u32,
+            25 => self.OFY as u32,
+            26 => self.H as i16 as u32, //Hardware bug, H is sign extended on read
+            27 => self.DQA as u32,
+            28 => self.DQB as u32,
+            29 => self.ZSF3 as u32,
+            30 => self.ZSF4 as u32,
+            31 => self.flag(),
+            _ => {error!(target: LOG_TARGET, "Tried to read unknown GTE control register {} ({} RAW)", ctrl_reg_name[reg], reg); 0}
+        }
+    }
+
+    /// Goes through the register interface, so the state is exactly what a program could save and restore itself
+    pub(super) fn save_state(&self, writer: &mut StateWriter) {
+        for reg in 0..32 {
+            writer.write_u32(self.control_register(reg));
+        }
+        for reg in 0..32 {
+            writer.write_u32(self.data_register(reg));
         }
     }
 
+    pub(super) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        for reg in 0..32 {
+            let val = reader.read_u32()?;
+            self.set_control_register(reg, val);
+        }
+        for reg in 0..32 {
+            let val = reader.read_u32()?;
+            //SXYP would push the FIFO and IRGB would overwrite IR1-3. Both are mirrors of registers restored on their own
+            if !matches!(reg, 15 | 28 | 29 | 31) {
+                self.set_data_register(reg, val);
+            }
+        }
+        Ok(())
+    }
+
     pub(super) fn execute_command(&mut self, command: u32) {
         self.FLAG = 0; // Reset calculation error flags
         match command & 0x3F {
+            0x1 => self.rtps(command),
             0x6 => self.nclip(),
             0x13 => self.ncds(),
+            0x1E => self.ncs(command),
+            0x20 => self.nct(command),
+            0x2D => self.avsz3(),
+            0x2E => self.avsz4(),
             0x30 => self.rtpt(command),
-            _ => error!("Unknown GTE command {:#X}!", command & 0x3F)
+            _ => error!(target: LOG_TARGET, "Unknown GTE command {:#X}!", command & 0x3F)
         };
     }
 }
@@ -335,13 +474,13 @@ impl GTE {
        self.SZ3 = val;
    }
 
-   fn push_sx(&mut self, val: u16) {
+   fn push_sx(&mut self, val: i16) {
     self.SX0 = self.SX1;
     self.SX1 = self.SX2;
     self.SX2 = val;
    }
 
-   fn push_sy(&mut self, val: u16) {
+   fn push_sy(&mut self, val: i16) {
     self.SY0 = self.SY1;
     self.SY1 = self.SY2;
     self.SY2 = val;
@@ -354,40 +493,243 @@ impl GTE {
            self.LZCS.leading_ones()
        }
    }
+
+   /// Stores the shifted result in MAC1-3, flagging the unshifted value if it doesn't fit in 44 bits.
+   /// Returns the shifted, untruncated value
+   fn set_mac(&mut self, index: usize, val: i64, shift: usize) -> i64 {
+       if val >= 1 << 43 {
+           self.FLAG.set_bit(31 - index, true);
+       } else if val < -(1 << 43) {
+           self.FLAG.set_bit(28 - index, true);
+       }
+       let val = val >> shift;
+       match index {
+           1 => self.MAC1 = val as i32,
+           2 => self.MAC2 = val as i32,
+           _ => self.MAC3 = val as i32,
+       }
+       val
+   }
+
+   /// Clamps a MAC value into IR1-3 range, which is 0..=0x7FFF when lm is set
+   fn saturate_ir(&mut self, index: usize, val: i64, lm: bool) -> i16 {
+       let min = if lm { 0 } else { -0x8000 };
+       if val < min || val > 0x7FFF {
+           self.FLAG.set_bit(25 - index, true);
+       }
+       val.clamp(min, 0x7FFF) as i16
+   }
+
+   /// Clamps a screen coordinate to -0x400..=0x3FF, setting the given flag bit if it was out of range
+   fn saturate_screen(&mut self, flag_bit: usize, val: i64) -> i16 {
+       if !(-0x400..=0x3FF).contains(&val) {
+           self.FLAG.set_bit(flag_bit, true);
+       }
+       val.clamp(-0x400, 0x3FF) as i16
+   }
+
+   /// FLAG with the error summary bit 31 set from bits 30-23 and 18-13
+   fn flag(&self) -> u32 {
+       let error = self.FLAG & 0x7F87_E000 != 0;
+       (self.FLAG & 0x7FFF_F000) | (error as u32) << 31
+   }
+
+   /// Stores the result in MAC0, flagging it if it doesn't fit in 32 bits. Returns the untruncated value
+   fn set_mac0(&mut self, val: i64) -> i64 {
+       if val > i32::MAX as i64 {
+           self.FLAG.set_bit(16, true);
+       } else if val < i32::MIN as i64 {
+           self.FLAG.set_bit(15, true);
+       }
+       self.MAC0 = val as i32;
+       val
+   }
+
+   fn set_otz(&mut self, val: i64) {
+       if !(0..=0xFFFF).contains(&val) {
+           self.FLAG.set_bit(18, true);
+       }
+       self.OTZ = val.clamp(0, 0xFFFF) as u16;
+   }
+
+   /// Pushes a color onto the RGB fifo, clamping each component to 0..=0xFF. The code byte comes from RGBC
+   fn push_color(&mut self, r: i64, g: i64, b: i64) {
+       let mut saturate = |flag_bit: usize, val: i64| {
+           if !(0..=0xFF).contains(&val) {
+               self.FLAG.set_bit(flag_bit, true);
+           }
+           val.clamp(0, 0xFF) as u8
+       };
+       let color = Color {
+           r: saturate(21, r),
+           g: saturate(20, g),
+           b: saturate(19, b),
+           c: self.RGB.c,
+       };
+       self.RGB0 = self.RGB1;
+       self.RGB1 = self.RGB2;
+       self.RGB2 = color;
+   }
+
+   fn orgb(&self) -> u32 {
+       let component = |ir: i16| ((ir / 0x80).clamp(0, 0x1F)) as u32;
+       component(self.IR1) | component(self.IR2) << 5 | component(self.IR3) << 10
+   }
 }
 
 // Internal GTE commands
 impl GTE {
+    fn rtps(&mut self, command: u32) {
+        self.rtp(self.VX0, self.VY0, self.VZ0, command.get_bit(19), command.get_bit(10), true);
+    }
+
+    /// Perspective transformation of a single vertex. Results are pushed onto the SXY and SZ fifos
+    fn rtp(&mut self, vx: i16, vy: i16, vz: i16, sf: bool, lm: bool, depth_cue: bool) {
+        let shift = if sf { 12 } else { 0 };
+        let (vx, vy, vz) = (vx as i64, vy as i64, vz as i64);
+
+        let mac1 = self.set_mac(1, (self.TRX as i64) * 0x1000 + self.RT11 as i64 * vx + self.RT12 as i64 * vy + self.RT13 as i64 * vz, shift);
+        let mac2 = self.set_mac(2, (self.TRY as i64) * 0x1000 + self.RT21 as i64 * vx + self.RT22 as i64 * vy + self.RT23 as i64 * vz, shift);
+        let mac3 = self.set_mac(3, (self.TRZ as i64) * 0x1000 + self.RT31 as i64 * vx + self.RT32 as i64 * vy + self.RT33 as i64 * vz, shift);
+        self.IR1 = self.saturate_ir(1, mac1, lm);
+        self.IR2 = self.saturate_ir(2, mac2, lm);
+        self.IR3 = self.saturate_ir(3, mac3, lm);
+
+        let sz = mac3 >> (12 - shift);
+        if !(0..=0xFFFF).contains(&sz) {
+            self.FLAG.set_bit(18, true);
+        }
+        self.push_sz(sz.clamp(0, 0xFFFF) as u16);
+
+        let n = self.divide() as i64;
+        let sx = self.set_mac0(n * self.IR1 as i64 + self.OFX as i64) >> 16;
+        let sy = self.set_mac0(n * self.IR2 as i64 + self.OFY as i64) >> 16;
+        let sx = self.saturate_screen(14, sx);
+        let sy = self.saturate_screen(13, sy);
+        self.push_sx(sx);
+        self.push_sy(sy);
+
+        if depth_cue {
+            let depth = self.set_mac0(n * self.DQA as i64 + self.DQB as i64) >> 12;
+            if !(0..=0x1000).contains(&depth) {
+                self.FLAG.set_bit(12, true);
+            }
+            self.IR0 = depth.clamp(0, 0x1000) as i16;
+        }
+    }
+
+    /// Unsigned Newton-Raphson division of H by SZ3 used by the perspective transformation.
+    /// Returns the quotient as a 1.16 fixed point value
+    fn divide(&mut self) -> u32 {
+        let h = self.H as u32;
+        let sz3 = self.SZ3 as u32;
+        if h >= sz3 * 2 {
+            self.FLAG.set_bit(17, true);
+            return 0x1FFFF;
+        }
+
+        let z = (sz3 as u16).leading_zeros();
+        let n = (h << z) as u64;
+        let d = (sz3 << z) as u64;
+        let u = UNR_TABLE[((d - 0x7FC0) >> 7) as usize] as u64 + 0x101;
+        let d = (0x2000080 - d * u) >> 8;
+        let d = (0x0000080 + d * u) >> 8;
+        ((n * d + 0x8000) >> 16).min(0x1FFFF) as u32
+    }
+
     fn rtpt(&mut self, command: u32) {
-        // self.MAC1 = (self.TRX * 0x1000 + (self.RT11*self.VX0 + self.RT12*self.VY0 + self.RT13*self.VZ0) as i32) >> ((command.get_bit(19) as usize) * 12);
-        // self.MAC2 = (self.TRY * 0x1000 + (self.RT21*self.VX0 + self.RT22*self.VY0 + self.RT23*self.VZ0) as i32) >> ((command.get_bit(19) as usize) * 12);
-        // self.MAC3 = (self.TRZ * 0x1000 + (self.RT31*self.VX0 + self.RT32*self.VY0 + self.RT33*self.VZ0) as i32) >> ((command.get_bit(19) as usize) * 12);
-        // self.IR1 = self.MAC1 as i16;
-        // self.IR2 = self.MAC2 as i16;
-        // self.IR3 = self.MAC3 as i16;
-        // self.push_sz((self.MAC3 >> ((!command.get_bit(19) as usize) * 12)) as u16);
-
-        // let mut div_val = (((self.H as u32*0x20000/self.SZ3 as u32)+1)/2);
-        // if div_val > 0x1FFFF {
-        //     div_val = 0x1FFFF;
-        //     self.FLAG.set_bit(17, true);
-        // }
-        // self.push_sx(((div_val * self.IR1 as u32 + self.OFX as u32) / 0x10000) as u16);
-        // self.push_sy(((div_val * self.IR2 as u32 + self.OFY as u32) / 0x10000) as u16);
-        // self.IR0 = ((div_val * self.IR1 as u32 + self.OFX as u32) / 0x10000) as i16;
-        // self.MAC0 = (div_val * self.IR1 as u32 + self.OFX as u32) as i32;
+        let sf = command.get_bit(19);
+        let lm = command.get_bit(10);
+        self.rtp(self.VX0, self.VY0, self.VZ0, sf, lm, false);
+        self.rtp(self.VX1, self.VY1, self.VZ1, sf, lm, false);
+        self.rtp(self.VX2, self.VY2, self.VZ2, sf, lm, true);
+    }
+
+    /// Average of the three most recent screen z values, used as the ordering table index
+    fn avsz3(&mut self) {
+        let sum = self.SZ1 as i64 + self.SZ2 as i64 + self.SZ3 as i64;
+        let mac0 = self.set_mac0(self.ZSF3 as i64 * sum);
+        self.set_otz(mac0 >> 12);
     }
 
+    /// Average of all four screen z values, used for quads
+    fn avsz4(&mut self) {
+        let sum = self.SZ0 as i64 + self.SZ1 as i64 + self.SZ2 as i64 + self.SZ3 as i64;
+        let mac0 = self.set_mac0(self.ZSF4 as i64 * sum);
+        self.set_otz(mac0 >> 12);
+    }
+
+    /// Cross product of the screen xy fifo. The sign gives the winding order of the triangle, used for backface culling
     fn nclip(&mut self) {
-        println!("GTE NCLIP mostly stubbed");
-        self.MAC0 = (self.SX0 * self.SY1 + self.SX1 * self.SY2 + self.SX2 * self.SY0 - self.SX0 * self.SY2 - self.SX1 * self.SY0 - self.SX2 * self.SY1) as i32;
+        let (sx0, sx1, sx2) = (self.SX0 as i64, self.SX1 as i64, self.SX2 as i64);
+        let (sy0, sy1, sy2) = (self.SY0 as i64, self.SY1 as i64, self.SY2 as i64);
+        self.set_mac0(sx0 * sy1 + sx1 * sy2 + sx2 * sy0 - sx0 * sy2 - sx1 * sy0 - sx2 * sy1);
+    }
+
+    /// Lights a normal with the light matrix, then colors it with the background color and light color matrix.
+    /// Leaves the result in MAC1-3 and IR1-3
+    fn light_normal(&mut self, vx: i16, vy: i16, vz: i16, sf: bool, lm: bool) {
+        let shift = if sf { 12 } else { 0 };
+        let (vx, vy, vz) = (vx as i64, vy as i64, vz as i64);
+
+        let mac1 = self.set_mac(1, self.L11 as i64 * vx + self.L12 as i64 * vy + self.L13 as i64 * vz, shift);
+        let mac2 = self.set_mac(2, self.L21 as i64 * vx + self.L22 as i64 * vy + self.L23 as i64 * vz, shift);
+        let mac3 = self.set_mac(3, self.L31 as i64 * vx + self.L32 as i64 * vy + self.L33 as i64 * vz, shift);
+        self.IR1 = self.saturate_ir(1, mac1, lm);
+        self.IR2 = self.saturate_ir(2, mac2, lm);
+        self.IR3 = self.saturate_ir(3, mac3, lm);
+
+        let (ir1, ir2, ir3) = (self.IR1 as i64, self.IR2 as i64, self.IR3 as i64);
+        let mac1 = self.set_mac(1, (self.RBK as i64) * 0x1000 + self.LR1 as i64 * ir1 + self.LR2 as i64 * ir2 + self.LR3 as i64 * ir3, shift);
+        let mac2 = self.set_mac(2, (self.GBK as i64) * 0x1000 + self.LG1 as i64 * ir1 + self.LG2 as i64 * ir2 + self.LG3 as i64 * ir3, shift);
+        let mac3 = self.set_mac(3, (self.BBK as i64) * 0x1000 + self.LB1 as i64 * ir1 + self.LB2 as i64 * ir2 + self.LB3 as i64 * ir3, shift);
+        self.IR1 = self.saturate_ir(1, mac1, lm);
+        self.IR2 = self.saturate_ir(2, mac2, lm);
+        self.IR3 = self.saturate_ir(3, mac3, lm);
+    }
+
+    /// Normal color without depth cueing. The lit color goes straight into the color fifo
+    fn normal_color(&mut self, vx: i16, vy: i16, vz: i16, sf: bool, lm: bool) {
+        self.light_normal(vx, vy, vz, sf, lm);
+        self.push_color(self.MAC1 as i64 >> 4, self.MAC2 as i64 >> 4, self.MAC3 as i64 >> 4);
+    }
+
+    fn ncs(&mut self, command: u32) {
+        self.normal_color(self.VX0, self.VY0, self.VZ0, command.get_bit(19), command.get_bit(10));
+    }
+
+    fn nct(&mut self, command: u32) {
+        let sf = command.get_bit(19);
+        let lm = command.get_bit(10);
+        self.normal_color(self.VX0, self.VY0, self.VZ0, sf, lm);
+        self.normal_color(self.VX1, self.VY1, self.VZ1, sf, lm);
+        self.normal_color(self.VX2, self.VY2, self.VZ2, sf, lm);
     }
 
     fn ncds(&mut self) {
-        println!("GTE NCDS stubbed");
+        NCDS_STUB_WARNING.call_once(|| warn!(target: LOG_TARGET, "GTE NCDS stubbed"));
     }
 }
 
+fn halfwords(low: i16, high: i16) -> u32 {
+    (low as u16 as u32) | (high as u16 as u32) << 16
+}
+
+fn screen_xy(x: i16, y: i16) -> u32 {
+    halfwords(x, y)
+}
+
+/// Reciprocal table used by the GTE division
+const UNR_TABLE: [u8; 0x101] = {
+    let mut table = [0; 0x101];
+    let mut i = 0;
+    while i < 0x101 {
+        let val = (0x40000 / (i as i32 + 0x100) + 1) / 2 - 0x101;
+        table[i] = if val > 0 { val as u8 } else { 0 };
+        i += 1;
+    }
+    table
+};
 
 const data_reg_name: [&str; 32] = [
     "vxy0", "vz0",  "vxy1", "vz1",  "vxy2", "vz2",  "rgb",  "otz",   // 00
@@ -401,4 +743,159 @@ const ctrl_reg_name: [&str; 32] = [
     "l11l12", "l13l21", "l22l23", "l31l32", "l33", "rbk",  "gbk",  "bbk",   // 08
     "lr1lr2", "lr3lg1", "lg2lg3", "lb1lb2", "lb3", "rfc",  "gfc",  "bfc",   // 10
     "ofx",    "ofy",    "h",      "dqa",    "dqb", "zsf3", "zsf4", "flag",  // 18
-];
\ No newline at end of file
+];
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtps() {
+        let mut gte = GTE::new();
+        //Identity rotation, translated 0x200 into the screen
+        gte.set_control_register(0, 0x1000);
+        gte.set_control_register(2, 0x1000);
+        gte.set_control_register(4, 0x1000);
+        gte.set_control_register(7, 0x200);
+        gte.set_control_register(24, 160 << 16); //OFX
+        gte.set_control_register(25, 120 << 16); //OFY
+        gte.set_control_register(26, 0x100); //H
+
+        gte.set_data_register(0, (0xFFC0 << 16) | 0x0080); //VX 0x80, VY -0x40
+        gte.set_data_register(1, 0);
+        gte.execute_command(0x0008_0001); //RTPS sf=1
+
+        assert_eq!(gte.data_register(19), 0x200);
+        //Screen distance is half of z, so the vertex is projected at half size around the offset
+        assert_eq!(gte.data_register(14), screen_xy(160 + 0x40, 120 - 0x20));
+        assert_eq!(gte.data_register(15), gte.data_register(14));
+        assert_eq!(gte.control_register(31) & (1 << 17), 0);
+    }
+
+    #[test]
+    fn test_rtpt_sz_fifo() {
+        let mut gte = GTE::new();
+        gte.set_control_register(0, 0x1000);
+        gte.set_control_register(2, 0x1000);
+        gte.set_control_register(4, 0x1000);
+        gte.set_control_register(26, 0x100);
+        gte.set_control_register(29, 0x555); //ZSF3, roughly 1/3
+
+        gte.set_data_register(19, 0x40); //Left over from an earlier transform
+        gte.set_data_register(1, 0x300);
+        gte.set_data_register(3, 0x600);
+        gte.set_data_register(5, 0x900);
+        gte.execute_command(0x0008_0030); //RTPT sf=1
+
+        //The oldest value is pushed out to SZ0 and the three new ones fill SZ1-3
+        assert_eq!(gte.data_register(16), 0x40);
+        assert_eq!(gte.data_register(17), 0x300);
+        assert_eq!(gte.data_register(18), 0x600);
+        assert_eq!(gte.data_register(19), 0x900);
+
+        gte.execute_command(0x2D); //AVSZ3
+        assert_eq!(gte.data_register(24), 0x555 * 0x1200);
+        assert_eq!(gte.data_register(7), (0x555 * 0x1200) >> 12);
+    }
+
+    #[test]
+    fn test_nclip_winding() {
+        let mut gte = GTE::new();
+        gte.set_data_register(12, screen_xy(0, 0));
+        gte.set_data_register(13, screen_xy(100, 0));
+        gte.set_data_register(14, screen_xy(0, 100));
+        gte.execute_command(0x06);
+        assert!((gte.data_register(24) as i32) > 0);
+
+        gte.set_data_register(13, screen_xy(0, 100));
+        gte.set_data_register(14, screen_xy(100, 0));
+        gte.execute_command(0x06);
+        assert!((gte.data_register(24) as i32) < 0);
+        assert_eq!(gte.control_register(31), 0);
+    }
+
+    #[test]
+    fn test_avsz4_mac0_overflow() {
+        let mut gte = GTE::new();
+        gte.set_control_register(30, 0x7FFF); //ZSF4
+        for reg in 16..20 {
+            gte.set_data_register(reg, 0xFFFF);
+        }
+        gte.execute_command(0x2E);
+        //0x7FFF * 0x3FFFC doesn't fit in MAC0, and OTZ saturates
+        assert_ne!(gte.control_register(31) & (1 << 16), 0);
+        assert_ne!(gte.control_register(31) & (1 << 18), 0);
+        assert_eq!(gte.data_register(7), 0xFFFF);
+    }
+
+    /// Identity light matrix and light color matrix, so a normal's x/y/z light red/green/blue
+    fn identity_lighting() -> GTE {
+        let mut gte = GTE::new();
+        gte.set_control_register(8, 0x1000); //L11
+        gte.set_control_register(10, 0x1000); //L22
+        gte.set_control_register(12, 0x1000); //L33
+        gte.set_control_register(16, 0x1000); //LR1
+        gte.set_control_register(18, 0x1000); //LG2
+        gte.set_control_register(20, 0x1000); //LB3
+        gte.set_data_register(6, 0x3000_0000); //Code 0x30
+        gte
+    }
+
+    #[test]
+    fn test_ncs() {
+        let mut gte = identity_lighting();
+        gte.set_control_register(13, 0x10); //RBK
+        gte.set_data_register(0, (0x400 << 16) | 0x800); //VX 0x800, VY 0x400
+        gte.set_data_register(1, 0x200);
+        gte.execute_command(0x0008_041E); //NCS sf=1 lm=1
+
+        assert_eq!(gte.data_register(25), 0x810);
+        assert_eq!(gte.data_register(9), 0x810);
+        assert_eq!(gte.data_register(22), 0x3020_4081);
+        assert_eq!(gte.control_register(31), 0);
+
+        //Normals facing away from the light clamp to zero with lm set, leaving only the background color
+        gte.set_data_register(0, 0xF800); //VX -0x800
+        gte.execute_command(0x0008_041E);
+        assert_eq!(gte.data_register(9), 0x10);
+        assert_eq!(gte.data_register(22) & 0xFF, 0x01);
+        assert_eq!(gte.data_register(21), 0x3020_4081);
+    }
+
+    #[test]
+    fn test_nct_color_fifo() {
+        let mut gte = identity_lighting();
+        gte.set_data_register(0, 0x0FF0); //VX 0xFF0
+        gte.set_data_register(3, 0x0FF0); //VZ1 0xFF0
+        gte.set_data_register(4, 0x0FF0_0000); //VY2 0xFF0
+        gte.execute_command(0x0008_0420); //NCT sf=1 lm=1
+
+        assert_eq!(gte.data_register(20), 0x3000_00FF);
+        assert_eq!(gte.data_register(21), 0x30FF_0000);
+        assert_eq!(gte.data_register(22), 0x3000_FF00);
+
+        //Brighter than 0xFF saturates and flags the color
+        gte.set_data_register(0, 0x2000);
+        gte.execute_command(0x0008_0420);
+        assert_eq!(gte.data_register(20), 0x3000_00FF);
+        assert_ne!(gte.control_register(31) & (1 << 21), 0);
+    }
+
+    #[test]
+    fn test_ir_saturation_flag() {
+        let mut gte = GTE::new();
+        gte.set_control_register(0, 0x7FFF); //RT11
+        gte.set_control_register(4, 0x1000);
+        gte.set_control_register(7, 0x1000);
+        gte.set_data_register(0, 0x7FFF); //VX
+        gte.execute_command(0x0008_0001); //RTPS sf=1
+
+        assert_eq!(gte.data_register(9), 0x7FFF);
+        let flag = gte.control_register(31);
+        assert_ne!(flag & (1 << 24), 0);
+        assert_ne!(flag & (1 << 31), 0);
+
+        //The next command starts with a clean FLAG
+        gte.execute_command(0x2D);
+        assert_eq!(gte.control_register(31), 0);
+    }
+}
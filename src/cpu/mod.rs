@@ -2,16 +2,32 @@ use bit_field::BitField;
 
 use cop0::Cop0;
 use instruction::{Instruction, NumberHelpers};
-use log::trace;
+use log::{debug, info, trace};
 
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
 use crate::timer::TimerState;
-use crate::{bus::MainBus, cdrom};
+use crate::{bus::{is_scratchpad, physical_address, BusAccess, MainBus}, cdrom};
 
 use self::gte::GTE;
 
+const LOG_TARGET: &str = "vaporstation::cpu";
+
+/// Cycles every instruction takes before any memory wait states
+pub const INSTRUCTION_CYCLES: u32 = 1;
+
+//Multiply/divide unit latency. Multiplies finish sooner when rs is small
+const MULT_CYCLES_SMALL: u32 = 6;
+const MULT_CYCLES_MEDIUM: u32 = 9;
+const MULT_CYCLES_LARGE: u32 = 13;
+const DIV_CYCLES: u32 = 36;
+
 mod cop0;
+pub mod disasm;
 mod instruction;
 mod gte;
+mod opcodes;
+
+pub use opcodes::{decode, implemented_opcodes, OpcodeClass, OpcodeInfo};
 
 #[derive(Debug, Clone, Copy)]
 pub enum InterruptSource {
@@ -45,24 +61,43 @@ pub enum Exception {
     Int = 0,  //Interrupt
 }
 
+/// A call into one of the bios function tables, caught as the cpu jumped to its vector
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BiosCall {
+    /// 0xA0, 0xB0 or 0xC0
+    pub vector: u32,
+    /// Function number, passed in $t1
+    pub function: u32,
+    /// $a0-$a3
+    pub args: [u32; 4],
+}
+
 #[derive(Debug)]
 struct LoadDelay {
     register: u8,
     value: u32,
-    cycle_loaded: u32,
 }
 
 pub struct R3000 {
     pub gen_registers: [u32; 32],
     cycle_count: u32,
+    access_cycles: u32,
     pub pc: u32,
     current_pc: u32,
     pub hi: u32,
     pub lo: u32,
+    //Cycles until the multiply/divide unit has its result in hi and lo
+    mul_div_busy: u32,
     pub main_bus: MainBus,
     delay_slot: u32,
+    //Address of the branch the delay slot belongs to, which is where EPC points if the delay slot faults
+    branch_pc: u32,
     pub cop0: Cop0,
     load_delays: Vec<LoadDelay>,
+    //Loads from the previous instruction that land once the current one finishes
+    in_flight_loads: Vec<LoadDelay>,
+    //Registers written by the instruction currently executing, as a bitmask
+    written_registers: u32,
     i_mask: u32,
     pub i_status: u32,
     pub log: bool,
@@ -70,7 +105,10 @@ pub struct R3000 {
     exec_delay: bool,
     last_was_branch: bool,
     gte: GTE,
-    pub last_touched_addr: u32,
+    /// Physical address of the last data read/write made by the previous step, for watchpoints
+    pub last_read_addr: Option<u32>,
+    pub last_write_addr: Option<u32>,
+    bios_trace: Option<Vec<BiosCall>>,
 }
 
 impl R3000 {
@@ -78,14 +116,19 @@ impl R3000 {
         R3000 {
             gen_registers: [0; 32],
             cycle_count: 0,
+            access_cycles: 0,
             pc: 0,
             current_pc: 0,
             hi: 0,
             lo: 0,
+            mul_div_busy: 0,
             main_bus: bus,
             delay_slot: 0,
+            branch_pc: 0,
             cop0: Cop0::new(),
             load_delays: Vec::new(),
+            in_flight_loads: Vec::new(),
+            written_registers: 0,
             i_mask: 0,
             i_status: 0,
             log: false,
@@ -93,7 +136,9 @@ impl R3000 {
             exec_delay: false,
             last_was_branch: false,
             gte: GTE::new(),
-            last_touched_addr: 0,
+            last_read_addr: None,
+            last_write_addr: None,
+            bios_trace: None,
         }
     }
     /// Resets cpu registers to zero and sets program counter to reset vector (0xBFC00000)
@@ -104,9 +149,10 @@ impl R3000 {
         }
         self.hi = 0;
         self.lo = 0;
+        self.mul_div_busy = 0;
         self.pc = 0xBFC00000; // Points to the bios entry point
         self.cop0
-            .write_reg(12, self.cop0.read_reg(12).set_bit(23, true).clone());
+            .write_reg(12, self.cop0.read_reg(12).set_bit(22, true).clone()); //BEV
         self.load_delays = Vec::new();
     }
 
@@ -120,22 +166,32 @@ impl R3000 {
         self.print_string(addr + 1);
     }
 
-    pub fn step_instruction(&mut self, timers: &mut TimerState) {
+    /// Executes the next instruction, along with its delay slot if it was a branch. A branch in that delay slot
+    /// leaves its own delay slot (the first branch's target) pending, which is all the next step runs.
+    /// Returns the number of cycles that took, including memory wait states
+    pub fn step_instruction(&mut self, timers: &mut TimerState) -> u32 {
+        self.access_cycles = 0;
         //Fast load exe
 
         if self.load_exe && self.pc == 0xbfc0700c {
-            println!("Jumping to exe...");
+            info!("Jumping to exe...");
             self.pc = 0x80010000;
         }
-        
 
+        self.last_read_addr = None;
+        self.last_write_addr = None;
 
-        if self.last_touched_addr == 0x11F9E4 {
-            println!("touched pc {:#X}", self.pc);
-            self.last_touched_addr = 0;
+        //Nothing can come between a branch and its delay slot
+        if self.delay_slot != 0 {
+            let cycles = self.execute_delay_slot(timers);
+            return cycles + self.access_cycles;
+        }
+
+        let vector = self.pc & 0x1FFF_FFFF;
+        if self.bios_trace.is_some() && (vector == 0xA0 || vector == 0xB0 || vector == 0xC0) {
+            self.trace_bios_call(vector);
         }
 
-     
         if self.pc == 0x000000B0 {
             // SYSCALL: Send character to serial port
             // This catches any characters and prints them to stdout instead
@@ -149,17 +205,33 @@ impl R3000 {
             }
         }
 
-        //Check for vblank
-        if self.main_bus.gpu.consume_vblank() {
-            self.fire_external_interrupt(InterruptSource::VBLANK);
-        };
+        //Take any interrupt that is pending in cause and unmasked in status
+        self.cop0
+            .set_external_interrupt(self.external_interrupt_pending());
+        if self.cop0.interrupt_pending() {
+            self.fire_exception(Exception::Int);
+        }
+        //A code breakpoint stops before the instruction runs, with EPC pointing at it
+        if self.cop0.code_breakpoint_hit(self.pc) {
+            self.fire_debug_exception(self.pc);
+        }
+
+        //Only a jump register can leave pc misaligned. The fetch faults with EPC and BadVaddr at the target
+        if !self.pc.is_multiple_of(4) {
+            self.current_pc = self.pc;
+            self.pc = self.pc.wrapping_add(4);
+            self.fire_address_error(Exception::AdEL, self.current_pc);
+            return INSTRUCTION_CYCLES + self.access_cycles;
+        }
 
         let instruction = self.main_bus.read_word(self.pc);
+        let mut cycles = INSTRUCTION_CYCLES + self.fetch_wait_cycles(self.pc);
         self.current_pc = self.pc;
         self.pc += 4;
 
         if self.log {
-            println!(
+            trace!(
+                target: LOG_TARGET,
                 "Executing {:#X} (FUNCT {:#X}) at {:#X} rs: {} rt: {} rd: {} (FULL {:#X})",
                 instruction.opcode(),
                 instruction.funct(),
@@ -173,46 +245,100 @@ impl R3000 {
 
         self.exec_delay = false;
         self.last_was_branch = false;
-        for i in (0..self.load_delays.len()).rev() {
-            if self.load_delays[i].cycle_loaded != self.cycle_count {
-                self.write_reg(self.load_delays[i].register, self.load_delays[i].value);
-                self.load_delays.remove(i);
-            }
-        }
-        self.execute_instruction(instruction, timers);
+        self.execute_in_load_delay(instruction, timers);
         self.cycle_count = self.cycle_count.wrapping_add(1);
 
 
-        //Execute branch delay operation
         if self.delay_slot != 0 {
-            let delay_instruction = self.main_bus.read_word(self.delay_slot);
-            if self.log {
-                println!(
-                    "DS executing {:#X} (FUNCT {:#X}) at {:#X} rs: {} ({:#}) rt: {} rd: {}",
-                    delay_instruction.opcode(),
-                    delay_instruction.funct(),
-                    self.current_pc + 4,
-                    instruction.rs(),
-                    self.gen_registers[instruction.rs() as usize],
-                    instruction.rt(),
-                    instruction.rd()
-                );
-            }
-            //self.trace_file.write(format!("{:08x}: {:08x}\n", self.delay_slot, delay_instruction).as_bytes());
-            //println!("{:08x}: {:08x}", self.delay_slot, delay_instruction);
+            cycles += self.execute_delay_slot(timers);
+        }
+
+        cycles + self.access_cycles
+    }
+
+    /// Runs the pending delay slot instruction and returns its cycles, not counting memory accesses
+    fn execute_delay_slot(&mut self, timers: &mut TimerState) -> u32 {
+        let delay_pc = std::mem::take(&mut self.delay_slot);
+        //A branch in a jump register's delay slot can have a misaligned target as its delay slot
+        if !delay_pc.is_multiple_of(4) {
             self.exec_delay = true;
-            for i in (0..self.load_delays.len()).rev() {
-                if self.load_delays[i].cycle_loaded != self.cycle_count {
-                    self.write_reg(self.load_delays[i].register, self.load_delays[i].value);
-                    self.load_delays.remove(i);
-                }
-            }
-            self.execute_instruction(delay_instruction, timers);
-            self.cycle_count = self.cycle_count.wrapping_add(1);
+            self.fire_address_error(Exception::AdEL, delay_pc);
             self.exec_delay = false;
-            self.delay_slot = 0;
+            return INSTRUCTION_CYCLES;
+        }
+        let delay_instruction = self.main_bus.read_word(delay_pc);
+        let cycles = INSTRUCTION_CYCLES + self.fetch_wait_cycles(delay_pc);
+        if self.log {
+            trace!(
+                target: LOG_TARGET,
+                "DS executing {:#X} (FUNCT {:#X}) at {:#X} rs: {} ({:#}) rt: {} rd: {}",
+                delay_instruction.opcode(),
+                delay_instruction.funct(),
+                delay_pc,
+                delay_instruction.rs(),
+                self.gen_registers[delay_instruction.rs() as usize],
+                delay_instruction.rt(),
+                delay_instruction.rd()
+            );
+        }
+        self.current_pc = delay_pc;
+        self.exec_delay = true;
+        self.execute_in_load_delay(delay_instruction, timers);
+        self.cycle_count = self.cycle_count.wrapping_add(1);
+        self.exec_delay = false;
+        cycles
+    }
+
+    /// Starts or stops recording calls to the bios function tables
+    pub fn set_bios_trace(&mut self, enabled: bool) {
+        self.bios_trace = if enabled { Some(self.bios_trace.take().unwrap_or_default()) } else { None };
+    }
+
+    /// Bios calls recorded since the last take, oldest first
+    pub fn take_bios_trace(&mut self) -> Vec<BiosCall> {
+        self.bios_trace.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    fn trace_bios_call(&mut self, vector: u32) {
+        let call = BiosCall {
+            vector,
+            function: self.read_reg(9),
+            args: [self.read_reg(4), self.read_reg(5), self.read_reg(6), self.read_reg(7)],
+        };
+        debug!(
+            "BIOS: {}({:#X}) args {:#X?} from {:#X}",
+            match vector { 0xA0 => 'A', 0xB0 => 'B', _ => 'C' },
+            call.function,
+            call.args,
+            self.read_reg(31)
+        );
+        if let Some(trace) = &mut self.bios_trace {
+            trace.push(call);
+        }
+    }
+
+    /// Executes an instruction while any load from the previous instruction is still in flight.
+    /// The loaded value only lands once this instruction is done, and is dropped if this instruction wrote
+    /// the same register itself, either directly or with another load
+    fn execute_in_load_delay(&mut self, instruction: u32, timers: &mut TimerState) {
+        self.in_flight_loads = std::mem::take(&mut self.load_delays);
+        self.written_registers = 0;
+        self.mul_div_busy = self.mul_div_busy.saturating_sub(INSTRUCTION_CYCLES);
+        self.execute_instruction(instruction, timers);
+        for delay in std::mem::take(&mut self.in_flight_loads) {
+            if !self.written_registers.get_bit(delay.register as usize) {
+                self.write_reg(delay.register, delay.value);
+            }
+        }
+    }
+
+    /// Instruction fetches from the cached segments are assumed to hit the instruction cache
+    fn fetch_wait_cycles(&self, addr: u32) -> u32 {
+        if addr < 0xA000_0000 {
+            0
+        } else {
+            self.main_bus.read_wait_cycles(addr)
         }
-        
     }
 
     pub fn execute_instruction(&mut self, instruction: u32, timers: &mut TimerState) {
@@ -500,6 +626,13 @@ impl R3000 {
                         self.op_mfc0(instruction);
                     }
 
+                    0x2 => {
+                        //CFC0. COP0 has no control registers, so there's nothing to read
+                        self.write_reg(instruction.rt(), 0);
+                    }
+
+                    0x6 => (), //CTC0
+
                     0x10 => {
                         //RFE
                         self.op_rfe();
@@ -553,7 +686,7 @@ impl R3000 {
 
             0x20 => {
                 //LB
-                self.op_lb(instruction);
+                self.op_lb(instruction, timers);
             }
 
             0x21 => {
@@ -568,7 +701,7 @@ impl R3000 {
 
             0x24 => {
                 //LBU
-                self.op_lbu(instruction);
+                self.op_lbu(instruction, timers);
             }
 
             0x25 => {
@@ -578,7 +711,7 @@ impl R3000 {
 
             0x28 => {
                 //SB
-                self.op_sb(instruction);
+                self.op_sb(instruction, timers);
             }
 
             0x29 => {
@@ -616,26 +749,12 @@ impl R3000 {
 
             0x32 => {
                 //LWC2
-                let addr = instruction
-                    .immediate_sign_extended()
-                    .wrapping_add(self.read_reg(instruction.rs()));
-                let val = self.read_bus_word(addr, timers);
-                self.gte.set_data_register(instruction.rt() as usize, val);
-
+                self.op_lwc2(instruction, timers);
             }
 
             0x3A => {
                 //SWC2
-                let addr = instruction
-                    .immediate_sign_extended()
-                    .wrapping_add(self.read_reg(instruction.rs()));
-                let val = if instruction.rt() > 31 {
-                    self.gte.control_register(instruction.rt() as usize - 32)
-                } else {
-                    self.gte.data_register(instruction.rt() as usize)
-                };
-                self.write_bus_word(addr, val, timers);
-
+                self.op_swc2(instruction, timers);
             }
 
             
@@ -648,6 +767,32 @@ impl R3000 {
         };
     }
 
+    //LWC2 and SWC2 always target the GTE data registers. The loaded value goes straight into the GTE,
+    //so it doesn't take part in the cpu's load delay
+    fn op_lwc2(&mut self, instruction: u32, timers: &mut TimerState) {
+        let addr = instruction
+            .immediate_sign_extended()
+            .wrapping_add(self.read_reg(instruction.rs()));
+        if addr % 4 != 0 {
+            self.fire_address_error(Exception::AdEL, addr);
+        } else {
+            let val = self.read_bus_word(addr, timers);
+            self.gte.set_data_register(instruction.rt() as usize, val);
+        }
+    }
+
+    fn op_swc2(&mut self, instruction: u32, timers: &mut TimerState) {
+        let addr = instruction
+            .immediate_sign_extended()
+            .wrapping_add(self.read_reg(instruction.rs()));
+        if addr % 4 != 0 {
+            self.fire_address_error(Exception::AdES, addr);
+        } else {
+            let val = self.gte.data_register(instruction.rt() as usize);
+            self.write_bus_word(addr, val, timers);
+        }
+    }
+
     fn op_sw(&mut self, instruction: u32, timers: &mut TimerState) {
         let addr = instruction
             .immediate_sign_extended()
@@ -655,7 +800,7 @@ impl R3000 {
 
         if addr % 4 != 0 {
             //unaligned address
-            self.fire_exception(Exception::AdES);
+            self.fire_address_error(Exception::AdES, addr);
         } else {
             let val = self.read_reg(instruction.rt());
             self.write_bus_word(addr, val, timers);
@@ -709,7 +854,7 @@ impl R3000 {
             .wrapping_add(self.read_reg(instruction.rs()));
 
         let word = self.read_bus_word(addr & !3, timers);
-        let reg_val = self.read_reg(instruction.rt());
+        let reg_val = self.unaligned_load_base(instruction.rt());
         self.delay_write_reg(
             instruction.rt(),
             match addr & 3 {
@@ -729,7 +874,7 @@ impl R3000 {
             .wrapping_add(self.read_reg(instruction.rs()));
 
         let word = self.read_bus_word(addr & !3, timers);
-        let reg_val = self.read_reg(instruction.rt());
+        let reg_val = self.unaligned_load_base(instruction.rt());
         self.delay_write_reg(
             instruction.rt(),
             match addr & 3 {
@@ -748,36 +893,36 @@ impl R3000 {
             .wrapping_add(self.read_reg(instruction.rs()));
         if addr % 2 != 0 {
             //unaligned address
-            self.fire_exception(Exception::AdES);
+            self.fire_address_error(Exception::AdES, addr);
         } else {
             let val = (self.read_reg(instruction.rt()) & 0xFFFF) as u16;
             self.write_bus_half_word(addr, val, timers);
         };
     }
 
-    fn op_sb(&mut self, instruction: u32) {
+    fn op_sb(&mut self, instruction: u32, timers: &mut TimerState) {
         let addr = instruction
             .immediate_sign_extended()
             .wrapping_add(self.read_reg(instruction.rs()));
         let val = (self.read_reg(instruction.rt()) & 0xFF) as u8;
-        self.write_bus_byte(addr, val);
+        self.write_bus_byte(addr, val, timers);
     }
 
     fn op_lhu(&mut self, instruction: u32, timers: &mut TimerState) {
         let addr =
             (instruction.immediate_sign_extended()).wrapping_add(self.read_reg(instruction.rs()));
         if addr % 2 != 0 {
-            self.fire_exception(Exception::AdEL);
+            self.fire_address_error(Exception::AdEL, addr);
         } else {
             let val = self.read_bus_half_word(addr, timers).zero_extended();
             self.delay_write_reg(instruction.rt(), val);
         };
     }
 
-    fn op_lbu(&mut self, instruction: u32) {
+    fn op_lbu(&mut self, instruction: u32, timers: &mut TimerState) {
         let addr =
             (instruction.immediate_sign_extended()).wrapping_add(self.read_reg(instruction.rs()));
-        let val = self.main_bus.read_byte(addr).zero_extended();
+        let val = self.read_bus_byte(addr, timers).zero_extended();
         self.delay_write_reg(instruction.rt(), val);
     }
 
@@ -785,7 +930,7 @@ impl R3000 {
         let addr =
             (instruction.immediate_sign_extended()).wrapping_add(self.read_reg(instruction.rs()));
         if addr % 4 != 0 {
-            self.fire_exception(Exception::AdEL);
+            self.fire_address_error(Exception::AdEL, addr);
         } else {
             let val = self.read_bus_word(addr, timers);
             self.delay_write_reg(instruction.rt(), val);
@@ -796,23 +941,22 @@ impl R3000 {
         let addr =
             (instruction.immediate_sign_extended()).wrapping_add(self.read_reg(instruction.rs()));
         if addr % 2 != 0 {
-            self.fire_exception(Exception::AdEL);
+            self.fire_address_error(Exception::AdEL, addr);
         } else {
             let val = self.read_bus_half_word(addr, timers).sign_extended();
             self.delay_write_reg(instruction.rt(), val);
         };
     }
 
-    fn op_lb(&mut self, instruction: u32) {
+    fn op_lb(&mut self, instruction: u32, timers: &mut TimerState) {
         let addr =
             (instruction.immediate_sign_extended()).wrapping_add(self.read_reg(instruction.rs()));
-        let val = self.main_bus.read_byte(addr).sign_extended();
+        let val = self.read_bus_byte(addr, timers).sign_extended();
         self.delay_write_reg(instruction.rt(), val);
     }
 
     fn op_rfe(&mut self) {
-        let status = self.cop0.read_reg(12) & 0x3f;
-        self.cop0.write_reg(12, (status & !0xf) | (status >> 2));
+        self.cop0.return_from_exception();
     }
 
     fn op_mfc0(&mut self, instruction: u32) {
@@ -888,72 +1032,73 @@ impl R3000 {
 
     fn op_bgtz(&mut self, instruction: u32) {
         if (self.read_reg(instruction.rs()) as i32) > 0 {
-            self.delay_slot = self.pc;
-            self.pc = (instruction.immediate_sign_extended() << 2).wrapping_add(self.delay_slot);
+            self.branch(instruction);
         };
     }
 
     fn op_blez(&mut self, instruction: u32) {
         if (self.read_reg(instruction.rs()) as i32) <= 0 {
-            self.delay_slot = self.pc;
-            self.pc = (instruction.immediate_sign_extended() << 2).wrapping_add(self.delay_slot);
+            self.branch(instruction);
         };
     }
 
     fn op_bne(&mut self, instruction: u32) {
         if self.read_reg(instruction.rs()) != self.read_reg(instruction.rt()) {
-            self.delay_slot = self.pc;
-            self.pc = (instruction.immediate_sign_extended() << 2).wrapping_add(self.delay_slot);
+            self.branch(instruction);
         };
     }
 
     fn op_beq(&mut self, instruction: u32) {
         if self.read_reg(instruction.rs()) == self.read_reg(instruction.rt()) {
-            self.delay_slot = self.pc;
-            self.pc = (instruction.immediate_sign_extended() << 2).wrapping_add(self.delay_slot);
+            self.branch(instruction);
         };
     }
 
     fn op_jal(&mut self, instruction: u32) {
-        self.delay_slot = self.pc;
-        self.write_reg(31, self.delay_slot + 4);
-        self.pc = (instruction.address() << 2) | (self.delay_slot & 0xF0000000);
+        self.write_reg(31, self.current_pc.wrapping_add(8));
+        self.op_j(instruction);
     }
 
     fn op_j(&mut self, instruction: u32) {
+        self.jump((instruction.address() << 2) | (self.current_pc.wrapping_add(4) & 0xF0000000));
+    }
+
+    /// Takes a relative branch. The offset is from the branch's delay slot
+    fn branch(&mut self, instruction: u32) {
+        self.jump((instruction.immediate_sign_extended() << 2).wrapping_add(self.current_pc.wrapping_add(4)));
+    }
+
+    /// Continues at target after the delay slot, which is the instruction at pc. For a branch that is itself
+    /// in a delay slot, pc is the first branch's target, which then runs as this branch's delay slot
+    fn jump(&mut self, target: u32) {
         self.delay_slot = self.pc;
-        self.pc = (instruction.address() << 2) | ((self.delay_slot) & 0xF0000000);
+        self.branch_pc = self.current_pc;
+        self.pc = target;
     }
 
     fn op_bgezal(&mut self, instruction: u32) {
-        let og_pc = self.pc;
         if self.read_reg(instruction.rs()) as i32 >= 0 {
-            self.delay_slot = self.pc;
-            self.pc = (instruction.immediate_sign_extended() << 2).wrapping_add(self.delay_slot);
+            self.branch(instruction);
         }
-        self.write_reg(31, og_pc + 4);
+        self.write_reg(31, self.current_pc.wrapping_add(8));
     }
 
     fn op_bltzal(&mut self, instruction: u32) {
-        let og_pc = self.pc;
         if self.read_reg(instruction.rs()).get_bit(31) {
-            self.delay_slot = self.pc;
-            self.pc = (instruction.immediate_sign_extended() << 2).wrapping_add(self.delay_slot);
+            self.branch(instruction);
         }
-        self.write_reg(31, og_pc + 4);
+        self.write_reg(31, self.current_pc.wrapping_add(8));
     }
 
     fn op_bgez(&mut self, instruction: u32) {
         if self.read_reg(instruction.rs()) as i32 >= 0 {
-            self.delay_slot = self.pc;
-            self.pc = (instruction.immediate_sign_extended() << 2).wrapping_add(self.delay_slot);
+            self.branch(instruction);
         }
     }
 
     fn op_bltz(&mut self, instruction: u32) {
         if self.read_reg(instruction.rs()).get_bit(31) {
-            self.delay_slot = self.pc;
-            self.pc = (instruction.immediate_sign_extended() << 2).wrapping_add(self.delay_slot);
+            self.branch(instruction);
         }
     }
 
@@ -965,7 +1110,26 @@ impl R3000 {
         );
     }
 
+    /// Multiplies take longer the more significant bits rs has
+    fn start_mult(&mut self, rs: u32) {
+        self.wait_mul_div();
+        self.mul_div_busy = if rs < 0x800 {
+            MULT_CYCLES_SMALL
+        } else if rs < 0x10_0000 {
+            MULT_CYCLES_MEDIUM
+        } else {
+            MULT_CYCLES_LARGE
+        };
+    }
+
+    /// Stalls until any multiply or divide in progress is done with hi and lo
+    fn wait_mul_div(&mut self) {
+        self.access_cycles += self.mul_div_busy;
+        self.mul_div_busy = 0;
+    }
+
     fn op_multu(&mut self, instruction: u32) {
+        self.start_mult(self.read_reg(instruction.rs()));
         let result =
             (self.read_reg(instruction.rs()) as u64) * (self.read_reg(instruction.rt()) as u64);
         self.lo = (result & 0xFFFF_FFFF) as u32;
@@ -973,6 +1137,9 @@ impl R3000 {
     }
 
     fn op_mult(&mut self, instruction: u32) {
+        //Negative values are timed by their magnitude
+        let rs = self.read_reg(instruction.rs()) as i32;
+        self.start_mult(if rs < 0 { !rs as u32 } else { rs as u32 });
         let result = ((self.read_reg(instruction.rs()) as i32) as i64
             * (self.read_reg(instruction.rt()) as i32) as i64) as u64;
         self.lo = result as u32;
@@ -1057,6 +1224,8 @@ impl R3000 {
     }
 
     fn op_divu(&mut self, instruction: u32) {
+        self.wait_mul_div();
+        self.mul_div_busy = DIV_CYCLES;
         let rs = self.read_reg(instruction.rs());
         let rt = self.read_reg(instruction.rt());
         self.lo = match rs.checked_div(rt) {
@@ -1072,6 +1241,8 @@ impl R3000 {
     }
 
     fn op_div(&mut self, instruction: u32) {
+        self.wait_mul_div();
+        self.mul_div_busy = DIV_CYCLES;
         let rs = self.read_reg(instruction.rs()) as i32;
         let rt = self.read_reg(instruction.rt()) as i32;
         self.lo = (match rs.checked_div(rt) {
@@ -1098,6 +1269,7 @@ impl R3000 {
     }
 
     fn op_mflo(&mut self, instruction: u32) {
+        self.wait_mul_div();
         self.write_reg(instruction.rd(), self.lo);
     }
 
@@ -1106,6 +1278,7 @@ impl R3000 {
     }
 
     fn op_mfhi(&mut self, instruction: u32) {
+        self.wait_mul_div();
         self.write_reg(instruction.rd(), self.hi);
     }
 
@@ -1115,23 +1288,14 @@ impl R3000 {
 
     fn op_jalr(&mut self, instruction: u32) {
         let target = self.read_reg(instruction.rs());
-        self.write_reg(instruction.rd(), self.pc + 4);
-        if target % 4 != 0 {
-            self.fire_exception(Exception::AdEL);
-        } else {
-            self.delay_slot = self.pc;
-            self.pc = target;
-        }
+        self.write_reg(instruction.rd(), self.current_pc.wrapping_add(8));
+        //A misaligned target faults when it's fetched, not here
+        self.jump(target);
     }
 
     fn op_jr(&mut self, instruction: u32) {
         let target = self.read_reg(instruction.rs());
-        if target % 4 != 0 {
-            self.fire_exception(Exception::AdEL);
-        } else {
-            self.delay_slot = self.pc;
-            self.pc = target;
-        }
+        self.jump(target);
     }
 
     fn op_srav(&mut self, instruction: u32) {
@@ -1181,70 +1345,125 @@ impl R3000 {
         self.fire_exception(Exception::Bp);
     }
 
+    /// Raises AdEL or AdES for a misaligned access to addr, recording it in BadVaddr
+    fn fire_address_error(&mut self, exception: Exception, addr: u32) {
+        self.cop0.set_bad_vaddr(addr);
+        self.fire_exception(exception);
+    }
+
     pub fn fire_exception(&mut self, exception: Exception) {
         //println!("CPU EXCEPTION: Type: {:?} PC: {:#X}", exception, self.current_pc);
-        self.cop0.set_cause_execode(&exception);
+        let epc = if exception == Exception::Int {
+            self.pc
+        } else {
+            self.pc - 4
+        };
+        let epc = self.exception_epc(epc);
+        self.pc = self.cop0.enter_exception(epc, exception);
+    }
 
+    /// Takes a hardware breakpoint hit for the instruction at pc through the debug vector
+    fn fire_debug_exception(&mut self, pc: u32) {
+        let epc = self.exception_epc(pc);
+        self.pc = self.cop0.enter_debug_exception(epc);
+    }
 
-        if self.delay_slot != 0 {
-            self.cop0.write_reg(13, self.cop0.read_reg(13) | (1 << 31));
-            self.cop0.write_reg(14, self.pc - 8);
+    /// The EPC for an exception at pc, setting the Cause branch delay bit
+    fn exception_epc(&mut self, pc: u32) -> u32 {
+        //A delay slot left behind by a faulting branch in a delay slot is never run
+        self.delay_slot = 0;
+        self.cop0.set_branch_delay(self.exec_delay);
+        if self.exec_delay {
+            //EPC points at the branch so it runs again after the handler returns
+            self.branch_pc
         } else {
-            self.cop0.write_reg(13, self.cop0.read_reg(13) & !(1 << 31));
-            if exception == Exception::Int {
-                self.cop0.write_reg(14, self.pc);
-            } else {
-                self.cop0.write_reg(14, self.pc - 4);
-            }
+            pc
         }
-
-        let old_status = self.cop0.read_reg(12);
-        self.cop0.write_reg(
-            12,
-            (old_status & !0x3F) | (((old_status & 0x3f) << 2) & 0x3f),
-        );
-        self.pc = if self.cop0.read_reg(12).get_bit(23) {
-            0xBFC0_0180
-        } else {
-            0x8000_0080
-        };
-
-        //self.cop0.write_reg(12, self.cop0.read_reg(12) << 4)
     }
 
+    /// Raises the source's bit in I_STAT. The exception itself is taken at the start of the next instruction
+    /// if the source is unmasked in I_MASK and COP0 lets it through
     pub fn fire_external_interrupt(&mut self, source: InterruptSource) {
-        let mask_bit = source.clone() as usize;
-        //println!("mask_bit num = {}", mask_bit);
+        let mask_bit = source as usize;
         self.i_status.set_bit(mask_bit, true);
-        if self.cop0.interrupt_enabled() && self.i_mask.get_bit(mask_bit) {
-            //println!("CPU: INT {:?}", source);
-            self.cycle_count = self.cycle_count.wrapping_add(1);
-            self.fire_exception(Exception::Int);
+    }
+
+    /// True when any interrupt raised in I_STAT is also enabled in I_MASK
+    pub fn external_interrupt_pending(&self) -> bool {
+        self.i_status & self.i_mask & 0x7FF != 0
+    }
+
+    ///Bookkeeping for a data read: wait states, the address for read watchpoints and the data breakpoint
+    fn track_read(&mut self, addr: u32) {
+        self.access_cycles += self.main_bus.read_wait_cycles(addr);
+        self.last_read_addr = Some(physical_address(addr));
+        self.check_data_breakpoint(addr, false);
+    }
+
+    ///Bookkeeping for a data write: the address for write watchpoints and the data breakpoint
+    fn track_write(&mut self, addr: u32) {
+        self.last_write_addr = Some(physical_address(addr));
+        self.check_data_breakpoint(addr, true);
+    }
+
+    ///Raises the exception right away, with EPC at the instruction. The access and the rest of the instruction still complete
+    fn check_data_breakpoint(&mut self, addr: u32, write: bool) {
+        if self.cop0.data_breakpoint_hit(addr, write) {
+            self.fire_debug_exception(self.current_pc);
+        }
+    }
+
+    ///Records the access in the bus log, if it's on and covers addr
+    fn log_access(&mut self, addr: u32, value: u32, width: u8, write: bool) {
+        if self.main_bus.bus_log_enabled() {
+            self.main_bus.log_access(BusAccess {
+                pc: self.current_pc,
+                addr: physical_address(addr),
+                value,
+                width,
+                write,
+            });
         }
     }
 
     fn read_bus_word(&mut self, addr: u32, timers: &mut TimerState) -> u32 {
-        //self.last_touched_addr = addr & 0x1fffffff;
-        match addr & 0x1fffffff {
+        self.track_read(addr);
+        let val = self.decode_read_word(addr, timers);
+        self.log_access(addr, val, 4, false);
+        val
+    }
+
+    /// Reads a word from whatever the cpu sees at addr, including the interrupt and timer registers.
+    /// Unlike the cpu's own loads this doesn't wait, log or trip watchpoints
+    pub(crate) fn decode_read_word(&mut self, addr: u32, timers: &mut TimerState) -> u32 {
+        match physical_address(addr) {
             0x1F801070 => {
                 //println!("Reading ISTATUS");
                 self.i_status
             }
             0x1F801074 => self.i_mask,
-            0x1F801100..=0x1F801128 => timers.read_word(addr & 0x1fffffff),
+            0x1F801100..=0x1F801128 => timers.read_word(physical_address(addr)),
             _ => self.main_bus.read_word(addr),
         }
     }
 
+    /// While the cache is isolated stores go to the cache instead of the bus, so they're dropped.
+    /// The scratchpad is its own memory and still takes them
+    fn store_isolated(&self, addr: u32) -> bool {
+        self.cop0.cache_isolated() && !is_scratchpad(addr)
+    }
+
     fn write_bus_word(&mut self, addr: u32, val: u32, timers: &mut TimerState) {
-        self.last_touched_addr = addr & 0x1fffffff;
-        if self.cop0.cache_isolated() {
-            //Cache is isolated, so don't write
+        self.track_write(addr);
+        if self.store_isolated(addr) {
             return;
         }
-        
+        self.log_access(addr, val, 4, true);
+        self.decode_write_word(addr, val, timers);
+    }
 
-        match addr & 0x1fffffff {
+    pub(crate) fn decode_write_word(&mut self, addr: u32, val: u32, timers: &mut TimerState) {
+        match physical_address(addr) {
             0x1F801070 => {
                 //println!("Writing I_STAT. val {:#X} pc {:#X} oldpc {:#X}", val, self.pc, self.old_pc);
                 self.i_status &= val;
@@ -1253,56 +1472,79 @@ impl R3000 {
                 //println!("Writing I_MASK val {:#X}", val);
                 self.i_mask = val;
             }
-            0x1F801100..=0x1F801128 => timers.write_word(addr & 0x1fffffff, val),
+            0x1F801100..=0x1F801128 => timers.write_word(physical_address(addr), val),
             _ => self.main_bus.write_word(addr, val),
         };
     }
 
     fn read_bus_half_word(&mut self, addr: u32, timers: &mut TimerState) -> u16 {
-        //self.last_touched_addr = addr & 0x1fffffff;
-        match addr & 0x1fffffff {
+        self.track_read(addr);
+        let val = self.decode_read_half_word(addr, timers);
+        self.log_access(addr, val as u32, 2, false);
+        val
+    }
+
+    pub(crate) fn decode_read_half_word(&mut self, addr: u32, timers: &mut TimerState) -> u16 {
+        match physical_address(addr) {
             0x1F801070 => self.i_status as u16,
             0x1F801074 => self.i_mask as u16,
-            0x1F801100..=0x1F801128 => timers.read_half_word(addr & 0x1fffffff),
+            0x1F801100..=0x1F801128 => timers.read_half_word(physical_address(addr)),
             _ => self.main_bus.read_half_word(addr),
         }
     }
     
-    pub fn read_bus_byte(&mut self, addr: u32) -> u8 {
-        //self.last_touched_addr = addr & 0x1fffffff;
-        match addr & 0x1fffffff {
+    fn read_bus_byte(&mut self, addr: u32, timers: &mut TimerState) -> u8 {
+        self.track_read(addr);
+        let val = self.decode_read_byte(addr, timers);
+        self.log_access(addr, val as u32, 1, false);
+        val
+    }
+
+    pub(crate) fn decode_read_byte(&mut self, addr: u32, timers: &mut TimerState) -> u8 {
+        match physical_address(addr) {
             0x1F801070 => self.i_status as u8,
             0x1F801072 => (self.i_status >> 8) as u8,
             0x1F801074 => self.i_mask as u8,
             0x1F801076 => (self.i_mask >> 8) as u8,
+            addr @ 0x1F801100..=0x1F80112B => (timers.read_half_word(addr & !1) >> ((addr & 1) * 8)) as u8,
             _ => self.main_bus.read_byte(addr),
         }
     }
    
 
     fn write_bus_half_word(&mut self, addr: u32, val: u16, timers: &mut TimerState) {
-        self.last_touched_addr = addr & 0x1fffffff;
-        if self.cop0.cache_isolated() {
-            //Cache is isolated, so don't write
+        self.track_write(addr);
+        if self.store_isolated(addr) {
             return;
         }
-        match addr & 0x1fffffff {
+        self.log_access(addr, val as u32, 2, true);
+        self.decode_write_half_word(addr, val, timers);
+    }
+
+    pub(crate) fn decode_write_half_word(&mut self, addr: u32, val: u16, timers: &mut TimerState) {
+        match physical_address(addr) {
             0x1F801070 => self.i_status &= val as u32,
             0x1F801074 => self.i_mask = val as u32,
-            0x1F801100..=0x1F801128 => timers.write_half_word(addr & 0x1fffffff, val),
+            0x1F801100..=0x1F801128 => timers.write_half_word(physical_address(addr), val),
             _ => self.main_bus.write_half_word(addr, val),
         };
     }
 
-    pub fn write_bus_byte(&mut self, addr: u32, val: u8) {
-        self.last_touched_addr = addr & 0x1fffffff;
-        if self.cop0.cache_isolated() {
-            //Cache is isolated, so don't write
+    pub fn write_bus_byte(&mut self, addr: u32, val: u8, timers: &mut TimerState) {
+        self.track_write(addr);
+        if self.store_isolated(addr) {
             return;
         }
-        match addr & 0x1fffffff {
+        self.log_access(addr, val as u32, 1, true);
+        self.decode_write_byte(addr, val, timers);
+    }
+
+    pub(crate) fn decode_write_byte(&mut self, addr: u32, val: u8, timers: &mut TimerState) {
+        match physical_address(addr) {
             0x1F801070 => self.i_status &= val as u32,
             0x1F801074 => self.i_mask = val as u32,
+            //The timers are 16 bits wide, so the rest of the register is written as zero
+            addr @ 0x1F801100..=0x1F80112B => timers.write_half_word(addr & !1, (val as u16) << ((addr & 1) * 8)),
             _ => self.main_bus.write_byte(addr, val),
         };
     }
@@ -1322,21 +1564,628 @@ impl R3000 {
             0 => (), //Prevent writing to the zero register
             _ => self.gen_registers[register_number as usize] = value,
         }
+        self.written_registers.set_bit(register_number as usize, true);
+    }
+
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        for reg in self.gen_registers.iter() {
+            writer.write_u32(*reg);
+        }
+        writer.write_u32(self.pc);
+        writer.write_u32(self.current_pc);
+        writer.write_u32(self.hi);
+        writer.write_u32(self.lo);
+        writer.write_u32(self.mul_div_busy);
+        writer.write_u32(self.delay_slot);
+        writer.write_u32(self.branch_pc);
+        writer.write_u32(self.cycle_count);
+        writer.write_u32(self.i_mask);
+        writer.write_u32(self.i_status);
+        writer.write_u32(self.load_delays.len() as u32);
+        for delay in &self.load_delays {
+            writer.write_u8(delay.register);
+            writer.write_u32(delay.value);
+        }
+        self.cop0.save_state(writer);
+        self.gte.save_state(writer);
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        for reg in self.gen_registers.iter_mut() {
+            *reg = reader.read_u32()?;
+        }
+        self.pc = reader.read_u32()?;
+        self.current_pc = reader.read_u32()?;
+        self.hi = reader.read_u32()?;
+        self.lo = reader.read_u32()?;
+        self.mul_div_busy = reader.read_u32()?;
+        self.delay_slot = reader.read_u32()?;
+        self.branch_pc = reader.read_u32()?;
+        self.cycle_count = reader.read_u32()?;
+        self.i_mask = reader.read_u32()?;
+        self.i_status = reader.read_u32()?;
+        let delay_count = reader.read_u32()?;
+        self.load_delays.clear();
+        for _ in 0..delay_count {
+            let register = reader.read_u8()?;
+            if register > 31 {
+                return Err(SaveStateError::Corrupt);
+            }
+            self.load_delays.push(LoadDelay {
+                register,
+                value: reader.read_u32()?,
+            });
+        }
+        self.cop0.load_state(reader)?;
+        self.gte.load_state(reader)
+    }
+
+    /// LWL and LWR merge into the value of an in flight load to the same register, so a LWL/LWR pair works back to back
+    fn unaligned_load_base(&self, register_number: u8) -> u32 {
+        match self
+            .in_flight_loads
+            .iter()
+            .find(|delay| delay.register == register_number)
+        {
+            Some(delay) => delay.value,
+            None => self.read_reg(register_number),
+        }
     }
 
     fn delay_write_reg(&mut self, register_number: u8, value: u32) {
         if register_number != 0 {
-            //Get rid of old writes to the same register
-            for i in (0..self.load_delays.len()).rev() {
-                if self.load_delays[i].register == register_number {
-                    self.load_delays.remove(i);
-                }
-            }
+            //Cancels a load to the same register that is still in flight
+            self.written_registers.set_bit(register_number as usize, true);
             self.load_delays.push(LoadDelay {
                 register: register_number,
-                value: value,
-                cycle_loaded: self.cycle_count,
+                value,
             });
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_cpu() -> R3000 {
+        let bus = MainBus::for_test();
+        R3000::new(bus)
+    }
+
+    #[test]
+    fn test_lwc2_swc2() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        cpu.main_bus.write_word(0x100, 0xFFFE_0003);
+
+        cpu.execute_instruction(0xC800_0100, &mut timers); //lwc2 vxy0, 0x100(r0)
+        assert_eq!(cpu.gte.data_register(0), 0xFFFE_0003);
+
+        cpu.execute_instruction(0xE800_0200, &mut timers); //swc2 vxy0, 0x200(r0)
+        assert_eq!(cpu.main_bus.read_word(0x200), 0xFFFE_0003);
+    }
+
+    #[test]
+    fn test_pending_interrupt_taken() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        cpu.pc = 0x1000;
+        cpu.cop0.write_reg(12, (1 << 8) | 1);
+        cpu.cop0.write_reg(13, 1 << 8); //Software interrupt 0
+
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.cop0.read_reg(14), 0x1000);
+        assert_eq!((cpu.cop0.read_reg(13) >> 2) & 0x1F, Exception::Int as u32);
+        //Handler's first instruction already ran
+        assert_eq!(cpu.pc, 0x8000_0084);
+    }
+
+    #[test]
+    fn test_external_interrupt_sets_cause() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        cpu.pc = 0x1000;
+        cpu.cop0.write_reg(12, 0); //Interrupts disabled so nothing is taken
+        cpu.write_bus_word(0x1F801074, 1, &mut timers); //Unmask vblank
+
+        cpu.fire_external_interrupt(InterruptSource::VBLANK);
+        assert!(cpu.external_interrupt_pending());
+        cpu.step_instruction(&mut timers);
+        assert!(cpu.cop0.read_reg(13).get_bit(10));
+
+        //Acknowledge by writing 0 to the vblank bit
+        cpu.write_bus_word(0x1F801070, !1, &mut timers);
+        assert!(!cpu.external_interrupt_pending());
+        cpu.step_instruction(&mut timers);
+        assert!(!cpu.cop0.read_reg(13).get_bit(10));
+    }
+
+    #[test]
+    fn test_implemented_opcodes_execute() {
+        //Every listed opcode must be handled without hitting one of the unknown instruction panics
+        for info in implemented_opcodes() {
+            let mut cpu = test_cpu();
+            let mut timers = TimerState::new();
+            cpu.pc = 0x1000;
+            cpu.execute_instruction(info.encode(), &mut timers);
+        }
+    }
+
+    #[test]
+    fn test_branch_delay_slot() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        cpu.main_bus.write_word(0x1000, 0x1000_0004); //beq r0, r0, 0x1014
+        cpu.main_bus.write_word(0x1004, 0x2401_0007); //addiu r1, r0, 7
+        cpu.main_bus.write_word(0x1008, 0x2402_0001); //addiu r2, r0, 1 (skipped)
+        cpu.pc = 0x1000;
+
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.pc, 0x1014);
+        assert_eq!(cpu.read_reg(1), 7);
+        assert_eq!(cpu.read_reg(2), 0);
+    }
+
+    #[test]
+    fn test_branch_in_delay_slot() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        cpu.main_bus.write_word(0x1000, 0x0800_0800); //j 0x2000
+        cpu.main_bus.write_word(0x1004, 0x0C00_0C00); //jal 0x3000
+        cpu.main_bus.write_word(0x2000, 0x2402_0001); //addiu r2, r0, 1
+        cpu.main_bus.write_word(0x2004, 0x2403_0001); //addiu r3, r0, 1 (skipped)
+        cpu.main_bus.write_word(0x3000, 0x2404_0001); //addiu r4, r0, 1
+        cpu.pc = 0x1000;
+
+        //The first target runs as the delay slot of the second jump, on its own step, then execution continues
+        //at the second target
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.pc, 0x3000);
+        assert_eq!(cpu.read_reg(2), 0);
+        //Link address is relative to the jal itself
+        assert_eq!(cpu.read_reg(31), 0x100C);
+
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.read_reg(2), 1);
+        assert_eq!(cpu.read_reg(3), 0);
+        assert_eq!(cpu.pc, 0x3000);
+
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.read_reg(4), 1);
+        assert_eq!(cpu.pc, 0x3004);
+    }
+
+    #[test]
+    fn test_back_to_back_branches() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        //Each jump is in the other's delay slot, so this never settles on one instruction
+        cpu.main_bus.write_word(0x1000, 0x0800_0400); //j 0x1000
+        cpu.main_bus.write_word(0x1004, 0x0800_0400); //j 0x1000
+        cpu.pc = 0x1000;
+        for _ in 0..10 {
+            cpu.step_instruction(&mut timers);
+        }
+        assert_eq!(cpu.pc, 0x1000);
+
+        //A fault in the delay slot of a branch that's in a delay slot points EPC at that second branch
+        cpu.main_bus.write_word(0x1000, 0x0800_0800); //j 0x2000
+        cpu.main_bus.write_word(0x1004, 0x0800_0C00); //j 0x3000
+        cpu.main_bus.write_word(0x2000, 0x0000_000C); //syscall
+        cpu.pc = 0x1000;
+        cpu.delay_slot = 0;
+        cpu.step_instruction(&mut timers);
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.cop0.read_reg(14), 0x1004);
+        assert!(cpu.cop0.read_reg(13).get_bit(31));
+        assert_eq!(cpu.pc, 0x8000_0080);
+    }
+
+    #[test]
+    fn test_load_delay_slot() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        cpu.main_bus.write_word(0x100, 0x1234_5678);
+        cpu.main_bus.write_word(0x1000, 0x8C01_0100); //lw r1, 0x100(r0)
+        cpu.main_bus.write_word(0x1004, 0x0020_1021); //addu r2, r1, r0
+        cpu.main_bus.write_word(0x1008, 0x0020_1821); //addu r3, r1, r0
+        cpu.gen_registers[1] = 0xAAAA;
+        cpu.pc = 0x1000;
+
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.read_reg(1), 0xAAAA);
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.read_reg(2), 0xAAAA); //Still the old value in the delay slot
+        assert_eq!(cpu.read_reg(1), 0x1234_5678);
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.read_reg(3), 0x1234_5678);
+    }
+
+    #[test]
+    fn test_load_delay_overwritten() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        cpu.main_bus.write_word(0x100, 0x1234_5678);
+        cpu.main_bus.write_word(0x1000, 0x8C01_0100); //lw r1, 0x100(r0)
+        cpu.main_bus.write_word(0x1004, 0x2401_0005); //addiu r1, r0, 5
+        cpu.pc = 0x1000;
+
+        cpu.step_instruction(&mut timers);
+        cpu.step_instruction(&mut timers);
+        //The delay slot instruction's own write wins over the load
+        assert_eq!(cpu.read_reg(1), 5);
+    }
+
+    /// Runs each instruction through the load delay the way step_instruction does
+    fn run_pipelined(cpu: &mut R3000, timers: &mut TimerState, instructions: &[u32]) {
+        for instruction in instructions {
+            cpu.execute_in_load_delay(*instruction, timers);
+        }
+    }
+
+    #[test]
+    fn test_unaligned_loads() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        cpu.main_bus.write_word(0x100, 0x3322_1100);
+        cpu.main_bus.write_word(0x104, 0x7766_5544);
+        let expected = [0x3322_1100, 0x4433_2211, 0x5544_3322, 0x6655_4433];
+
+        for (offset, expected) in expected.iter().enumerate() {
+            let offset = offset as u32;
+            cpu.gen_registers[2] = 0xDEAD_BEEF;
+            run_pipelined(
+                &mut cpu,
+                &mut timers,
+                &[
+                    0x9802_0100 + offset, //lwr r2, 0x100+offset(r0)
+                    0x8802_0103 + offset, //lwl r2, 0x103+offset(r0)
+                    0,
+                ],
+            );
+            assert_eq!(cpu.read_reg(2), *expected, "offset {}", offset);
+        }
+
+        //Merges into a plain load that is still in flight, not the old register value
+        cpu.main_bus.write_word(0x200, 0xAABB_CCDD);
+        cpu.gen_registers[2] = 0;
+        run_pipelined(
+            &mut cpu,
+            &mut timers,
+            &[
+                0x8C02_0200, //lw r2, 0x200(r0)
+                0x9802_0101, //lwr r2, 0x101(r0)
+                0,
+            ],
+        );
+        assert_eq!(cpu.read_reg(2), 0xAA33_2211);
+    }
+
+    #[test]
+    fn test_sub_word_loads_and_stores() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        cpu.main_bus.write_word(0x100, 0x1122_3344);
+        cpu.main_bus.write_word(0x104, 0x5566_7788);
+        cpu.gen_registers[2] = 0x1234_56FF;
+        cpu.gen_registers[5] = 0xABCD_8001;
+        run_pipelined(
+            &mut cpu,
+            &mut timers,
+            &[
+                0xA002_0101, //sb r2, 0x101(r0)
+                0xA405_0106, //sh r5, 0x106(r0)
+                0x8003_0101, //lb r3, 0x101(r0)
+                0x9004_0101, //lbu r4, 0x101(r0)
+                0x8406_0106, //lh r6, 0x106(r0)
+                0x9407_0106, //lhu r7, 0x106(r0)
+                0,
+            ],
+        );
+        //Only the addressed lane changes
+        assert_eq!(cpu.main_bus.read_word(0x100), 0x1122_FF44);
+        assert_eq!(cpu.main_bus.read_word(0x104), 0x8001_7788);
+        assert_eq!(cpu.read_reg(3), 0xFFFF_FFFF);
+        assert_eq!(cpu.read_reg(4), 0x0000_00FF);
+        assert_eq!(cpu.read_reg(6), 0xFFFF_8001);
+        assert_eq!(cpu.read_reg(7), 0x0000_8001);
+    }
+
+    #[test]
+    fn test_unaligned_stores() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        cpu.gen_registers[2] = 0x4433_2211;
+        let expected = [
+            (0x4433_2211, 0xFFFF_FFFF),
+            (0x3322_11FF, 0xFFFF_FF44),
+            (0x2211_FFFF, 0xFFFF_4433),
+            (0x11FF_FFFF, 0xFF44_3322),
+        ];
+
+        for (offset, (low, high)) in expected.iter().enumerate() {
+            let offset = offset as u32;
+            cpu.main_bus.write_word(0x300, 0xFFFF_FFFF);
+            cpu.main_bus.write_word(0x304, 0xFFFF_FFFF);
+            run_pipelined(
+                &mut cpu,
+                &mut timers,
+                &[
+                    0xB802_0300 + offset, //swr r2, 0x300+offset(r0)
+                    0xA802_0303 + offset, //swl r2, 0x303+offset(r0)
+                ],
+            );
+            assert_eq!(cpu.main_bus.read_word(0x300), *low, "offset {}", offset);
+            assert_eq!(cpu.main_bus.read_word(0x304), *high, "offset {}", offset);
+        }
+    }
+
+    const DIV: u32 = 0x0022_001A; //div r1, r2
+    const DIVU: u32 = 0x0022_001B; //divu r1, r2
+    const MULT: u32 = 0x0022_0018; //mult r1, r2
+    const MULTU: u32 = 0x0022_0019; //multu r1, r2
+
+    /// Runs op with r1 and r2 set and returns hi and lo
+    fn mul_div(cpu: &mut R3000, timers: &mut TimerState, op: u32, rs: u32, rt: u32) -> (u32, u32) {
+        cpu.gen_registers[1] = rs;
+        cpu.gen_registers[2] = rt;
+        cpu.execute_instruction(op, timers);
+        (cpu.hi, cpu.lo)
+    }
+
+    #[test]
+    fn test_mul_div_results() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        assert_eq!(mul_div(&mut cpu, &mut timers, DIV, -7i32 as u32, 2), (-1i32 as u32, -3i32 as u32));
+        assert_eq!(mul_div(&mut cpu, &mut timers, DIVU, 7, 2), (1, 3));
+
+        //Dividing by zero doesn't trap. Hi gets the dividend and lo is all ones, or 1 for negative signed dividends
+        assert_eq!(mul_div(&mut cpu, &mut timers, DIV, 5, 0), (5, 0xFFFF_FFFF));
+        assert_eq!(mul_div(&mut cpu, &mut timers, DIV, -5i32 as u32, 0), (-5i32 as u32, 1));
+        assert_eq!(mul_div(&mut cpu, &mut timers, DIVU, 0x8000_0000, 0), (0x8000_0000, 0xFFFF_FFFF));
+        assert_eq!(mul_div(&mut cpu, &mut timers, DIV, 0x8000_0000, -1i32 as u32), (0, 0x8000_0000));
+
+        //The full 64 bit product, signed and unsigned
+        assert_eq!(mul_div(&mut cpu, &mut timers, MULT, 0x7FFF_FFFF, 0x7FFF_FFFF), (0x3FFF_FFFF, 1));
+        assert_eq!(mul_div(&mut cpu, &mut timers, MULT, 0x8000_0000, -1i32 as u32), (0, 0x8000_0000));
+        assert_eq!(mul_div(&mut cpu, &mut timers, MULT, -3i32 as u32, 4), (0xFFFF_FFFF, -12i32 as u32));
+        assert_eq!(mul_div(&mut cpu, &mut timers, MULTU, 0xFFFF_FFFF, 0xFFFF_FFFF), (0xFFFF_FFFE, 1));
+
+        cpu.execute_instruction(0x0000_1812, &mut timers); //mflo r3
+        cpu.execute_instruction(0x0000_2010, &mut timers); //mfhi r4
+        assert_eq!((cpu.read_reg(3), cpu.read_reg(4)), (1, 0xFFFF_FFFE));
+    }
+
+    #[test]
+    fn test_mflo_waits_for_result() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        cpu.main_bus.write_word(0x1000, MULT);
+        cpu.main_bus.write_word(0x1004, 0x0000_1812); //mflo r3
+        cpu.main_bus.write_word(0x1008, DIV);
+        cpu.main_bus.write_word(0x100C, 0);
+        cpu.main_bus.write_word(0x1010, 0x0000_1812); //mflo r3
+        cpu.gen_registers[1] = 0x10_0000;
+        cpu.gen_registers[2] = 2;
+        cpu.pc = 0x1000;
+
+        assert_eq!(cpu.step_instruction(&mut timers), INSTRUCTION_CYCLES);
+        //One cycle of the multiply overlapped with the mflo itself
+        assert_eq!(cpu.step_instruction(&mut timers), MULT_CYCLES_LARGE);
+        assert_eq!(cpu.read_reg(3), 0x20_0000);
+
+        cpu.step_instruction(&mut timers);
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.step_instruction(&mut timers), DIV_CYCLES - 1);
+        assert_eq!(cpu.read_reg(3), 0x8_0000);
+    }
+
+    #[test]
+    fn test_signed_overflow_traps() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        let cases = [
+            (0x0022_1820, 0x7FFF_FFFF, 0x7FFF_FFFF), //add r3, r1, r2
+            (0x2023_0001, 0x7FFF_FFFF, 0),           //addi r3, r1, 1
+            (0x0022_1822, 0x8000_0000, 1),           //sub r3, r1, r2
+        ];
+        for (instruction, rs, rt) in cases.iter() {
+            cpu.main_bus.write_word(0x1000, *instruction);
+            cpu.gen_registers[1] = *rs;
+            cpu.gen_registers[2] = *rt;
+            cpu.gen_registers[3] = 0x1234;
+            cpu.pc = 0x1000;
+            cpu.step_instruction(&mut timers);
+
+            assert_eq!((cpu.cop0.read_reg(13) >> 2) & 0x1F, Exception::Ovf as u32);
+            assert_eq!(cpu.cop0.read_reg(14), 0x1000);
+            assert_eq!(cpu.pc, 0x8000_0080);
+            assert_eq!(cpu.read_reg(3), 0x1234, "{:#X} wrote its destination", instruction);
+        }
+
+        //Signed results that fit don't trap
+        cpu.gen_registers[1] = 0xFFFF_FFFF;
+        cpu.gen_registers[2] = 0x8000_0001;
+        cpu.execute_instruction(0x0022_1820, &mut timers); //add r3, r1, r2
+        assert_eq!(cpu.read_reg(3), 0x8000_0000);
+    }
+
+    /// addu, addiu, subu, and, or, xor, nor, each writing to a different register
+    const ALU_PROGRAM: [u32; 8] = [
+        0x0022_1821, //addu r3, r1, r2
+        0x2424_FFFF, //addiu r4, r1, -1
+        0x0022_2823, //subu r5, r1, r2
+        0x0022_3024, //and r6, r1, r2
+        0x0022_3825, //or r7, r1, r2
+        0x0022_4026, //xor r8, r1, r2
+        0x0022_4827, //nor r9, r1, r2
+        0x0022_0021, //addu r0, r1, r2
+    ];
+
+    #[test]
+    fn test_wrapping_alu_ops() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        cpu.gen_registers[1] = 0x8000_0000;
+        cpu.gen_registers[2] = 0xF0F0_F0F1;
+        for instruction in ALU_PROGRAM.iter() {
+            cpu.execute_instruction(*instruction, &mut timers);
+        }
+        assert_eq!(cpu.read_reg(3), 0x70F0_F0F1);
+        assert_eq!(cpu.read_reg(4), 0x7FFF_FFFF);
+        assert_eq!(cpu.read_reg(5), 0x8F0F_0F0F);
+        assert_eq!(cpu.read_reg(6), 0x8000_0000);
+        assert_eq!(cpu.read_reg(7), 0xF0F0_F0F1);
+        assert_eq!(cpu.read_reg(8), 0x70F0_F0F1);
+        assert_eq!(cpu.read_reg(9), 0x0F0F_0F0E);
+        assert_eq!(cpu.read_reg(0), 0);
+        //None of these trap on overflow
+        assert_eq!(cpu.pc, 0);
+    }
+
+    /// Rough throughput check for the ALU ops. Run with `cargo test --release -- --ignored bench_alu_ops --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_alu_ops() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        cpu.gen_registers[1] = 0x1234_5678;
+        cpu.gen_registers[2] = 0x9ABC_DEF0;
+        let iterations = 10_000_000;
+        let start = std::time::Instant::now();
+        for _ in 0..iterations {
+            for instruction in ALU_PROGRAM.iter() {
+                cpu.execute_instruction(*instruction, &mut timers);
+            }
+        }
+        let elapsed = start.elapsed();
+        println!(
+            "{:.2} ns per instruction",
+            elapsed.as_nanos() as f64 / (iterations * ALU_PROGRAM.len()) as f64
+        );
+    }
+
+    #[test]
+    fn test_scratchpad_store_while_isolated() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        cpu.cop0.write_reg(12, 1 << 16); //Isolate cache
+        cpu.write_bus_word(0x1F80_0010, 0xCAFE, &mut timers);
+        cpu.write_bus_word(0x0000_0010, 0xCAFE, &mut timers);
+        assert_eq!(cpu.main_bus.read_word(0x1F80_0010), 0xCAFE);
+        assert_eq!(cpu.main_bus.read_word(0x0000_0010), 0);
+    }
+
+    #[test]
+    fn test_isolated_stores_dont_reach_ram() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        cpu.main_bus.write_word(0x100, 0x5555_5555);
+        cpu.main_bus.write_word(0x104, 0x5555_5555);
+        cpu.execute_instruction(0x3C01_0001, &mut timers); //lui r1, 0x1
+        cpu.execute_instruction(0x4081_6000, &mut timers); //mtc0 r1, sr. Isolates the cache
+        //The BIOS cache flush stores zeros everywhere
+        cpu.execute_instruction(0xAC00_0100, &mut timers); //sw r0, 0x100(r0)
+        cpu.execute_instruction(0xA400_0104, &mut timers); //sh r0, 0x104(r0)
+        cpu.execute_instruction(0xA000_0106, &mut timers); //sb r0, 0x106(r0)
+        cpu.execute_instruction(0x4080_6000, &mut timers); //mtc0 r0, sr
+        assert_eq!(cpu.main_bus.read_word(0x100), 0x5555_5555);
+        assert_eq!(cpu.main_bus.read_word(0x104), 0x5555_5555);
+
+        cpu.execute_instruction(0xAC00_0100, &mut timers); //sw r0, 0x100(r0)
+        assert_eq!(cpu.main_bus.read_word(0x100), 0);
+    }
+
+    #[test]
+    fn test_misaligned_load_address_error() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        cpu.main_bus.write_word(0x1000, 0x3401_0101); //ori r1, r0, 0x101
+        cpu.main_bus.write_word(0x1004, 0x8C22_0000); //lw r2, 0(r1)
+        cpu.main_bus.write_word(0x1008, 0xA422_0000); //sh r2, 0(r1)
+        cpu.pc = 0x1000;
+        cpu.step_instruction(&mut timers);
+        cpu.step_instruction(&mut timers);
+
+        assert_eq!((cpu.cop0.read_reg(13) >> 2) & 0x1F, Exception::AdEL as u32);
+        assert_eq!(cpu.cop0.read_reg(8), 0x101);
+        assert_eq!(cpu.cop0.read_reg(14), 0x1004);
+        assert_eq!(cpu.pc, 0x8000_0080);
+        assert_eq!(cpu.read_reg(2), 0);
+
+        //BadVaddr can't be written by software
+        cpu.cop0.write_reg(8, 0);
+        assert_eq!(cpu.cop0.read_reg(8), 0x101);
+
+        cpu.pc = 0x1008;
+        cpu.step_instruction(&mut timers);
+        assert_eq!((cpu.cop0.read_reg(13) >> 2) & 0x1F, Exception::AdES as u32);
+        assert_eq!(cpu.cop0.read_reg(14), 0x1008);
+    }
+
+    #[test]
+    fn test_misaligned_jump_target() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        cpu.write_reg(1, 0x2002);
+        cpu.main_bus.write_word(0x1000, 0x0020_F809); //jalr r1
+        cpu.main_bus.write_word(0x1004, 0); //nop
+        cpu.pc = 0x1000;
+        cpu.step_instruction(&mut timers);
+        //The jump and its delay slot run normally
+        assert_eq!(cpu.read_reg(31), 0x1008);
+        assert_eq!(cpu.pc, 0x2002);
+
+        cpu.step_instruction(&mut timers);
+        assert_eq!((cpu.cop0.read_reg(13) >> 2) & 0x1F, Exception::AdEL as u32);
+        assert_eq!(cpu.cop0.read_reg(13) >> 31, 0);
+        assert_eq!(cpu.cop0.read_reg(8), 0x2002);
+        assert_eq!(cpu.cop0.read_reg(14), 0x2002);
+        assert_eq!(cpu.pc, 0x8000_0080);
+    }
+
+    #[test]
+    fn test_cop0_moves() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        cpu.write_reg(1, 0x1040_0401);
+        cpu.main_bus.write_word(0x1000, 0x4081_6000); //mtc0 r1, Status
+        cpu.main_bus.write_word(0x1004, 0x4081_7800); //mtc0 r1, PRID
+        cpu.main_bus.write_word(0x1008, 0x4002_6000); //mfc0 r2, Status
+        cpu.main_bus.write_word(0x100C, 0x4003_7800); //mfc0 r3, PRID
+        cpu.main_bus.write_word(0x1010, 0x4044_6000); //cfc0 r4, 12
+        cpu.write_reg(4, 0xFFFF);
+        cpu.pc = 0x1000;
+        for _ in 0..5 {
+            cpu.step_instruction(&mut timers);
+        }
+        assert_eq!(cpu.read_reg(2), 0x1040_0401);
+        assert_eq!(cpu.read_reg(3), 0x2);
+        assert_eq!(cpu.read_reg(4), 0);
+    }
+
+    #[test]
+    fn test_code_breakpoint() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        cpu.main_bus.write_word(0x1000, 0x3401_0001); //ori r1, r0, 1
+        cpu.main_bus.write_word(0x1004, 0x3402_0002); //ori r2, r0, 2
+        cpu.cop0.write_reg(3, 0x1004); //BPC
+        cpu.cop0.write_reg(11, 0xFFFF_FFFF); //BPCM
+        cpu.cop0.write_reg(7, 0xE180_0000); //DCIC, code breakpoints on
+        cpu.pc = 0x1000;
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.cop0.read_reg(7) & 0x3F, 0);
+        cpu.step_instruction(&mut timers);
+
+        assert_eq!((cpu.cop0.read_reg(13) >> 2) & 0x1F, Exception::Bp as u32);
+        assert_eq!(cpu.cop0.read_reg(14), 0x1004);
+        //The step goes on to run the first instruction of the debug handler
+        assert_eq!(cpu.current_pc, 0x8000_0040);
+        assert_eq!(cpu.cop0.read_reg(7) & 0x3F, 0x3);
+        assert_eq!(cpu.read_reg(1), 1);
+        assert_eq!(cpu.read_reg(2), 0);
+    }
+}
@@ -0,0 +1,158 @@
+use super::instruction::Instruction;
+
+/// Which field of an instruction picks the operation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpcodeClass {
+    /// Selected by the primary opcode (bits 26-31)
+    Primary,
+    /// SPECIAL (opcode 0x0), selected by funct
+    Special,
+    /// REGIMM (opcode 0x1), selected by rt
+    RegImm,
+    /// COP0 (opcode 0x10), selected by rs
+    Cop0,
+    /// COP2 (opcode 0x12), selected by rs. rs 0x10 and up is an immediate GTE command
+    Cop2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpcodeInfo {
+    pub mnemonic: &'static str,
+    pub class: OpcodeClass,
+    /// Value of the selecting field for this class
+    pub code: u8,
+}
+
+impl OpcodeInfo {
+    const fn new(mnemonic: &'static str, class: OpcodeClass, code: u8) -> Self {
+        Self {
+            mnemonic,
+            class,
+            code,
+        }
+    }
+
+    /// Instruction word for this opcode with every operand field set to zero
+    pub fn encode(&self) -> u32 {
+        let code = self.code as u32;
+        match self.class {
+            OpcodeClass::Primary => code << 26,
+            OpcodeClass::Special => code,
+            OpcodeClass::RegImm => (0x1 << 26) | (code << 16),
+            OpcodeClass::Cop0 => (0x10 << 26) | (code << 21),
+            OpcodeClass::Cop2 => (0x12 << 26) | (code << 21),
+        }
+    }
+}
+
+use OpcodeClass::*;
+
+const IMPLEMENTED_OPCODES: [OpcodeInfo; 70] = [
+    OpcodeInfo::new("SLL", Special, 0x0),
+    OpcodeInfo::new("SRL", Special, 0x2),
+    OpcodeInfo::new("SRA", Special, 0x3),
+    OpcodeInfo::new("SLLV", Special, 0x4),
+    OpcodeInfo::new("SRLV", Special, 0x6),
+    OpcodeInfo::new("SRAV", Special, 0x7),
+    OpcodeInfo::new("JR", Special, 0x8),
+    OpcodeInfo::new("JALR", Special, 0x9),
+    OpcodeInfo::new("SYSCALL", Special, 0xC),
+    OpcodeInfo::new("BREAK", Special, 0xD),
+    OpcodeInfo::new("MFHI", Special, 0x10),
+    OpcodeInfo::new("MTHI", Special, 0x11),
+    OpcodeInfo::new("MFLO", Special, 0x12),
+    OpcodeInfo::new("MTLO", Special, 0x13),
+    OpcodeInfo::new("MULT", Special, 0x18),
+    OpcodeInfo::new("MULTU", Special, 0x19),
+    OpcodeInfo::new("DIV", Special, 0x1A),
+    OpcodeInfo::new("DIVU", Special, 0x1B),
+    OpcodeInfo::new("ADD", Special, 0x20),
+    OpcodeInfo::new("ADDU", Special, 0x21),
+    OpcodeInfo::new("SUB", Special, 0x22),
+    OpcodeInfo::new("SUBU", Special, 0x23),
+    OpcodeInfo::new("AND", Special, 0x24),
+    OpcodeInfo::new("OR", Special, 0x25),
+    OpcodeInfo::new("XOR", Special, 0x26),
+    OpcodeInfo::new("NOR", Special, 0x27),
+    OpcodeInfo::new("SLT", Special, 0x2A),
+    OpcodeInfo::new("SLTU", Special, 0x2B),
+    OpcodeInfo::new("BLTZ", RegImm, 0x0),
+    OpcodeInfo::new("BGEZ", RegImm, 0x1),
+    OpcodeInfo::new("BLTZAL", RegImm, 0x10),
+    OpcodeInfo::new("BGEZAL", RegImm, 0x11),
+    OpcodeInfo::new("J", Primary, 0x2),
+    OpcodeInfo::new("JAL", Primary, 0x3),
+    OpcodeInfo::new("BEQ", Primary, 0x4),
+    OpcodeInfo::new("BNE", Primary, 0x5),
+    OpcodeInfo::new("BLEZ", Primary, 0x6),
+    OpcodeInfo::new("BGTZ", Primary, 0x7),
+    OpcodeInfo::new("ADDI", Primary, 0x8),
+    OpcodeInfo::new("ADDIU", Primary, 0x9),
+    OpcodeInfo::new("SLTI", Primary, 0xA),
+    OpcodeInfo::new("SLTIU", Primary, 0xB),
+    OpcodeInfo::new("ANDI", Primary, 0xC),
+    OpcodeInfo::new("ORI", Primary, 0xD),
+    OpcodeInfo::new("XORI", Primary, 0xE),
+    OpcodeInfo::new("LUI", Primary, 0xF),
+    OpcodeInfo::new("MFC0", Cop0, 0x0),
+    OpcodeInfo::new("CFC0", Cop0, 0x2),
+    OpcodeInfo::new("MTC0", Cop0, 0x4),
+    OpcodeInfo::new("CTC0", Cop0, 0x6),
+    OpcodeInfo::new("RFE", Cop0, 0x10),
+    OpcodeInfo::new("MFC2", Cop2, 0x0),
+    OpcodeInfo::new("CFC2", Cop2, 0x2),
+    OpcodeInfo::new("MTC2", Cop2, 0x4),
+    OpcodeInfo::new("CTC2", Cop2, 0x6),
+    OpcodeInfo::new("COP2", Cop2, 0x10),
+    OpcodeInfo::new("LB", Primary, 0x20),
+    OpcodeInfo::new("LH", Primary, 0x21),
+    OpcodeInfo::new("LWL", Primary, 0x22),
+    OpcodeInfo::new("LW", Primary, 0x23),
+    OpcodeInfo::new("LBU", Primary, 0x24),
+    OpcodeInfo::new("LHU", Primary, 0x25),
+    OpcodeInfo::new("LWR", Primary, 0x26),
+    OpcodeInfo::new("SB", Primary, 0x28),
+    OpcodeInfo::new("SH", Primary, 0x29),
+    OpcodeInfo::new("SWL", Primary, 0x2A),
+    OpcodeInfo::new("SW", Primary, 0x2B),
+    OpcodeInfo::new("SWR", Primary, 0x2E),
+    OpcodeInfo::new("LWC2", Primary, 0x32),
+    OpcodeInfo::new("SWC2", Primary, 0x3A),
+];
+
+/// Every opcode the interpreter executes
+pub fn implemented_opcodes() -> Vec<OpcodeInfo> {
+    IMPLEMENTED_OPCODES.to_vec()
+}
+
+/// Finds the implemented opcode an instruction decodes to, the same way the interpreter picks it.
+/// Returns None for reserved or unimplemented encodings
+pub fn decode(instruction: u32) -> Option<OpcodeInfo> {
+    let (class, code) = match instruction.opcode() {
+        0x0 => (Special, instruction.funct()),
+        0x1 => (RegImm, instruction.rt()),
+        0x10 => (Cop0, instruction.rs()),
+        //Any COP2 word with bit 25 set is a GTE command
+        0x12 if instruction.rs() >= 0x10 => (Cop2, 0x10),
+        0x12 => (Cop2, instruction.rs()),
+        opcode => (Primary, opcode),
+    };
+    IMPLEMENTED_OPCODES
+        .iter()
+        .find(|info| info.class == class && info.code == code)
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_implemented_opcodes() {
+        for info in implemented_opcodes() {
+            assert_eq!(decode(info.encode()), Some(info), "{}", info.mnemonic);
+        }
+        assert_eq!(decode(0xFC00_0000), None); //Reserved primary opcode 0x3F
+        assert_eq!(decode(0x0000_0001), None); //Reserved SPECIAL funct 0x1
+    }
+}
@@ -1,6 +1,7 @@
 use crate::cpu::{InterruptSource, R3000};
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
 use bit_field::BitField;
-use log::{error, info, trace};
+use log::{error, info, trace, warn};
 
 const NUM_CHANNELS: usize = 7;
 
@@ -40,6 +41,30 @@ impl Channel {
         }
     }
 
+    fn sync_mode(&self) -> u32 {
+        self.control.get_bits(9..=10)
+    }
+
+    /// Words moved by a block or immediate mode transfer. Immediate mode has a single block, where 0 means 0x10000 words
+    fn word_count(&self) -> u32 {
+        let block_size = match self.block & 0xFFFF {
+            0 if self.sync_mode() == 0 => 0x10000,
+            size => size,
+        };
+        match self.sync_mode() {
+            0 => block_size,
+            _ => block_size * (self.block >> 16),
+        }
+    }
+
+    /// Next word address in the direction set by CHCR bit 1
+    fn step_address(&self, addr: u32) -> u32 {
+        match self.control.get_bit(1) {
+            true => addr.wrapping_sub(4) & 0x1FFFFC,
+            false => (addr + 4) & 0x1FFFFC,
+        }
+    }
+
     fn complete(&mut self) {
         self.control.set_bit(24, false);
         self.control.set_bit(28, false);
@@ -102,6 +127,29 @@ impl DMAState {
         }
     }
 
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        for channel in self.channels.iter() {
+            writer.write_u32(channel.base_addr);
+            writer.write_u32(channel.block);
+            writer.write_u32(channel.control);
+        }
+        writer.write_u32(self.control);
+        writer.write_u32(self.interrupt);
+        writer.write_u32(self.cycles_to_wait as u32);
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        for channel in self.channels.iter_mut() {
+            channel.base_addr = reader.read_u32()?;
+            channel.block = reader.read_u32()?;
+            channel.control = reader.read_u32()?;
+        }
+        self.control = reader.read_u32()?;
+        self.interrupt = reader.read_u32()?;
+        self.cycles_to_wait = reader.read_u32()? as usize;
+        Ok(())
+    }
+
     pub fn read_word(&mut self, addr: u32) -> u32 {
         let channel_num = (((addr & 0x000000F0) >> 4) - 0x8) as usize;
         //println!("Reading DMA addr {:#X}", addr);
@@ -171,7 +219,7 @@ impl DMAState {
     pub fn update_master_flag(&mut self) {
         let should_flag = self.interrupt.get_bit(15)
             || (self.interrupt.get_bit(23)
-                && (self.interrupt.get_bits(16..=22) > 0 && self.interrupt.get_bits(24..=30) > 0));
+                && (self.interrupt.get_bits(16..=22) & self.interrupt.get_bits(24..=30)) > 0);
         self.interrupt.set_bit(31, should_flag);
     }
 
@@ -184,10 +232,6 @@ impl DMAState {
             self.interrupt.set_bit(24 + channel_num, true);
         }
     }
-
-    fn irq_channel_enabled(&self, channel_num: usize) -> bool {
-        self.interrupt.get_bit(15) || self.interrupt.get_bit(16 + channel_num) //&& self.interrupt.get_bit(23)// && !self.interrupt.get_bit(24 + channel_num)
-    }
 }
 
 pub fn execute_dma_cycle(cpu: &mut R3000) {
@@ -213,162 +257,104 @@ pub fn execute_dma_cycle(cpu: &mut R3000) {
         match num {
             2 => {
                 //GPU
-                match cpu.main_bus.dma.channels[num].control {
-                    0x01000401 => {
+                let channel = cpu.main_bus.dma.channels[num].clone();
+                let from_ram = channel.control.get_bit(0);
+                match channel.sync_mode() {
+                    2 => {
                         //Linked list mode. mem -> gpu
-                        let mut addr = cpu.main_bus.dma.channels[num].base_addr;
+                        let mut addr = channel.base_addr & 0x1FFFFC;
                         trace!("Starting linked list transfer. addr {:#X}", addr);
                         let mut header = cpu.main_bus.read_word(addr);
-                        //println!("base addr: {:#X}. base header: {:#X}", addr, header);
                         loop {
                             let num_words = (header >> 24) & 0xFF;
                             for i in 0..num_words {
                                 let packet = cpu.main_bus.read_word((addr + 4) + (i * 4));
                                 cpu.main_bus.gpu.send_gp0_command(packet);
                             }
-                            //println!("addr {:#X}, header {:#X}, nw {}", addr, header, num_words);
                             if header & 0x800000 != 0 || header == 0x00FFFFFF {
                                 break;
                             }
 
                             if addr == 0 {
-                                println!("Hit DMA infinite loop");
+                                warn!("Hit DMA infinite loop");
                                 break;
                             }
 
-                            //println!("Addr {:X}", addr);
-
-                            addr = header & 0xFFFFFF;
+                            addr = header & 0x1FFFFC;
                             header = cpu.main_bus.read_word(addr);
                         }
                         cpu.main_bus.dma.channels[num].base_addr = 0xFFFFFF;
-                        //println!("DMA2 linked list transfer done.");
-                        cpu.main_bus.dma.channels[num].complete();
-
-
-                        cpu.main_bus.dma.raise_irq(num);
-                        if cpu.main_bus.dma.irq_channel_enabled(num) {
-                            cpu.fire_external_interrupt(InterruptSource::DMA);
-                        } else {
-                            trace!("DMA IRQ Rejected");
-                            trace!("DICR: {:#X}", cpu.main_bus.dma.interrupt);
-                        }
                     }
-
-                    0x01000201 => {
-                        //VramWrite
-                        trace!("DMA: Starting VramWrite");
-                        let entries = (cpu.main_bus.dma.channels[num].block >> 16) & 0xFFFF;
-                        let block_size = (cpu.main_bus.dma.channels[num].block) & 0xFFFF;
-                        let base_addr = cpu.main_bus.dma.channels[num].base_addr & 0xFFFFFF;
-                        trace!("Block size {} Num blocks {} base {:#X}", block_size, entries, base_addr);
-                        for i in 0..entries {
-                            for j in 0..block_size {
-                                let packet = cpu
-                                    .main_bus
-                                    .read_word(base_addr + ((i * block_size) * 4) + (j * 4));
+                    _ => {
+                        //Block and immediate modes. Mem -> gpu is a VRAM write or a command list,
+                        //gpu -> mem reads GPUREAD
+                        trace!("DMA: Starting GPU block transfer. From RAM: {}", from_ram);
+                        let mut addr = channel.base_addr & 0x1FFFFC;
+                        for _ in 0..channel.word_count() {
+                            if from_ram {
+                                let packet = cpu.main_bus.read_word(addr);
                                 cpu.main_bus.gpu.send_gp0_command(packet);
+                            } else {
+                                let word = cpu.main_bus.gpu.read_word_gp0();
+                                cpu.main_bus.write_word(addr, word);
                             }
+                            addr = channel.step_address(addr);
                         }
                         trace!("DMA2 block transfer done.");
-                        cpu.main_bus.dma.channels[num].base_addr += entries * block_size * 4;
-                        cpu.main_bus.dma.channels[num].complete();
-                        cpu.main_bus.dma.raise_irq(num);
-                        if cpu.main_bus.dma.irq_channel_enabled(num) {
-                            cpu.fire_external_interrupt(InterruptSource::DMA);
-                        } else {
-                            trace!("DMA IRQ Rejected");
-                            trace!("DICR: {:#X}", cpu.main_bus.dma.interrupt);
-                        }
-                    }
-                    0x1000200 => {
-                        //VramRead
-                        //TODO: Implement properly
-
-                        let entries = (cpu.main_bus.dma.channels[num].block >> 16) & 0xFFFF;
-                        let block_size = (cpu.main_bus.dma.channels[num].block) & 0xFFFF;
-                        let base_addr = cpu.main_bus.dma.channels[num].base_addr & 0xFFFFFF;
-                        for i in 0..entries {
-                            for j in 0..block_size {
-                                //Lets just write all zeros for now
-                                cpu.main_bus.write_word(
-                                    base_addr + ((i * block_size) * 4) + (j * 4),
-                                    0x0,
-                                );
-                            }
-                        }
-                        cpu.main_bus.dma.channels[num].base_addr += entries * block_size * 4;
-                        cpu.main_bus.dma.channels[num].complete();
-                        cpu.main_bus.dma.raise_irq(num);
-                        if cpu.main_bus.dma.irq_channel_enabled(num) {
-                            cpu.fire_external_interrupt(InterruptSource::DMA);
-                        } else {
-                            trace!("DMA IRQ Rejected");
-                            trace!("DICR: {:#X}", cpu.main_bus.dma.interrupt);
-                        }
-                    }
-                    _ => {
-                        panic!("Unknown gpu DMA mode. This must be a custom transfer. Control was {:#X}", cpu.main_bus.dma.channels[num].control)
+                        cpu.main_bus.dma.channels[num].base_addr = addr;
                     }
                 }
+                finish_transfer(cpu, num);
             }
 
             3 => {
-                let words = (cpu.main_bus.dma.channels[num].block) & 0xFFFF;
-                let base_addr = (cpu.main_bus.dma.channels[num].base_addr & 0xFFFFFF) as usize;
-                let data = cpu.main_bus.cd_drive.sector_data_take();
-                cpu.main_bus.memory.data[base_addr..(base_addr + (words * 4) as usize)].copy_from_slice(data);
-                cpu.main_bus.dma.channels[num].complete();
-                cpu.main_bus.dma.raise_irq(num);
-                if cpu.main_bus.dma.irq_channel_enabled(num) {
-                    cpu.fire_external_interrupt(InterruptSource::DMA);
-                } else {
-                    trace!("DMA IRQ Rejected");
-                    trace!("DICR: {:#X}", cpu.main_bus.dma.interrupt);
-                }     
+                //CDROM. Words come out of the drive's data FIFO, which refills from the disc while want_data is set
+                let channel = cpu.main_bus.dma.channels[num].clone();
+                let mut addr = channel.base_addr & 0x1FFFFC;
+                for _ in 0..channel.word_count() {
+                    let bytes = [(); 4].map(|_| cpu.main_bus.cd_drive.pop_data());
+                    cpu.main_bus.memory.write_word(addr, u32::from_le_bytes(bytes));
+                    addr = channel.step_address(addr);
+                }
+                cpu.main_bus.dma.channels[num].base_addr = addr;
+                finish_transfer(cpu, num);
             }
 
             4 => {
-                //SPU
-                //Lets just ignore this one for now
-                cpu.main_bus.dma.channels[num].complete();
-                cpu.main_bus.dma.raise_irq(num);
-                if cpu.main_bus.dma.irq_channel_enabled(num) {
-                    cpu.fire_external_interrupt(InterruptSource::DMA);
-                } else {
-                    trace!("DMA IRQ Rejected");
-                    trace!("DICR: {:#X}", cpu.main_bus.dma.interrupt);
+                //SPU. Block mode, to or from the SPU's transfer address
+                let channel = &cpu.main_bus.dma.channels[num];
+                let words = (channel.block & 0xFFFF) * (channel.block >> 16).max(1);
+                let base = channel.base_addr & 0x1FFFFC;
+                let to_spu = channel.control.get_bit(0);
+                for i in 0..words {
+                    let addr = (base + i * 4) & 0x1FFFFC;
+                    if to_spu {
+                        let word = cpu.main_bus.memory.read_word(addr);
+                        cpu.main_bus.spu.dma_write(word);
+                    } else {
+                        let word = cpu.main_bus.spu.dma_read();
+                        cpu.main_bus.memory.write_word(addr, word);
+                    }
                 }
+                finish_transfer(cpu, num);
             }
 
             6 => {
                 //OTC
-                //OTC is only used to reset the ordering table. So we can ignore a lot of the parameters
-                let entries = cpu.main_bus.dma.channels[num].block & 0xFFFF;
-                let base = cpu.main_bus.dma.channels[num].base_addr & 0xFFFFFF;
-                println!("Initializing {} entries ending at {:#X}", entries, base);
-                
-                for i in 0..=entries {
-                    let addr = base - ((entries - i) * 4);
-                    if i == 0 {
-                        //The first entry should point to the end of memory
-                        cpu.main_bus.write_word(addr, 0xFFFFFF);
-                        //println!("Wrote DMA6 end at {:#X} val {:#X}", addr, 0xFFFFFF);
-                    } else {
-                        //All the others should point to the address below
-                        cpu.main_bus.write_word(addr, (addr - 4) & 0xFFFFFF);
-                        //println!("Wrote DMA6 header at {:#X} val {:#X}", addr, (addr - 4) & 0xFFFFFF);
-                    }
+                //OTC is only used to reset the ordering table. Each entry points to the one below it,
+                //down to the last which holds the end of list marker
+                let channel = cpu.main_bus.dma.channels[num].clone();
+                let entries = channel.word_count();
+                let mut addr = channel.base_addr & 0x1FFFFC;
+                trace!("Initializing {} entries ending at {:#X}", entries, addr);
+                for i in 0..entries {
+                    let next = (addr.wrapping_sub(4)) & 0x1FFFFC;
+                    let value = if i == entries - 1 { 0xFFFFFF } else { next };
+                    cpu.main_bus.memory.write_word(addr, value);
+                    addr = next;
                 }
                 trace!("DMA6 done. Marking complete and raising irq");
-                cpu.main_bus.dma.channels[num].complete();
-                cpu.main_bus.dma.raise_irq(num);
-                if cpu.main_bus.dma.irq_channel_enabled(num) {
-                    cpu.fire_external_interrupt(InterruptSource::DMA);
-                } else {
-                    trace!("DMA IRQ Rejected");
-                    trace!("DICR: {:#X}", cpu.main_bus.dma.interrupt);
-                }
+                finish_transfer(cpu, num);
             }
             _ => panic!("Unable to transfer unknown DMA channel {}!", num),
         }
@@ -377,6 +363,21 @@ pub fn execute_dma_cycle(cpu: &mut R3000) {
     cpu.main_bus.dma.cycles_to_wait = 200; // Lets give the cpu some time to see that the DMA is done
 }
 
+/// Marks the channel's transfer done and sets its DICR flag if the channel's interrupt is enabled.
+/// The cpu only sees an interrupt when that raises the DICR master flag, so unacknowledged flags hold off later ones
+fn finish_transfer(cpu: &mut R3000, num: usize) {
+    cpu.main_bus.dma.channels[num].complete();
+    cpu.main_bus.dma.raise_irq(num);
+    let was_flagged = cpu.main_bus.dma.interrupt.get_bit(31);
+    cpu.main_bus.dma.update_master_flag();
+    if !was_flagged && cpu.main_bus.dma.interrupt.get_bit(31) {
+        cpu.fire_external_interrupt(InterruptSource::DMA);
+    } else {
+        trace!("DMA IRQ Rejected");
+        trace!("DICR: {:#X}", cpu.main_bus.dma.interrupt);
+    }
+}
+
 fn write_dicr(current_value: u32, value: u32) -> u32 {
     if value.get_bit(15) {error!("OH GOD BIT 15 IS SET")}
     let normal_bits = value & 0xFFFFFF; //These bits are written normally
@@ -396,4 +397,80 @@ mod tests {
         assert_eq!(write_dicr(0x7F000000, 0x7F000000), 0x0);
         assert_eq!(write_dicr(0x0, 0x7F000001), 0x1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_gpu_linked_list_and_block() {
+        use crate::bus::MainBus;
+        let bus = MainBus::for_test();
+        let mut cpu = R3000::new(bus);
+        cpu.main_bus.dma.write_word(0x1F8010F0, 0x800); //Enable channel 2
+        cpu.main_bus.dma.write_word(0x1F8010F4, (1 << 23) | (1 << 18));
+
+        //A 2x1 VRAM write at 0,0 split over two packets. The second header ends the list
+        for (addr, word) in [
+            (0x100, 0x0200_0200),
+            (0x104, 0xA000_0000),
+            (0x108, 0x0000_0000),
+            (0x200, 0x02FF_FFFF),
+            (0x204, 0x0001_0002),
+            (0x208, 0x7C00_03E0),
+        ] {
+            cpu.main_bus.write_word(addr, word);
+        }
+        cpu.main_bus.dma.write_word(0x1F8010A0, 0x100);
+        cpu.main_bus.dma.write_word(0x1F8010A8, 0x01000401);
+        execute_dma_cycle(&mut cpu);
+        assert_eq!(cpu.main_bus.gpu.get_vram()[0..2], [0x03E0, 0x7C00]);
+        assert_eq!(cpu.main_bus.dma.read_word(0x1F8010A8) & (1 << 24), 0);
+        assert!(cpu.main_bus.dma.read_word(0x1F8010F4).get_bit(26));
+        assert!(cpu.main_bus.dma.read_word(0x1F8010F4).get_bit(31));
+        assert!(cpu.i_status.get_bit(3));
+
+        //A 2x1 VRAM write at 32,0 in one block of 4 words
+        for (i, word) in [0xA000_0000, 0x0000_0020, 0x0001_0002, 0x7FFF_001F].iter().enumerate() {
+            cpu.main_bus.write_word(0x300 + i as u32 * 4, *word);
+        }
+        cpu.main_bus.dma.write_word(0x1F8010A0, 0x300);
+        cpu.main_bus.dma.write_word(0x1F8010A4, 0x0001_0004);
+        cpu.main_bus.dma.write_word(0x1F8010A8, 0x01000201);
+        execute_dma_cycle(&mut cpu);
+        assert_eq!(cpu.main_bus.gpu.get_vram()[32..34], [0x001F, 0x7FFF]);
+        assert_eq!(cpu.main_bus.dma.read_word(0x1F8010A0), 0x310);
+    }
+
+    #[test]
+    fn test_otc_and_dicr_masking() {
+        use crate::bus::MainBus;
+        let bus = MainBus::for_test();
+        let mut cpu = R3000::new(bus);
+        cpu.main_bus.dma.write_word(0x1F8010F0, 0x0800_0000); //Enable channel 6
+        cpu.main_bus.write_word(0xFF0, 0x1234);
+
+        //Channel 6's interrupt is masked, so the transfer finishes without one
+        cpu.main_bus.dma.write_word(0x1F8010E0, 0x1000);
+        cpu.main_bus.dma.write_word(0x1F8010E4, 4);
+        cpu.main_bus.dma.write_word(0x1F8010E8, 0x11000002);
+        execute_dma_cycle(&mut cpu);
+        assert_eq!(cpu.main_bus.read_word(0x1000), 0xFFC);
+        assert_eq!(cpu.main_bus.read_word(0xFFC), 0xFF8);
+        assert_eq!(cpu.main_bus.read_word(0xFF8), 0xFF4);
+        assert_eq!(cpu.main_bus.read_word(0xFF4), 0xFFFFFF);
+        assert_eq!(cpu.main_bus.read_word(0xFF0), 0x1234);
+        assert_eq!(cpu.main_bus.dma.read_word(0x1F8010E8) & (1 << 24), 0);
+        assert_eq!(cpu.main_bus.dma.read_word(0x1F8010F4), 0);
+        assert!(!cpu.i_status.get_bit(3));
+
+        //Unmasked, but the master enable is still off
+        cpu.main_bus.dma.write_word(0x1F8010F4, 1 << 22);
+        cpu.main_bus.dma.write_word(0x1F8010E8, 0x11000002);
+        execute_dma_cycle(&mut cpu);
+        assert!(cpu.main_bus.dma.read_word(0x1F8010F4).get_bit(30));
+        assert!(!cpu.i_status.get_bit(3));
+
+        cpu.main_bus.dma.write_word(0x1F8010F4, (1 << 23) | (1 << 22) | (1 << 30));
+        cpu.main_bus.dma.write_word(0x1F8010E8, 0x11000002);
+        execute_dma_cycle(&mut cpu);
+        assert!(cpu.main_bus.dma.read_word(0x1F8010F4).get_bit(31));
+        assert!(cpu.i_status.get_bit(3));
+    }
+}
@@ -0,0 +1,68 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use crate::bios::BIOS_SIZE;
+
+#[derive(Debug, PartialEq)]
+pub enum EmuError {
+    /// The bios image isn't 512KB. Holds the size it actually was
+    BiosWrongSize(usize),
+    /// Nothing exists at the given path
+    FileNotFound(PathBuf),
+    /// The file exists but couldn't be read
+    Io(PathBuf, io::ErrorKind),
+    /// The memory card image isn't 128KB. Holds the size it actually was
+    MemoryCardWrongSize(usize),
+    /// The disc image isn't something the emulator can load. Holds the reason
+    UnsupportedDiscFormat(String),
+    /// A cheat code line that isn't a supported GameShark code. Holds the line
+    InvalidCheat(String),
+}
+
+impl EmuError {
+    pub(crate) fn from_io(path: PathBuf, error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::NotFound => EmuError::FileNotFound(path),
+            kind => EmuError::Io(path, kind),
+        }
+    }
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmuError::BiosWrongSize(size) => write!(
+                f,
+                "BIOS image is {} bytes, expected {} (512KB). Is this a PS1 bios dump?",
+                size, BIOS_SIZE
+            ),
+            EmuError::FileNotFound(path) => write!(f, "File not found: {}", path.display()),
+            EmuError::Io(path, kind) => write!(f, "Unable to read {}: {:?}", path.display(), kind),
+            EmuError::MemoryCardWrongSize(size) => {
+                write!(f, "Memory card image is {} bytes, expected {} (128KB)", size, 128 * 1024)
+            }
+            EmuError::UnsupportedDiscFormat(reason) => write!(f, "Unsupported disc format: {}", reason),
+            EmuError::InvalidCheat(line) => write!(f, "Invalid cheat code: {}", line),
+        }
+    }
+}
+
+impl std::error::Error for EmuError {}
+
+/// PSXEmu::run_to_pc or run_until_break used up its cycle budget without stopping
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeoutError {
+    /// Where the pc ended up instead
+    pub pc: u32,
+    /// Cycles that were run
+    pub cycles: u64,
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Didn't stop within {} cycles. Gave up at {:#X}", self.cycles, self.pc)
+    }
+}
+
+impl std::error::Error for TimeoutError {}
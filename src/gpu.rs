@@ -1,10 +1,23 @@
 use bit_field::BitField;
-use log::{error, trace};
+use log::{debug, trace, warn};
 
-const H_RES: u32 = H_BLANK_START + 20;
-const V_RES: u32 = V_BLANK_START + 40;
-const H_BLANK_START: u32 = 640;
-const V_BLANK_START: u32 = 480;
+use crate::color::{self, bgr555_to_rgba8, command_color_to_bgr555};
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+const LOG_TARGET: &str = "vaporstation::gpu";
+
+//Video timings from psx-spx. Scanlines are in gpu cycles
+const H_RES: u32 = 3413;
+const H_RES_PAL: u32 = 3406;
+const V_RES: u32 = 263;
+const V_RES_PAL: u32 = 314;
+//Each scanline ends in hblank, and vblank starts after the visible lines
+const H_BLANK_START: u32 = 2560;
+const V_BLANK_START: u32 = 240;
+const V_BLANK_START_PAL: u32 = 288;
+
+const FNV_OFFSET_BASIS: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01B3;
 
 #[derive(Copy, Clone, Debug)]
 enum TextureColorMode {
@@ -13,6 +26,84 @@ enum TextureColorMode {
     FifteenBit,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum VideoStandard {
+    Ntsc,
+    Pal,
+}
+
+impl VideoStandard {
+    pub fn refresh_rate(&self) -> f64 {
+        match self {
+            VideoStandard::Ntsc => 59.94,
+            VideoStandard::Pal => 50.0,
+        }
+    }
+
+    fn cycles_per_line(&self) -> u32 {
+        match self {
+            VideoStandard::Ntsc => H_RES,
+            VideoStandard::Pal => H_RES_PAL,
+        }
+    }
+
+    fn lines_per_frame(&self) -> u32 {
+        match self {
+            VideoStandard::Ntsc => V_RES,
+            VideoStandard::Pal => V_RES_PAL,
+        }
+    }
+
+    fn vblank_start_line(&self) -> u32 {
+        match self {
+            VideoStandard::Ntsc => V_BLANK_START,
+            VideoStandard::Pal => V_BLANK_START_PAL,
+        }
+    }
+}
+
+/// Video timing edges crossed while the gpu runs. Each holds the gpu cycle it happened on, counted from the start of the span
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimingEvent {
+    /// A scanline entered hblank
+    HBlank(u32),
+    /// The frame entered vblank
+    VBlank(u32),
+    /// The frame wrapped around to the next field
+    Field(u32),
+}
+
+pub const VRAM_WIDTH: u32 = 1024;
+pub const VRAM_HEIGHT: u32 = 512;
+
+//Upscaled buffers get big quickly, 4x is already 16MB
+const MAX_UPSCALE: u32 = 4;
+
+/// Read only view of vram, which is 1024x512 halfwords of BGR555 with the mask bit on top, or of the
+/// upscaled framebuffer, which is the same at a multiple of the size. Coordinates wrap around the edges like they do for the gpu
+pub struct VramView<'a> {
+    vram: &'a [u16],
+    width: u32,
+    height: u32,
+}
+
+impl VramView<'_> {
+    /// Width and height in halfwords
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// The raw halfword at x, y
+    pub fn get_pixel(&self, x: u32, y: u32) -> u16 {
+        self.vram[((y % self.height) * self.width + x % self.width) as usize]
+    }
+
+    /// The pixel at x, y as 8 bit per channel color. Alpha is always opaque, the mask bit isn't transparency
+    pub fn get_pixel_rgba(&self, x: u32, y: u32) -> [u8; 4] {
+        bgr555_to_rgba8(self.get_pixel(x, y))
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Resolution {
     pub height: u32,
@@ -39,23 +130,22 @@ impl Point {
         }
     }
 
-    fn from_word_with_offset(word: u32, color: u16, offset: Point) -> Self {
+    fn from_components(x: i16, y: i16, color: u16) -> Self {
         Self {
-            x: ((word & 0xFFFF) as i32 + offset.x as i32) as i16,
-            y: (((word >> 16) & 0xFFFF) as i32 + offset.y as i32) as i16,
+            x,
+            y,
             color,
             tex_x: 0,
             tex_y: 0,
         }
     }
 
-    fn from_components(x: i16, y: i16, color: u16) -> Self {
+    ///Same point at scale times the resolution. Texture coordinates stay the same, they're in native vram
+    fn scaled(&self, scale: i16) -> Self {
         Self {
-            x,
-            y,
-            color,
-            tex_x: 0,
-            tex_y: 0,
+            x: self.x.wrapping_mul(scale),
+            y: self.y.wrapping_mul(scale),
+            ..*self
         }
     }
 
@@ -70,10 +160,97 @@ impl Point {
     }
 }
 
+///A drawing command as the gpu received it, kept for debug exports
+#[derive(Clone, Debug)]
+struct RecordedCommand {
+    words: Vec<u32>,
+    draw_offset: (i16, i16),
+}
+
+/// A command sent to one of the gpu's ports, tagged with its command byte and what it does.
+/// GP0 commands are kept whole, starting with the command word and followed by their parameters
+#[derive(Clone, Debug, PartialEq)]
+pub enum GpuCommand {
+    Gp0 { opcode: u8, name: &'static str, words: Vec<u32> },
+    Gp1 { opcode: u8, name: &'static str, word: u32 },
+}
+
+impl GpuCommand {
+    fn gp0(words: Vec<u32>) -> Self {
+        let command = words[0];
+        let name = match command.gp0_header() {
+            0x0 => match command.command() {
+                0x01 => "Clear cache",
+                0x02 => "Fill rectangle",
+                0x1F => "Interrupt request",
+                _ => "NOP",
+            },
+            0x1 => match (command.get_bit(28), command.get_bit(26), command.get_bit(27)) {
+                (false, false, false) => "Flat triangle",
+                (false, false, true) => "Flat quad",
+                (false, true, false) => "Textured triangle",
+                (false, true, true) => "Textured quad",
+                (true, false, false) => "Gouraud triangle",
+                (true, false, true) => "Gouraud quad",
+                (true, true, false) => "Gouraud textured triangle",
+                (true, true, true) => "Gouraud textured quad",
+            },
+            0x2 => match (command.get_bit(28), command.get_bit(27)) {
+                (false, false) => "Line",
+                (false, true) => "Polyline",
+                (true, false) => "Gouraud line",
+                (true, true) => "Gouraud polyline",
+            },
+            0x3 => match (command.get_bit(26), (command >> 27) & 0x3) {
+                (false, 0) => "Rectangle",
+                (false, 1) => "Dot",
+                (false, 2) => "8x8 rectangle",
+                (false, _) => "16x16 rectangle",
+                (true, 0) => "Sprite",
+                (true, 1) => "1x1 sprite",
+                (true, 2) => "8x8 sprite",
+                (true, _) => "16x16 sprite",
+            },
+            0x4 => "VRAM to VRAM copy",
+            0x5 => "CPU to VRAM transfer",
+            0x6 => "VRAM to CPU transfer",
+            _ => match command.command() {
+                0xE1 => "Draw mode",
+                0xE2 => "Texture window",
+                0xE3 => "Draw area top left",
+                0xE4 => "Draw area bottom right",
+                0xE5 => "Draw offset",
+                0xE6 => "Mask bit setting",
+                _ => "Unknown",
+            },
+        };
+        GpuCommand::Gp0 { opcode: command.command(), name, words }
+    }
+
+    fn gp1(word: u32) -> Self {
+        let name = match word.command() {
+            0x00 => "Reset",
+            0x01 => "Reset command buffer",
+            0x02 => "Acknowledge interrupt",
+            0x03 => "Display enable",
+            0x04 => "DMA direction",
+            0x05 => "Display area start",
+            0x06 => "Horizontal display range",
+            0x07 => "Vertical display range",
+            0x08 => "Display mode",
+            0x10..=0x1F => "Get gpu information",
+            _ => "Unknown",
+        };
+        GpuCommand::Gp1 { opcode: word.command(), name, word }
+    }
+}
+
 pub struct Gpu {
     vram: Vec<u16>,
     status_reg: u32,
     pixel_count: u32,
+    //Gpu cycles that haven't made up a whole dot yet
+    dot_remainder: u32,
     enabled: bool,
     gp0_buffer: Vec<u32>,
 
@@ -88,14 +265,39 @@ pub struct Gpu {
     draw_area_tl_point: Point,
     draw_area_br_point: Point,
     draw_offset: Point,
+    set_mask: bool,
+    check_mask: bool,
+    texture_window: u32,
+    //Last value put on GPUREAD. Reads return it again while nothing new is pending
+    gpuread_latch: u32,
 
     irq_fired: bool,
-    vblank_consumed: bool,
-    hblank_consumed: bool,
     show_frame: bool,
 
     display_h_res: u32,
     display_v_res: u32,
+    //Top left of the displayed area in vram, set with GP1(05h)
+    display_start_x: u32,
+    display_start_y: u32,
+    interlaced: bool,
+    odd_field: bool,
+    video_standard: VideoStandard,
+    forced_video_standard: Option<VideoStandard>,
+
+    frame_count: u32,
+    ignored_commands: u32,
+
+    //Drawing commands recorded during the current and last finished frame
+    frame_commands: Vec<RecordedCommand>,
+    last_frame_commands: Vec<RecordedCommand>,
+    //Every GP0/GP1 word since tracing was turned on
+    trace: Option<Vec<GpuCommand>>,
+
+    //Internal resolution multiplier, and the buffer primitives are drawn into at that resolution
+    upscale: u32,
+    upscaled_vram: Vec<u16>,
+    //Set while the rasterizer is drawing into upscaled_vram instead of vram
+    drawing_upscaled: bool,
 }
 
 impl Gpu {
@@ -104,6 +306,7 @@ impl Gpu {
             vram: vec![0; 1_048_576 / 2],
             status_reg: 0x1C000000,
             pixel_count: 0,
+            dot_remainder: 0,
             enabled: false,
             gp0_buffer: Vec::new(),
 
@@ -119,25 +322,47 @@ impl Gpu {
             draw_area_br_point: Point::from_components(0, 0, 0),
 
             draw_offset: Point::from_components(0, 0, 0),
+            set_mask: false,
+            check_mask: false,
+            texture_window: 0,
+            gpuread_latch: 0,
             irq_fired: false,
-            vblank_consumed: false,
-            hblank_consumed: false,
             show_frame: false,
 
             display_h_res: 640,
             display_v_res: 480,
+            display_start_x: 0,
+            display_start_y: 0,
+            interlaced: false,
+            odd_field: false,
+            video_standard: VideoStandard::Ntsc,
+            forced_video_standard: None,
+
+            frame_count: 0,
+            ignored_commands: 0,
+
+            frame_commands: Vec::new(),
+            last_frame_commands: Vec::new(),
+            trace: None,
+
+            upscale: 1,
+            upscaled_vram: Vec::new(),
+            drawing_upscaled: false,
         }
     }
 
     //Only reseting the big stuff. This will probably bite me later
     pub fn reset(&mut self) {
         self.vram = vec![0; 1_048_576 / 2];
+        self.sync_upscaled(0, 0, VRAM_WIDTH, VRAM_HEIGHT);
         self.status_reg = 0x1C000000;
         self.gp0_buffer = Vec::new();
+        self.frame_commands.clear();
+        self.last_frame_commands.clear();
     }
 
     pub fn read_status_register(&mut self) -> u32 {
-        println!("Reading GPUSTAT");
+        trace!(target: LOG_TARGET, "Reading GPUSTAT");
         let mut stat: u32 = 0;
 
         stat |= (self.texpage_x_base) as u32;
@@ -149,15 +374,26 @@ impl Gpu {
             TextureColorMode::FifteenBit => 2,
         } << 7;
 
+        stat |= (self.set_mask as u32) << 11;
+        stat |= (self.check_mask as u32) << 12;
+
         stat |= 0x1C000000;
 
+        if self.odd_line() {
+            stat |= 1 << 31;
+        }
 
         stat
     }
 
     pub fn read_word_gp0(&mut self) -> u32 {
-        println!("Reading gp0");
-        0x0 as u32
+        //VRAM to CPU transfers aren't implemented, so this is always the latched value
+        self.gpuread_latch
+    }
+
+    /// The last value put on GPUREAD, without reading the port
+    pub(crate) fn gpuread_latch(&self) -> u32 {
+        self.gpuread_latch
     }
 
     pub fn send_gp0_command(&mut self, value: u32) {
@@ -180,14 +416,20 @@ impl Gpu {
                         let y1 = (self.gp0_buffer[1] >> 16) & 0xFFFF;
                         let x2 = ((self.gp0_buffer[2] & 0xFFFF) + x1);
                         let y2 = (((self.gp0_buffer[2] >> 16) & 0xFFFF) + y1);
-                        self.draw_solid_box(
-                            x1,
-                            y1,
-                            x2,
-                            y2,
-                            b24color_to_b15color(self.gp0_buffer[0] & 0x1FFFFFF) + 1,
-                            false,
-                        );
+                        let fill = command_color_to_bgr555(self.gp0_buffer[0]) + 1;
+                        //Quick fills ignore the mask settings
+                        let mask = (self.set_mask, self.check_mask);
+                        self.set_mask = false;
+                        self.check_mask = false;
+                        self.draw_at_each_scale(|gpu, scale| {
+                            let scale = scale as u32;
+                            gpu.draw_solid_box(x1 * scale, y1 * scale, x2 * scale, y2 * scale, fill, false)
+                        });
+                        (self.set_mask, self.check_mask) = mask;
+                    }
+                    0x1F => {
+                        warn!(target: LOG_TARGET, "GPU IRQ requested, but it isn't emulated");
+                        self.ignored_commands += 1;
                     }
                     _ => {
                         //NOP
@@ -197,9 +439,7 @@ impl Gpu {
 
             0x1 => {
                 //Render Polygon
-
-                // If the polygon is textured or gouraud shaded, lets just lock up the emulator.
-                // I only want to test flat shaded polygons right now
+                //Texture blended polygons aren't drawn yet. They're skipped and counted as ignored
 
                 let is_gouraud = command.get_bit(28);
                 let is_textured = command.get_bit(26);
@@ -216,13 +456,14 @@ impl Gpu {
                     return;
                 }
 
-                let fill = b24color_to_b15color(self.gp0_buffer[0] & 0x1FFFFFF);
+                let fill = command_color_to_bgr555(self.gp0_buffer[0]);
                 self.blend_enabled = self.gp0_buffer[0].get_bit(24);
                 self.blend_color = fill;
                 if is_quad {
                     if is_textured && is_gouraud {
                         //Should be blending in colors. Do that later
-                        //println!("Tried to try draw texture blended quad!");
+                        debug!(target: LOG_TARGET, "Skipping texture blended quad");
+                        self.ignored_commands += 1;
                     } else if is_textured {
                         //println!("GPU: Tex quad");
                         let points: Vec<Point> = vec![
@@ -254,25 +495,25 @@ impl Gpu {
                         self.texpage_y_base = ((self.gp0_buffer[4] >> 20) & 0x1) as u16;
                         self.blend_color = fill;
 
-                        self.draw_textured_quad(&points, command.get_bit(25));
+                        self.draw_at_each_scale(|gpu, scale| gpu.draw_textured_quad(&scale_points(&points, scale), command.get_bit(25)));
                     } else if is_gouraud {
                         //println!("GPU: gouraud quad");
                         let points: Vec<Point> = vec![
                             Point::from_word(self.gp0_buffer[1], fill),
                             Point::from_word(
                                 self.gp0_buffer[3],
-                                b24color_to_b15color(self.gp0_buffer[2]),
+                                command_color_to_bgr555(self.gp0_buffer[2]),
                             ),
                             Point::from_word(
                                 self.gp0_buffer[5],
-                                b24color_to_b15color(self.gp0_buffer[4]),
+                                command_color_to_bgr555(self.gp0_buffer[4]),
                             ),
                             Point::from_word(
                                 self.gp0_buffer[7],
-                                b24color_to_b15color(self.gp0_buffer[6]),
+                                command_color_to_bgr555(self.gp0_buffer[6]),
                             ),
                         ];
-                        self.draw_shaded_quad(&points, command.get_bit(25));
+                        self.draw_at_each_scale(|gpu, scale| gpu.draw_shaded_quad(&scale_points(&points, scale), command.get_bit(25)));
                     } else {
                         let points: Vec<Point> = vec![
                             Point::from_word(self.gp0_buffer[1], 0),
@@ -280,11 +521,12 @@ impl Gpu {
                             Point::from_word(self.gp0_buffer[3], 0),
                             Point::from_word(self.gp0_buffer[4], 0),
                         ];
-                        self.draw_solid_quad(&points, fill, command.get_bit(25));
+                        self.draw_at_each_scale(|gpu, scale| gpu.draw_solid_quad(&scale_points(&points, scale), fill, command.get_bit(25)));
                     };
                 } else {
                     if is_gouraud && is_textured {
-                        //println!("Tried to try draw texture blended tri! Queue {:?}", self.gp0_buffer);
+                        debug!(target: LOG_TARGET, "Skipping texture blended tri. Queue {:?}", self.gp0_buffer);
+                        self.ignored_commands += 1;
                     } else if is_textured {
                         //println!("GPU: Tex tri");
                         let points: Vec<Point> = vec![
@@ -316,29 +558,29 @@ impl Gpu {
                         //     fill
                         // };
                         self.blend_color = fill;
-                        self.draw_textured_triangle(&points, command.get_bit(25));
+                        self.draw_at_each_scale(|gpu, scale| gpu.draw_textured_triangle(&scale_points(&points, scale), command.get_bit(25)));
                     } else if is_gouraud {
                         //println!("GPU: gouraud tri");
                         let points: Vec<Point> = vec![
                             Point::from_word(self.gp0_buffer[1], fill),
                             Point::from_word(
                                 self.gp0_buffer[3],
-                                b24color_to_b15color(self.gp0_buffer[2]),
+                                command_color_to_bgr555(self.gp0_buffer[2]),
                             ),
                             Point::from_word(
                                 self.gp0_buffer[5],
-                                b24color_to_b15color(self.gp0_buffer[4]),
+                                command_color_to_bgr555(self.gp0_buffer[4]),
                             ),
                         ];
                         ////println!("{:?}", points);
-                        self.draw_shaded_triangle(&points, command.get_bit(25));
+                        self.draw_at_each_scale(|gpu, scale| gpu.draw_shaded_triangle(&scale_points(&points, scale), command.get_bit(25)));
                     } else {
                         let points: Vec<Point> = vec![
                             Point::from_word(self.gp0_buffer[1], 0),
                             Point::from_word(self.gp0_buffer[2], 0),
                             Point::from_word(self.gp0_buffer[3], 0),
                         ];
-                        self.draw_solid_triangle(&points, fill, command.get_bit(25));
+                        self.draw_at_each_scale(|gpu, scale| gpu.draw_solid_triangle(&scale_points(&points, scale), fill, command.get_bit(25)));
                     }
                 }
             }
@@ -352,6 +594,7 @@ impl Gpu {
                         return;
                     }
                     //TODO draw polyline
+                    self.ignored_commands += 1;
                 } else {
                     if self.gp0_buffer.len() < (3 + if command.get_bit(28) { 2 } else { 0 }) {
                         //Not enough commands
@@ -359,6 +602,7 @@ impl Gpu {
                     }
 
                     //TODO draw line
+                    self.ignored_commands += 1;
                 }
             }
 
@@ -378,121 +622,56 @@ impl Gpu {
                 match size {
                     0b01 => {
                         //println!("GPU: Single point");
-                        //Draw single pixel
+                        //Draw single pixel. The offset can push it past the edge of vram, where it wraps around
                         let point = Point::from_word(self.gp0_buffer[1], 0);
+                        let x = (point.x as i32 + self.draw_offset.x as i32).rem_euclid(VRAM_WIDTH as i32);
+                        let y = (point.y as i32 + self.draw_offset.y as i32).rem_euclid(VRAM_HEIGHT as i32);
 
-                        let address = point_to_address(point.x as u32, point.y as u32) as usize;
-                        let color = if command.get_bit(25) {
-                            //Transparent
-                            alpha_composite(
-                                self.vram[address],
-                                b24color_to_b15color(self.gp0_buffer[0] & 0x1FFFFFF),
-                            )
-                        } else {
-                            b24color_to_b15color(self.gp0_buffer[0] & 0x1FFFFFF)
-                        };
-                        self.vram[address] = color;
+                        self.plot(x as u32, y as u32, command_color_to_bgr555(self.gp0_buffer[0]), command.get_bit(25));
+                        self.sync_upscaled(x as u32, y as u32, 1, 1);
                     }
 
-                    0b0 => {
-                        //Draw variable size
+                    _ => {
+                        //Variable size rectangles give their size in the last word. 8x8 and 16x16 sprites don't have one.
+                        //The box covers exactly width x height pixels from the top left
+                        let size = match size {
+                            0b10 => Point::from_components(8, 8, 0),
+                            0b11 => Point::from_components(16, 16, 0),
+                            _ => rectangle_size(self.gp0_buffer[length - 1]),
+                        };
+                        let offset = self.draw_offset;
                         if command.get_bit(26) {
-                            //println!("GPU: Tex box");
-                            let tl_point = Point::new_textured_point(
+                            let mut tl_point = Point::new_textured_point(
                                 self.gp0_buffer[1],
                                 ((self.gp0_buffer[2] >> 8) & 0xFF) as i16,
                                 (self.gp0_buffer[2] & 0xFF) as i16,
                             );
-
-                            let size = Point::from_word(self.gp0_buffer[3], 0);
+                            tl_point.x = tl_point.x.wrapping_add(offset.x);
+                            tl_point.y = tl_point.y.wrapping_add(offset.y);
 
                             self.palette_x = ((self.gp0_buffer[2] >> 16) & 0x3F) as u16;
                             self.palette_y = ((self.gp0_buffer[2] >> 22) & 0x1FF) as u16;
 
-                            self.draw_textured_box(&tl_point, size.x, size.y, command.get_bit(25));
+                            self.draw_at_each_scale(|gpu, scale| {
+                                gpu.draw_textured_box(&tl_point.scaled(scale), size.x * scale, size.y * scale, command.get_bit(25))
+                            });
                         } else {
-                            //println!("GPU: solid box");
                             let tl_point = Point::from_word(self.gp0_buffer[1], 0);
-                            let br_point =
-                                Point::from_word_with_offset(self.gp0_buffer[2], 0, tl_point);
-
-                            //println!("tl: {:?} br: {:?}", tl_point, br_point);
-
-                            self.draw_solid_box(
-                                (tl_point.x + self.draw_offset.x) as u32,
-                                (tl_point.y + self.draw_offset.y) as u32,
-                                (br_point.x + self.draw_offset.x) as u32,
-                                (br_point.y + self.draw_offset.y) as u32,
-                                b24color_to_b15color(self.gp0_buffer[0] & 0x1FFFFFF),
-                                command.get_bit(25),
-                            );
-                        }
-                    }
-
-                    0b10 => {
-                        //8x8 sprite
-                        //println!("GPU: 8x8 sprite");
-                        if command.get_bit(26) {
-                            let tl_point = Point::new_textured_point(
-                                self.gp0_buffer[1],
-                                ((self.gp0_buffer[2] >> 8) & 0xFF) as i16,
-                                (self.gp0_buffer[2] & 0xFF) as i16,
-                            );
-
-                            let size = Point::from_components(8, 8, 0);
-
-                            self.palette_x = ((self.gp0_buffer[2] >> 16) & 0x3F) as u16;
-                            self.palette_y = ((self.gp0_buffer[2] >> 22) & 0x1FF) as u16;
-
-                            self.draw_textured_box(&tl_point, size.x, size.y, command.get_bit(25));
-                        } else {
-                            let x1 = self.gp0_buffer[1] & 0xFFFF;
-                            let y1 = (self.gp0_buffer[1] >> 16) & 0xFFFF;
-                            self.draw_solid_box(
-                                x1,
-                                y1,
-                                x1 + 8,
-                                y1 + 8,
-                                b24color_to_b15color(self.gp0_buffer[0] & 0x1FFFFFF),
-                                command.get_bit(25),
-                            );
-                        }
-                    }
-
-                    0b11 => {
-                        //16x16 sprite
-                        //println!("GPU: 16x16 sprite");
-                        if command.get_bit(26) {
-                            let tl_point = Point::new_textured_point(
-                                self.gp0_buffer[1],
-                                ((self.gp0_buffer[2] >> 8) & 0xFF) as i16,
-                                (self.gp0_buffer[2] & 0xFF) as i16,
-                            );
-
-                            let size = Point::from_components(16, 16, 0);
-
-                            self.palette_x = ((self.gp0_buffer[2] >> 16) & 0x3F) as u16;
-                            self.palette_y = ((self.gp0_buffer[2] >> 22) & 0x1FF) as u16;
-
-                            self.draw_textured_box(&tl_point, size.x, size.y, command.get_bit(25));
-                        } else {
-                            let x1 = self.gp0_buffer[1] & 0xFFFF;
-                            let y1 = (self.gp0_buffer[1] >> 16) & 0xFFFF;
-                            self.draw_solid_box(
-                                x1,
-                                y1,
-                                x1 + 16,
-                                y1 + 16,
-                                b24color_to_b15color(self.gp0_buffer[0] & 0x1FFFFFF),
-                                command.get_bit(25),
-                            );
+                            let x1 = tl_point.x.wrapping_add(offset.x);
+                            let y1 = tl_point.y.wrapping_add(offset.y);
+                            let fill = command_color_to_bgr555(self.gp0_buffer[0]);
+                            self.draw_at_each_scale(|gpu, scale| {
+                                gpu.draw_solid_box(
+                                    x1.wrapping_mul(scale) as u32,
+                                    y1.wrapping_mul(scale) as u32,
+                                    x1.wrapping_add(size.x).wrapping_mul(scale) as u32,
+                                    y1.wrapping_add(size.y).wrapping_mul(scale) as u32,
+                                    fill,
+                                    command.get_bit(25),
+                                )
+                            });
                         }
                     }
-
-                    _ => {
-                        //Lets do nothing with the others
-                        //println!("Invalid size rect");
-                    }
                 }
             }
 
@@ -508,17 +687,10 @@ impl Gpu {
                 let y_source = (self.gp0_buffer[1] >> 16) & 0xFFFF;
                 let x_dest = self.gp0_buffer[2] & 0xFFFF;
                 let y_dest = (self.gp0_buffer[2] >> 16) & 0xFFFF;
-                let mut width = self.gp0_buffer[3] & 0xFFFF;
-                let mut height = (self.gp0_buffer[3] >> 16) & 0xFFFF;
-
-                if width == 0 {width = 1024};
-                if height == 0 {height = 512};
-
-                if width == 0 || height == 0 {
-                    panic!("0 width or height! w {} h {}", width, height);
-                }
+                let (width, height) = transfer_size(self.gp0_buffer[3]);
 
                 self.copy_rectangle(x_source, y_source, x_dest, y_dest, width, height);
+                self.sync_upscaled(x_dest, y_dest, width, height);
             }
             0x5 => {
                 //CPU To VRAM
@@ -526,29 +698,27 @@ impl Gpu {
                     //Not enough for the header
                     return;
                 }
-                let mut width = ((self.gp0_buffer[2] & 0xFFFF) as u16);
-                let mut height = (((self.gp0_buffer[2] >> 16) & 0xFFFF) as u16);
-                if width == 0 {width = 1024};
-                if height == 0 {height = 512};
-                let length = (((width) * height + if width % 2 != 0 {1} else {0}) / 2) + 3;
-                if self.gp0_buffer.len() < length as usize {
+                let (width, height) = transfer_size(self.gp0_buffer[2]);
+                let pixel_count = width * height;
+                let length = pixel_count.div_ceil(2) as usize + 3;
+                if self.gp0_buffer.len() < length {
                     //Not enough commands
                     return;
                 }
 
-                let base_x = ((self.gp0_buffer[1] & 0xFFFF) as u16);
-                let base_y = ((self.gp0_buffer[1] >> 16) & 0xFFFF) as u16;
-
-
-                for index in 3..(length) {
-                    let p2 = ((self.gp0_buffer[index as usize] >> 16) & 0xFFFF) as u16;
-                    let p1 = (self.gp0_buffer[index as usize] & 0xFFFF) as u16;
-                    let x = base_x + (((index - 3) * 2) % (width));
-                    let y = base_y + (((index - 3) * 2) / (width));
-                    let addr = point_to_address(x as u32, y as u32);
-                    self.vram[addr as usize] = p1;
-                    self.vram[(addr + 1) as usize] = p2;
+                let base_x = self.gp0_buffer[1] & 0xFFFF;
+                let base_y = (self.gp0_buffer[1] >> 16) & 0xFFFF;
+
+                //Pixels are streamed row by row, so with an odd width a word can be split across two rows.
+                //This is common for 24 bit images, where the width in halfwords is 1.5x the width in pixels
+                for pixel in 0..pixel_count {
+                    let word = self.gp0_buffer[3 + (pixel / 2) as usize];
+                    let value = (word >> ((pixel % 2) * 16)) as u16;
+                    let x = (base_x + (pixel % width)) % 1024;
+                    let y = (base_y + (pixel / width)) % 512;
+                    self.vram[point_to_address(x, y) as usize] = value;
                 }
+                self.sync_upscaled(base_x, base_y, width, height);
             }
 
             0x6 => {
@@ -558,13 +728,10 @@ impl Gpu {
                 }
 
                 let width = (self.gp0_buffer[2] & 0xFFFF) as u16;
-                let height = (((self.gp0_buffer[2] >> 16) & 0xFFFF) as u16) * 2;
-
-                if width == 0 || height == 0 {
-                    panic!("0 width or height! w {} h {}", width, height);
-                }
-                trace!("VRAM to CPU")
+                let height = ((self.gp0_buffer[2] >> 16) & 0xFFFF) as u16;
+                debug!(target: LOG_TARGET, "Ignoring VRAM to CPU transfer of {}x{}", width, height);
                 //Lets ignore this one for now
+                self.ignored_commands += 1;
             }
             0x7 => {
                 //Env commands
@@ -578,7 +745,7 @@ impl Gpu {
                             1 => TextureColorMode::EightBit,
                             2 => TextureColorMode::FifteenBit,
                             3 => TextureColorMode::FifteenBit, // This one is FifteenBit, for some reason
-                            _ => panic!("Unknown texture color mode {}", (command >> 7) & 0x3),
+                            _ => unreachable!(),
                         };
                     }
 
@@ -602,51 +769,64 @@ impl Gpu {
 
                     0xE2 => {
                         //Texture window area
-                        //Not needed rn
-                        //println!("GP0 command E2 not implemented!");
+                        //Only kept for GP1(10h). Not needed for drawing rn
+                        self.texture_window = command & 0xFFFFF;
                     }
 
                     0xE5 => {
-                        //Set Drawing Offset
-                        let x = (command & 0x7FF) as i16;
-                        let y = ((command >> 11) & 0x7FF) as i16;
+                        //Set Drawing Offset. Both are signed 11 bit, so 0x400 is -1024
+                        let x = sign_extend_11(command & 0x7FF);
+                        let y = sign_extend_11((command >> 11) & 0x7FF);
                         self.draw_offset = Point::from_components(x, y, 0);
                     }
 
                     0xE6 => {
-                        //Mask bit
-                        //Also no needed
-                        //println!("GP0 command E6 not implemented!");
+                        //Mask bit setting
+                        self.set_mask = command.get_bit(0);
+                        self.check_mask = command.get_bit(1);
                     }
 
                     
-                    _ => error!(
-                        "Unknown GPU ENV command {:#X}. Full command queue is {:#X}",
-                        command.command(),
-                        self.gp0_buffer[0]
-                    ),
+                    _ => {
+                        warn!(
+                            target: LOG_TARGET,
+                            "Unknown GPU ENV command {:#X}. Full command queue is {:#X}",
+                            command.command(),
+                            self.gp0_buffer[0]
+                        );
+                        self.ignored_commands += 1;
+                    }
                 }
             }
 
-            0x1F => {
-                panic!("GPU IRQ requested!");
+            _ => {
+                warn!(target: LOG_TARGET, "Unknown GP0 command {:#X}!", command.command());
+                self.ignored_commands += 1;
             }
-
-            _ => error!("unknown gp0 {:#X}!", command.gp0_header()),
         }
         //Made it to the end, so the command must have been executed
+        if let Some(trace) = &mut self.trace {
+            trace.push(GpuCommand::gp0(self.gp0_buffer.clone()));
+        }
+        self.record_command();
         self.gp0_clear();
     }
 
     pub fn send_gp1_command(&mut self, command: u32) {
-        //println!("GP1 Command {:#X} parameter {:#X}", command.command(), command.parameter());
+        if let Some(trace) = &mut self.trace {
+            trace.push(GpuCommand::gp1(command));
+        }
+        trace!(target: LOG_TARGET, "GP1 command {:#X} parameter {:#X}", command.command(), command.parameter());
         match command.command() {
             0x0 => {
                 //Reset GPU
                 self.enabled = false;
                 self.status_reg = 0;
                 self.pixel_count = 0;
+                self.display_start_x = 0;
+                self.display_start_y = 0;
                 self.vram = vec![0; 1_048_576 / 2];
+                self.sync_upscaled(0, 0, VRAM_WIDTH, VRAM_HEIGHT);
             }
 
             0x1 => {
@@ -658,6 +838,12 @@ impl Gpu {
                 self.show_frame = true;
             }
 
+            0x5 => {
+                //Start of display area
+                self.display_start_x = command.get_bits(0..=9);
+                self.display_start_y = command.get_bits(10..=18);
+            }
+
             0x6 => {
                 //Horizontal Display Range
                 //Ignore this one for now
@@ -684,39 +870,143 @@ impl Gpu {
                 } else {
                     240
                 };
+                self.interlaced = command.get_bit(5);
+                self.video_standard = if command.get_bit(3) {
+                    VideoStandard::Pal
+                } else {
+                    VideoStandard::Ntsc
+                };
             }
 
             0x10 => {
                 //Get gpu information
-                //Ignoring this too
+                //Puts the requested value in GPUREAD. Unknown indices leave the old value there
+                match command & 0x7 {
+                    0x2 => self.gpuread_latch = self.texture_window,
+                    0x3 => self.gpuread_latch = self.draw_area_word(&self.draw_area_tl_point),
+                    0x4 => self.gpuread_latch = self.draw_area_word(&self.draw_area_br_point),
+                    0x5 => {
+                        self.gpuread_latch = (self.draw_offset.x as u32 & 0x7FF)
+                            | ((self.draw_offset.y as u32 & 0x7FF) << 11)
+                    }
+                    0x7 => self.gpuread_latch = 2, //GPU version
+                    _ => (),
+                }
+            }
+            _ => {
+                warn!(
+                    target: LOG_TARGET,
+                    "Unknown GP1 command {:#X} parameter {:#X}!",
+                    command.command(),
+                    command.parameter()
+                );
+                self.ignored_commands += 1;
+            }
+        }
+    }
+
+    /// Runs the gpu for the given number of cycles and returns the timing edges it crossed, in order
+    pub fn timing_events(&mut self, cycles: u32) -> impl Iterator<Item = TimingEvent> {
+        let mut events = Vec::new();
+        for offset in 0..cycles {
+            let was_hblank = self.is_hblank();
+            let was_vblank = self.is_vblank();
+            let field = self.odd_field;
+            self.execute_cycle();
+            if !was_hblank && self.is_hblank() {
+                events.push(TimingEvent::HBlank(offset));
+            }
+            if !was_vblank && self.is_vblank() {
+                events.push(TimingEvent::VBlank(offset));
+            }
+            if field != self.odd_field {
+                events.push(TimingEvent::Field(offset));
             }
-            _ => error!(
-                "Unknown gp1 command {:#X} parameter {}!",
-                command.command(),
-                command.parameter()
-            ),
         }
+        events.into_iter()
     }
 
     pub fn execute_cycle(&mut self) {
         self.pixel_count += 1;
 
-        if self.pixel_count % H_RES == 0 {
-            self.hblank_consumed = false;
+        if self.pixel_count == self.vblank_start() + 1 {
+            //Just crossed into vblank, so the frame is done
+            self.frame_count = self.frame_count.wrapping_add(1);
+            self.last_frame_commands = std::mem::take(&mut self.frame_commands);
         }
 
-        if self.pixel_count > H_RES * V_RES {
+        if self.pixel_count > self.cycles_per_frame() {
             self.pixel_count = 0;
-            self.vblank_consumed = false;
+            self.odd_field = !self.odd_field;
         }
     }
 
+    ///Drawing area corner packed the way GP0(E3h)/GP0(E4h) take it
+    fn draw_area_word(&self, point: &Point) -> u32 {
+        (point.x as u32 & 0x3FF) | ((point.y as u32 & 0x1FF) << 10)
+    }
+
+    ///GPUSTAT bit 31. Always 0 during vblank. Interlaced modes report the current field,
+    ///progressive modes report the parity of the scanline being drawn
+    fn odd_line(&self) -> bool {
+        if self.is_vblank() {
+            false
+        } else if self.interlaced {
+            self.odd_field
+        } else {
+            (self.pixel_count / self.cycles_per_line()) % 2 == 1
+        }
+    }
+
+    ///Video standard used for frame timing. A forced standard wins over the one the game set with GP1(08h)
+    pub fn video_standard(&self) -> VideoStandard {
+        self.forced_video_standard.unwrap_or(self.video_standard)
+    }
+
+    pub fn force_video_standard(&mut self, standard: Option<VideoStandard>) {
+        self.forced_video_standard = standard;
+    }
+
+    fn cycles_per_line(&self) -> u32 {
+        self.video_standard().cycles_per_line()
+    }
+
+    ///Number of gpu cycles in a full frame, including vblank
+    pub fn cycles_per_frame(&self) -> u32 {
+        self.cycles_per_line() * self.video_standard().lines_per_frame()
+    }
+
+    ///Gpu cycle within the frame where vblank begins
+    fn vblank_start(&self) -> u32 {
+        self.cycles_per_line() * self.video_standard().vblank_start_line()
+    }
+
     pub fn is_vblank(&self) -> bool {
-        self.pixel_count > H_RES * V_BLANK_START
+        self.pixel_count > self.vblank_start()
     }
 
     pub fn is_hblank(&self) -> bool {
-        self.pixel_count % H_RES > H_BLANK_START
+        self.pixel_count % self.cycles_per_line() > H_BLANK_START
+    }
+
+    ///Gpu cycles per dot at the current horizontal resolution
+    fn dot_clock_divider(&self) -> u32 {
+        match self.display_h_res {
+            256 => 10,
+            320 => 8,
+            368 => 7,
+            512 => 5,
+            _ => 4,
+        }
+    }
+
+    ///Dots drawn in the given number of gpu cycles. Timer 0 can count these
+    pub fn dot_clock_ticks(&mut self, cycles: u32) -> u32 {
+        let divider = self.dot_clock_divider();
+        self.dot_remainder += cycles;
+        let dots = self.dot_remainder / divider;
+        self.dot_remainder %= divider;
+        dots
     }
 
     pub fn resolution(&self) -> Resolution {
@@ -726,32 +1016,223 @@ impl Gpu {
         }
     }
 
-    pub fn consume_vblank(&mut self) -> bool {
-        if !self.vblank_consumed && self.is_vblank() {
-            self.vblank_consumed = true;
-            true
-        } else {
-            false
+    pub fn end_of_frame(&self) -> bool {
+        self.pixel_count == self.cycles_per_frame()
+    }
+
+    ///Reads vram as packed 24 bit color, like the display does in 24 bit mode. x is in 24 bit pixels
+    pub fn read_pixel_24(&self, x: u32, y: u32) -> (u8, u8, u8) {
+        let address = point_to_byte_address_24(x, y);
+        (
+            self.vram_byte(address),
+            self.vram_byte(address + 1),
+            self.vram_byte(address + 2),
+        )
+    }
+
+    fn vram_byte(&self, byte_address: u32) -> u8 {
+        let halfword = self.vram[((byte_address / 2) as usize) % 524288];
+        (halfword >> ((byte_address % 2) * 8)) as u8
+    }
+
+    ///Number of GP0/GP1 commands that were unknown or only partially handled
+    pub fn ignored_commands(&self) -> u32 {
+        self.ignored_commands
+    }
+
+    ///FNV-1a hash of the displayed area of vram, one halfword per dot. It's spelled out rather than using std's hasher
+    ///so the value is stable between builds. The area wraps at the edges of vram like the display does
+    pub fn display_hash(&self) -> u64 {
+        let mut hash = FNV_OFFSET_BASIS;
+        for y in 0..self.display_v_res {
+            for x in 0..self.display_h_res {
+                let pixel = self.vram[point_to_address(
+                    (self.display_start_x + x) % VRAM_WIDTH,
+                    (self.display_start_y + y) % VRAM_HEIGHT,
+                ) as usize];
+                hash = pixel
+                    .to_le_bytes()
+                    .iter()
+                    .fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(FNV_PRIME));
+            }
         }
+        hash
     }
 
-    pub fn consume_hblank(&mut self) -> bool {
-        if !self.hblank_consumed && self.is_hblank() {
-            self.hblank_consumed = true;
-            true
-        } else {
-            false
+    ///Starts or stops recording every command the gpu runs. Stopping throws away anything not taken yet
+    pub fn set_trace(&mut self, enabled: bool) {
+        self.trace = if enabled { Some(self.trace.take().unwrap_or_default()) } else { None };
+    }
+
+    ///Commands recorded since the last take, in the order they ran. GP0 commands show up once all their words are in
+    pub fn take_trace(&mut self) -> Vec<GpuCommand> {
+        self.trace.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    ///Sends a recorded trace through the command ports again. Run it on a fresh gpu to reproduce what was drawn
+    pub fn replay(&mut self, trace: &[GpuCommand]) {
+        for command in trace {
+            match command {
+                GpuCommand::Gp0 { words, .. } => words.iter().for_each(|word| self.send_gp0_command(*word)),
+                GpuCommand::Gp1 { word, .. } => self.send_gp1_command(*word),
+            }
         }
     }
 
-    pub fn end_of_frame(&self) -> bool {
-        self.pixel_count == (self.display_h_res + 20) * (self.display_v_res + 40)
+    ///Number of vblank edges seen since power on
+    pub fn frame_count(&self) -> u32 {
+        self.frame_count
     }
 
     pub fn get_vram(&self) -> &Vec<u16> {
         &self.vram
     }
 
+    pub fn vram_view(&self) -> VramView<'_> {
+        VramView {
+            vram: &self.vram,
+            width: VRAM_WIDTH,
+            height: VRAM_HEIGHT,
+        }
+    }
+
+    ///What a frontend should display. This is the upscaled buffer when upscaling is on, otherwise vram
+    pub fn get_framebuffer(&self) -> VramView<'_> {
+        if self.upscale == 1 {
+            return self.vram_view();
+        }
+        VramView {
+            vram: &self.upscaled_vram,
+            width: VRAM_WIDTH * self.upscale,
+            height: VRAM_HEIGHT * self.upscale,
+        }
+    }
+
+    ///Draws primitives a second time at factor times the resolution, from 1 (off) up to 4.
+    ///Vram itself stays at native resolution, so transfers and texturing see what the game expects
+    pub fn set_upscale(&mut self, factor: u32) {
+        self.upscale = factor.clamp(1, MAX_UPSCALE);
+        self.upscaled_vram = if self.upscale == 1 {
+            Vec::new()
+        } else {
+            vec![0; self.vram.len() * (self.upscale * self.upscale) as usize]
+        };
+        self.sync_upscaled(0, 0, VRAM_WIDTH, VRAM_HEIGHT);
+    }
+
+    pub fn upscale(&self) -> u32 {
+        self.upscale
+    }
+
+    /// Host side settings like the upscale factor, forced video standard and command recording aren't part of the state
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16_slice(&self.vram);
+        writer.write_u32(self.status_reg);
+        writer.write_u32(self.pixel_count);
+        writer.write_u32(self.dot_remainder);
+        writer.write_bool(self.enabled);
+        writer.write_u32(self.gp0_buffer.len() as u32);
+        for word in self.gp0_buffer.iter() {
+            writer.write_u32(*word);
+        }
+
+        writer.write_u16(self.texpage_x_base);
+        writer.write_u16(self.texpage_y_base);
+        writer.write_u8(match self.texmode {
+            TextureColorMode::FourBit => 0,
+            TextureColorMode::EightBit => 1,
+            TextureColorMode::FifteenBit => 2,
+        });
+        writer.write_u16(self.palette_x);
+        writer.write_u16(self.palette_y);
+        writer.write_bool(self.blend_enabled);
+        writer.write_u16(self.blend_color);
+
+        for point in [&self.draw_area_tl_point, &self.draw_area_br_point, &self.draw_offset].iter() {
+            writer.write_u16(point.x as u16);
+            writer.write_u16(point.y as u16);
+        }
+        writer.write_bool(self.set_mask);
+        writer.write_bool(self.check_mask);
+        writer.write_u32(self.texture_window);
+        writer.write_u32(self.gpuread_latch);
+
+        writer.write_bool(self.irq_fired);
+        writer.write_bool(self.show_frame);
+        writer.write_u32(self.display_h_res);
+        writer.write_u32(self.display_v_res);
+        writer.write_u32(self.display_start_x);
+        writer.write_u32(self.display_start_y);
+        writer.write_bool(self.interlaced);
+        writer.write_bool(self.odd_field);
+        writer.write_u8(match self.video_standard {
+            VideoStandard::Ntsc => 0,
+            VideoStandard::Pal => 1,
+        });
+        writer.write_u32(self.frame_count);
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        let vram = reader.read_u16_vec()?;
+        if vram.len() != self.vram.len() {
+            return Err(SaveStateError::Corrupt);
+        }
+        self.vram = vram;
+        self.status_reg = reader.read_u32()?;
+        self.pixel_count = reader.read_u32()?;
+        self.dot_remainder = reader.read_u32()?;
+        self.enabled = reader.read_bool()?;
+        let buffered = reader.read_u32()? as usize;
+        //The longest command is a CPU to VRAM copy of all of vram, at two pixels per word
+        if buffered > (VRAM_WIDTH * VRAM_HEIGHT) as usize {
+            return Err(SaveStateError::Corrupt);
+        }
+        self.gp0_buffer.clear();
+        for _ in 0..buffered {
+            self.gp0_buffer.push(reader.read_u32()?);
+        }
+
+        self.texpage_x_base = reader.read_u16()?;
+        self.texpage_y_base = reader.read_u16()?;
+        self.texmode = match reader.read_u8()? {
+            0 => TextureColorMode::FourBit,
+            1 => TextureColorMode::EightBit,
+            2 => TextureColorMode::FifteenBit,
+            _ => return Err(SaveStateError::Corrupt),
+        };
+        self.palette_x = reader.read_u16()?;
+        self.palette_y = reader.read_u16()?;
+        self.blend_enabled = reader.read_bool()?;
+        self.blend_color = reader.read_u16()?;
+
+        for point in [&mut self.draw_area_tl_point, &mut self.draw_area_br_point, &mut self.draw_offset].iter_mut() {
+            let x = reader.read_u16()? as i16;
+            let y = reader.read_u16()? as i16;
+            **point = Point::from_components(x, y, 0);
+        }
+        self.set_mask = reader.read_bool()?;
+        self.check_mask = reader.read_bool()?;
+        self.texture_window = reader.read_u32()?;
+        self.gpuread_latch = reader.read_u32()?;
+
+        self.irq_fired = reader.read_bool()?;
+        self.show_frame = reader.read_bool()?;
+        self.display_h_res = reader.read_u32()?;
+        self.display_v_res = reader.read_u32()?;
+        self.display_start_x = reader.read_u32()? % VRAM_WIDTH;
+        self.display_start_y = reader.read_u32()? % VRAM_HEIGHT;
+        self.interlaced = reader.read_bool()?;
+        self.odd_field = reader.read_bool()?;
+        self.video_standard = match reader.read_u8()? {
+            0 => VideoStandard::Ntsc,
+            1 => VideoStandard::Pal,
+            _ => return Err(SaveStateError::Corrupt),
+        };
+        self.frame_count = reader.read_u32()?;
+        self.sync_upscaled(0, 0, VRAM_WIDTH, VRAM_HEIGHT);
+        Ok(())
+    }
+
     ///Returns irq status. If true, function will return true then clear irq status
     pub fn consume_irq(&mut self) -> bool {
         if self.irq_fired {
@@ -770,6 +1251,85 @@ impl Gpu {
         self.gp0_buffer.clear();
     }
 
+    ///Keeps a copy of the command in gp0_buffer if it draws something
+    fn record_command(&mut self) {
+        let command = self.gp0_buffer[0];
+        let is_drawing = match command.gp0_header() {
+            0x0 => command.command() == 0x2, //Quick rectangle fill
+            0x1..=0x3 => true,
+            _ => false,
+        };
+        if is_drawing {
+            self.frame_commands.push(RecordedCommand {
+                words: self.gp0_buffer.clone(),
+                draw_offset: (self.draw_offset.x, self.draw_offset.y),
+            });
+        }
+    }
+
+    ///Renders the primitives drawn during the last finished frame as an SVG, in vram coordinates
+    pub fn export_frame_svg(&self) -> String {
+        let mut svg = String::from(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"1024\" height=\"512\" viewBox=\"0 0 1024 512\">\n",
+        );
+        for command in &self.last_frame_commands {
+            svg.push_str("  ");
+            svg.push_str(&command_to_svg(command));
+            svg.push('\n');
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    ///Runs a draw at native resolution, then again into the upscaled buffer if there is one.
+    ///The draw is given the scale to multiply its coordinates by
+    fn draw_at_each_scale<F: Fn(&mut Self, i16)>(&mut self, draw: F) {
+        draw(self, 1);
+        if self.upscale > 1 {
+            self.drawing_upscaled = true;
+            draw(self, self.upscale as i16);
+            self.drawing_upscaled = false;
+        }
+    }
+
+    ///Scale of the buffer the rasterizer is drawing into
+    fn render_scale(&self) -> i16 {
+        if self.drawing_upscaled {
+            self.upscale as i16
+        } else {
+            1
+        }
+    }
+
+    ///Buffer the rasterizer draws into, and the index of x, y in it
+    fn render_target(&mut self, x: u32, y: u32) -> (&mut Vec<u16>, usize) {
+        if self.drawing_upscaled {
+            let width = VRAM_WIDTH * self.upscale;
+            let address = y.wrapping_mul(width).wrapping_add(x) as usize % self.upscaled_vram.len();
+            (&mut self.upscaled_vram, address)
+        } else {
+            (&mut self.vram, point_to_address(x, y) as usize % 524288)
+        }
+    }
+
+    ///Copies a native area of vram into the upscaled buffer, after something other than the rasterizer changed it
+    fn sync_upscaled(&mut self, x: u32, y: u32, width: u32, height: u32) {
+        if self.upscale == 1 {
+            return;
+        }
+        let scale = self.upscale;
+        for row in y..y + height.min(VRAM_HEIGHT) {
+            for column in x..x + width.min(VRAM_WIDTH) {
+                let (column, row) = (column % VRAM_WIDTH, row % VRAM_HEIGHT);
+                let pixel = self.vram[point_to_address(column, row) as usize];
+                for sub_y in 0..scale {
+                    let start = ((row * scale + sub_y) * VRAM_WIDTH * scale + column * scale) as usize;
+                    self.upscaled_vram[start..start + scale as usize].fill(pixel);
+                }
+            }
+        }
+    }
+
     fn copy_horizontal_line(
         &mut self,
         x_source: u32,
@@ -778,11 +1338,15 @@ impl Gpu {
         y_dest: u32,
         width: u32,
     ) {
-        for x_offset in 0..=width {
+        for x_offset in 0..width {
             let val =
                 self.vram[(point_to_address(x_source + x_offset, y_source) as usize) % 524288];
-            let addr = point_to_address(x_dest + x_offset, y_dest) as usize;
-            self.vram[addr % 524288] = val;
+            let addr = point_to_address(x_dest + x_offset, y_dest) as usize % 524288;
+            if self.check_mask && self.vram[addr].get_bit(15) {
+                //Destination pixel is protected
+                continue;
+            }
+            self.vram[addr] = if self.set_mask { val | color::MASK_BIT } else { val };
         }
     }
 
@@ -806,20 +1370,29 @@ impl Gpu {
         }
     }
 
+    ///Writes one rasterized pixel. While mask checking is on, pixels with the mask bit set are left alone
+    fn plot(&mut self, x: u32, y: u32, fill: u16, transparent: bool) {
+        let (check_mask, set_mask) = (self.check_mask, self.set_mask);
+        let (target, address) = self.render_target(x, y);
+        if check_mask && target[address] & color::MASK_BIT != 0 {
+            return;
+        }
+        let color = if transparent {
+            alpha_composite(target[address], fill)
+        } else {
+            fill
+        };
+        target[address] = if set_mask { color | color::MASK_BIT } else { color };
+    }
+
     fn draw_horizontal_line(&mut self, x1: u32, x2: u32, y: u32, fill: u16, transparent: bool) {
         
         for x in x1..x2 {
             if self.out_of_draw_area(&Point::from_components(x as i16, y as i16, 0)) {
                 continue;
             }
-            let address = point_to_address(x, y) as usize;
-            let color = if transparent {
-                alpha_composite(self.vram[address % 524288], fill)
-            } else {
-                fill
-            };
             if fill != 0 {
-                self.vram[address % 524288] = color;
+                self.plot(x, y, fill, transparent);
             }
         }
     }
@@ -842,24 +1415,20 @@ impl Gpu {
             if self.out_of_draw_area(&Point::from_components(x, y, 0)) {
                 continue;
             }
-            let address = point_to_address(x as u32, y as u32) as usize;
             let fill = lerp_color(start_color, end_color, start, end, x);
             ////println!("x {} end {} fill {:#X}", x, end, fill);
-            let color = if transparent {
-                alpha_composite(self.vram[address % 524288], fill)
-            } else {
-                fill
-            };
             if fill != 0 {
-                self.vram[address % 524288] = color;
+                self.plot(x as u32, y as u32, fill, transparent);
             }
         }
     }
     fn out_of_draw_area(&self, test_point: &Point) -> bool {
-        !(test_point.x > self.draw_area_tl_point.x
-            && test_point.x < self.draw_area_br_point.x
-            && test_point.y > self.draw_area_tl_point.y
-            && test_point.y < self.draw_area_br_point.y)
+        //The area includes both corners. Upscaled, each native pixel inside it covers scale x scale pixels
+        let scale = self.render_scale();
+        !(test_point.x >= self.draw_area_tl_point.x * scale
+            && test_point.x < (self.draw_area_br_point.x + 1) * scale
+            && test_point.y >= self.draw_area_tl_point.y * scale
+            && test_point.y < (self.draw_area_br_point.y + 1) * scale)
     }
 
     fn draw_horizontal_line_textured(
@@ -880,23 +1449,17 @@ impl Gpu {
                 continue;
             }
 
-            let address = point_to_address(x as u32, y as u32) as usize;
-
-            let fill = self.get_texel(
+            let texel = self.get_texel(
                 lerp_coords(x1_tex, x2_tex, start, end, x),
                 lerp_coords(y1_tex, y2_tex, start, end, x),
             );
-            //let fill = 0xFFFF;
-            ////println!("x {} end {} fill {:#X}", x, end, fill);
-
-            let color = if transparent {
-                alpha_composite(self.vram[address % 524288], fill)
-            } else {
-                fill
-            };
-            if fill != 0 {
-                self.vram[address % 524288] = color;
+            //A texel of 0x0000 is fully transparent, so leave whatever is already there
+            if texel == 0 {
+                continue;
             }
+            let fill = self.blend_texel(texel);
+            ////println!("x {} end {} fill {:#X}", x, end, fill);
+            self.plot(x as u32, y as u32, fill, transparent);
         }
     }
 
@@ -907,15 +1470,17 @@ impl Gpu {
     }
 
     fn draw_textured_box(&mut self, tl_point: &Point, width: i16, height: i16, transparent: bool) {
+        //Width and height are in render pixels, but the texture is always at native resolution
+        let scale = self.render_scale();
         for offset in 0..height {
             self.draw_horizontal_line_textured(
                 tl_point.x,
                 tl_point.x + width,
                 tl_point.y + offset,
-                tl_point.tex_y + offset,
-                tl_point.tex_y + offset,
+                tl_point.tex_y + offset / scale,
+                tl_point.tex_y + offset / scale,
                 tl_point.tex_x,
-                tl_point.tex_x + width,
+                tl_point.tex_x + width / scale,
                 transparent,
             )
         }
@@ -1175,6 +1740,7 @@ impl Gpu {
         self.draw_textured_triangle(&[points[1], points[3], points[2]], transparent);
     }
 
+    ///Returns the raw texel at the given texture coordinates, before any blending
     fn get_texel(&self, x: i16, y: i16) -> u16 {
         //TODO inline variables. Just did this because I'm lazy
         let page_x = self.texpage_x_base;
@@ -1183,7 +1749,7 @@ impl Gpu {
         let clut_y = self.palette_y;
         let size = self.texmode;
 
-        let pixel_val = match size {
+        match size {
             TextureColorMode::FifteenBit => {
                 self.vram[point_to_address(
                     ((page_x * 64) as u32 + x as u32) as u32,
@@ -1208,41 +1774,50 @@ impl Gpu {
                 self.vram
                     [point_to_address((clut_x * 16 + clut_index) as u32, clut_y as u32) as usize]
             }
-        };
+        }
+    }
+
+    fn blend_texel(&self, texel: u16) -> u16 {
         if self.blend_enabled {
-            pixel_val & self.blend_color
+            texel & self.blend_color
         } else {
-            pixel_val
+            texel
         }
     }
 }
 
-fn point_to_address(x: u32, y: u32) -> u32 {
-    ((1024) as u32 * y).wrapping_add(x)
+fn sign_extend_11(value: u32) -> i16 {
+    ((value << 5) as u16 as i16) >> 5
 }
 
-fn b24color_to_b15color(color: u32) -> u16 {
-    let r = ((color >> 16) & 0xFF) / 8;
-    let g = ((color >> 8) & 0xFF) / 8;
-    let b = (color & 0xFF) / 8;
-    ((r << 10) | (g << 5) | b) as u16
+fn scale_points(points: &[Point], scale: i16) -> Vec<Point> {
+    points.iter().map(|point| point.scaled(scale)).collect()
+}
+
+/// Width and height of a VRAM transfer. They wrap to 1..=1024 and 1..=512, so 0 is the whole of VRAM
+fn transfer_size(word: u32) -> (u32, u32) {
+    let width = ((word & 0xFFFF).wrapping_sub(1) & 0x3FF) + 1;
+    let height = ((word >> 16).wrapping_sub(1) & 0x1FF) + 1;
+    (width, height)
+}
+
+fn point_to_address(x: u32, y: u32) -> u32 {
+    (VRAM_WIDTH * y).wrapping_add(x)
 }
 
-fn b15_to_rgb(color: u16) -> (u8, u8, u8) {
-    (
-        ((color >> 10) & 0x1F) as u8,
-        ((color >> 5) & 0x1F) as u8,
-        (color & 0x1F) as u8,
-    )
+///Width and height of a variable size rectangle. Only 10 bits of width and 9 of height are used
+fn rectangle_size(word: u32) -> Point {
+    Point::from_components((word & 0x3FF) as i16, ((word >> 16) & 0x1FF) as i16, 0)
 }
 
-fn rgb_to_b15(r: u8, g: u8, b: u8) -> u16 {
-    ((r as u16) << 10) | ((g as u16) << 5) | (b as u16)
+///Byte offset of a 24 bit pixel. Each 2048 byte vram line holds the pixels packed 3 bytes apiece
+fn point_to_byte_address_24(x: u32, y: u32) -> u32 {
+    (2048 * y).wrapping_add(x * 3)
 }
 
 fn lerp_color(y0: u16, y1: u16, x0: i16, x1: i16, x: i16) -> u16 {
-    let (sr, sg, sb) = b15_to_rgb(y0);
-    let (er, eg, eb) = b15_to_rgb(y1);
+    let (sr, sg, sb) = color::channels(y0);
+    let (er, eg, eb) = color::channels(y1);
 
     let ir = (sr as f32 + ((er as i32 - sr as i32) as f32 * ((x - x0) as f32 / (x1 - x0) as f32)))
         as u16;
@@ -1251,7 +1826,7 @@ fn lerp_color(y0: u16, y1: u16, x0: i16, x1: i16, x: i16) -> u16 {
     let ib = (sb as f32 + ((eb as i32 - sb as i32) as f32 * ((x - x0) as f32 / (x1 - x0) as f32)))
         as u16;
 
-    rgb_to_b15(ir as u8, ig as u8, ib as u8)
+    color::from_channels(ir as u8, ig as u8, ib as u8)
 
     //(y0 as f32 + ((y1 - y0) as f32 * ((x - x0) as f32 / (x1 - x0) as f32))) as u16
 }
@@ -1262,9 +1837,94 @@ fn lerp_coords(y0: i16, y1: i16, x0: i16, x1: i16, x: i16) -> i16 {
 
 //TODO Make colors more accurate
 fn alpha_composite(background_color: u16, alpha_color: u16) -> u16 {
-    let (b_r, b_g, b_b) = b15_to_rgb(background_color);
-    let (a_r, a_g, a_b) = b15_to_rgb(alpha_color);
-    rgb_to_b15(a_r + b_r, a_g + b_g, a_b + b_b)
+    let (b_r, b_g, b_b) = color::channels(background_color);
+    let (a_r, a_g, a_b) = color::channels(alpha_color);
+    color::from_channels(a_r + b_r, a_g + b_g, a_b + b_b)
+}
+
+///Hex color for the 24 bit color in the low bits of a command word. Red is the low byte
+fn svg_color(word: u32) -> String {
+    format!("#{:02x}{:02x}{:02x}", word & 0xFF, (word >> 8) & 0xFF, (word >> 16) & 0xFF)
+}
+
+fn svg_points(points: &[Point]) -> String {
+    points
+        .iter()
+        .map(|p| format!("{},{}", p.x, p.y))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+///Converts a recorded drawing command to an SVG element, placed the same way the rasterizer places it
+fn command_to_svg(command: &RecordedCommand) -> String {
+    let words = &command.words;
+    let header = words[0];
+    let color = svg_color(header);
+    //Bit 25 marks semi transparent primitives. Quick fills don't have the flag, it's part of their command byte
+    let opacity = if header.gp0_header() != 0x0 && header.get_bit(25) {
+        " fill-opacity=\"0.5\""
+    } else {
+        ""
+    };
+    let rect = |x: i16, y: i16, width: i16, height: i16| {
+        format!(
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\"{}/>",
+            x, y, width, height, color, opacity
+        )
+    };
+
+    match header.gp0_header() {
+        0x0 => {
+            //Quick rectangle fill
+            let tl = Point::from_word(words[1], 0);
+            let size = Point::from_word(words[2], 0);
+            rect(tl.x, tl.y, size.x, size.y)
+        }
+        0x1 => {
+            let verts = if header.get_bit(27) { 4 } else { 3 };
+            let stride = 1 + header.get_bit(26) as usize + header.get_bit(28) as usize;
+            let points: Vec<Point> = (0..verts)
+                .map(|i| Point::from_word(words[1 + i * stride], 0))
+                .collect();
+            //Quads are drawn as a strip, so the outline goes 0 1 3 2
+            let outline = if verts == 4 {
+                vec![points[0], points[1], points[3], points[2]]
+            } else {
+                points
+            };
+            format!("<polygon points=\"{}\" fill=\"{}\"{}/>", svg_points(&outline), color, opacity)
+        }
+        0x2 => {
+            //Gouraud lines have a color word before every vertex after the first
+            let stride = 1 + header.get_bit(28) as usize;
+            let points: Vec<Point> = words[1..]
+                .iter()
+                .enumerate()
+                .take_while(|(_, word)| *word & 0xF000F000 != 0x50005000)
+                .filter(|(i, _)| i % stride == 0)
+                .map(|(_, word)| Point::from_word(*word, 0))
+                .collect();
+            format!("<polyline points=\"{}\" fill=\"none\" stroke=\"{}\"/>", svg_points(&points), color)
+        }
+        _ => {
+            let tl = Point::from_word(words[1], 0);
+            let (offset_x, offset_y) = command.draw_offset;
+            let (x, y) = (tl.x.wrapping_add(offset_x), tl.y.wrapping_add(offset_y));
+            match (header >> 27) & 0x3 {
+                0b01 => rect(x, y, 1, 1),
+                0b10 => rect(x, y, 8, 8),
+                0b11 => rect(x, y, 16, 16),
+                _ if header.get_bit(26) => {
+                    let size = Point::from_word(words[3], 0);
+                    rect(x, y, size.x, size.y)
+                }
+                _ => {
+                    let size = Point::from_word(words[2], 0);
+                    rect(x, y, size.x, size.y)
+                }
+            }
+        }
+    }
 }
 
 //Helper trait + impl
@@ -1291,6 +1951,244 @@ impl Command for u32 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_log;
+    use log::Level;
+
+    #[test]
+    fn test_unknown_gp1_logs_warning() {
+        test_log::capture();
+        let mut gpu = Gpu::new();
+        gpu.send_gp1_command(0x2000_0123);
+        assert_eq!(gpu.ignored_commands, 1);
+        assert!(test_log::logged(LOG_TARGET, Level::Warn, "Unknown GP1 command 0x20 parameter 0x123!"));
+    }
+
+    #[test]
+    fn test_unhandled_gp0_skipped() {
+        let mut gpu = Gpu::new();
+        gpu.send_gp0_command(0xE700_0000); //No such ENV command
+        gpu.send_gp0_command(0x1F00_0000); //IRQ request
+        assert!(gpu.gp0_buffer.is_empty());
+        assert_eq!(gpu.ignored_commands, 2);
+
+        //Texture blended quads are skipped once all their words are in
+        gpu.send_gp0_command(0x3C00_0000);
+        for _ in 0..11 {
+            gpu.send_gp0_command(0);
+        }
+        assert!(gpu.gp0_buffer.is_empty());
+        assert_eq!(gpu.ignored_commands, 3);
+
+        //Commands after them still work
+        gpu.send_gp0_command(0xE600_0003);
+        assert!(gpu.set_mask && gpu.check_mask);
+    }
+
+    #[test]
+    fn test_long_gp0_commands() {
+        let mut gpu = Gpu::new();
+        //Polyline with 40 vertices. It's only done at the terminator
+        gpu.send_gp0_command(0x4800_0000);
+        for vertex in 0..40 {
+            gpu.send_gp0_command(vertex);
+        }
+        assert_eq!(gpu.gp0_buffer.len(), 41);
+        gpu.send_gp0_command(0x5555_5555);
+        assert!(gpu.gp0_buffer.is_empty());
+
+        //8x8 CPU to VRAM transfer, 35 words in all
+        gpu.send_gp0_command(0xA000_0000);
+        gpu.send_gp0_command(0x0010_0010);
+        gpu.send_gp0_command(0x0008_0008);
+        for pixel in 0..32 {
+            gpu.send_gp0_command(pixel << 16 | pixel);
+        }
+        assert!(gpu.gp0_buffer.is_empty());
+        assert_eq!(gpu.vram[point_to_address(0x17, 0x17) as usize], 31);
+
+        //Oversized transfers wrap, so this is 1x2 and only needs one word of data
+        gpu.send_gp0_command(0xA000_0000);
+        gpu.send_gp0_command(0);
+        gpu.send_gp0_command(0x0402_0401);
+        gpu.send_gp0_command(0x1234_5678);
+        assert!(gpu.gp0_buffer.is_empty());
+        assert_eq!(gpu.vram[point_to_address(0, 1) as usize], 0x1234);
+        assert_eq!(transfer_size(0), (1024, 512));
+    }
+
+    #[test]
+    fn test_vram_view() {
+        let mut gpu = Gpu::new();
+        //Write a red pixel and a white one with the mask bit to 5, 3
+        gpu.send_gp0_command(0xA000_0000);
+        gpu.send_gp0_command(0x0003_0005);
+        gpu.send_gp0_command(0x0001_0002);
+        gpu.send_gp0_command(0xFFFF_001F);
+
+        let view = gpu.vram_view();
+        assert_eq!(view.dimensions(), (1024, 512));
+        assert_eq!(view.get_pixel(5, 3), 0x001F);
+        assert_eq!(view.get_pixel(6, 3), 0xFFFF);
+        assert_eq!(view.get_pixel(5 + 1024, 3 + 512), 0x001F);
+        assert_eq!(view.get_pixel_rgba(5, 3), [0xFF, 0, 0, 0xFF]);
+        assert_eq!(view.get_pixel_rgba(6, 3), [0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(view.get_pixel_rgba(0, 0), [0, 0, 0, 0xFF]);
+    }
+
+    #[test]
+    fn test_trace_replay() {
+        let mut gpu = Gpu::new();
+        gpu.send_gp0_command(0xE600_0000); //Not traced yet
+        gpu.set_trace(true);
+        //Draw area covering the top left, then a red and a blue rectangle
+        gpu.send_gp1_command(0x0300_0000);
+        gpu.send_gp0_command(0xE300_0000);
+        gpu.send_gp0_command(0xE400_0000 | (100 << 10) | 100);
+        for (color, position) in [(0x0000_00FF, 0x0005_0005), (0x00FF_0000, 0x0010_0020)].iter() {
+            gpu.send_gp0_command(0x6000_0000 | color);
+            gpu.send_gp0_command(*position);
+            gpu.send_gp0_command(0x0008_0010);
+        }
+
+        let trace = gpu.take_trace();
+        assert_eq!(trace.len(), 5);
+        assert_eq!(trace[0], GpuCommand::Gp1 { opcode: 0x03, name: "Display enable", word: 0x0300_0000 });
+        assert_eq!(
+            trace[3],
+            GpuCommand::Gp0 { opcode: 0x60, name: "Rectangle", words: vec![0x6000_00FF, 0x0005_0005, 0x0008_0010] }
+        );
+        assert!(gpu.take_trace().is_empty());
+        assert!(gpu.get_vram().iter().any(|pixel| *pixel != 0));
+
+        let mut replayed = Gpu::new();
+        replayed.replay(&trace);
+        assert!(replayed.get_vram() == gpu.get_vram());
+
+        gpu.set_trace(false);
+        gpu.send_gp0_command(0);
+        gpu.set_trace(true);
+        assert!(gpu.take_trace().is_empty());
+
+        //Packets are only traced once they're complete
+        gpu.send_gp0_command(0x2000_FF00);
+        gpu.send_gp0_command(0x0014_000A);
+        assert!(gpu.take_trace().is_empty());
+        gpu.send_gp0_command(0x0014_0064);
+        gpu.send_gp0_command(0x0050_000A);
+        match &gpu.take_trace()[..] {
+            [GpuCommand::Gp0 { opcode: 0x20, name: "Flat triangle", words }] => assert_eq!(words.len(), 4),
+            trace => panic!("Unexpected trace {:?}", trace),
+        }
+    }
+
+    #[test]
+    fn test_draw_offset_wraps() {
+        let mut gpu = Gpu::new();
+        gpu.send_gp0_command(0xE300_0000);
+        gpu.send_gp0_command(0xE400_0000 | (511 << 10) | 1023);
+        gpu.send_gp0_command(0xE500_0000 | 0x400); //x offset of -1024
+        assert_eq!(gpu.draw_offset.x, -1024);
+        assert_eq!(gpu.draw_offset.y, 0);
+
+        //Pixel at 1024, 5 lands on 0, 5
+        gpu.send_gp0_command(0x68FF_FFFF);
+        gpu.send_gp0_command(0x0005_0400);
+        assert_eq!(gpu.vram[point_to_address(0, 5) as usize], 0x7FFF);
+
+        gpu.send_gp1_command(0x1000_0005);
+        assert_eq!(gpu.read_word_gp0(), 0x400);
+
+        //Largest positive offset, and a pixel that goes past the bottom right corner
+        gpu.send_gp0_command(0xE500_0000 | (0x3FF << 11) | 0x3FF);
+        assert_eq!((gpu.draw_offset.x, gpu.draw_offset.y), (1023, 1023));
+        gpu.send_gp0_command(0x68FF_FFFF);
+        gpu.send_gp0_command(0x0001_0003);
+        assert_eq!(gpu.vram[point_to_address(2, 0) as usize], 0x7FFF);
+    }
+
+    #[test]
+    fn test_variable_rectangle_extent() {
+        let mut gpu = Gpu::new();
+        gpu.send_gp0_command(0xE300_0000);
+        gpu.send_gp0_command(0xE400_0000 | (511 << 10) | 1023);
+        gpu.send_gp0_command(0x6000_00FF);
+        gpu.send_gp0_command(0x0032_0028);
+        gpu.send_gp0_command(0x0003_0004);
+        assert_eq!(gpu.vram.iter().filter(|pixel| **pixel != 0).count(), 12);
+        assert_eq!(gpu.vram[point_to_address(40, 50) as usize], 0x001F);
+        assert_eq!(gpu.vram[point_to_address(43, 52) as usize], 0x001F);
+
+        //Bits above the size fields are ignored
+        gpu.send_gp0_command(0x6000_FF00);
+        gpu.send_gp0_command(0x0001_0001);
+        gpu.send_gp0_command(0xFE01_FC02);
+        assert_eq!(gpu.vram.iter().filter(|pixel| **pixel == 0x03E0).count(), 2);
+    }
+
+    #[test]
+    fn test_fixed_size_sprites() {
+        let mut gpu = Gpu::new();
+        gpu.send_gp0_command(0xE300_0000);
+        gpu.send_gp0_command(0xE400_0000 | (511 << 10) | 1023);
+        gpu.send_gp0_command(0xE500_0000 | (3 << 11) | 5);
+        //8x8 at 10, 20 lands at 15, 23 after the offset. No size word, so the next word is a new command
+        gpu.send_gp0_command(0x7000_00FF);
+        gpu.send_gp0_command(0x0014_000A);
+        gpu.send_gp0_command(0x7800_FF00);
+        gpu.send_gp0_command(0x0064_0064);
+
+        let filled = |gpu: &Gpu, x: u32, y: u32| gpu.vram[point_to_address(x, y) as usize];
+        for y in 21..33 {
+            for x in 13..25 {
+                let inside = (15..23).contains(&x) && (23..31).contains(&y);
+                assert_eq!(filled(&gpu, x, y) == 0x001F, inside, "{}, {}", x, y);
+            }
+        }
+        //16x16 at 105, 103
+        assert_eq!(filled(&gpu, 105, 103), 0x03E0);
+        assert_eq!(filled(&gpu, 120, 118), 0x03E0);
+        assert_eq!(filled(&gpu, 121, 118), 0);
+        assert_eq!(filled(&gpu, 120, 119), 0);
+    }
+
+    #[test]
+    fn test_upscaled_rectangle() {
+        let mut gpu = Gpu::new();
+        gpu.set_upscale(2);
+        gpu.send_gp0_command(0xE300_0000);
+        gpu.send_gp0_command(0xE400_0000 | (511 << 10) | 1023);
+        //Red 4x3 rectangle at 10, 10
+        gpu.send_gp0_command(0x6000_00FF);
+        gpu.send_gp0_command(0x000A_000A);
+        gpu.send_gp0_command(0x0003_0004);
+
+        let red = command_color_to_bgr555(0xFF);
+        let framebuffer = gpu.get_framebuffer();
+        assert_eq!(framebuffer.dimensions(), (2048, 1024));
+        for y in 18..28 {
+            for x in 18..30 {
+                let inside = (20..28).contains(&x) && (20..26).contains(&y);
+                assert_eq!(framebuffer.get_pixel(x, y) == red, inside, "{}, {}", x, y);
+            }
+        }
+        //Vram is still native
+        assert_eq!(gpu.vram_view().get_pixel(13, 12), red);
+        assert_eq!(gpu.vram_view().get_pixel(14, 12), 0);
+
+        //Transfers land in vram and get copied up
+        gpu.send_gp0_command(0xA000_0000);
+        gpu.send_gp0_command(0x0064_0064);
+        gpu.send_gp0_command(0x0001_0001);
+        gpu.send_gp0_command(0x1234);
+        assert_eq!(gpu.vram_view().get_pixel(100, 100), 0x1234);
+        let framebuffer = gpu.get_framebuffer();
+        assert_eq!(framebuffer.get_pixel(201, 201), 0x1234);
+        assert_eq!(framebuffer.get_pixel(202, 201), 0);
+
+        gpu.set_upscale(1);
+        assert_eq!(gpu.get_framebuffer().dimensions(), (1024, 512));
+    }
+
     #[test]
     fn test_lerp_color() {
         assert_eq!(15, lerp_color(10, 20, 100, 200, 150));
@@ -1300,4 +2198,206 @@ mod tests {
     fn test_lerp_color_negative() {
         assert_eq!(15, lerp_color(20, 10, 100, 200, 150));
     }
+
+    fn run_gpu_cycles(gpu: &mut Gpu, cycles: u32) {
+        for _ in 0..cycles {
+            gpu.execute_cycle();
+        }
+    }
+
+    #[test]
+    fn test_timing_events_ntsc_frame() {
+        let mut gpu = Gpu::new();
+        let events: Vec<TimingEvent> = gpu.timing_events(gpu.cycles_per_frame()).collect();
+        let hblanks: Vec<u32> = events
+            .iter()
+            .filter_map(|event| match event {
+                TimingEvent::HBlank(offset) => Some(*offset),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(hblanks.len(), V_RES as usize);
+        assert_eq!(hblanks[0], H_BLANK_START);
+        assert_eq!(hblanks[1], H_RES + H_BLANK_START);
+
+        let vblanks: Vec<&TimingEvent> = events.iter().filter(|event| matches!(event, TimingEvent::VBlank(_))).collect();
+        assert_eq!(vblanks, vec![&TimingEvent::VBlank(V_BLANK_START * H_RES)]);
+        assert!(!events.iter().any(|event| matches!(event, TimingEvent::Field(_))));
+
+        //The next cycle starts the next field
+        assert_eq!(gpu.timing_events(1).collect::<Vec<_>>(), vec![TimingEvent::Field(0)]);
+    }
+
+    #[test]
+    fn test_textured_rect_skips_transparent_texels() {
+        let mut gpu = Gpu::new();
+        gpu.send_gp0_command(0xE3000000); //Draw area top left 0,0
+        gpu.send_gp0_command(0xE407FFFF); //Draw area bottom right 1023,511
+        gpu.send_gp0_command(0xE1000101); //Texpage at x 64, 15 bit color
+
+        //Texture with transparent texels at 0 and 2
+        for word in [0xA0000000, 0x00000040, 0x00010004, 0x12340000, 0x56780000] {
+            gpu.send_gp0_command(word);
+        }
+        //Background already in the destination
+        for word in [0xA0000000, 0x000A000A, 0x00010004, 0x7C007C00, 0x7C007C00] {
+            gpu.send_gp0_command(word);
+        }
+
+        //Raw textured 4x1 rectangle at 10,10
+        for word in [0x65000000, 0x000A000A, 0x00000000, 0x00010004] {
+            gpu.send_gp0_command(word);
+        }
+
+        let base = point_to_address(10, 10) as usize;
+        assert_eq!(&gpu.get_vram()[base..base + 4], &[0x7C00, 0x1234, 0x7C00, 0x5678]);
+    }
+
+    #[test]
+    fn test_24bit_transfer_readback() {
+        let mut gpu = Gpu::new();
+        //2x2 24 bit image, which is 3 halfwords wide. Placed at 24 bit pixel 8,4
+        let image: [[u8; 6]; 2] = [[1, 2, 3, 4, 5, 6], [7, 8, 9, 10, 11, 12]];
+        for word in [0xA0000000, 0x0004000C, 0x00020003, 0x04030201, 0x08070605, 0x0C0B0A09] {
+            gpu.send_gp0_command(word);
+        }
+
+        for (row, bytes) in image.iter().enumerate() {
+            for pixel in 0..2 {
+                let expected = (bytes[pixel * 3], bytes[pixel * 3 + 1], bytes[pixel * 3 + 2]);
+                assert_eq!(gpu.read_pixel_24(8 + pixel as u32, 4 + row as u32), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_export_frame_svg() {
+        let mut gpu = Gpu::new();
+        gpu.send_gp0_command(0x020000FF); //Red quick fill
+        gpu.send_gp0_command(0x00200010); //At 16,32
+        gpu.send_gp0_command(0x00180030); //48x24
+        gpu.send_gp0_command(0x2000FF00); //Green flat triangle
+        gpu.send_gp0_command(0x0014000A); //10,20
+        gpu.send_gp0_command(0x00140064); //100,20
+        gpu.send_gp0_command(0x0050000A); //10,80
+        gpu.send_gp0_command(0xE5000000 | (3 << 11) | 2); //Draw offset 2,3
+        gpu.send_gp0_command(0x700000FF); //Red 8x8 sprite
+        gpu.send_gp0_command(0x00050004); //At 4,5
+        gpu.send_gp0_command(0x680000FF); //Red pixel
+        gpu.send_gp0_command(0x00070006); //At 6,7
+
+        //Nothing until the frame is finished
+        assert!(!gpu.export_frame_svg().contains("<rect"));
+        run_gpu_cycles(&mut gpu, H_RES * V_BLANK_START + 1);
+
+        let svg = gpu.export_frame_svg();
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains("<rect x=\"16\" y=\"32\" width=\"48\" height=\"24\" fill=\"#ff0000\"/>"));
+        assert!(svg.contains("<polygon points=\"10,20 100,20 10,80\" fill=\"#00ff00\"/>"));
+        assert!(svg.contains("<rect x=\"6\" y=\"8\" width=\"8\" height=\"8\" fill=\"#ff0000\"/>"));
+        assert!(svg.contains("<rect x=\"8\" y=\"10\" width=\"1\" height=\"1\" fill=\"#ff0000\"/>"));
+    }
+
+    #[test]
+    fn test_gpuread_latch() {
+        let mut gpu = Gpu::new();
+        gpu.send_gp0_command(0xE3000000 | (7 << 10) | 5); //Draw area top left 5,7
+        gpu.send_gp1_command(0x10000003);
+        assert_eq!(gpu.read_word_gp0(), (7 << 10) | 5);
+        //Nothing pending, so the latch is read again
+        assert_eq!(gpu.read_word_gp0(), (7 << 10) | 5);
+
+        //Indices without a value keep the old one
+        gpu.send_gp1_command(0x10000000);
+        assert_eq!(gpu.read_word_gp0(), (7 << 10) | 5);
+
+        gpu.send_gp1_command(0x10000007);
+        assert_eq!(gpu.read_word_gp0(), 2);
+    }
+
+    #[test]
+    fn test_vram_copy_mask_bit() {
+        let mut gpu = Gpu::new();
+        gpu.vram[point_to_address(0, 0) as usize] = 0x1234;
+        gpu.vram[point_to_address(1, 0) as usize] = 0x0042;
+        gpu.vram[point_to_address(100, 0) as usize] = 0x8001; //Masked
+        gpu.vram[point_to_address(101, 0) as usize] = 0x0002;
+        gpu.vram[point_to_address(2, 0) as usize] = 0x0777; //Just past the copy
+        let copy = [0x80000000, 0x00000000, 0x00000064, 0x00010002];
+
+        gpu.send_gp0_command(0xE6000002); //Check mask
+        for word in copy.iter() {
+            gpu.send_gp0_command(*word);
+        }
+        assert_eq!(gpu.vram[point_to_address(100, 0) as usize], 0x8001);
+        assert_eq!(gpu.vram[point_to_address(101, 0) as usize], 0x0042);
+        assert_eq!(gpu.vram[point_to_address(102, 0) as usize], 0);
+
+        gpu.send_gp0_command(0xE6000001); //Set mask
+        for word in copy.iter() {
+            gpu.send_gp0_command(*word);
+        }
+        assert_eq!(gpu.vram[point_to_address(100, 0) as usize], 0x9234);
+        assert_eq!(gpu.vram[point_to_address(101, 0) as usize], 0x8042);
+        assert_eq!(gpu.read_status_register() & 0x1800, 0x0800);
+    }
+
+    #[test]
+    fn test_clipped_fill_sets_mask() {
+        let mut gpu = Gpu::new();
+        gpu.send_gp0_command(0xE300_0000);
+        gpu.send_gp0_command(0xE400_0000 | (20 << 10) | 20);
+        gpu.send_gp0_command(0xE600_0001); //Set mask
+        gpu.send_gp0_command(0x6000_00FF);
+        gpu.send_gp0_command(0x0000_0000);
+        gpu.send_gp0_command(0x0028_0028);
+
+        //Only the part inside the draw area is filled and masked
+        let pixel = |gpu: &Gpu, x: u32, y: u32| gpu.vram[point_to_address(x, y) as usize];
+        assert_eq!(gpu.vram.iter().filter(|pixel| **pixel == 0x801F).count(), 21 * 21);
+        assert_eq!(pixel(&gpu, 20, 5), 0x801F);
+        assert_eq!(pixel(&gpu, 21, 5), 0);
+        assert_eq!(pixel(&gpu, 5, 21), 0);
+
+        gpu.send_gp0_command(0xE400_0000 | (511 << 10) | 1023);
+        gpu.send_gp0_command(0xE600_0002); //Check mask
+        gpu.send_gp0_command(0x6000_FF00);
+        gpu.send_gp0_command(0x000A_000A);
+        gpu.send_gp0_command(0x0014_0014);
+        for y in 10..30 {
+            for x in 10..30 {
+                let expected = if x <= 20 && y <= 20 { 0x801F } else { 0x03E0 };
+                assert_eq!(pixel(&gpu, x, y), expected, "{}, {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_stat_odd_line_progressive() {
+        let mut gpu = Gpu::new();
+        gpu.send_gp1_command(0x08000001); //320x240, not interlaced
+        assert!(!gpu.read_status_register().get_bit(31));
+        run_gpu_cycles(&mut gpu, H_RES);
+        assert!(gpu.read_status_register().get_bit(31));
+        run_gpu_cycles(&mut gpu, H_RES);
+        assert!(!gpu.read_status_register().get_bit(31));
+
+        //Vblank always reads as even
+        run_gpu_cycles(&mut gpu, H_RES * (V_BLANK_START - 1));
+        assert!(gpu.is_vblank());
+        assert!(!gpu.read_status_register().get_bit(31));
+    }
+
+    #[test]
+    fn test_stat_odd_line_interlaced() {
+        let mut gpu = Gpu::new();
+        gpu.send_gp1_command(0x08000027); //640x480, interlaced
+        run_gpu_cycles(&mut gpu, H_RES * 10);
+        let first_field = gpu.read_status_register().get_bit(31);
+        //Same line one frame later should be the other field
+        run_gpu_cycles(&mut gpu, H_RES * V_RES + 1);
+        assert_ne!(first_field, gpu.read_status_register().get_bit(31));
+        run_gpu_cycles(&mut gpu, H_RES * V_RES + 1);
+        assert_eq!(first_field, gpu.read_status_register().get_bit(31));
+    }
 }
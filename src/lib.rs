@@ -1,41 +1,86 @@
 use bios::Bios;
 use bus::MainBus;
+pub use bus::BusAccess;
 use controller::{ButtonState, controller_execute_cycle, ControllerType};
-use cpu::R3000;
-use gpu::Resolution;
+use cpu::{BiosCall, R3000};
+use gpu::{GpuCommand, Resolution, TimingEvent, VideoStandard, VramView};
+use std::ops::RangeInclusive;
 use std::panic;
+use std::path::Path;
+use std::time::Duration;
 use timer::TimerState;
 
-use crate::cdrom::disc::Disc;
+use crate::cdrom::disc::{self, Disc};
+use crate::cheats::Cheat;
 use crate::cpu::InterruptSource;
 use crate::dma::execute_dma_cycle;
 use crate::gpu::Gpu;
 use crate::memory::Memory;
+use crate::memory_card::MemoryCard;
+use crate::savestate::{StateReader, StateWriter};
 
+pub mod audio;
 mod bios;
 mod bus;
 pub mod cdrom;
+mod cheats;
+pub mod color;
 pub mod controller;
 pub mod cpu;
 mod dma;
+mod error;
 pub mod gpu;
 mod memory;
+mod memory_card;
+mod savestate;
 mod spu;
+#[cfg(test)]
+mod test_log;
 mod timer;
 
+pub use error::{EmuError, TimeoutError};
+pub use savestate::SaveStateError;
+
+/// R3000 master clock
+const CPU_CLOCK_HZ: u64 = 33_868_800;
+/// The gpu runs at 11/7 of the cpu clock
+const GPU_CLOCK_NUMERATOR: u64 = 11;
+const GPU_CLOCK_DENOMINATOR: u64 = 7;
+
+/// Counts of commands each subsystem couldn't handle. Useful as a rough compatibility check for a game
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Diagnostics {
+    pub ignored_gpu_commands: u32,
+    pub ignored_cdrom_commands: u32,
+}
+
+/// What stopped the emulator
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakReason {
+    /// Execution reached a breakpoint. Holds the virtual pc
+    Breakpoint(u32),
+    /// An instruction read from a watched physical address
+    ReadWatchpoint(u32),
+    /// An instruction wrote to a watched physical address
+    WriteWatchpoint(u32),
+}
+
 pub struct PSXEmu {
     pub r3000: R3000,
     timers: TimerState,
-    cycle_count: u32,
-    halt_requested: bool,
-    sw_breakpoints: Vec<u32>,
-    watchpoints: Vec<u32>
+    cycle_count: u64,
+    gpu_cycle_remainder: u64,
+    break_reason: Option<BreakReason>,
+    breakpoints: Vec<u32>,
+    read_watchpoints: Vec<u32>,
+    write_watchpoints: Vec<u32>,
+    cheats: Vec<Cheat>,
 }
 
 impl PSXEmu {
-    /// Creates a new instance of the emulator.
-    pub fn new(bios: Vec<u8>) -> PSXEmu {
-        let bios = Bios::new(bios);
+    /// Creates a new instance of the emulator. Fails if the bios image isn't 512KB
+    pub fn new(bios: Vec<u8>) -> Result<PSXEmu, EmuError> {
+        let bios = Bios::new(bios)?;
         let memory = Memory::new();
         let gpu = Gpu::new();
         let bus = MainBus::new(bios, memory, gpu);
@@ -45,12 +90,26 @@ impl PSXEmu {
             r3000: r3000,
             timers: TimerState::new(),
             cycle_count: 0,
-            halt_requested: false,
-            sw_breakpoints: Vec::new(),
-            watchpoints: Vec::new(),
+            gpu_cycle_remainder: 0,
+            break_reason: None,
+            breakpoints: Vec::new(),
+            read_watchpoints: Vec::new(),
+            write_watchpoints: Vec::new(),
+            cheats: Vec::new(),
         };
         emu.reset();
-        emu
+        Ok(emu)
+    }
+
+    /// Creates an emulator from a bios file, optionally with a disc image (.bin, .img, .cue, .iso or .chd) inserted
+    pub fn from_files<P: AsRef<Path>>(bios_path: P, disc_path: Option<P>) -> Result<PSXEmu, EmuError> {
+        let bios_path = bios_path.as_ref();
+        let bios = std::fs::read(bios_path).map_err(|e| EmuError::from_io(bios_path.to_path_buf(), e))?;
+        let mut emu = PSXEmu::new(bios)?;
+        if let Some(disc_path) = disc_path {
+            emu.load_disc(disc::open(disc_path.as_ref())?);
+        }
+        Ok(emu)
     }
 
     /// Resets system to startup condition
@@ -59,61 +118,157 @@ impl PSXEmu {
         self.r3000.main_bus.gpu.reset();
     }
 
-    /// Runs a single time unit. Each unit has the correct-ish ratio of cpu:gpu cycles
+    /// Executes one instruction (plus its delay slot) and runs the rest of the system for the cycles it took,
+    /// including memory wait states. Does nothing while a breakpoint or watchpoint halt is pending
     pub fn step_cycle(&mut self) {
-        for _ in 0..2 {
-            if self.halt_requested {return};
-            self.run_cpu_cycle();
-            self.run_gpu_cycle();
+        if self.halt_requested() {return};
+        self.run_instruction();
+    }
+
+    pub fn run_cpu_cycle(&mut self) {
+        self.run_instruction();
+    }
+
+    /// Runs the emulator for at least the given number of cpu cycles. Instructions aren't split,
+    /// so this can overshoot by a few cycles. Returns the number of cycles actually run
+    pub fn run_cycles(&mut self, cycles: u64) -> u64 {
+        let mut elapsed = 0;
+        while elapsed < cycles && !self.halt_requested() {
+            elapsed += self.run_instruction() as u64;
         }
+        elapsed
+    }
 
-        //One extra gpu cycle gets close enough to correct timing
-        self.run_gpu_cycle();
+    /// Runs until a breakpoint or watchpoint fires and returns which one it was, giving up after max_cycles.
+    /// If the pc is sitting on a breakpoint it is stepped over first
+    pub fn run_until_break(&mut self, max_cycles: u64) -> Result<BreakReason, TimeoutError> {
+        self.clear_halt();
+        let mut elapsed = 0;
+        if self.breakpoints.contains(&self.r3000.pc) {
+            elapsed += self.execute_instruction() as u64;
+        }
+        while !self.halt_requested() {
+            if elapsed >= max_cycles {
+                return Err(TimeoutError {
+                    pc: self.r3000.pc,
+                    cycles: elapsed,
+                });
+            }
+            elapsed += self.run_instruction() as u64;
+        }
+        Ok(self.break_reason.unwrap())
     }
 
-    pub fn run_cpu_cycle(&mut self) {
-        if self.sw_breakpoints.contains(&self.r3000.pc) {
-            self.halt_requested = true;
-            return;
+    /// Runs until the virtual pc is at target, for automated tests that boot to a known point.
+    /// Breakpoints and watchpoints are ignored. Everything is driven by the cycle count, so the same
+    /// inputs always stop in the same state
+    pub fn run_to_pc(&mut self, target: u32, max_cycles: u64) -> Result<(), TimeoutError> {
+        self.break_reason = None;
+        let mut elapsed = 0;
+        while self.r3000.pc != target {
+            if elapsed >= max_cycles {
+                return Err(TimeoutError {
+                    pc: self.r3000.pc,
+                    cycles: elapsed,
+                });
+            }
+            elapsed += self.execute_instruction() as u64;
         }
+        Ok(())
+    }
 
-        if self.watchpoints.contains(&self.r3000.last_touched_addr) {
-            self.halt_requested = true;
-            return;
+    /// Returns the cycles the instruction took, or 0 if a breakpoint stopped it from running
+    fn run_instruction(&mut self) -> u32 {
+        //Breakpoints are on the virtual pc, so 0x80010000 and 0x00010000 are different breakpoints
+        if self.breakpoints.contains(&self.r3000.pc) {
+            self.break_reason = Some(BreakReason::Breakpoint(self.r3000.pc));
+            return 0;
         }
+        self.execute_instruction()
+    }
 
+    /// Runs the instruction at pc without checking breakpoints. Watchpoints hit by it halt before the next one
+    fn execute_instruction(&mut self) -> u32 {
         // if self.r3000.pc == 0x80079b44 {
         //     let a0 = self.r3000.gen_registers[4];
         //     let a1 = self.r3000.gen_registers[5];
         //     println!("Add prim: ot_addr {:#X} ({:#X})  p_addr {:#X} ({:#X})", a0, self.r3000.main_bus.read_word(a0), a1, self.r3000.main_bus.read_word(a1));
         // }
- 
-        controller_execute_cycle(&mut self.r3000);
-        cdrom::step_cycle(&mut self.r3000);
-        self.r3000.step_instruction(&mut self.timers);
-        execute_dma_cycle(&mut self.r3000);
-        self.cycle_count += 1;
-        self.timers.update_sys_clock(&mut self.r3000);
-        if self.cycle_count % 8 == 0 {
-            self.timers.update_sys_div_8(&mut self.r3000);
+
+        let cycles = self.r3000.step_instruction(&mut self.timers);
+        self.r3000.main_bus.spu.execute_cycles(cycles);
+        for _ in 0..cycles {
+            controller_execute_cycle(&mut self.r3000);
+            cdrom::step_cycle(&mut self.r3000);
+            execute_dma_cycle(&mut self.r3000);
+            self.cycle_count += 1;
+            self.timers.update_sys_clock(&mut self.r3000);
+            if self.cycle_count % 8 == 0 {
+                self.timers.update_sys_div_8(&mut self.r3000);
+            }
+        }
+
+        self.gpu_cycle_remainder += cycles as u64 * GPU_CLOCK_NUMERATOR;
+        let gpu_cycles = self.gpu_cycle_remainder / GPU_CLOCK_DENOMINATOR;
+        self.gpu_cycle_remainder %= GPU_CLOCK_DENOMINATOR;
+        self.run_gpu_cycles(gpu_cycles as u32);
+
+        if let Some(addr) = self.r3000.last_read_addr.filter(|addr| self.read_watchpoints.contains(addr)) {
+            self.break_reason = Some(BreakReason::ReadWatchpoint(addr));
+        }
+        if let Some(addr) = self.r3000.last_write_addr.filter(|addr| self.write_watchpoints.contains(addr)) {
+            self.break_reason = Some(BreakReason::WriteWatchpoint(addr));
         }
+        cycles
     }
 
-    fn run_gpu_cycle(&mut self) {
-        self.r3000.main_bus.gpu.execute_cycle();
-        self.timers.update_dot_clock(&mut self.r3000);
-        if self.r3000.main_bus.gpu.consume_hblank() {
-            self.timers.update_h_blank(&mut self.r3000);
+    fn run_gpu_cycles(&mut self, cycles: u32) {
+        for _ in 0..self.r3000.main_bus.gpu.dot_clock_ticks(cycles) {
+            self.timers.update_dot_clock(&mut self.r3000);
+        }
+        for event in self.r3000.main_bus.gpu.timing_events(cycles) {
+            match event {
+                TimingEvent::HBlank(_) => self.timers.update_h_blank(&mut self.r3000),
+                TimingEvent::VBlank(_) => {
+                    self.r3000.fire_external_interrupt(InterruptSource::VBLANK);
+                    self.apply_cheats();
+                }
+                TimingEvent::Field(_) => (),
+            }
         }
     }
 
-    ///Runs the emulator till one frame has been generated
-    pub fn run_frame(&mut self) {
-        while !self.r3000.main_bus.gpu.end_of_frame() {
-            self.step_cycle();
+    /// Cpu cycles in one frame of the active video standard, vblank included
+    pub fn cycles_per_frame(&self) -> u64 {
+        self.r3000.main_bus.gpu.cycles_per_frame() as u64 * GPU_CLOCK_DENOMINATOR / GPU_CLOCK_NUMERATOR
+    }
+
+    ///Runs the emulator till the gpu reports the next vblank.
+    ///Returns false if a halt was requested, or a whole frame's worth of cycles went by without a vblank
+    pub fn run_frame(&mut self) -> bool {
+        let start_frame = self.r3000.main_bus.gpu.frame_count();
+        //The first vblank can be up to a frame away, so only give up after the frame plus some slack
+        let budget = self.cycles_per_frame() + self.cycles_per_frame() / 4;
+        let mut elapsed = 0;
+        while elapsed < budget {
+            if self.halt_requested() {
+                return false;
+            }
+            elapsed += self.run_instruction() as u64;
+            if self.r3000.main_bus.gpu.frame_count() != start_frame {
+                return true;
+            }
         }
-        //Step the gpu once more to get it off this frame
-        self.r3000.main_bus.gpu.execute_cycle();
+        false
+    }
+
+    ///Runs as many cycles as the real console would execute in the given amount of time.
+    ///Returns the number of frames that were completed
+    pub fn run_for(&mut self, budget: Duration) -> u32 {
+        let cycles = budget.as_nanos() * CPU_CLOCK_HZ as u128 / 1_000_000_000;
+        let start_frame = self.r3000.main_bus.gpu.frame_count();
+        self.run_cycles(cycles as u64);
+        self.r3000.main_bus.gpu.frame_count().wrapping_sub(start_frame)
     }
 
     pub fn load_executable(&mut self, start_addr: u32, entrypoint: u32, _sp: u32, data: &Vec<u8>) {
@@ -128,11 +283,46 @@ impl PSXEmu {
         // self.r3000.gen_registers[30] = sp;
     }
 
-    pub fn load_disc(&mut self, disc: Disc) {
+    /// Serializes the cpu, cop0, gte, main ram, scratchpad, gpu, dma, timers, spu, and cdrom drive into a versioned blob.
+    /// The bios, the loaded disc, and the controller and memory card ports are not included, so a state saved
+    /// in the middle of a pad or memory card transfer won't resume it.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        self.r3000.save_state(&mut writer);
+        self.r3000.main_bus.memory.save_state(&mut writer);
+        self.r3000.main_bus.scratchpad.save_state(&mut writer);
+        self.r3000.main_bus.gpu.save_state(&mut writer);
+        self.r3000.main_bus.dma.save_state(&mut writer);
+        self.r3000.main_bus.spu.save_state(&mut writer);
+        self.r3000.main_bus.cd_drive.save_state(&mut writer);
+        self.timers.save_state(&mut writer);
+        writer.write_u64(self.cycle_count);
+        writer.write_u64(self.gpu_cycle_remainder);
+        writer.finish()
+    }
+
+    /// Restores a blob produced by save_state. If an error is returned the emulator may be partially restored,
+    /// so the caller should reset or load a known good state
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        let mut reader = StateReader::new(data)?;
+        self.r3000.load_state(&mut reader)?;
+        self.r3000.main_bus.memory.load_state(&mut reader)?;
+        self.r3000.main_bus.scratchpad.load_state(&mut reader)?;
+        self.r3000.main_bus.gpu.load_state(&mut reader)?;
+        self.r3000.main_bus.dma.load_state(&mut reader)?;
+        self.r3000.main_bus.spu.load_state(&mut reader)?;
+        self.r3000.main_bus.cd_drive.load_state(&mut reader)?;
+        self.timers.load_state(&mut reader)?;
+        self.cycle_count = reader.read_u64()?;
+        self.gpu_cycle_remainder = reader.read_u64()?;
+        Ok(())
+    }
+
+    pub fn load_disc(&mut self, disc: Box<dyn Disc>) {
         self.r3000.main_bus.cd_drive.load_disc(disc);
     }
 
-    pub fn loaded_disc(&self) -> &Option<Disc> {
+    pub fn loaded_disc(&self) -> &Option<Box<dyn Disc>> {
         self.r3000.main_bus.cd_drive.disc()
     }
 
@@ -140,14 +330,70 @@ impl PSXEmu {
         self.r3000.main_bus.cd_drive.remove_disc();
     }
 
+    /// Opens or closes the cd drive lid, for swapping discs
+    pub fn set_lid_open(&mut self, open: bool) {
+        self.r3000.main_bus.cd_drive.set_lid_open(open);
+    }
+
     pub fn get_vram(&self) -> &Vec<u16> {
         self.r3000.main_bus.gpu.get_vram()
     }
 
+    /// Renders primitives at factor times the native resolution, from 1 (off) to 4. See get_framebuffer
+    pub fn set_upscale(&mut self, factor: u32) {
+        self.r3000.main_bus.gpu.set_upscale(factor);
+    }
+
+    /// Vram as it should be displayed, at the upscaled resolution if upscaling is on
+    pub fn get_framebuffer(&self) -> VramView<'_> {
+        self.r3000.main_bus.gpu.get_framebuffer()
+    }
+
+    /// Starts or stops recording the commands sent to the gpu. See Gpu::replay
+    pub fn set_gpu_trace(&mut self, enabled: bool) {
+        self.r3000.main_bus.gpu.set_trace(enabled);
+    }
+
+    pub fn take_gpu_trace(&mut self) -> Vec<GpuCommand> {
+        self.r3000.main_bus.gpu.take_trace()
+    }
+
+    /// Starts or stops recording A, B and C table bios calls, for seeing what a game asks the bios to do
+    pub fn set_bios_trace(&mut self, enabled: bool) {
+        self.r3000.set_bios_trace(enabled);
+    }
+
+    pub fn take_bios_trace(&mut self) -> Vec<BiosCall> {
+        self.r3000.take_bios_trace()
+    }
+
+    /// Vram with pixel accessors, so callers don't need to know its layout
+    pub fn vram(&self) -> VramView<'_> {
+        self.r3000.main_bus.gpu.vram_view()
+    }
+
+    /// Hash of the displayed part of vram, to compare a rendered frame against a known good one.
+    /// Anything off screen, like textures and the back buffer, doesn't affect it
+    pub fn framebuffer_hash(&self) -> u64 {
+        self.r3000.main_bus.gpu.display_hash()
+    }
+
+    ///SVG of the primitives the gpu drew during the last finished frame. Handy for spotting overdraw and misplaced geometry
+    pub fn export_frame_svg(&self) -> String {
+        self.r3000.main_bus.gpu.export_frame_svg()
+    }
+
     pub fn get_bios(&self) -> &Vec<u8> {
         self.r3000.main_bus.bios.get_data()
     }
 
+    pub fn diagnostics(&self) -> Diagnostics {
+        Diagnostics {
+            ignored_gpu_commands: self.r3000.main_bus.gpu.ignored_commands(),
+            ignored_cdrom_commands: self.r3000.main_bus.cd_drive.ignored_commands(),
+        }
+    }
+
     pub fn manually_fire_interrupt(&mut self, source: InterruptSource) {
         self.r3000.fire_external_interrupt(source);
     }
@@ -160,21 +406,93 @@ impl PSXEmu {
         self.r3000.gen_registers[reg_num] = value;
     }
 
+    /// Adds a GameShark code, one "AAAAAAAA VVVV" line per line of the string. It's applied every vblank from now on.
+    /// Supports 8 bit (30) and 16 bit (80) writes, and the 16 bit equal (D0) and not equal (D1) conditionals
+    pub fn add_cheat(&mut self, code: &str) -> Result<(), EmuError> {
+        self.cheats.push(Cheat::parse(code)?);
+        Ok(())
+    }
+
+    pub fn clear_cheats(&mut self) {
+        self.cheats.clear();
+    }
+
+    fn apply_cheats(&mut self) {
+        for cheat in &self.cheats {
+            cheat.apply(&mut self.r3000.main_bus);
+        }
+    }
+
+    /// Starts or stops recording cpu accesses to the hardware registers. See set_bus_log_range to watch other addresses
+    pub fn set_bus_log(&mut self, enabled: bool) {
+        self.set_bus_log_range(if enabled { Some(bus::IO_REGION) } else { None });
+    }
+
+    /// Records cpu accesses to physical addresses in range, or stops recording with None
+    pub fn set_bus_log_range(&mut self, range: Option<RangeInclusive<u32>>) {
+        self.r3000.main_bus.set_bus_log(range);
+    }
+
+    pub fn take_bus_log(&mut self) -> Vec<BusAccess> {
+        self.r3000.main_bus.take_bus_log()
+    }
+
+    /// Guest memory accessors, for test fixtures, cheats and the debugger. Addresses are decoded the same way as the cpu's,
+    /// so any segment mirror works, and reads and writes of io registers have the same side effects they would for the cpu.
+    /// They don't trip watchpoints or data breakpoints, show up in the bus log, or get dropped while the cache is isolated
+    pub fn read8(&mut self, addr: u32) -> u8 {
+        self.r3000.decode_read_byte(addr, &mut self.timers)
+    }
+
+    pub fn read16(&mut self, addr: u32) -> u16 {
+        self.r3000.decode_read_half_word(addr, &mut self.timers)
+    }
+
+    pub fn read32(&mut self, addr: u32) -> u32 {
+        self.r3000.decode_read_word(addr, &mut self.timers)
+    }
+
+    pub fn write8(&mut self, addr: u32, value: u8) {
+        self.r3000.decode_write_byte(addr, value, &mut self.timers);
+    }
+
+    pub fn write16(&mut self, addr: u32, value: u16) {
+        self.r3000.decode_write_half_word(addr, value, &mut self.timers);
+    }
+
+    pub fn write32(&mut self, addr: u32, value: u32) {
+        self.r3000.decode_write_word(addr, value, &mut self.timers);
+    }
+
     pub fn halt_requested(&self) -> bool {
-        self.halt_requested
+        self.break_reason.is_some()
+    }
+
+    /// Why the emulator is halted, if it is
+    pub fn break_reason(&self) -> Option<BreakReason> {
+        self.break_reason
     }
 
     pub fn clear_halt(&mut self) {
-        self.halt_requested = false;
+        self.break_reason = None;
+    }
+
+    /// Halts before executing the instruction at the given virtual address
+    pub fn add_breakpoint(&mut self, addr: u32) {
+        self.breakpoints.push(addr);
     }
 
-    pub fn add_sw_breakpoint(&mut self, addr: u32) {
-        println!("Adding breakpoint");
-        self.sw_breakpoints.push(addr);
+    pub fn remove_breakpoint(&mut self, addr: u32) {
+        self.breakpoints.retain(|&x| x != addr);
     }
 
-    pub fn remove_sw_breakpoint(&mut self, addr: u32) {
-        self.sw_breakpoints.retain(|&x| x != addr);
+    /// Overrides the NTSC/PAL frame timing the game asks for. None goes back to whatever the game set
+    pub fn force_video_standard(&mut self, standard: Option<VideoStandard>) {
+        self.r3000.main_bus.gpu.force_video_standard(standard);
+    }
+
+    pub fn video_standard(&self) -> VideoStandard {
+        self.r3000.main_bus.gpu.video_standard()
     }
 
     pub fn display_resolution(&self) -> Resolution {
@@ -185,16 +503,483 @@ impl PSXEmu {
         self.r3000.main_bus.controllers.update_button_state(state);
     }
 
+    /// Sets the pressed buttons of the digital pad in port 0 or 1, as controller::BUTTON_* bits.
+    /// Port 1 starts out empty and gets a pad the first time this is called for it. Other ports are ignored
+    pub fn set_button_state(&mut self, port: u8, buttons: u16) {
+        self.r3000.main_bus.controllers.set_button_state(port as usize, buttons);
+    }
+
+    /// Sets the stick axes of the pad in port 0 or 1, 0x80 being centered. Games see them once they put the pad in analog mode
+    pub fn set_analog(&mut self, port: u8, rx: u8, ry: u8, lx: u8, ly: u8) {
+        self.r3000.main_bus.controllers.set_analog(port as usize, [rx, ry, lx, ly]);
+    }
+
+    /// Plugs the memory card image at path into slot 0 or 1. The image is created, already formatted, if it doesn't exist.
+    /// Sectors are written back to the file as games save them
+    pub fn attach_memory_card<P: AsRef<Path>>(&mut self, slot: usize, path: P) -> Result<(), EmuError> {
+        let card = MemoryCard::from_file(path)?;
+        self.r3000.main_bus.controllers.attach_memory_card(slot, card);
+        Ok(())
+    }
+
+    /// Plugs a rom (an action replay style cartridge, say) into the parallel port
+    pub fn attach_exp1_rom(&mut self, rom: Vec<u8>) {
+        self.r3000.main_bus.attach_exp1_rom(rom);
+    }
+
+    /// (small, large) rumble motor values the game last sent to the pad in the given port.
+    /// Ports without a pad report both motors off
+    pub fn rumble_state(&self, port: usize) -> (u8, u8) {
+        self.r3000.main_bus.controllers.rumble_state(port)
+    }
+
+    /// Appends the audio produced since the last call to out, as interleaved left/right samples at audio_sample_rate()
+    pub fn drain_audio(&mut self, out: &mut Vec<i16>) {
+        self.r3000.main_bus.spu.drain_audio(out);
+    }
+
+    pub fn audio_sample_rate(&self) -> u32 {
+        audio::SAMPLE_RATE
+    }
+
     pub fn frame_ready(&self) -> bool {
         self.r3000.main_bus.gpu.end_of_frame()
     }
 
-    pub fn add_watchpoint(&mut self, addr: u32) {
-        println!("Adding watchpoint for addr {:#X} ({:#X} masked)", addr, addr & 0x1fffffff);
-        self.watchpoints.push(addr & 0x1FFFFFFF);
+    /// Halts after any instruction that reads from the address. Matches on the physical address
+    pub fn add_read_watchpoint(&mut self, addr: u32) {
+        self.read_watchpoints.push(bus::physical_address(addr));
+    }
+
+    pub fn remove_read_watchpoint(&mut self, addr: u32) {
+        self.read_watchpoints.retain(|&x| x != bus::physical_address(addr));
+    }
+
+    /// Halts after any instruction that writes to the address. Matches on the physical address
+    pub fn add_write_watchpoint(&mut self, addr: u32) {
+        self.write_watchpoints.push(bus::physical_address(addr));
+    }
+
+    pub fn remove_write_watchpoint(&mut self, addr: u32) {
+        self.write_watchpoints.retain(|&x| x != bus::physical_address(addr));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds an emulator whose bios is just a jump-to-self loop at the reset vector
+    fn looping_emu() -> PSXEmu {
+        let mut bios = vec![0; 512 * 1024];
+        bios[0..4].copy_from_slice(&0x0BF00000u32.to_le_bytes()); //j 0xBFC00000
+        PSXEmu::new(bios).unwrap()
+    }
+
+    /// The looping bios runs a jump and its delay slot from uncached rom every step
+    const BIOS_STEP_CYCLES: u32 = 2 * (cpu::INSTRUCTION_CYCLES + 20);
+
+    #[test]
+    fn test_run_cycles_tight_loop() {
+        let mut emu = looping_emu();
+        //addiu r1, r1, 1; j 0x1000; nop
+        emu.r3000.main_bus.write_word(0x1000, 0x2421_0001);
+        emu.r3000.main_bus.write_word(0x1004, 0x0800_0400);
+        emu.r3000.main_bus.write_word(0x1008, 0);
+
+        //Cached, so every instruction is a single cycle
+        emu.r3000.pc = 0x8000_1000;
+        let ran = emu.run_cycles(300);
+        assert_eq!(ran, 300);
+        assert_eq!(emu.r3000.gen_registers[1], 100);
+
+        //Uncached fetches stall for ram on every instruction
+        emu.r3000.gen_registers[1] = 0;
+        emu.r3000.pc = 0xA000_1000;
+        let per_iteration = 3 * (cpu::INSTRUCTION_CYCLES + emu.r3000.main_bus.read_wait_cycles(0xA000_1000));
+        emu.run_cycles(per_iteration as u64 * 10);
+        assert_eq!(emu.r3000.gen_registers[1], 10);
+    }
+
+    #[test]
+    fn test_video_timer_sources() {
+        let mut emu = looping_emu();
+        //256 wide, so a dot is 10 gpu cycles
+        emu.r3000.main_bus.gpu.send_gp1_command(0x0800_0000);
+        emu.timers.timer_0.write_mode(0x100);
+        emu.timers.timer_1.write_mode(0x100);
+
+        //Ten scanlines
+        let line_cycles = emu.r3000.main_bus.gpu.cycles_per_frame() as u64 / 263;
+        let elapsed = emu.run_cycles(10 * line_cycles * GPU_CLOCK_DENOMINATOR / GPU_CLOCK_NUMERATOR + 1);
+        assert_eq!(emu.timers.timer_1.value, 10);
+        let dots = (elapsed * GPU_CLOCK_NUMERATOR / GPU_CLOCK_DENOMINATOR / 10) as u32;
+        assert!(emu.timers.timer_0.value.abs_diff(dots) <= 1, "{} dots, expected {}", emu.timers.timer_0.value, dots);
+    }
+
+    #[test]
+    fn test_cheats_applied_each_frame() {
+        let mut emu = looping_emu();
+        emu.add_cheat("80012340 BEEF").unwrap();
+        assert!(emu.add_cheat("12345678 0000").is_err());
+        assert!(emu.run_frame());
+        assert_eq!(emu.read16(0x8001_2340), 0xBEEF);
+
+        //Put back after the game overwrites it
+        emu.write16(0x8001_2340, 0);
+        assert!(emu.run_frame());
+        assert_eq!(emu.read16(0x8001_2340), 0xBEEF);
+
+        emu.clear_cheats();
+        emu.write16(0x8001_2340, 0);
+        assert!(emu.run_frame());
+        assert_eq!(emu.read16(0x8001_2340), 0);
+    }
+
+    #[test]
+    fn test_memory_access() {
+        let mut emu = looping_emu();
+        emu.write32(0x8001_0000, 0x1234_5678);
+        let bytes: Vec<u8> = (0..4).map(|i| emu.read8(0x0001_0000 + i)).collect();
+        assert_eq!(bytes, [0x78, 0x56, 0x34, 0x12]);
+        emu.write16(0xA001_0002, 0xABCD);
+        assert_eq!(emu.read32(0x0001_0000), 0xABCD_5678);
+
+        emu.write8(0x1F80_0010, 0x42);
+        assert_eq!(emu.read16(0x1F80_0010), 0x42);
+        //j 0xBFC00000 at the reset vector
+        assert_eq!(emu.read32(0xBFC0_0000), 0x0BF0_0000);
+
+        //The interrupt and timer registers live outside the main bus
+        emu.write32(0x1F80_1074, 0x0000_0004);
+        assert_eq!(emu.read8(0x1F80_1074), 0x04);
+        emu.write16(0x1F80_1108, 0x1234);
+        assert_eq!(emu.read32(0x1F80_1108), 0x1234);
+        emu.write8(0x1F80_1109, 0x56);
+        assert_eq!(emu.read16(0x1F80_1108), 0x5600);
+    }
+
+    #[test]
+    fn test_bus_log() {
+        let mut emu = looping_emu();
+        //lui r1, 0x1F80; ori r2, r0, 0x1234; sw r2, 0x1814(r1); sw r2, 0x100(r0); lw r3, 0x1814(r1); j 0x1014; nop
+        let program = [0x3C01_1F80u32, 0x3402_1234, 0xAC22_1814, 0xAC02_0100, 0x8C23_1814, 0x0800_0405, 0];
+        for (i, word) in program.iter().enumerate() {
+            emu.write32(0x1000 + i as u32 * 4, *word);
+        }
+        emu.set_bus_log(true);
+        emu.r3000.pc = 0x8000_1000;
+        emu.run_to_pc(0x8000_1014, 1000).unwrap();
+
+        //The RAM store isn't in the I/O region
+        let log = emu.take_bus_log();
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0], BusAccess { pc: 0x8000_1008, addr: 0x1F80_1814, value: 0x1234, width: 4, write: true });
+        assert_eq!((log[1].pc, log[1].addr, log[1].width, log[1].write), (0x8000_1010, 0x1F80_1814, 4, false));
+
+        emu.set_bus_log_range(Some(0x100..=0x103));
+        emu.r3000.pc = 0x8000_1000;
+        emu.run_to_pc(0x8000_1014, 1000).unwrap();
+        assert_eq!(emu.take_bus_log(), [BusAccess { pc: 0x8000_100C, addr: 0x100, value: 0x1234, width: 4, write: true }]);
+    }
+
+    #[test]
+    fn test_bios_trace() {
+        let mut emu = looping_emu();
+        //addiu a0, r0, 0x41; addiu a1, r0, 2; addiu t1, r0, 0x3F; jal 0xB0; nop; j 0x1014; nop
+        for (i, word) in [0x2404_0041u32, 0x2405_0002, 0x2409_003F, 0x0C00_002C, 0, 0x0800_0405, 0].iter().enumerate() {
+            emu.r3000.main_bus.write_word(0x1000 + i as u32 * 4, *word);
+        }
+        //jr ra; nop standing in for the bios dispatcher
+        emu.r3000.main_bus.write_word(0xB0, 0x03E0_0008);
+        emu.r3000.main_bus.write_word(0xB4, 0);
+
+        emu.set_bios_trace(true);
+        emu.r3000.pc = 0x8000_1000;
+        emu.run_to_pc(0x8000_1014, 1000).unwrap();
+        let trace = emu.take_bios_trace();
+        assert_eq!(trace, [BiosCall { vector: 0xB0, function: 0x3F, args: [0x41, 2, 0, 0] }]);
+        assert!(emu.take_bios_trace().is_empty());
+    }
+
+    #[test]
+    fn test_run_until_break() {
+        let mut emu = looping_emu();
+        //addiu r1, r1, 1 (x3); lw r2, 0x100(r0); sw r1, 0x104(r0)
+        emu.r3000.main_bus.write_word(0x1000, 0x2421_0001);
+        emu.r3000.main_bus.write_word(0x1004, 0x2421_0001);
+        emu.r3000.main_bus.write_word(0x1008, 0x2421_0001);
+        emu.r3000.main_bus.write_word(0x100C, 0x8C02_0100);
+        emu.r3000.main_bus.write_word(0x1010, 0xAC01_0104);
+        emu.r3000.main_bus.write_word(0x1014, 0);
+        emu.r3000.pc = 0x8000_1000;
+
+        //Physical address of the same instruction doesn't count
+        emu.add_breakpoint(0x0000_1004);
+        emu.add_breakpoint(0x8000_1008);
+        assert_eq!(emu.run_until_break(1000), Ok(BreakReason::Breakpoint(0x8000_1008)));
+        assert_eq!(emu.r3000.pc, 0x8000_1008);
+        assert_eq!(emu.r3000.gen_registers[1], 2);
+
+        emu.add_read_watchpoint(0x8000_0100);
+        emu.add_write_watchpoint(0xA000_0104);
+        assert_eq!(emu.run_until_break(1000), Ok(BreakReason::ReadWatchpoint(0x100)));
+        assert_eq!(emu.r3000.pc, 0x8000_1010);
+        assert_eq!(emu.run_until_break(1000), Ok(BreakReason::WriteWatchpoint(0x104)));
+        assert_eq!(emu.r3000.main_bus.read_word(0x104), 3);
+
+        //Byte loads trip watchpoints too. lbu r3, 0x108(r0)
+        emu.r3000.main_bus.write_word(0x1014, 0x9003_0108);
+        emu.add_read_watchpoint(0x108);
+        assert_eq!(emu.run_until_break(1000), Ok(BreakReason::ReadWatchpoint(0x108)));
+
+        //Nothing left to hit, so it gives up
+        let timeout = emu.run_until_break(1000).unwrap_err();
+        assert!(timeout.cycles >= 1000);
+    }
+
+    /// Counts r1 up to 100, then spins at 0x80010010
+    const COUNTING_STUB: [u32; 6] = [
+        0x2402_0064, //addiu r2, r0, 100
+        0x2421_0001, //addiu r1, r1, 1
+        0x1422_FFFE, //bne r1, r2, -2
+        0,
+        0x0800_4004, //j 0x80010010
+        0,
+    ];
+
+    fn stub_emu() -> PSXEmu {
+        let mut emu = looping_emu();
+        let data: Vec<u8> = COUNTING_STUB.iter().flat_map(|word| word.to_le_bytes()).collect();
+        emu.load_executable(0x8001_0000, 0x8001_0000, 0, &data);
+        emu.r3000.pc = 0x8001_0000;
+        emu
+    }
+
+    #[test]
+    fn test_run_to_pc() {
+        let mut emu = stub_emu();
+        //A reason left over from an earlier run doesn't survive
+        emu.break_reason = Some(BreakReason::ReadWatchpoint(0x100));
+        assert_eq!(emu.run_to_pc(0x8001_0010, 10_000), Ok(()));
+        assert_eq!(emu.r3000.gen_registers[1], 100);
+        assert!(!emu.halt_requested());
+
+        //Same program, same cycle count
+        let mut again = stub_emu();
+        again.run_to_pc(0x8001_0010, 10_000).unwrap();
+        assert_eq!(again.cycle_count, emu.cycle_count);
+        assert_eq!(again.framebuffer_hash(), emu.framebuffer_hash());
+
+        let error = emu.run_to_pc(0x8002_0000, 500).unwrap_err();
+        assert_eq!(error.pc & !0xF, 0x8001_0010 & !0xF);
+        assert!(error.cycles >= 500);
+    }
+
+    #[test]
+    fn test_framebuffer_hash() {
+        let mut emu = looping_emu();
+        let blank = emu.framebuffer_hash();
+        assert_eq!(blank, looping_emu().framebuffer_hash());
+
+        //Write two white pixels to the top left of vram
+        emu.r3000.main_bus.gpu.send_gp0_command(0xA000_0000);
+        emu.r3000.main_bus.gpu.send_gp0_command(0);
+        emu.r3000.main_bus.gpu.send_gp0_command(0x0001_0002);
+        emu.r3000.main_bus.gpu.send_gp0_command(0x7FFF_7FFF);
+        let drawn = emu.framebuffer_hash();
+        assert_ne!(drawn, blank);
+
+        //Off screen writes don't count
+        emu.r3000.main_bus.gpu.send_gp0_command(0xA000_0000);
+        emu.r3000.main_bus.gpu.send_gp0_command(0x01F4_03E8);
+        emu.r3000.main_bus.gpu.send_gp0_command(0x0001_0002);
+        emu.r3000.main_bus.gpu.send_gp0_command(0x7FFF_7FFF);
+        assert_eq!(emu.framebuffer_hash(), drawn);
+
+        //Moving the display to blank vram gives the blank hash again
+        emu.r3000.main_bus.gpu.send_gp1_command(0x0500_0000 | (8 << 10));
+        assert_eq!(emu.framebuffer_hash(), blank);
+    }
+
+    #[test]
+    fn test_truncated_bios() {
+        assert_eq!(PSXEmu::new(vec![0; 256 * 1024]).err(), Some(EmuError::BiosWrongSize(256 * 1024)));
+    }
+
+    #[test]
+    fn test_from_files_errors() {
+        let dir = std::env::temp_dir().join(format!("vaporstation_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let bios_path = dir.join("bios.bin");
+        std::fs::write(&bios_path, vec![0; 512 * 1024]).unwrap();
+
+        let missing = dir.join("missing.bin");
+        assert_eq!(
+            PSXEmu::from_files(&missing, None).err(),
+            Some(EmuError::FileNotFound(missing.clone()))
+        );
+        assert!(matches!(
+            PSXEmu::from_files(&bios_path, Some(&dir.join("game.mds"))).err(),
+            Some(EmuError::UnsupportedDiscFormat(_))
+        ));
+
+        let disc_path = dir.join("game.bin");
+        std::fs::write(&disc_path, vec![0; 2352 * 2]).unwrap();
+        let emu = PSXEmu::from_files(&bios_path, Some(&disc_path)).unwrap();
+        assert_eq!(emu.loaded_disc().as_ref().unwrap().title(), "game.bin");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut emu = looping_emu();
+        assert_eq!(emu.r3000.main_bus.gpu.frame_count(), 0);
+        assert!(emu.run_frame());
+        assert_eq!(emu.r3000.main_bus.gpu.frame_count(), 1);
+        assert!(emu.run_frame());
+        assert_eq!(emu.r3000.main_bus.gpu.frame_count(), 2);
+    }
+
+    #[test]
+    fn test_run_for() {
+        let mut emu = looping_emu();
+        let frame_time = Duration::from_nanos(1_000_000_000 / 60);
+        let frames = emu.run_for(frame_time);
+        let expected_cycles = CPU_CLOCK_HZ / 60;
+        //The bios loop is two uncached instructions per step, so it can overshoot by one step
+        assert!(emu.cycle_count >= expected_cycles);
+        assert!(emu.cycle_count - expected_cycles < 2 * (BIOS_STEP_CYCLES as u64));
+        assert!(frames >= 1);
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let mut emu = looping_emu();
+        assert_eq!(emu.diagnostics(), Diagnostics::default());
+
+        emu.r3000.main_bus.gpu.send_gp1_command(0x3F000000);
+        emu.r3000.main_bus.write_byte(0x1F801800, 0);
+        emu.r3000.main_bus.write_byte(0x1F801801, 0x50);
+
+        assert_eq!(
+            emu.diagnostics(),
+            Diagnostics {
+                ignored_gpu_commands: 1,
+                ignored_cdrom_commands: 1,
+            }
+        );
+    }
+
+    /// CPU cycles taken by one full frame, measured between two vblank edges
+    fn measure_frame_cycles(emu: &mut PSXEmu) -> u64 {
+        assert!(emu.run_frame());
+        let start = emu.cycle_count;
+        assert!(emu.run_frame());
+        emu.cycle_count - start
+    }
+
+    #[test]
+    fn test_force_video_standard() {
+        let mut emu = looping_emu();
+        emu.r3000.main_bus.gpu.send_gp1_command(0x08000000); //Game asks for NTSC
+        let ntsc_cycles = measure_frame_cycles(&mut emu);
+
+        emu.force_video_standard(Some(VideoStandard::Pal));
+        emu.r3000.main_bus.gpu.send_gp1_command(0x08000000);
+        assert_eq!(emu.video_standard(), VideoStandard::Pal);
+        let pal_cycles = measure_frame_cycles(&mut emu);
+        let expected = emu.cycles_per_frame();
+        assert!(pal_cycles.abs_diff(expected) <= 2 * (BIOS_STEP_CYCLES as u64));
+        assert!(pal_cycles > ntsc_cycles);
+
+        emu.force_video_standard(None);
+        assert_eq!(emu.video_standard(), VideoStandard::Ntsc);
+    }
+
+    #[test]
+    fn test_frame_cycle_budget() {
+        //Documented as the cpu clock over the refresh rate. The real scanline timing is within a percent or two of that
+        let mut emu = looping_emu();
+        let ntsc_cycles = measure_frame_cycles(&mut emu);
+        assert!(ntsc_cycles.abs_diff(564_480) < 564_480 / 50, "{}", ntsc_cycles);
+        assert!(ntsc_cycles.abs_diff(emu.cycles_per_frame()) <= 2 * (BIOS_STEP_CYCLES as u64));
+
+        emu.force_video_standard(Some(VideoStandard::Pal));
+        let pal_cycles = measure_frame_cycles(&mut emu);
+        assert!(pal_cycles.abs_diff(677_376) < 677_376 / 50, "{}", pal_cycles);
+        assert!(pal_cycles.abs_diff(emu.cycles_per_frame()) <= 2 * (BIOS_STEP_CYCLES as u64));
+    }
+
+    #[test]
+    fn test_save_state_round_trip() {
+        let mut emu = looping_emu();
+        for _ in 0..1000 {
+            emu.step_cycle();
+        }
+        emu.set_gen_reg(8, 0x1234);
+        emu.r3000.hi = 0xABCD;
+        emu.r3000.main_bus.write_word(0x100, 0xCAFEBABE);
+        emu.r3000.cop0.write_reg(14, 0x80001000);
+        let saved_pc = emu.r3000.pc;
+        let saved_vram = emu.get_vram().clone();
+
+        let state = emu.save_state();
+
+        for _ in 0..1000 {
+            emu.step_cycle();
+        }
+        emu.set_gen_reg(8, 0);
+        emu.r3000.hi = 0;
+        emu.r3000.main_bus.write_word(0x100, 0);
+        emu.r3000.cop0.write_reg(14, 0);
+        emu.r3000.main_bus.gpu.send_gp0_command(0xA0000000); //Copy two white pixels to vram
+        emu.r3000.main_bus.gpu.send_gp0_command(0);
+        emu.r3000.main_bus.gpu.send_gp0_command(0x00010002);
+        emu.r3000.main_bus.gpu.send_gp0_command(0x7FFF7FFF);
+        assert_ne!(emu.get_vram(), &saved_vram);
+
+        emu.load_state(&state).unwrap();
+        assert_eq!(emu.read_gen_reg(8), 0x1234);
+        assert_eq!(emu.r3000.hi, 0xABCD);
+        assert_eq!(emu.r3000.main_bus.read_word(0x100), 0xCAFEBABE);
+        assert_eq!(emu.r3000.cop0.read_reg(14), 0x80001000);
+        assert_eq!(emu.r3000.pc, saved_pc);
+        assert_eq!(emu.get_vram(), &saved_vram);
+    }
+
+    #[test]
+    fn test_save_state_resumes_identically() {
+        let mut emu = looping_emu();
+        //addiu r1, r1, 1; j 0x1000; nop
+        emu.r3000.main_bus.write_word(0x1000, 0x2421_0001);
+        emu.r3000.main_bus.write_word(0x1004, 0x0800_0400);
+        emu.r3000.main_bus.write_word(0x1008, 0);
+        emu.r3000.pc = 0x8000_1000;
+        //Timer 2 interrupts on a target it resets at, and the gpu is halfway through a fill
+        emu.timers.write_word(0x1F801128, 0x40);
+        emu.timers.write_word(0x1F801124, 0x58);
+        emu.r3000.main_bus.gpu.send_gp0_command(0x0200_00FF);
+        emu.run_cycles(5000);
+        let state = emu.save_state();
+
+        emu.run_cycles(5000);
+        let expected = emu.save_state();
+
+        let mut restored = looping_emu();
+        restored.load_state(&state).unwrap();
+        restored.run_cycles(5000);
+        assert!(restored.save_state() == expected);
     }
 
-    pub fn remove_watchpoint(&mut self, addr: u32) {
-        self.watchpoints.retain(|&x| x != addr & 0x1FFFFFFF);
+    #[test]
+    fn test_load_state_truncated() {
+        let mut emu = looping_emu();
+        let state = emu.save_state();
+        assert_eq!(
+            emu.load_state(&state[..state.len() / 2]),
+            Err(SaveStateError::Truncated)
+        );
     }
 }
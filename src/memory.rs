@@ -1,5 +1,7 @@
 use byteorder::{ByteOrder, LittleEndian};
 
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
 pub struct Memory {
     pub data: Vec<u8>,
 }
@@ -42,4 +44,17 @@ impl Memory {
     pub fn write_byte(&mut self, addr: u32, value: u8) {
         self.data[addr as usize] = value;
     }
+
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.data);
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        let data = reader.read_bytes()?;
+        if data.len() != self.data.len() {
+            return Err(SaveStateError::Corrupt);
+        }
+        self.data = data;
+        Ok(())
+    }
 }
@@ -0,0 +1,261 @@
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use log::error;
+
+use crate::error::EmuError;
+
+const CARD_SIZE: usize = 128 * 1024;
+const SECTOR_SIZE: usize = 128;
+const SECTOR_COUNT: u16 = (CARD_SIZE / SECTOR_SIZE) as u16;
+
+const READ_COMMAND: u8 = 0x52;
+const WRITE_COMMAND: u8 = 0x57;
+
+//Flag bit 3 stays set until the first write, so the BIOS can tell the card was swapped
+const FLAG_NEW_CARD: u8 = 0x08;
+
+//End of transfer status bytes
+const STATUS_GOOD: u8 = b'G';
+const STATUS_BAD_CHECKSUM: u8 = b'N';
+const STATUS_BAD_SECTOR: u8 = 0xFF;
+
+//Step of the first data byte in a read and a write. Step 0 is the command byte after the 0x81 select
+const READ_DATA_START: usize = 9;
+const WRITE_DATA_START: usize = 5;
+
+/// A 128KB memory card. Cards attached from a file write every sector back to it as it's saved
+pub(crate) struct MemoryCard {
+    data: Vec<u8>,
+    path: Option<PathBuf>,
+    flag: u8,
+
+    //State of the transfer in progress
+    command: u8,
+    sector: u16,
+    checksum: u8,
+    previous_byte: u8,
+    write_buffer: Vec<u8>,
+}
+
+impl MemoryCard {
+    /// A freshly formatted card with every block free
+    pub(crate) fn new() -> Self {
+        let mut data = vec![0; CARD_SIZE];
+        data[0..2].copy_from_slice(b"MC");
+        for frame in 1..16 {
+            let directory = &mut data[frame * SECTOR_SIZE..(frame + 1) * SECTOR_SIZE];
+            directory[0] = 0xA0; //Free block
+            directory[8..10].copy_from_slice(&[0xFF, 0xFF]); //No next block
+        }
+        //Broken sector list, with nothing in it
+        for frame in 16..36 {
+            let entry = &mut data[frame * SECTOR_SIZE..(frame + 1) * SECTOR_SIZE];
+            entry[0..4].copy_from_slice(&[0xFF; 4]);
+            entry[8..10].copy_from_slice(&[0xFF, 0xFF]);
+        }
+        //Header and directory frames end with the xor of their other bytes
+        for frame in data.chunks_exact_mut(SECTOR_SIZE).take(36) {
+            frame[SECTOR_SIZE - 1] = frame[..SECTOR_SIZE - 1].iter().fold(0, |sum, byte| sum ^ byte);
+        }
+        Self::from_data(data, None)
+    }
+
+    /// Loads the card image at path, or creates a formatted one there if the file doesn't exist yet
+    pub(crate) fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, EmuError> {
+        let path = path.as_ref().to_path_buf();
+        if !path.exists() {
+            let mut card = MemoryCard::new();
+            fs::write(&path, &card.data).map_err(|e| EmuError::from_io(path.clone(), e))?;
+            card.path = Some(path);
+            return Ok(card);
+        }
+        let data = fs::read(&path).map_err(|e| EmuError::from_io(path.clone(), e))?;
+        if data.len() != CARD_SIZE {
+            return Err(EmuError::MemoryCardWrongSize(data.len()));
+        }
+        Ok(Self::from_data(data, Some(path)))
+    }
+
+    fn from_data(data: Vec<u8>, path: Option<PathBuf>) -> Self {
+        Self {
+            data,
+            path,
+            flag: FLAG_NEW_CARD,
+            command: 0,
+            sector: 0,
+            checksum: 0,
+            previous_byte: 0,
+            write_buffer: Vec::with_capacity(SECTOR_SIZE),
+        }
+    }
+
+    /// Handles byte `step` of a transfer, where step 0 is the command byte. Returns the reply
+    /// and whether the card acks it. No ack ends the transfer
+    pub(crate) fn transfer(&mut self, step: usize, val: u8) -> (u8, bool) {
+        if step == 0 {
+            self.command = val;
+            return match val {
+                READ_COMMAND | WRITE_COMMAND => (self.flag, true),
+                _ => (0xFF, false),
+            };
+        }
+        match self.command {
+            READ_COMMAND => self.read_step(step, val),
+            _ => self.write_step(step, val),
+        }
+    }
+
+    fn read_step(&mut self, step: usize, val: u8) -> (u8, bool) {
+        match step {
+            1 => (0x5A, true),
+            2 => (0x5D, true),
+            3 | 4 => self.address_byte(step, val),
+            5 => (0x5C, true),
+            6 => (0x5D, true),
+            //Confirmed address. A bad sector number reads back as 0xFFFF and ends the transfer
+            step if self.sector >= SECTOR_COUNT => (0xFF, step == 7),
+            7 => ((self.sector >> 8) as u8, true),
+            8 => (self.sector as u8, true),
+            step if step < READ_DATA_START + SECTOR_SIZE => {
+                let byte = self.data[self.sector_offset() + step - READ_DATA_START];
+                self.checksum ^= byte;
+                (byte, true)
+            }
+            step if step == READ_DATA_START + SECTOR_SIZE => (self.checksum, true),
+            _ => (STATUS_GOOD, false),
+        }
+    }
+
+    fn write_step(&mut self, step: usize, val: u8) -> (u8, bool) {
+        match step {
+            1 => (0x5A, true),
+            2 => (0x5D, true),
+            3 => {
+                self.write_buffer.clear();
+                self.address_byte(step, val)
+            }
+            4 => self.address_byte(step, val),
+            //The card echoes each byte back one byte late
+            step if step < WRITE_DATA_START + SECTOR_SIZE => {
+                self.write_buffer.push(val);
+                self.checksum ^= val;
+                (std::mem::replace(&mut self.previous_byte, val), true)
+            }
+            step if step == WRITE_DATA_START + SECTOR_SIZE => {
+                self.checksum ^= val;
+                (self.previous_byte, true)
+            }
+            step if step == WRITE_DATA_START + SECTOR_SIZE + 1 => (0x5C, true),
+            step if step == WRITE_DATA_START + SECTOR_SIZE + 2 => (0x5D, true),
+            _ => (self.finish_write(), false),
+        }
+    }
+
+    /// Takes the sector number, MSB at step 3 and LSB at step 4. The checksum starts with both of them
+    fn address_byte(&mut self, step: usize, val: u8) -> (u8, bool) {
+        if step == 3 {
+            self.sector = (val as u16) << 8;
+            self.checksum = val;
+        } else {
+            self.sector |= val as u16;
+            self.checksum ^= val;
+        }
+        //MSB gets 0 back, LSB gets the MSB
+        let reply = if step == 3 { 0x00 } else { self.previous_byte };
+        self.previous_byte = val;
+        (reply, true)
+    }
+
+    /// Status byte at the end of a write. Only a valid sector with a matching checksum gets written
+    fn finish_write(&mut self) -> u8 {
+        if self.sector >= SECTOR_COUNT {
+            return STATUS_BAD_SECTOR;
+        }
+        //The checksum of the address, data and checksum byte itself comes out to 0 when it matches
+        if self.checksum != 0 || self.write_buffer.len() != SECTOR_SIZE {
+            return STATUS_BAD_CHECKSUM;
+        }
+        let offset = self.sector_offset();
+        self.data[offset..offset + SECTOR_SIZE].copy_from_slice(&self.write_buffer);
+        self.flag &= !FLAG_NEW_CARD;
+        self.persist(offset);
+        STATUS_GOOD
+    }
+
+    fn persist(&self, offset: usize) {
+        if let Some(path) = &self.path {
+            let result = OpenOptions::new().write(true).open(path).and_then(|mut file| {
+                file.seek(SeekFrom::Start(offset as u64))?;
+                file.write_all(&self.data[offset..offset + SECTOR_SIZE])
+            });
+            if let Err(e) = result {
+                error!("MEMCARD: Unable to save sector to {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    fn sector_offset(&self) -> usize {
+        self.sector as usize * SECTOR_SIZE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs a whole transfer after the 0x81 select byte and returns the replies
+    fn transfer(card: &mut MemoryCard, bytes: &[u8]) -> Vec<u8> {
+        bytes.iter().enumerate().map(|(step, byte)| card.transfer(step, *byte).0).collect()
+    }
+
+    fn write_command(sector: u16, data: &[u8], checksum: u8) -> Vec<u8> {
+        let mut bytes = vec![WRITE_COMMAND, 0x00, 0x00, (sector >> 8) as u8, sector as u8];
+        bytes.extend_from_slice(data);
+        bytes.extend_from_slice(&[checksum, 0x00, 0x00, 0x00]);
+        bytes
+    }
+
+    #[test]
+    fn test_write_and_read_sector() {
+        let path = std::env::temp_dir().join(format!("vaporstation_memcard_test_{}.mcd", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut card = MemoryCard::from_file(&path).unwrap();
+        assert_eq!(&fs::read(&path).unwrap()[0..2], b"MC");
+
+        let data: Vec<u8> = (0..SECTOR_SIZE as u8).collect();
+        let checksum = data.iter().fold(0x01 ^ 0x23, |sum, byte| sum ^ byte);
+
+        //A bad checksum is rejected
+        let reply = transfer(&mut card, &write_command(0x123, &data, checksum ^ 1));
+        assert_eq!(*reply.last().unwrap(), STATUS_BAD_CHECKSUM);
+
+        let reply = transfer(&mut card, &write_command(0x123, &data, checksum));
+        assert_eq!(reply[..6], [FLAG_NEW_CARD, 0x5A, 0x5D, 0x00, 0x01, 0x23]);
+        assert_eq!(reply[6..8], [0x00, 0x01]);
+        assert_eq!(reply[reply.len() - 4..], [0x7F, 0x5C, 0x5D, STATUS_GOOD]);
+        assert!(!card.transfer(reply.len() - 1, 0).1);
+
+        let mut read = vec![READ_COMMAND, 0x00, 0x00, 0x01, 0x23];
+        read.resize(READ_DATA_START + SECTOR_SIZE + 2, 0x00);
+        let reply = transfer(&mut card, &read);
+        //The flag drops the new card bit after the first write
+        assert_eq!(reply[..9], [0x00, 0x5A, 0x5D, 0x00, 0x01, 0x5C, 0x5D, 0x01, 0x23]);
+        assert_eq!(reply[READ_DATA_START..READ_DATA_START + SECTOR_SIZE], data[..]);
+        assert_eq!(reply[READ_DATA_START + SECTOR_SIZE..], [checksum, STATUS_GOOD]);
+
+        //Saved to the file, so it's there next time
+        let reloaded = MemoryCard::from_file(&path).unwrap();
+        assert_eq!(reloaded.data[0x123 * SECTOR_SIZE..0x124 * SECTOR_SIZE], data[..]);
+
+        //Sectors past the end of the card
+        let reply = transfer(&mut card, &[READ_COMMAND, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(reply[7..], [0xFF, 0xFF]);
+        fs::remove_file(&path).unwrap();
+
+        fs::write(&path, [0; 100]).unwrap();
+        assert!(matches!(MemoryCard::from_file(&path), Err(EmuError::MemoryCardWrongSize(100))));
+        fs::remove_file(&path).unwrap();
+    }
+}
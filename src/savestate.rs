@@ -0,0 +1,178 @@
+use std::io::Cursor;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+const MAGIC: &[u8; 4] = b"VPST";
+const VERSION: u32 = 9;
+
+#[derive(Debug, PartialEq)]
+pub enum SaveStateError {
+    /// The blob doesn't start with the save state magic, so it probably isn't a save state
+    BadMagic,
+    /// The blob was written by an incompatible version of the emulator
+    UnsupportedVersion(u32),
+    /// The blob ended before all of the state was read
+    Truncated,
+    /// A value in the blob is out of range for the field it belongs to
+    Corrupt,
+}
+
+/// Little endian binary writer used by each component to dump its state
+pub(crate) struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub(crate) fn new() -> Self {
+        let mut data = Vec::new();
+        data.extend_from_slice(MAGIC);
+        data.write_u32::<LittleEndian>(VERSION).unwrap();
+        Self { data }
+    }
+
+    pub(crate) fn write_u8(&mut self, val: u8) {
+        self.data.push(val);
+    }
+
+    pub(crate) fn write_bool(&mut self, val: bool) {
+        self.data.push(val as u8);
+    }
+
+    pub(crate) fn write_u16(&mut self, val: u16) {
+        self.data.write_u16::<LittleEndian>(val).unwrap();
+    }
+
+    pub(crate) fn write_u32(&mut self, val: u32) {
+        self.data.write_u32::<LittleEndian>(val).unwrap();
+    }
+
+    pub(crate) fn write_u64(&mut self, val: u64) {
+        self.data.write_u64::<LittleEndian>(val).unwrap();
+    }
+
+    /// Writes a length prefixed byte buffer
+    pub(crate) fn write_bytes(&mut self, val: &[u8]) {
+        self.write_u32(val.len() as u32);
+        self.data.extend_from_slice(val);
+    }
+
+    /// Writes a length prefixed halfword buffer
+    pub(crate) fn write_u16_slice(&mut self, val: &[u16]) {
+        self.write_u32(val.len() as u32);
+        for v in val {
+            self.write_u16(*v);
+        }
+    }
+
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Reader for blobs produced by StateWriter. Every read is checked against the end of the blob
+pub(crate) struct StateReader<'a> {
+    cursor: Cursor<&'a [u8]>,
+}
+
+impl<'a> StateReader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Result<Self, SaveStateError> {
+        if data.len() < MAGIC.len() || &data[0..MAGIC.len()] != MAGIC {
+            return Err(SaveStateError::BadMagic);
+        }
+        let mut reader = Self {
+            cursor: Cursor::new(data),
+        };
+        reader.cursor.set_position(MAGIC.len() as u64);
+        let version = reader.read_u32()?;
+        if version != VERSION {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+        Ok(reader)
+    }
+
+    pub(crate) fn read_u8(&mut self) -> Result<u8, SaveStateError> {
+        self.cursor.read_u8().map_err(|_| SaveStateError::Truncated)
+    }
+
+    pub(crate) fn read_bool(&mut self) -> Result<bool, SaveStateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub(crate) fn read_u16(&mut self) -> Result<u16, SaveStateError> {
+        self.cursor
+            .read_u16::<LittleEndian>()
+            .map_err(|_| SaveStateError::Truncated)
+    }
+
+    pub(crate) fn read_u32(&mut self) -> Result<u32, SaveStateError> {
+        self.cursor
+            .read_u32::<LittleEndian>()
+            .map_err(|_| SaveStateError::Truncated)
+    }
+
+    pub(crate) fn read_u64(&mut self) -> Result<u64, SaveStateError> {
+        self.cursor
+            .read_u64::<LittleEndian>()
+            .map_err(|_| SaveStateError::Truncated)
+    }
+
+    pub(crate) fn read_bytes(&mut self) -> Result<Vec<u8>, SaveStateError> {
+        let len = self.read_u32()? as usize;
+        let start = self.cursor.position() as usize;
+        let data = self.cursor.get_ref();
+        if start + len > data.len() {
+            return Err(SaveStateError::Truncated);
+        }
+        let bytes = data[start..start + len].to_vec();
+        self.cursor.set_position((start + len) as u64);
+        Ok(bytes)
+    }
+
+    pub(crate) fn read_u16_vec(&mut self) -> Result<Vec<u16>, SaveStateError> {
+        let len = self.read_u32()? as usize;
+        let mut result = Vec::with_capacity(len.min(self.cursor.get_ref().len()));
+        for _ in 0..len {
+            result.push(self.read_u16()?);
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.write_u8(0x12);
+        writer.write_bool(true);
+        writer.write_u16(0x3456);
+        writer.write_u32(0xDEADBEEF);
+        writer.write_u64(0x0123_4567_89AB_CDEF);
+        writer.write_bytes(&[1, 2, 3]);
+        writer.write_u16_slice(&[0xAAAA, 0xBBBB]);
+        let data = writer.finish();
+
+        let mut reader = StateReader::new(&data).unwrap();
+        assert_eq!(reader.read_u8(), Ok(0x12));
+        assert_eq!(reader.read_bool(), Ok(true));
+        assert_eq!(reader.read_u16(), Ok(0x3456));
+        assert_eq!(reader.read_u32(), Ok(0xDEADBEEF));
+        assert_eq!(reader.read_u64(), Ok(0x0123_4567_89AB_CDEF));
+        assert_eq!(reader.read_bytes(), Ok(vec![1, 2, 3]));
+        assert_eq!(reader.read_u16_vec(), Ok(vec![0xAAAA, 0xBBBB]));
+        assert_eq!(reader.read_u8(), Err(SaveStateError::Truncated));
+    }
+
+    #[test]
+    fn test_rejects_bad_header() {
+        assert!(matches!(StateReader::new(b"nope"), Err(SaveStateError::BadMagic)));
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            StateReader::new(&data),
+            Err(SaveStateError::UnsupportedVersion(v)) if v == VERSION + 1
+        ));
+    }
+}
@@ -1,45 +1,827 @@
+use std::collections::VecDeque;
+
+use bit_field::BitField;
+
+use crate::audio::AudioQueue;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+const SPU_BASE: u32 = 0x1F801C00;
+const VOICE_COUNT: usize = 24;
+const RAM_SIZE: usize = 512 * 1024;
+
+const MAIN_VOLUME_LEFT: u32 = 0x1F801D80;
+const MAIN_VOLUME_RIGHT: u32 = 0x1F801D82;
+const REVERB_VOLUME_LEFT: u32 = 0x1F801D84;
+const REVERB_VOLUME_RIGHT: u32 = 0x1F801D86;
+
+const KON_LOW: u32 = 0x1F801D88;
+const KON_HIGH: u32 = 0x1F801D8A;
+const KOFF_LOW: u32 = 0x1F801D8C;
+const KOFF_HIGH: u32 = 0x1F801D8E;
+const EON_LOW: u32 = 0x1F801D98;
+const EON_HIGH: u32 = 0x1F801D9A;
+const ENDX_LOW: u32 = 0x1F801D9C;
+const ENDX_HIGH: u32 = 0x1F801D9E;
+const REVERB_BASE: u32 = 0x1F801DA2;
+const TRANSFER_ADDRESS: u32 = 0x1F801DA6;
+const TRANSFER_FIFO: u32 = 0x1F801DA8;
+const SPUCNT: u32 = 0x1F801DAA;
+const TRANSFER_CONTROL: u32 = 0x1F801DAC;
+const SPUSTAT: u32 = 0x1F801DAE;
+const CD_VOLUME_LEFT: u32 = 0x1F801DB0;
+const CD_VOLUME_RIGHT: u32 = 0x1F801DB2;
+
+//Reverb configuration, named as in psx-spx. m* and d* are addresses relative to the current reverb address in 8 byte units
+const D_APF1: u32 = 0x1F801DC0;
+const D_APF2: u32 = 0x1F801DC2;
+const V_IIR: u32 = 0x1F801DC4;
+const V_COMB1: u32 = 0x1F801DC6;
+const V_COMB2: u32 = 0x1F801DC8;
+const V_COMB3: u32 = 0x1F801DCA;
+const V_COMB4: u32 = 0x1F801DCC;
+const V_WALL: u32 = 0x1F801DCE;
+const V_APF1: u32 = 0x1F801DD0;
+const V_APF2: u32 = 0x1F801DD2;
+const M_LSAME: u32 = 0x1F801DD4;
+const M_RSAME: u32 = 0x1F801DD6;
+const M_LCOMB1: u32 = 0x1F801DD8;
+const M_RCOMB1: u32 = 0x1F801DDA;
+const M_LCOMB2: u32 = 0x1F801DDC;
+const M_RCOMB2: u32 = 0x1F801DDE;
+const D_LSAME: u32 = 0x1F801DE0;
+const D_RSAME: u32 = 0x1F801DE2;
+const M_LDIFF: u32 = 0x1F801DE4;
+const M_RDIFF: u32 = 0x1F801DE6;
+const M_LCOMB3: u32 = 0x1F801DE8;
+const M_RCOMB3: u32 = 0x1F801DEA;
+const M_LCOMB4: u32 = 0x1F801DEC;
+const M_RCOMB4: u32 = 0x1F801DEE;
+const D_LDIFF: u32 = 0x1F801DF0;
+const D_RDIFF: u32 = 0x1F801DF2;
+const M_LAPF1: u32 = 0x1F801DF4;
+const M_RAPF1: u32 = 0x1F801DF6;
+const M_LAPF2: u32 = 0x1F801DF8;
+const M_RAPF2: u32 = 0x1F801DFA;
+const V_LIN: u32 = 0x1F801DFC;
+const V_RIN: u32 = 0x1F801DFE;
+
+//Per voice registers, as offsets from the voice's base address
+const VOICE_VOLUME_LEFT: u32 = 0x0;
+const VOICE_VOLUME_RIGHT: u32 = 0x2;
+const VOICE_PITCH: u32 = 0x4;
+const VOICE_START_ADDRESS: u32 = 0x6;
+const VOICE_ADSR_LOW: u32 = 0x8;
+const VOICE_ADSR_HIGH: u32 = 0xA;
+const VOICE_ADSR_VOLUME: u32 = 0xC;
+const VOICE_REPEAT_ADDRESS: u32 = 0xE;
+
+//ADPCM blocks are 16 bytes: a shift/filter byte, a flags byte, then 28 4 bit samples
+const BLOCK_SIZE: u32 = 16;
+const SAMPLES_PER_BLOCK: usize = 28;
+const FLAG_LOOP_END: u8 = 0x1;
+const FLAG_REPEAT: u8 = 0x2;
+const FLAG_LOOP_START: u8 = 0x4;
+
+//ADPCM prediction filters, in 1/64ths. The SPU has one more than XA
+const POS_TABLE: [i32; 5] = [0, 60, 115, 98, 122];
+const NEG_TABLE: [i32; 5] = [0, 0, -52, -55, -60];
+
+//Frames of CD audio the SPU holds on to before the oldest are dropped
+const CD_INPUT_CAPACITY: usize = 44100;
+
+//The SPU runs at 44.1kHz, one sample every 768 cpu cycles
+const CYCLES_PER_SAMPLE: u32 = 768;
+
+const MAX_ENVELOPE: i32 = 0x7FFF;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AdsrPhase {
+    Off,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Voice {
+    phase: AdsrPhase,
+    envelope: i32,
+    //Samples left before the envelope moves again
+    envelope_wait: u32,
+
+    //Byte address of the ADPCM block being played, and the flags it had
+    address: u32,
+    block_flags: u8,
+    samples: [i16; SAMPLES_PER_BLOCK],
+    //Last two decoded samples, for the prediction filters
+    history: [i32; 2],
+    //Position in the block in 1/4096ths of a sample
+    pitch_counter: u32,
+}
+
+impl Voice {
+    fn new() -> Self {
+        Self {
+            phase: AdsrPhase::Off,
+            envelope: 0,
+            envelope_wait: 0,
+            address: 0,
+            block_flags: 0,
+            samples: [0; SAMPLES_PER_BLOCK],
+            history: [0; 2],
+            pitch_counter: 0,
+        }
+    }
+
+    /// Decodes the ADPCM block at the voice's address. A block at the very end of RAM wraps around to the start
+    fn decode_block(&mut self, ram: &[u8]) {
+        let mut block = [0; BLOCK_SIZE as usize];
+        for (i, byte) in block.iter_mut().enumerate() {
+            *byte = ram[(self.address as usize + i) % RAM_SIZE];
+        }
+        let shift = match block[0] & 0xF {
+            shift if shift > 12 => 9,
+            shift => shift,
+        };
+        let filter = ((block[0] >> 4) as usize).min(4);
+        self.block_flags = block[1];
+        let [mut old, mut older] = self.history;
+        for (i, sample) in self.samples.iter_mut().enumerate() {
+            let nibble = (block[2 + i / 2] >> ((i & 1) * 4)) & 0xF;
+            let raw = ((((nibble << 4) as i8) as i32) << 8) >> shift;
+            let predicted = raw + ((old * POS_TABLE[filter] + older * NEG_TABLE[filter] + 32) >> 6);
+            let clamped = predicted.clamp(i16::MIN as i32, i16::MAX as i32);
+            older = old;
+            old = clamped;
+            *sample = clamped as i16;
+        }
+        self.history = [old, older];
+    }
+
+    /// Advances the ADSR envelope by one sample
+    fn step_envelope(&mut self, adsr_low: u16, adsr_high: u16) {
+        if self.envelope_wait > 1 {
+            self.envelope_wait -= 1;
+            return;
+        }
+        let sustain_level = ((adsr_low.get_bits(0..=3) as i32) + 1) * 0x800;
+        //(shift, step, exponential, decreasing) for the current phase
+        let (shift, step, exponential, decreasing) = match self.phase {
+            AdsrPhase::Off => return,
+            AdsrPhase::Attack => (adsr_low.get_bits(10..=14), 7 - adsr_low.get_bits(8..=9) as i32, adsr_low.get_bit(15), false),
+            AdsrPhase::Decay => (adsr_low.get_bits(4..=7) << 2, -8, true, true),
+            AdsrPhase::Sustain => {
+                let decreasing = adsr_high.get_bit(14);
+                let step = adsr_high.get_bits(6..=7) as i32;
+                let step = if decreasing { -8 + step } else { 7 - step };
+                (adsr_high.get_bits(8..=12), step, adsr_high.get_bit(15), decreasing)
+            }
+            AdsrPhase::Release => (adsr_high.get_bits(0..=4) << 2, -8, adsr_high.get_bit(5), true),
+        };
+
+        //Envelope timing from psx-spx. Big shifts wait longer between steps, small ones take bigger steps
+        let shift = shift as u32;
+        let mut wait = 1 << shift.saturating_sub(11);
+        let mut delta = step << 11u32.saturating_sub(shift);
+        if exponential && !decreasing && self.envelope > 0x6000 {
+            wait *= 4;
+        }
+        if exponential && decreasing {
+            delta = (delta * self.envelope) >> 15;
+        }
+        self.envelope = (self.envelope + delta).clamp(0, MAX_ENVELOPE);
+        self.envelope_wait = wait;
+
+        self.phase = match self.phase {
+            AdsrPhase::Attack if self.envelope == MAX_ENVELOPE => AdsrPhase::Decay,
+            AdsrPhase::Decay if self.envelope <= sustain_level => AdsrPhase::Sustain,
+            AdsrPhase::Release if self.envelope == 0 => AdsrPhase::Off,
+            phase => phase,
+        };
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.phase as u8);
+        writer.write_u32(self.envelope as u32);
+        writer.write_u32(self.envelope_wait);
+        writer.write_u32(self.address);
+        writer.write_u8(self.block_flags);
+        for sample in self.samples.iter() {
+            writer.write_u16(*sample as u16);
+        }
+        for sample in self.history.iter() {
+            writer.write_u32(*sample as u32);
+        }
+        writer.write_u32(self.pitch_counter);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.phase = match reader.read_u8()? {
+            0 => AdsrPhase::Off,
+            1 => AdsrPhase::Attack,
+            2 => AdsrPhase::Decay,
+            3 => AdsrPhase::Sustain,
+            4 => AdsrPhase::Release,
+            _ => return Err(SaveStateError::Corrupt),
+        };
+        self.envelope = reader.read_u32()? as i32;
+        self.envelope_wait = reader.read_u32()?;
+        self.address = reader.read_u32()?;
+        self.block_flags = reader.read_u8()?;
+        for sample in self.samples.iter_mut() {
+            *sample = reader.read_u16()? as i16;
+        }
+        for sample in self.history.iter_mut() {
+            *sample = reader.read_u32()? as i32;
+        }
+        self.pitch_counter = reader.read_u32()?;
+        Ok(())
+    }
+}
+
 pub struct SPU {
-    main_volume: u32,
-    reverb_volume: u32,
-    spu_control: u16,
-    spu_status: u16,
-    voice0_volume: u32,
+    //Every register as it was last written. Reads come from here unless the register is computed
+    registers: [u16; 0x200],
+    voices: [Voice; VOICE_COUNT],
+    //Voices that reached the end of their sample since their last key on
+    endx: u32,
+    cycle_counter: u32,
+
+    ram: Vec<u8>,
+    //Byte address in ram the next manual or DMA transfer goes to
+    transfer_address: u32,
+    //CD-DA and XA audio from the drive, mixed in one frame per sample
+    cd_input: VecDeque<(i16, i16)>,
+    output: AudioQueue,
+
+    //Byte address in the reverb work area the taps are relative to
+    reverb_address: u32,
+    //Reverb runs at half rate, so every other sample reuses the last output
+    reverb_odd_sample: bool,
+    reverb_output: (i32, i32),
 }
 
 impl SPU {
     pub fn new() -> Self {
-        Self {
-            main_volume: 0,
-            reverb_volume: 0,
-            spu_control: 0x8000, //Start with spu enabled
-            spu_status: 0,
-            voice0_volume: 0,
+        let mut spu = Self {
+            registers: [0; 0x200],
+            voices: [Voice::new(); VOICE_COUNT],
+            endx: 0,
+            cycle_counter: 0,
+            ram: vec![0; RAM_SIZE],
+            transfer_address: 0,
+            cd_input: VecDeque::new(),
+            output: AudioQueue::new(),
+            reverb_address: 0,
+            reverb_odd_sample: false,
+            reverb_output: (0, 0),
+        };
+        spu.registers[register_index(SPUCNT)] = 0x8000; //Start with spu enabled
+        spu.registers[register_index(TRANSFER_CONTROL)] = 0x4;
+        spu
+    }
+
+    /// Queued host audio output isn't part of the state
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16_slice(&self.registers);
+        for voice in self.voices.iter() {
+            voice.save_state(writer);
+        }
+        writer.write_u32(self.endx);
+        writer.write_u32(self.cycle_counter);
+        writer.write_bytes(&self.ram);
+        writer.write_u32(self.transfer_address);
+        writer.write_u32(self.cd_input.len() as u32);
+        for (left, right) in self.cd_input.iter() {
+            writer.write_u16(*left as u16);
+            writer.write_u16(*right as u16);
         }
+        writer.write_u32(self.reverb_address);
+        writer.write_bool(self.reverb_odd_sample);
+        writer.write_u32(self.reverb_output.0 as u32);
+        writer.write_u32(self.reverb_output.1 as u32);
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        let registers = reader.read_u16_vec()?;
+        if registers.len() != self.registers.len() {
+            return Err(SaveStateError::Corrupt);
+        }
+        self.registers.copy_from_slice(&registers);
+        for voice in self.voices.iter_mut() {
+            voice.load_state(reader)?;
+        }
+        self.endx = reader.read_u32()?;
+        self.cycle_counter = reader.read_u32()?;
+        let ram = reader.read_bytes()?;
+        if ram.len() != RAM_SIZE {
+            return Err(SaveStateError::Corrupt);
+        }
+        self.ram = ram;
+        self.transfer_address = reader.read_u32()? % RAM_SIZE as u32;
+        let cd_frames = reader.read_u32()? as usize;
+        if cd_frames > CD_INPUT_CAPACITY {
+            return Err(SaveStateError::Corrupt);
+        }
+        self.cd_input.clear();
+        for _ in 0..cd_frames {
+            let left = reader.read_u16()? as i16;
+            let right = reader.read_u16()? as i16;
+            self.cd_input.push_back((left, right));
+        }
+        self.reverb_address = reader.read_u32()?;
+        self.reverb_odd_sample = reader.read_bool()?;
+        self.reverb_output = (reader.read_u32()? as i32, reader.read_u32()? as i32);
+        Ok(())
     }
 
     pub fn read_half_word(&mut self, addr: u32) -> u16 {
         match addr {
-            0x1F801DAE => self.spu_status,
-            0x1F801DAA => self.spu_control,
-            0x1F801DAC => 0x4, //SPU transfer control
-            0x1F801C00 => (self.voice0_volume & 0xFFFF) as u16,
-            _ => 0, //{println!("Read unknown SPU address {:#X}", addr); 0}
+            //The low 6 bits of SPUCNT show up in SPUSTAT once they've been applied, which is right away here
+            SPUSTAT => self.registers[register_index(SPUCNT)] & 0x3F,
+            ENDX_LOW => self.endx as u16,
+            ENDX_HIGH => (self.endx >> 16) as u16,
+            _ => match voice_register(addr) {
+                Some((voice, VOICE_ADSR_VOLUME)) => self.voices[voice].envelope as u16,
+                _ => self.registers[register_index(addr)],
+            },
         }
     }
 
     pub fn write_half_word(&mut self, addr: u32, value: u16) {
+        self.registers[register_index(addr)] = value;
         match addr {
-            0x1F801D80 => self.main_volume = (value as u32) | (self.main_volume & 0xFFFF0000),
-            0x1F801D82 => self.main_volume = ((value as u32) << 4) | (self.main_volume & 0xFFFF),
-            0x1F801D84 => self.reverb_volume = (value as u32) | (self.reverb_volume & 0xFFFF0000),
-            0x1F801D86 => {
-                self.reverb_volume = ((value as u32) << 4) | (self.reverb_volume & 0xFFFF)
+            //Transfer addresses are in 8 byte units
+            TRANSFER_ADDRESS => self.transfer_address = (value as u32) * 8,
+            TRANSFER_FIFO => self.write_ram(value),
+            REVERB_BASE => self.reverb_address = (value as u32) * 8,
+            KON_LOW => self.key_on(value as u32),
+            KON_HIGH => self.key_on((value as u32) << 16),
+            KOFF_LOW => self.key_off(value as u32),
+            KOFF_HIGH => self.key_off((value as u32) << 16),
+            _ => {
+                if let Some((voice, VOICE_ADSR_VOLUME)) = voice_register(addr) {
+                    self.voices[voice].envelope = (value as i16).max(0) as i32;
+                }
+            }
+        }
+    }
+
+    /// Runs the SPU for the given number of cpu cycles
+    pub fn execute_cycles(&mut self, cycles: u32) {
+        self.cycle_counter += cycles;
+        while self.cycle_counter >= CYCLES_PER_SAMPLE {
+            self.cycle_counter -= CYCLES_PER_SAMPLE;
+            self.step();
+        }
+    }
+
+    /// Produces one 44.1kHz frame. Every active voice plays its next sample and advances its envelope,
+    /// then the voices, CD audio and reverb are mixed into the output queue
+    pub fn step(&mut self) {
+        let mut left = 0;
+        let mut right = 0;
+        let mut reverb_left = 0;
+        let mut reverb_right = 0;
+        let reverb_voices = self.registers[register_index(EON_LOW)] as u32 | (self.registers[register_index(EON_HIGH)] as u32) << 16;
+        for voice in 0..VOICE_COUNT {
+            if self.voice_active(voice) {
+                let sample = self.next_voice_sample(voice);
+                let voice_left = apply_volume(sample, self.voice_register_value(voice, VOICE_VOLUME_LEFT));
+                let voice_right = apply_volume(sample, self.voice_register_value(voice, VOICE_VOLUME_RIGHT));
+                left += voice_left;
+                right += voice_right;
+                if reverb_voices.get_bit(voice) {
+                    reverb_left += voice_left;
+                    reverb_right += voice_right;
+                }
+
+                let adsr_low = self.voice_register_value(voice, VOICE_ADSR_LOW);
+                let adsr_high = self.voice_register_value(voice, VOICE_ADSR_HIGH);
+                self.voices[voice].step_envelope(adsr_low, adsr_high);
+            }
+        }
+
+        let control = self.registers[register_index(SPUCNT)];
+        //Bit 14 clear mutes the voices. CD audio still plays
+        if !control.get_bit(14) {
+            left = 0;
+            right = 0;
+            reverb_left = 0;
+            reverb_right = 0;
+        }
+        let cd_frame = self.cd_input.pop_front().unwrap_or((0, 0));
+        if control.get_bit(0) {
+            let cd_left = (cd_frame.0 as i32 * self.registers[register_index(CD_VOLUME_LEFT)] as i16 as i32) >> 15;
+            let cd_right = (cd_frame.1 as i32 * self.registers[register_index(CD_VOLUME_RIGHT)] as i16 as i32) >> 15;
+            left += cd_left;
+            right += cd_right;
+            //Bit 2 sends CD audio to the reverb too
+            if control.get_bit(2) {
+                reverb_left += cd_left;
+                reverb_right += cd_right;
+            }
+        }
+
+        let (wet_left, wet_right) = self.step_reverb(clamp_sample(reverb_left) as i32, clamp_sample(reverb_right) as i32);
+        left += wet_left;
+        right += wet_right;
+
+        let left = apply_volume(clamp_sample(left) as i32, self.registers[register_index(MAIN_VOLUME_LEFT)]);
+        let right = apply_volume(clamp_sample(right) as i32, self.registers[register_index(MAIN_VOLUME_RIGHT)]);
+        self.output.push(&[(clamp_sample(left), clamp_sample(right))]);
+    }
+
+    /// Runs the reverb unit on one frame of input and returns its output. The reverb runs at 22.05kHz,
+    /// so odd samples just repeat the last output. The work area only gets written while SPUCNT bit 7 is set
+    fn step_reverb(&mut self, input_left: i32, input_right: i32) -> (i32, i32) {
+        self.reverb_odd_sample = !self.reverb_odd_sample;
+        if self.reverb_odd_sample {
+            return self.reverb_output;
+        }
+
+        let left_in = mul(input_left, self.reverb_register(V_LIN));
+        let right_in = mul(input_right, self.reverb_register(V_RIN));
+        let iir = self.reverb_register(V_IIR);
+        let wall = self.reverb_register(V_WALL);
+
+        //Same side and cross side reflections, each through the IIR filter
+        for (input, tap, feedback) in [
+            (left_in, M_LSAME, D_LSAME),
+            (right_in, M_RSAME, D_RSAME),
+            (left_in, M_LDIFF, D_RDIFF),
+            (right_in, M_RDIFF, D_LDIFF),
+        ] {
+            let destination = self.reverb_offset(tap);
+            let previous = self.reverb_read(destination - 2);
+            let reflected = input + mul(self.reverb_read(self.reverb_offset(feedback)), wall);
+            self.reverb_write(destination, mul(reflected - previous, iir) + previous);
+        }
+
+        let left = self.reverb_comb([M_LCOMB1, M_LCOMB2, M_LCOMB3, M_LCOMB4]);
+        let right = self.reverb_comb([M_RCOMB1, M_RCOMB2, M_RCOMB3, M_RCOMB4]);
+        let left = self.reverb_all_pass(left, M_LAPF1, D_APF1, V_APF1);
+        let left = self.reverb_all_pass(left, M_LAPF2, D_APF2, V_APF2);
+        let right = self.reverb_all_pass(right, M_RAPF1, D_APF1, V_APF1);
+        let right = self.reverb_all_pass(right, M_RAPF2, D_APF2, V_APF2);
+
+        self.reverb_output = (
+            mul(left, self.reverb_register(REVERB_VOLUME_LEFT)),
+            mul(right, self.reverb_register(REVERB_VOLUME_RIGHT)),
+        );
+        let base = self.reverb_base();
+        //Past the end of ram the address goes back to the start of the work area
+        self.reverb_address = ((self.reverb_address + 2) & 0x7FFFE).max(base);
+        self.reverb_output
+    }
+
+    fn reverb_comb(&self, taps: [u32; 4]) -> i32 {
+        let volumes = [V_COMB1, V_COMB2, V_COMB3, V_COMB4];
+        taps.iter()
+            .zip(volumes.iter())
+            .map(|(tap, volume)| mul(self.reverb_read(self.reverb_offset(*tap)), self.reverb_register(*volume)))
+            .sum()
+    }
+
+    fn reverb_all_pass(&mut self, input: i32, tap: u32, delay: u32, volume: u32) -> i32 {
+        let volume = self.reverb_register(volume);
+        let destination = self.reverb_offset(tap);
+        let delayed = self.reverb_read(destination - self.reverb_offset(delay));
+        let output = clamp_sample(input - mul(delayed, volume)) as i32;
+        self.reverb_write(destination, output);
+        mul(output, volume) + delayed
+    }
+
+    fn reverb_register(&self, addr: u32) -> i32 {
+        self.registers[register_index(addr)] as i16 as i32
+    }
+
+    fn reverb_offset(&self, addr: u32) -> i32 {
+        self.registers[register_index(addr)] as i32 * 8
+    }
+
+    fn reverb_base(&self) -> u32 {
+        self.registers[register_index(REVERB_BASE)] as u32 * 8
+    }
+
+    /// Ram address for an offset from the reverb address. Addresses wrap around inside the work area,
+    /// which runs from the reverb base to the end of ram
+    fn reverb_ram_address(&self, offset: i32) -> usize {
+        let base = self.reverb_base() as i32;
+        let size = RAM_SIZE as i32 - base;
+        let relative = self.reverb_address as i32 - base + offset;
+        (base + relative.rem_euclid(size)) as usize & 0x7FFFE
+    }
+
+    fn reverb_read(&self, offset: i32) -> i32 {
+        let address = self.reverb_ram_address(offset);
+        i16::from_le_bytes([self.ram[address], self.ram[address + 1]]) as i32
+    }
+
+    fn reverb_write(&mut self, offset: i32, value: i32) {
+        if self.registers[register_index(SPUCNT)].get_bit(7) {
+            let address = self.reverb_ram_address(offset);
+            self.ram[address..address + 2].copy_from_slice(&clamp_sample(value).to_le_bytes());
+        }
+    }
+
+    /// Queues CD audio frames from the drive
+    pub(crate) fn push_cd_audio(&mut self, frames: &[(i16, i16)]) {
+        self.cd_input.extend(frames.iter());
+        let overflow = self.cd_input.len().saturating_sub(CD_INPUT_CAPACITY);
+        self.cd_input.drain(..overflow);
+    }
+
+    /// Moves the mixed output onto the end of out as interleaved left, right samples
+    pub(crate) fn drain_audio(&mut self, out: &mut Vec<i16>) {
+        self.output.drain_into(out);
+    }
+
+    /// Takes the CD audio that hasn't been mixed yet
+    #[cfg(test)]
+    pub(crate) fn take_cd_input(&mut self) -> Vec<(i16, i16)> {
+        self.cd_input.drain(..).collect()
+    }
+
+    /// DMA channel 4 write. Words go to ram at the transfer address like two FIFO writes
+    pub(crate) fn dma_write(&mut self, word: u32) {
+        self.write_ram(word as u16);
+        self.write_ram((word >> 16) as u16);
+    }
+
+    /// DMA channel 4 read from ram at the transfer address
+    pub(crate) fn dma_read(&mut self) -> u32 {
+        let address = self.transfer_address as usize;
+        let word = u32::from_le_bytes([
+            self.ram[address],
+            self.ram[(address + 1) % RAM_SIZE],
+            self.ram[(address + 2) % RAM_SIZE],
+            self.ram[(address + 3) % RAM_SIZE],
+        ]);
+        self.transfer_address = (self.transfer_address + 4) % RAM_SIZE as u32;
+        word
+    }
+
+    fn write_ram(&mut self, value: u16) {
+        let address = self.transfer_address as usize;
+        self.ram[address..address + 2].copy_from_slice(&value.to_le_bytes());
+        self.transfer_address = (self.transfer_address + 2) % RAM_SIZE as u32;
+    }
+
+    /// The voice's current sample with its envelope applied, then moves along by the voice's pitch.
+    /// Finishing a block sets ENDX on a loop end flag, and either jumps to the repeat address or stops the voice
+    fn next_voice_sample(&mut self, voice: usize) -> i32 {
+        let pitch = self.voice_register_value(voice, VOICE_PITCH).min(0x4000) as u32;
+        let repeat_address = self.voice_register_value(voice, VOICE_REPEAT_ADDRESS) as u32 * 8;
+        let state = &mut self.voices[voice];
+        let sample = state.samples[(state.pitch_counter >> 12) as usize] as i32;
+        let sample = (sample * state.envelope) >> 15;
+
+        state.pitch_counter += pitch;
+        if state.pitch_counter >> 12 >= SAMPLES_PER_BLOCK as u32 {
+            state.pitch_counter -= (SAMPLES_PER_BLOCK as u32) << 12;
+            if state.block_flags & FLAG_LOOP_END != 0 {
+                self.endx.set_bit(voice, true);
+                if state.block_flags & FLAG_REPEAT == 0 {
+                    state.phase = AdsrPhase::Off;
+                    state.envelope = 0;
+                    return sample;
+                }
+                state.address = repeat_address;
+            } else {
+                state.address = (state.address + BLOCK_SIZE) % RAM_SIZE as u32;
+            }
+            self.load_block(voice);
+        }
+        sample
+    }
+
+    /// Decodes the block at the voice's address. A loop start flag makes it the voice's repeat address
+    fn load_block(&mut self, voice: usize) {
+        self.voices[voice].decode_block(&self.ram);
+        if self.voices[voice].block_flags & FLAG_LOOP_START != 0 {
+            let repeat_address = (self.voices[voice].address / 8) as u16;
+            let index = register_index(SPU_BASE + voice as u32 * 0x10 + VOICE_REPEAT_ADDRESS);
+            self.registers[index] = repeat_address;
+        }
+    }
+
+    /// A voice is active from key on until its release reaches silence
+    pub fn voice_active(&self, voice: usize) -> bool {
+        self.voices[voice].phase != AdsrPhase::Off
+    }
+
+    fn voice_register_value(&self, voice: usize, offset: u32) -> u16 {
+        self.registers[register_index(SPU_BASE + voice as u32 * 0x10 + offset)]
+    }
+
+    fn key_on(&mut self, voices: u32) {
+        for voice in (0..VOICE_COUNT).filter(|voice| voices.get_bit(*voice)) {
+            self.voices[voice] = Voice {
+                phase: AdsrPhase::Attack,
+                address: self.voice_register_value(voice, VOICE_START_ADDRESS) as u32 * 8,
+                ..Voice::new()
+            };
+            self.load_block(voice);
+            self.endx.set_bit(voice, false);
+        }
+    }
+
+    fn key_off(&mut self, voices: u32) {
+        for voice in (0..VOICE_COUNT).filter(|voice| voices.get_bit(*voice)) {
+            if self.voices[voice].phase != AdsrPhase::Off {
+                self.voices[voice].phase = AdsrPhase::Release;
+                self.voices[voice].envelope_wait = 0;
             }
-            0x1F801DA6 => (), //SPU data transfer address
-            0x1F801DA8 => (), //SPU data transfer fifo
-            0x1F801DAA => self.spu_control = value,
-            0x1F801C00 => self.voice0_volume = value as u32, //TODO implement real voice registers
-            _ => (), //println!("Wrote unknown SPU address {:#X} with {:#X}", addr, value)
+        }
+    }
+}
+
+/// Scales a sample by a volume register. Fixed volumes are a signed 15 bit value at half scale.
+/// Sweep mode isn't emulated, those play at full volume
+fn apply_volume(sample: i32, volume: u16) -> i32 {
+    let volume = match volume.get_bit(15) {
+        true => 0x7FFF,
+        false => ((volume << 1) as i16) as i32,
+    };
+    (sample * volume) >> 15
+}
+
+/// Fixed point multiply by a signed 1.15 factor
+fn mul(sample: i32, factor: i32) -> i32 {
+    (sample * factor) >> 15
+}
+
+fn clamp_sample(sample: i32) -> i16 {
+    sample.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+}
+
+fn register_index(addr: u32) -> usize {
+    ((addr - SPU_BASE) / 2) as usize & 0x1FF
+}
+
+/// Voice number and register offset for addresses in the per voice register block
+fn voice_register(addr: u32) -> Option<(usize, u32)> {
+    let offset = addr - SPU_BASE;
+    let voice = (offset / 0x10) as usize;
+    match voice < VOICE_COUNT {
+        true => Some((voice, offset % 0x10)),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_voice_registers_and_key_on() {
+        let mut spu = SPU::new();
+        spu.write_half_word(0x1F801C30, 0x3FFF); //Voice 3 left volume
+        assert_eq!(spu.read_half_word(0x1F801C30), 0x3FFF);
+        spu.write_half_word(SPUCNT, 0xC001);
+        assert_eq!(spu.read_half_word(SPUSTAT), 0x1);
+
+        //Fastest linear attack, then slow release
+        spu.write_half_word(0x1F801C38, 0x007F);
+        spu.write_half_word(0x1F801C3A, 0x0000);
+        assert!(!spu.voice_active(3));
+        spu.write_half_word(KON_LOW, 1 << 3);
+        assert!(spu.voice_active(3));
+        assert!(!spu.voice_active(4));
+
+        spu.execute_cycles(CYCLES_PER_SAMPLE * 4);
+        assert!(spu.read_half_word(0x1F801C3C) > 0);
+
+        spu.write_half_word(KOFF_LOW, 1 << 3);
+        for _ in 0..0x10000 {
+            spu.step();
+        }
+        assert!(!spu.voice_active(3));
+        assert_eq!(spu.read_half_word(0x1F801C3C), 0);
+    }
+
+    #[test]
+    fn test_adpcm_voice_mix() {
+        let mut spu = SPU::new();
+        //One block at 0x1000 with no shift or filter, samples 1, 2, -1, 0, ... and loop start, repeat and loop end set
+        spu.write_half_word(TRANSFER_ADDRESS, 0x200);
+        for value in [0x0700, 0x0F21, 0, 0, 0, 0, 0, 0] {
+            spu.write_half_word(TRANSFER_FIFO, value);
+        }
+        spu.write_half_word(TRANSFER_ADDRESS, 0x200);
+        assert_eq!(spu.dma_read(), 0x0F21_0700);
+
+        spu.write_half_word(0x1F801C00, 0x3FFF);
+        spu.write_half_word(0x1F801C02, 0x3FFF);
+        spu.write_half_word(0x1F801C04, 0x1000); //One sample per output frame
+        spu.write_half_word(0x1F801C06, 0x200);
+        //Slowest linear attack, so the envelope holds near where it's set
+        spu.write_half_word(0x1F801C08, 0x7F00);
+        spu.write_half_word(MAIN_VOLUME_LEFT, 0x3FFF);
+        spu.write_half_word(MAIN_VOLUME_RIGHT, 0x3FFF);
+        spu.write_half_word(SPUCNT, 0xC000);
+        spu.write_half_word(KON_LOW, 1);
+        spu.write_half_word(0x1F801C0C, 0x4000);
+        assert_eq!(spu.voices[0].samples[..4], [4096, 8192, -4096, 0]);
+
+        for _ in 0..SAMPLES_PER_BLOCK {
+            spu.step();
+        }
+        let mut samples = Vec::new();
+        spu.drain_audio(&mut samples);
+        assert_eq!(samples.len(), SAMPLES_PER_BLOCK * 2);
+        //4096 at half envelope is 2048, and 0x3FFF volumes are just under full scale
+        assert_eq!(samples[..2], [2046, 2046]);
+        //The attack has stepped the envelope to 0x4004 by the second sample
+        assert_eq!(samples[2..6], [4095, 4095, -2049, -2049]);
+        assert_eq!(spu.read_half_word(ENDX_LOW), 1);
+        //Repeats from the loop start
+        assert!(spu.voice_active(0));
+        assert_eq!(spu.read_half_word(0x1F801C0E), 0x200);
+    }
+
+    #[test]
+    fn test_block_at_end_of_ram() {
+        let mut spu = SPU::new();
+        //The last block of RAM runs 8 bytes past the end, which come from the start
+        spu.ram[RAM_SIZE - 8] = 0x00;
+        spu.ram[0] = 0x21;
+        spu.write_half_word(0x1F801C04, 0x1000);
+        spu.write_half_word(0x1F801C06, 0xFFFF);
+        spu.write_half_word(SPUCNT, 0xC000);
+        spu.write_half_word(KON_LOW, 1);
+        assert_eq!(spu.voices[0].samples[12..14], [4096, 8192]);
+        for _ in 0..SAMPLES_PER_BLOCK * 4 {
+            spu.step();
+        }
+    }
+
+    #[test]
+    fn test_reverb_tail() {
+        let mut spu = SPU::new();
+        //One block of a steady 4096 that ends the voice
+        spu.write_half_word(TRANSFER_ADDRESS, 0x200);
+        for value in [0x0100, 0x1111, 0x1111, 0x1111, 0x1111, 0x1111, 0x1111, 0x1111] {
+            spu.write_half_word(TRANSFER_FIFO, value);
+        }
+        spu.write_half_word(0x1F801C00, 0x3FFF);
+        spu.write_half_word(0x1F801C02, 0x3FFF);
+        spu.write_half_word(0x1F801C04, 0x1000);
+        spu.write_half_word(0x1F801C06, 0x200);
+        spu.write_half_word(0x1F801C08, 0x7F00);
+
+        //A 0x800 byte work area at the end of ram, so it wraps every 1024 reverb samples.
+        //The IIR writes the input 0x200 bytes ahead and the comb reads it back 0x100 bytes ahead,
+        //128 reverb samples later. The all-pass filters just pass it through
+        spu.write_half_word(REVERB_BASE, 0xFF00);
+        for (register, value) in [
+            (V_LIN, 0x7FFF),
+            (V_RIN, 0x7FFF),
+            (V_IIR, 0x7FFF),
+            (M_LSAME, 0x40),
+            (M_RSAME, 0x48),
+            (M_LDIFF, 0x50),
+            (M_RDIFF, 0x58),
+            (M_LCOMB1, 0x20),
+            (M_RCOMB1, 0x28),
+            (V_COMB1, 0x7FFF),
+            (M_LAPF1, 0x60),
+            (M_RAPF1, 0x68),
+            (M_LAPF2, 0x70),
+            (M_RAPF2, 0x78),
+            (REVERB_VOLUME_LEFT, 0x7FFF),
+            (REVERB_VOLUME_RIGHT, 0x7FFF),
+            (MAIN_VOLUME_LEFT, 0x3FFF),
+            (MAIN_VOLUME_RIGHT, 0x3FFF),
+        ] {
+            spu.write_half_word(register, value);
+        }
+        spu.write_half_word(EON_LOW, 1);
+        spu.write_half_word(SPUCNT, 0xC080);
+        spu.write_half_word(KON_LOW, 1);
+        spu.write_half_word(0x1F801C0C, 0x4000);
+
+        for _ in 0..SAMPLES_PER_BLOCK {
+            spu.step();
+        }
+        assert!(!spu.voice_active(0));
+        for _ in 0..600 {
+            spu.step();
+        }
+        let mut samples = Vec::new();
+        spu.drain_audio(&mut samples);
+        let (dry, tail) = samples.split_at(SAMPLES_PER_BLOCK * 2);
+        assert!(dry.iter().all(|sample| *sample != 0));
+        assert!(tail.iter().any(|sample| *sample != 0));
+        //Nothing comes back before the comb tap reaches the written input
+        assert!(tail[..100].iter().all(|sample| *sample == 0));
+
+        //The reverb address stays inside the work area as it wraps
+        for _ in 0..4000 {
+            spu.step();
+            assert!(spu.reverb_address >= 0xFF00 * 8);
         }
     }
 }
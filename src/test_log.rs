@@ -0,0 +1,43 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::sync::{Mutex, Once};
+
+/// Keeps every warning so tests can check what was logged. There's only one logger per process, so all tests share it
+struct CaptureLogger {
+    records: Mutex<Vec<(String, Level, String)>>,
+}
+
+impl Log for CaptureLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let entry = (record.target().to_string(), record.level(), record.args().to_string());
+        self.records.lock().unwrap().push(entry);
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CaptureLogger = CaptureLogger {
+    records: Mutex::new(Vec::new()),
+};
+static INSTALL: Once = Once::new();
+
+/// Starts capturing, if another test hasn't already
+pub(crate) fn capture() {
+    INSTALL.call_once(|| {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(LevelFilter::Warn);
+    });
+}
+
+/// Whether a message containing text has been logged to target at level
+pub(crate) fn logged(target: &str, level: Level, text: &str) -> bool {
+    LOGGER
+        .records
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|(t, l, message)| t == target && *l == level && message.contains(text))
+}
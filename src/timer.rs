@@ -1,5 +1,7 @@
 use crate::cpu::{InterruptSource, R3000};
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
 use bit_field::BitField;
+use log::warn;
 
 #[derive(PartialEq, Debug)]
 enum Cause {
@@ -12,6 +14,8 @@ pub struct Timer {
     pub value: u32,
     pub target: u32,
     pub mode: u32,
+    //One-shot timers only interrupt once per mode write
+    irq_fired: bool,
 }
 
 impl Timer {
@@ -20,49 +24,65 @@ impl Timer {
             timer_number: num,
             value: 0,
             target: 0,
-            mode: 0,
+            //Bit 10 is the interrupt request line, which is active low
+            mode: 0x400,
+            irq_fired: false,
         }
     }
 
     pub fn increment(&mut self, cpu: &mut R3000) {
-        self.value += 1;
-        match self.mode.get_bit(3) {
-            true => {
-                if self.value as u16 >= self.target as u16 {
-                    self.value = 0;
-                    self.trigger(cpu, Cause::Full);
-                }
-            }
-            false => {
-                if self.value as u16 >= 0xFFFF {
-                    self.value = 0;
-                    self.trigger(cpu, Cause::Target);
-                }
-            }
+        //With mode bit 3 set the counter goes back to 0 after reaching the target, otherwise after 0xFFFF
+        self.value = if self.mode.get_bit(3) && self.value == self.target {
+            0
+        } else {
+            (self.value + 1) & 0xFFFF
+        };
+
+        if self.value == self.target {
+            self.trigger(cpu, Cause::Target);
+        }
+        if self.value == 0xFFFF {
+            self.trigger(cpu, Cause::Full);
         }
     }
 
     fn trigger(&mut self, cpu: &mut R3000, cause: Cause) {
         match cause {
-            Cause::Full => self.mode.set_bit(12, true),
             Cause::Target => self.mode.set_bit(11, true),
+            Cause::Full => self.mode.set_bit(12, true),
         };
 
-        // println!("Timer {} triggered because of {:?}", self.timer_number, cause);
-        // println!("Timer {} mode is {:#X}", self.timer_number, self.mode);
+        let enabled = match cause {
+            Cause::Target => self.mode.get_bit(4),
+            Cause::Full => self.mode.get_bit(5),
+        };
+        //Bit 6 clear is one-shot mode
+        if !enabled || (self.irq_fired && !self.mode.get_bit(6)) {
+            return;
+        }
 
-        if (self.mode.get_bit(4) == true && cause == Cause::Target)
-            || (self.mode.get_bit(5) == true && cause == Cause::Full)
-        {
-            println!("Firing timer interrupt");
-            let source = match self.timer_number {
-                0 => InterruptSource::TMR0,
-                1 => InterruptSource::TMR1,
-                2 => InterruptSource::TMR2,
-                _ => panic!("Invalid timer source"),
-            };
-            cpu.fire_external_interrupt(source);
+        //Toggle mode flips the request line and only interrupts on the falling edge. Pulse mode always interrupts
+        if self.mode.get_bit(7) {
+            let line = !self.mode.get_bit(10);
+            self.mode.set_bit(10, line);
+            if line {
+                return;
+            }
         }
+
+        self.irq_fired = true;
+        let source = match self.timer_number {
+            0 => InterruptSource::TMR0,
+            1 => InterruptSource::TMR1,
+            2 => InterruptSource::TMR2,
+            _ => panic!("Invalid timer source"),
+        };
+        cpu.fire_external_interrupt(source);
+    }
+
+    /// Timer 2's sync modes 0 and 3 stop the counter. The other timers sync to the blanking signals instead, which isn't emulated
+    fn paused(&self) -> bool {
+        self.timer_number == 2 && self.mode.get_bit(0) && matches!(self.mode.get_bits(1..=2), 0 | 3)
     }
 
     pub fn read_mode(&mut self) -> u32 {
@@ -72,9 +92,27 @@ impl Timer {
         mode
     }
 
+    /// Writing the mode resets the counter and raises the interrupt request line. The reached flags are read only
     pub fn write_mode(&mut self, value: u32) {
-        self.mode = value;
+        self.mode = (value & 0x3FF) | (self.mode & 0x1800);
         self.mode.set_bit(10, true);
+        self.value = 0;
+        self.irq_fired = false;
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u32(self.value);
+        writer.write_u32(self.target);
+        writer.write_u32(self.mode);
+        writer.write_bool(self.irq_fired);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.value = reader.read_u32()?;
+        self.target = reader.read_u32()?;
+        self.mode = reader.read_u32()?;
+        self.irq_fired = reader.read_bool()?;
+        Ok(())
     }
 }
 
@@ -93,6 +131,18 @@ impl TimerState {
         }
     }
 
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        self.timer_0.save_state(writer);
+        self.timer_1.save_state(writer);
+        self.timer_2.save_state(writer);
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.timer_0.load_state(reader)?;
+        self.timer_1.load_state(reader)?;
+        self.timer_2.load_state(reader)
+    }
+
     pub fn update_sys_clock(&mut self, cpu: &mut R3000) {
         let mode0 = self.timer_0.mode.get_bits(8..=9);
         let mode1 = self.timer_1.mode.get_bits(8..=9);
@@ -106,7 +156,7 @@ impl TimerState {
             self.timer_1.increment(cpu);
         }
 
-        if mode2 == 0 || mode2 == 1 {
+        if (mode2 == 0 || mode2 == 1) && !self.timer_2.paused() {
             self.timer_2.increment(cpu);
         }
     }
@@ -130,7 +180,7 @@ impl TimerState {
     pub fn update_sys_div_8(&mut self, cpu: &mut R3000) {
         let mode2 = self.timer_2.mode.get_bits(8..=9);
 
-        if mode2 == 2 || mode2 == 3 {
+        if (mode2 == 2 || mode2 == 3) && !self.timer_2.paused() {
             self.timer_2.increment(cpu);
         }
     }
@@ -149,13 +199,15 @@ impl TimerState {
             0x1F801124 => self.timer_2.read_mode(),
             0x1F801128 => self.timer_2.target,
             _ => {
-                println!("Unknown timer address {:#X}. Returning 0", addr);
+                warn!("TIMER: Unknown word read {:#X}. Returning 0", addr);
                 0
             }
         }
     }
 
     pub fn write_word(&mut self, addr: u32, val: u32) {
+        //The counter registers are 16 bits wide
+        let val = val & 0xFFFF;
         match addr {
             0x1F801100 => self.timer_0.value = val,
             0x1F801104 => self.timer_0.write_mode(val),
@@ -168,74 +220,71 @@ impl TimerState {
             0x1F801120 => self.timer_2.value = val,
             0x1F801124 => self.timer_2.write_mode(val),
             0x1F801128 => self.timer_2.target = val,
-            _ => println!("Unknown timer address"),
+            _ => warn!("TIMER: Unknown word write {:#X} val: {:#X}", addr, val),
         }
     }
 
+    /// Half word accesses go to the low half of each register. The upper halves read as 0 and ignore writes
     pub fn read_half_word(&mut self, addr: u32) -> u16 {
-        //println!("Reading halfword timer");
-        0
-        // match addr {
-        //     0x1F801100 => self.timer_0.value as u16,
-        //     //0x1F801102 => (self.timer_0.value >> 16) as u16,
-
-        //     0x1F801104 => self.timer_0.read_mode() as u16,
-        //     //0x1F801106 => (self.timer_0.read_mode() >> 16) as u16,
-
-        //     0x1F801108 => self.timer_0.target as u16,
-        //     //0x1F80110A => (self.timer_0.target >> 16) as u16,
-
-        //     0x1F801110 => self.timer_1.value as u16,
-        //     //0x1F801112 => (self.timer_1.value >> 16) as u16,
-
-        //     0x1F801114 => self.timer_1.read_mode() as u16,
-        //     //0x1F801116 => (self.timer_1.read_mode() >> 16) as u16,
-
-        //     0x1F801118 => self.timer_1.target as u16,
-        //     //0x1F80111A => (self.timer_1.target >> 16) as u16,
-
-        //     0x1F801120 => self.timer_2.value as u16,
-        //     //0x1F801122 => (self.timer_2.value >> 16) as u16,
-
-        //     0x1F801124 => self.timer_2.read_mode() as u16,
-        //     //0x1F801126 => (self.timer_2.read_mode() >> 16) as u16,
-
-        //     0x1F801128 => self.timer_2.target as u16,
-        //     //0x1F80112A => (self.timer_2.target >> 16) as u16,
-        //     _ => {
-        //         println!("Unknown timer address. Returning 0");
-        //         0
-        //     }
-        //}
+        match addr & 0x2 {
+            0 => self.read_word(addr) as u16,
+            _ => 0,
+        }
     }
 
     pub fn write_half_word(&mut self, addr: u32, value: u16) {
-        //println!("Tried to write timer half");
-        match addr {
-            0x1F801100 => self.timer_0.value &= (value as u32) & 0xFFFF0000,
-            0x1F801102 => self.timer_0.value &= (((value as u32) << 16) & 0xFFFF),
-
-            0x1F801104 => self.timer_0.write_mode(value as u32),
-            //0x1F801106 => self.timer_0.read_mode(value as u32),
-            0x1F801108 => self.timer_0.target = value as u32,
-            0x1F80110A => self.timer_0.target &= (value as u32) << 16,
-
-            0x1F801110 => self.timer_1.value = value as u32,
-            0x1F801112 => self.timer_1.value &= (value as u32) << 16,
-
-            0x1F801114 => self.timer_1.write_mode(value as u32),
-            //0x1F801116 => self.timer_1.read_mode(value as u32),
-            0x1F801118 => self.timer_1.target = value as u32,
-            //0x1F80111A => (self.timer_1.target >> 16) as u16,
-            0x1F801120 => self.timer_2.value = value as u32,
-            //0x1F801122 => (self.timer_2.value >> 16) as u16,
-            0x1F801124 => self.timer_2.write_mode(value as u32),
-            //0x1F801126 => (self.timer_2.read_mode() >> 16) as u16,
-            0x1F801128 => self.timer_2.target = value as u32,
-            //0x1F80112A => (self.timer_2.target >> 16) as u16,
-            _ => {
-                println!("Half wrote unknown timer address {:#X}", addr)
-            }
+        if addr & 0x2 == 0 {
+            self.write_word(addr, value as u32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::MainBus;
+
+    #[test]
+    fn test_target_irq_and_reset() {
+        let bus = MainBus::for_test();
+        let mut cpu = R3000::new(bus);
+        let mut timers = TimerState::new();
+        //Reset at target, IRQ at target, repeat, sysclock
+        timers.write_word(0x1F801108, 100);
+        timers.write_half_word(0x1F801104, 0x58);
+
+        for _ in 0..100 {
+            timers.update_sys_clock(&mut cpu);
+        }
+        assert_eq!(timers.read_word(0x1F801100), 100);
+        assert!(cpu.i_status.get_bit(4));
+        let mode = timers.read_half_word(0x1F801104);
+        assert!(mode.get_bit(11));
+        assert!(!mode.get_bit(12));
+        //Reading the mode clears the reached flags
+        assert!(!timers.read_word(0x1F801104).get_bit(11));
+
+        timers.update_sys_clock(&mut cpu);
+        assert_eq!(timers.read_word(0x1F801100), 0);
+
+        //Repeat mode interrupts again on the next pass
+        cpu.i_status = 0;
+        for _ in 0..100 {
+            timers.update_sys_clock(&mut cpu);
+        }
+        assert!(cpu.i_status.get_bit(4));
+
+        //One-shot mode doesn't
+        timers.write_word(0x1F801104, 0x18);
+        cpu.i_status = 0;
+        for _ in 0..300 {
+            timers.update_sys_clock(&mut cpu);
+        }
+        assert_eq!(cpu.i_status, 1 << 4);
+        cpu.i_status = 0;
+        for _ in 0..300 {
+            timers.update_sys_clock(&mut cpu);
         }
+        assert_eq!(cpu.i_status, 0);
     }
 }